@@ -0,0 +1,90 @@
+//! Run small synthetic workloads headlessly for a number of frames.
+//!
+//! Run with `cargo bench --features bench`. Each workload also prints where the time goes, as
+//! measured by `GameBoy::stats()`.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gb_rs::{
+    cartridge::Cartridge,
+    gameboy::GameBoy,
+    machine::{BootRom, MachineConfig},
+};
+
+/// Number of frames run by each iteration
+const FRAMES: u64 = 60;
+
+/// Build a 32KB ROM with the given code at 0150 (where the entry point jumps to).
+fn rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    // NOP; JP 0150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+    rom
+}
+
+/// The LCD is off, and the CPU runs an arithmetic loop that writes to the WRAM.
+fn cpu_heavy() -> Vec<u8> {
+    rom(&[
+        0xAF, 0xE0, 0x40, // XOR A; LDH (LCDC),A
+        0x21, 0x00, 0xC0, // LD HL,C000
+        // loop:
+        0x04, // INC B
+        0x80, // ADD A,B
+        0x77, // LD (HL),A
+        0x2C, // INC L
+        0xCB, 0x37, // SWAP A
+        0x18, 0xF8, // JR loop
+    ])
+}
+
+/// The background, the window and 40 sprites are displayed, and the CPU is halted until the
+/// next VBlank.
+fn ppu_heavy() -> Vec<u8> {
+    rom(&[
+        0xAF, 0xE0, 0x40, // XOR A; LDH (LCDC),A
+        0x21, 0x00, 0xFE, // LD HL,FE00
+        0x0E, 0xA0, // LD C,A0
+        // Fill the OAM with its own addresses, so that the sprites are spread over the screen
+        0x7D, // oam: LD A,L
+        0x22, // LD (HL+),A
+        0x0D, // DEC C
+        0x20, 0xFB, // JR NZ,oam
+        0x3E, 0x40, 0xE0, 0x4A, 0xE0, 0x4B, // LD A,40; LDH (WY),A; LDH (WX),A
+        0x3E, 0xE3, 0xE0, 0x40, // LD A,E3; LDH (LCDC),A
+        0x3E, 0x01, 0xE0, 0xFF, // LD A,01; LDH (IE),A
+        // halt:
+        0x76, // HALT
+        0xAF, 0xE0, 0x0F, // XOR A; LDH (IF),A
+        0x18, 0xFA, // JR halt
+    ])
+}
+
+fn game_boy(rom: Vec<u8>) -> GameBoy {
+    let config = MachineConfig::default().boot_rom(BootRom::Skip);
+    GameBoy::new(Cartridge::from_bytes(rom), config).unwrap()
+}
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frames");
+    group.throughput(Throughput::Elements(FRAMES));
+    for (name, rom) in [("cpu_heavy", cpu_heavy()), ("ppu_heavy", ppu_heavy())] {
+        let mut gb = game_boy(rom.clone());
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for _ in 0..FRAMES {
+                    gb.run_frame();
+                }
+            })
+        });
+
+        let mut gb = game_boy(rom);
+        gb.set_profiling(true);
+        for _ in 0..FRAMES * 10 {
+            gb.run_frame();
+        }
+        println!("{name}: {}", gb.stats());
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frames);
+criterion_main!(benches);
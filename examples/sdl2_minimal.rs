@@ -0,0 +1,131 @@
+//! A minimal frontend for gb-rs, using SDL2 for video, audio and input.
+//!
+//! This only uses the public API of the `gb_rs` crate, and is meant to show how to embed the
+//! emulator in your own frontend.
+//!
+//! Run it with `cargo run --release --example sdl2_minimal --features sdl2 -- path/to/rom.gb`.
+use std::num::NonZeroU32;
+
+use anyhow::{Context, Result};
+use gb_rs::{
+    cartridge::Cartridge,
+    gameboy::GameBoy,
+    joypad::Button,
+    machine::{BootRom, MachineConfig},
+    SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+use sdl2::{
+    audio::{AudioQueue, AudioSpecDesired},
+    event::Event,
+    keyboard::Keycode,
+    pixels::PixelFormatEnum,
+};
+
+const SCALE: u32 = 3;
+
+fn button_for_key(key: Keycode) -> Option<Button> {
+    match key {
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Right => Some(Button::Right),
+        Keycode::A => Some(Button::A),
+        Keycode::B => Some(Button::B),
+        Keycode::Return => Some(Button::Start),
+        Keycode::Space => Some(Button::Select),
+        _ => None,
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let rom = std::env::args()
+        .nth(1)
+        .context("Usage: sdl2_minimal <ROM>")?;
+
+    let cartridge = Cartridge::load(&rom)?;
+    let config = MachineConfig::default().boot_rom(BootRom::Skip);
+    let mut gb = GameBoy::new(cartridge, config)?;
+
+    let sdl = sdl2::init().map_err(anyhow::Error::msg)?;
+
+    // Video: the emulator's frames are streamed into a texture which is scaled to the window
+    let video = sdl.video().map_err(anyhow::Error::msg)?;
+    let window = video
+        .window(
+            "gb-rs (SDL2)",
+            SCREEN_WIDTH as u32 * SCALE,
+            SCREEN_HEIGHT as u32 * SCALE,
+        )
+        .position_centered()
+        .build()?;
+    // Presenting with vsync is what paces the emulation (at ~60 frames per second)
+    let mut canvas = window.into_canvas().present_vsync().build()?;
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator.create_texture_streaming(
+        PixelFormatEnum::RGBA32,
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    )?;
+
+    // Audio: samples are queued at the end of each frame
+    let audio = sdl.audio().map_err(anyhow::Error::msg)?;
+    let spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(2),
+        samples: Some(1024),
+    };
+    let queue: AudioQueue<f32> = audio.open_queue(None, &spec).map_err(anyhow::Error::msg)?;
+    let sample_rate = NonZeroU32::new(queue.spec().freq as u32)
+        .context("The audio device's sample rate is 0Hz")?;
+    gb.set_sample_rate(sample_rate);
+    queue.resume();
+
+    let mut samples = Vec::new();
+    let mut event_pump = sdl.event_pump().map_err(anyhow::Error::msg)?;
+
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(button) = button_for_key(key) {
+                        gb.set_button(button, true);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    if let Some(button) = button_for_key(key) {
+                        gb.set_button(button, false);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Run the emulation until the next frame is ready
+        let frame = gb.run_frame();
+        texture.update(None, frame, SCREEN_WIDTH * 4)?;
+        canvas
+            .copy(&texture, None, None)
+            .map_err(anyhow::Error::msg)?;
+        canvas.present();
+
+        gb.audio_drain(&mut samples);
+        queue.queue_audio(&samples).map_err(anyhow::Error::msg)?;
+        samples.clear();
+    }
+
+    gb.save();
+
+    Ok(())
+}
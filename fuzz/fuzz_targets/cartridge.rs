@@ -0,0 +1,23 @@
+//! Parse arbitrary ROM files, and access a valid cartridge through its MBC: corrupted headers and
+//! truncated ROMs must be reported as errors, not panics.
+#![no_main]
+
+use gb_rs::cartridge::Cartridge;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut cart) = Cartridge::parse(data.to_vec()) else {
+        return;
+    };
+    let _ = cart.header().cartridge_type.to_string();
+
+    // Switch through the banks with the bytes of the ROM itself
+    for (i, &byte) in data.iter().take(0x100).enumerate() {
+        let addr = (i as u16) << 7;
+        cart.write_rom(addr, byte);
+        cart.read_rom(addr);
+        cart.read_rom(0x4000 | addr);
+        cart.write_ram(0xA000 | (addr & 0x1FFF), byte);
+        cart.read_ram(0xA000 | (addr & 0x1FFF));
+    }
+});
@@ -0,0 +1,34 @@
+//! Disassemble arbitrary bytes, then execute them on a CPU connected to plain RAM: neither should
+//! ever panic or hang, whatever the code does.
+#![no_main]
+
+use gb_rs::{disasm::Disassembler, testing::FlatCpu, CpuState};
+use libfuzzer_sys::fuzz_target;
+
+/// Enough to run through loops and jumps, while keeping each run short
+const MAX_INSTRUCTIONS: usize = 10_000;
+
+fuzz_target!(|data: &[u8]| {
+    for instr in Disassembler::new(data).run() {
+        let _ = instr.to_string();
+    }
+
+    // The first 2 bytes are the initial PC, so that the code also runs across the end of the
+    // address space
+    let Some((pc, code)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let pc = u16::from_le_bytes(*pc);
+    let mut cpu = FlatCpu::new();
+    for (i, &byte) in code.iter().take(0x10000).enumerate() {
+        cpu.write(pc.wrapping_add(i as u16), byte);
+    }
+    cpu.set_state(&CpuState {
+        pc,
+        sp: 0xFFFE,
+        ..CpuState::default()
+    });
+    for _ in 0..MAX_INSTRUCTIONS {
+        cpu.step();
+    }
+});
@@ -0,0 +1,389 @@
+//! Run the test ROM suites and keep score of how many pass.
+//!
+//! The suites are expected in the layout of the [game-boy-test-roms] collection (`just test_roms`
+//! downloads it in `test_roms/`). How a ROM reports its result depends on the suite:
+//! - Blargg's tests print "Passed" or "Failed" on the serial port.
+//! - Mooneye's tests execute `LD B,B` when they're done, with the Fibonacci sequence 3, 5, 8, 13,
+//!   21, 34 in B, C, D, E, H and L if they passed.
+//! - Tests such as dmg-acid2 only draw their result on screen: the last frame is compared to the
+//!   reference screenshot found next to the ROM (`<name>-dmg.png`), using the grayscale palette.
+//!
+//! [game-boy-test-roms]: https://github.com/c-sp/gameboy-test-roms
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::File,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+use gb_rs::{
+    cartridge::Cartridge,
+    frame_hash,
+    gameboy::GameBoy,
+    machine::{BootRom, MachineConfig},
+    testing::load_screenshot,
+    DmgPalette, Reg,
+};
+use log::{info, warn};
+
+/// Suites run when none are given on the command line
+pub const DEFAULT_SUITES: &[&str] = &["blargg", "mooneye-test-suite", "dmg-acid2"];
+
+/// Directories of the suites that don't contain automated tests
+const SKIPPED_DIRS: &[&str] = &["manual-only", "utils"];
+
+/// Values of B, C, D, E, H and L when a Mooneye test passes
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// The ROM didn't report anything before the frame limit
+    Timeout,
+    /// The emulator panicked
+    Crash,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::Timeout => "timeout",
+            Outcome::Crash => "crash",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    /// Path of the ROM, relative to the test ROMs directory
+    pub rom: String,
+    pub outcome: Outcome,
+    /// Number of frames the ROM ran for
+    pub frames: u32,
+    /// Hash of the last frame
+    pub frame_hash: u64,
+}
+
+impl TestResult {
+    /// The directory the ROM is in, e.g. `blargg/cpu_instrs/individual`
+    pub fn category(&self) -> &str {
+        self.rom.rsplit_once('/').map_or("", |(dir, _)| dir)
+    }
+}
+
+/// Run all the ROMs of the given suites, print the scoreboard and save the results to `output`.
+pub fn run(dir: &Path, suites: &[String], max_frames: u32, output: &Path) -> Result<()> {
+    let mut roms = Vec::new();
+    for suite in suites {
+        let path = dir.join(suite);
+        if !path.is_dir() {
+            bail!(
+                "Test suite {} not found (run `just test_roms` to download the test ROMs)",
+                path.display()
+            );
+        }
+        collect_roms(&path, &mut roms)?;
+    }
+    roms.sort();
+    info!("Running {} test ROMs from {}", roms.len(), dir.display());
+
+    let results = run_all(dir, &roms, max_frames);
+    print!("{}", scoreboard(&results));
+
+    File::create(output)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, to_json(&results).as_bytes()))
+        .with_context(|| format!("Failed to write results to {}", output.display()))?;
+    info!("Results saved to {}", output.display());
+
+    Ok(())
+}
+
+/// Recursively look for the DMG test ROMs in `dir`.
+fn collect_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_dir() {
+            if !SKIPPED_DIRS.contains(&name.as_ref()) && !name.starts_with("cgb") {
+                collect_roms(&path, roms)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext == "gb") && runs_on_dmg(&name) {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether the model suffix of a Mooneye test's name (e.g. `-GS`, `-dmgABC`, `-cgb`) includes the
+/// DMG. Tests without a suffix run on all models.
+fn runs_on_dmg(file_name: &str) -> bool {
+    let stem = file_name.trim_end_matches(".gb");
+    let Some((_, suffix)) = stem.rsplit_once('-') else {
+        return true;
+    };
+    if suffix.chars().all(|c| "GSCA".contains(c)) {
+        suffix.contains('G')
+    } else if ["dmg", "mgb", "sgb", "cgb", "agb", "ags"]
+        .iter()
+        .any(|model| suffix.starts_with(model))
+    {
+        suffix.contains("dmgABC")
+    } else {
+        true
+    }
+}
+
+/// Run the ROMs on all the available cores.
+fn run_all(dir: &Path, roms: &[PathBuf], max_frames: u32) -> Vec<TestResult> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(roms.len()));
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+    // The default hook would print a backtrace for every crashing ROM
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|info| warn!("{}", info)));
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                while let Some(rom) = roms.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let name = rom
+                        .strip_prefix(dir)
+                        .unwrap_or(rom)
+                        .to_string_lossy()
+                        .replace('\\', "/");
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| run_rom(rom, max_frames)))
+                        .unwrap_or(Ok((Outcome::Crash, 0, 0)));
+                    let (outcome, frames, frame_hash) = result.unwrap_or_else(|e| {
+                        warn!("{}: {:#}", name, e);
+                        (Outcome::Crash, 0, 0)
+                    });
+                    results.lock().unwrap().push(TestResult {
+                        rom: name,
+                        outcome,
+                        frames,
+                        frame_hash,
+                    });
+                }
+            });
+        }
+    });
+    panic::set_hook(hook);
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|a, b| a.rom.cmp(&b.rom));
+    results
+}
+
+/// Run a single ROM until it reports its result, and return it with the number of frames it took
+/// and the hash of the last frame.
+fn run_rom(rom: &Path, max_frames: u32) -> Result<(Outcome, u32, u64)> {
+    let data = std::fs::read(rom).with_context(|| format!("Failed to read {}", rom.display()))?;
+    let reference = reference_screenshot(rom)?;
+    // Not using `Cartridge::load()`, as some tests deliberately have a broken header, and we
+    // don't want to create save files
+    let config = MachineConfig::default()
+        .boot_rom(BootRom::Skip)
+        .palette(DmgPalette::GRAYSCALE)
+        .soft_break(true);
+    let mut gb = GameBoy::new(Cartridge::from_bytes(data), config)?;
+
+    let mut serial = Vec::new();
+    for frame in 1..=max_frames {
+        gb.run_frame();
+        serial.extend(gb.take_serial_output());
+        let outcome = if contains(&serial, b"Passed") {
+            Some(Outcome::Pass)
+        } else if contains(&serial, b"Failed") {
+            Some(Outcome::Fail)
+        } else if gb.is_paused() {
+            let regs = [Reg::B, Reg::C, Reg::D, Reg::E, Reg::H, Reg::L].map(|r| gb.reg(r));
+            if regs == MOONEYE_PASS {
+                Some(Outcome::Pass)
+            } else if let Some(reference) = &reference {
+                Some(compare(gb.run_frame(), reference))
+            } else {
+                Some(Outcome::Fail)
+            }
+        } else {
+            None
+        };
+        if let Some(outcome) = outcome {
+            return Ok((outcome, frame, frame_hash(gb.run_frame())));
+        }
+    }
+
+    let frame = gb.run_frame();
+    let outcome = match &reference {
+        Some(reference) => compare(frame, reference),
+        None => Outcome::Timeout,
+    };
+    Ok((outcome, max_frames, frame_hash(frame)))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn compare(frame: &[u8], reference: &[u8]) -> Outcome {
+    if frame_hash(frame) == frame_hash(reference) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail
+    }
+}
+
+/// Load the DMG reference screenshot of the given ROM, if there's one, as an RGBA frame.
+fn reference_screenshot(rom: &Path) -> Result<Option<Vec<u8>>> {
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    let Some(path) = ["-dmg.png", "-dmg-cgb.png"]
+        .iter()
+        .map(|suffix| rom.with_file_name(format!("{stem}{suffix}")))
+        .find(|path| path.is_file())
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(load_screenshot(&path)?))
+}
+
+/// Number of passed tests and total number of tests
+type Score = (usize, usize);
+
+/// Scores per category and overall
+fn scores(results: &[TestResult]) -> (BTreeMap<&str, Score>, Score) {
+    let mut categories = BTreeMap::new();
+    let mut total = (0, 0);
+    for result in results {
+        let passed = usize::from(result.outcome == Outcome::Pass);
+        let score = categories.entry(result.category()).or_insert((0, 0));
+        score.0 += passed;
+        score.1 += 1;
+        total.0 += passed;
+        total.1 += 1;
+    }
+    (categories, total)
+}
+
+fn scoreboard(results: &[TestResult]) -> String {
+    let (categories, (passed, total)) = scores(results);
+    let width = categories.keys().map(|c| c.len()).max().unwrap_or(0).max(5);
+    let mut s = String::new();
+    for (category, (passed, total)) in &categories {
+        let _ = writeln!(s, "{category:<width$}  {passed:>4}/{total:<4}");
+        for result in results
+            .iter()
+            .filter(|r| r.category() == *category && r.outcome != Outcome::Pass)
+        {
+            let name = result.rom.rsplit('/').next().unwrap_or_default();
+            let _ = writeln!(s, "    {:<8} {}", result.outcome.as_str(), name);
+        }
+    }
+    let _ = writeln!(s, "{:<width$}  {passed:>4}/{total:<4}", "TOTAL");
+    s
+}
+
+/// Serialize the results to JSON, one test per line so that the results of two runs can be
+/// compared with `diff`.
+fn to_json(results: &[TestResult]) -> String {
+    let (categories, (passed, total)) = scores(results);
+    let mut s = String::from("{\n");
+    let _ = writeln!(s, "  \"passed\": {passed},\n  \"total\": {total},");
+    s.push_str("  \"categories\": {\n");
+    for (i, (category, (passed, total))) in categories.iter().enumerate() {
+        let sep = if i + 1 < categories.len() { "," } else { "" };
+        let _ = writeln!(
+            s,
+            "    {}: {{\"passed\": {passed}, \"total\": {total}}}{sep}",
+            json_string(category)
+        );
+    }
+    s.push_str("  },\n  \"results\": [\n");
+    for (i, result) in results.iter().enumerate() {
+        let sep = if i + 1 < results.len() { "," } else { "" };
+        let _ = writeln!(
+            s,
+            "    {{\"rom\": {}, \"result\": \"{}\", \"frames\": {}, \"frame_hash\": \"{:016x}\"}}{sep}",
+            json_string(&result.rom),
+            result.outcome.as_str(),
+            result.frames,
+            result.frame_hash
+        );
+    }
+    s.push_str("  ]\n}\n");
+    s
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(rom: &str, outcome: Outcome) -> TestResult {
+        TestResult {
+            rom: rom.to_string(),
+            outcome,
+            frames: 10,
+            frame_hash: 0xabc,
+        }
+    }
+
+    #[test]
+    fn test_runs_on_dmg() {
+        assert!(runs_on_dmg("add_sp_e_timing.gb"));
+        assert!(runs_on_dmg("di_timing-GS.gb"));
+        assert!(runs_on_dmg("boot_regs-dmgABC.gb"));
+        assert!(runs_on_dmg("boot_hwio-dmgABCmgb.gb"));
+        assert!(runs_on_dmg("dmg-acid2.gb"));
+        assert!(!runs_on_dmg("boot_regs-dmg0.gb"));
+        assert!(!runs_on_dmg("boot_div-S.gb"));
+        assert!(!runs_on_dmg("boot_regs-cgb.gb"));
+    }
+
+    #[test]
+    fn test_scoreboard() {
+        let results = vec![
+            result("blargg/cpu_instrs/01-special.gb", Outcome::Pass),
+            result("blargg/cpu_instrs/02-interrupts.gb", Outcome::Timeout),
+            result("dmg-acid2/dmg-acid2.gb", Outcome::Pass),
+        ];
+        let (categories, total) = scores(&results);
+        assert_eq!(categories["blargg/cpu_instrs"], (1, 2));
+        assert_eq!(categories["dmg-acid2"], (1, 1));
+        assert_eq!(total, (2, 3));
+        assert!(scoreboard(&results).contains("    timeout  02-interrupts.gb\n"));
+
+        let json = to_json(&results);
+        assert!(json.starts_with("{\n  \"passed\": 2,\n  \"total\": 3,\n"));
+        assert!(json.contains(
+            "    {\"rom\": \"dmg-acid2/dmg-acid2.gb\", \"result\": \"pass\", \"frames\": 10, \"frame_hash\": \"0000000000000abc\"}\n"
+        ));
+        assert_eq!(json_string("a\"b\\"), r#""a\"b\\""#);
+    }
+}
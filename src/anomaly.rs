@@ -0,0 +1,79 @@
+//! Reporting of anomalies, i.e. situations that the emulated hardware would survive but that the
+//! emulator doesn't expect (invalid register accesses, impossible writes, broken headers...).
+//!
+//! By default anomalies are logged and recorded, and emulation carries on with a sensible fallback
+//! value (usually open bus), so that a broken ROM can't bring down the application embedding the
+//! emulator, and long fuzzing or soak runs don't abort on the first one. Recorded anomalies can be
+//! retrieved with [`GameBoy::take_anomalies()`](crate::gameboy::GameBoy::take_anomalies).
+//!
+//! With the `strict` feature enabled, an anomaly is a bug and panics instead, which is what you
+//! want when working on the emulator.
+
+use std::cell::RefCell;
+
+/// Something unexpected that happened during emulation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    pub message: String,
+}
+
+/// Anomalies recorded by a component (APU, cartridge...) until the [`GameBoy`] collects them.
+///
+/// [`GameBoy`]: crate::gameboy::GameBoy
+#[derive(Debug, Default)]
+pub(crate) struct AnomalyLog(RefCell<Vec<Anomaly>>);
+
+impl AnomalyLog {
+    /// Record an anomaly. Only used by the `anomaly!` macro.
+    #[cfg(not(feature = "strict"))]
+    pub(crate) fn report(&self, message: String) {
+        log::warn!("Anomaly: {}", message);
+        self.0.borrow_mut().push(Anomaly { message });
+    }
+
+    /// Move the anomalies recorded so far to the end of `anomalies`.
+    pub(crate) fn drain_into(&self, anomalies: &mut Vec<Anomaly>) {
+        anomalies.append(&mut self.0.borrow_mut());
+    }
+}
+
+/// Report an anomaly.
+///
+/// Records the anomaly in the given [`AnomalyLog`] and evaluates to `$fallback`, or panics with
+/// the given message if the `strict` feature is enabled.
+macro_rules! anomaly {
+    ($log:expr, $fallback:expr, $($arg:tt)+) => {{
+        #[cfg(not(feature = "strict"))]
+        {
+            $log.report(format!($($arg)+));
+            $fallback
+        }
+        #[cfg(feature = "strict")]
+        {
+            panic!($($arg)+)
+        }
+    }};
+}
+
+#[cfg(all(test, not(feature = "strict")))]
+mod tests {
+    use crate::{bus::Bus, cartridge::Cartridge};
+
+    #[test]
+    fn test_anomalies_are_recorded() {
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        let other = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        assert_eq!(bus.apu.read_wav(0xFF40), 0xFF);
+        bus.apu.write_io(0xFF27, 0x12);
+        assert_eq!(bus.cartridge.read_ram(0x2000), 0xFF);
+        bus.cartridge.write_ram(0x2000, 0x12);
+
+        let anomalies = bus.take_anomalies();
+        assert_eq!(anomalies.len(), 4);
+        assert_eq!(anomalies[1].message, "Invalid sound register ff27");
+        assert_eq!(anomalies[2].message, "Invalid external RAM address 0x2000");
+        assert!(bus.take_anomalies().is_empty());
+        // Each Game Boy has its own anomalies
+        assert!(other.take_anomalies().is_empty());
+    }
+}
@@ -7,7 +7,15 @@ pub(crate) use noise::NoiseChannel;
 pub(crate) use tone::ToneChannel;
 pub(crate) use wave::WaveChannel;
 
-use super::Timer;
+use super::{frame_sequencer::FrameSequencer, Timer};
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter, Stateful};
+
+/// Convert the 4-bit digital output of a channel to the analog output of its DAC: 0 maps to 1.0
+/// and 15 to -1.0.
+fn dac(digital: u8) -> f32 {
+    1.0 - digital as f32 / 7.5
+}
 
 #[derive(Debug)]
 struct LengthCounter {
@@ -38,8 +46,24 @@ impl LengthCounter {
         self.length_counter = length;
     }
 
-    fn enable(&mut self) {
-        self.length_enabled = true;
+    /// Enable or disable the length counter (bit 6 of NRx4).
+    ///
+    /// Enabling the length counter when the next step of the frame sequencer doesn't clock it
+    /// clocks it an extra time. Returns `true` if that made it reach 0, in which case the channel
+    /// should be disabled (unless it's also being triggered).
+    fn set_enabled(&mut self, enabled: bool, frame_sequencer: &FrameSequencer) -> bool {
+        let was_enabled = self.length_enabled;
+        self.length_enabled = enabled;
+        if !was_enabled
+            && enabled
+            && !frame_sequencer.next_step_clocks_length()
+            && self.length_counter > 0
+        {
+            self.length_counter -= 1;
+            return self.length_counter == 0;
+        }
+
+        false
     }
 
     fn reset(&mut self) {
@@ -47,9 +71,16 @@ impl LengthCounter {
         self.length_counter = 0;
     }
 
-    fn trigger(&mut self) {
+    /// Handle the triggering of the channel.
+    ///
+    /// An expired counter is reloaded with the maximum length, minus the extra clock it gets if
+    /// it's enabled and the next step of the frame sequencer doesn't clock it.
+    fn trigger(&mut self, frame_sequencer: &FrameSequencer) {
         if self.length_counter == 0 {
             self.length_counter = self.default_length;
+            if self.length_enabled && !frame_sequencer.next_step_clocks_length() {
+                self.length_counter -= 1;
+            }
         }
     }
 }
@@ -112,3 +143,32 @@ impl VolumeEnvelope {
         self.timer.reset();
     }
 }
+
+impl Stateful for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.length_enabled);
+        w.u16(self.length_counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.length_enabled = r.bool()?;
+        self.length_counter = r.u16()?;
+        Ok(())
+    }
+}
+
+impl Stateful for VolumeEnvelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.start_volume);
+        w.u8(self.volume);
+        w.bool(self.volume_increase);
+        self.timer.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.start_volume = r.u8()?;
+        self.volume = r.u8()?;
+        self.volume_increase = r.bool()?;
+        self.timer.load_state(r)
+    }
+}
@@ -3,27 +3,45 @@ use std::ops::ShrAssign;
 use bitvec::{field::BitField, order::Lsb0, view::BitView};
 use log::trace;
 
-use crate::apu::{frame_sequencer::FrameSequencer, Timer};
+use crate::apu::{frame_sequencer::FrameSequencer, ChannelSnapshot, Timer};
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter, Stateful};
+use crate::timing::CYCLES_PER_SECOND;
 
-use super::{LengthCounter, VolumeEnvelope};
+use super::{dac, LengthCounter, VolumeEnvelope};
+
+/// Value of the LFSR when the channel is triggered: all 15 bits set
+const LSFR_TRIGGER_VALUE: u16 = 0x7FFF;
 
 /// Linear Feedback Shift Register
+///
+/// This is the 15-bit form of the register: each clock, bits 0 and 1 are XORed, the register is
+/// shifted right, and the result goes into bit 14 (and bit 6 too in 7-bit mode). It starts with
+/// all bits set, and the output is bit 0 inverted. (Pan Docs describe the equivalent complemented
+/// form, which starts at 0 and uses XNOR.)
 #[derive(Debug)]
 struct Lsfr {
     reg: u16,
+    /// Short (7-bit) mode, from NR43 bit 3
     width_mode: bool,
 }
 
 impl Lsfr {
     fn new() -> Self {
         Self {
-            reg: 0xffff,
+            reg: LSFR_TRIGGER_VALUE,
             width_mode: false,
         }
     }
 
+    /// Restart the sequence, when the channel is triggered. The width mode is left alone.
+    fn trigger(&mut self) {
+        self.reg = LSFR_TRIGGER_VALUE;
+    }
+
+    /// Power off.
     fn reset(&mut self) {
-        self.reg = 0x0000;
+        self.reg = LSFR_TRIGGER_VALUE;
         self.width_mode = false;
     }
 
@@ -91,7 +109,7 @@ impl NoiseChannel {
 
     pub(crate) fn nr41(&self) -> u8 {
         // NR41 is write-only
-        0xFF
+        0
     }
 
     pub(crate) fn set_nr41(&mut self, b: u8) {
@@ -99,7 +117,7 @@ impl NoiseChannel {
     }
 
     pub(crate) fn nr42(&self) -> u8 {
-        let mut res = 0xFF;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
 
         bits[4..=7].store(self.volume_envelope.start_volume);
@@ -123,7 +141,7 @@ impl NoiseChannel {
     }
 
     pub(crate) fn nr43(&self) -> u8 {
-        let mut res = 0xFF;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
 
         bits[4..=7].store(self.shift);
@@ -149,7 +167,7 @@ impl NoiseChannel {
     }
 
     pub(crate) fn nr44(&self) -> u8 {
-        let mut res = 0xff;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
 
         bits.set(6, self.length_counter.length_enabled);
@@ -157,21 +175,19 @@ impl NoiseChannel {
         res
     }
 
-    pub(crate) fn set_nr44(&mut self, b: u8) {
+    pub(crate) fn set_nr44(&mut self, b: u8, frame_sequencer: &FrameSequencer) {
         let bits = b.view_bits::<Lsb0>();
-        if bits[6] {
-            self.length_counter.enable();
-        } else {
-            self.length_counter.reset();
+        if self.length_counter.set_enabled(bits[6], frame_sequencer) && !bits[7] {
+            self.enabled = false;
         }
 
         if bits[7] {
             // trigger
             trace!("Noise channel triggered");
             self.enabled = true;
-            self.lsfr.reset();
+            self.lsfr.trigger();
             self.volume_envelope.trigger();
-            self.length_counter.trigger();
+            self.length_counter.trigger(frame_sequencer);
             if !self.is_dac_on() {
                 self.enabled = false;
             }
@@ -188,15 +204,29 @@ impl NoiseChannel {
         self.timer.reset();
     }
 
-    pub(crate) fn output(&self) -> i16 {
-        if self.enabled && self.is_dac_on() && self.lsfr.output() {
-            self.volume_envelope.volume() as i16
+    /// Analog output of the channel, or 0.0 if its DAC is off.
+    pub(crate) fn output(&self) -> f32 {
+        if !self.is_dac_on() {
+            return 0.0;
+        }
+        if self.enabled && self.lsfr.output() {
+            dac(self.volume_envelope.volume())
         } else {
-            0
+            dac(0)
         }
     }
 
-    fn is_dac_on(&self) -> bool {
+    pub(crate) fn snapshot(&self) -> ChannelSnapshot {
+        ChannelSnapshot {
+            enabled: self.enabled,
+            dac_enabled: self.is_dac_on(),
+            volume: self.volume_envelope.volume(),
+            frequency: CYCLES_PER_SECOND as f32 / self.timer.period.max(1) as f32,
+            samples: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_dac_on(&self) -> bool {
         self.volume_envelope.is_dac_on()
     }
 
@@ -204,3 +234,82 @@ impl NoiseChannel {
         self.enabled
     }
 }
+
+impl Stateful for NoiseChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.lsfr.reg);
+        w.bool(self.lsfr.width_mode);
+        self.timer.save_state(w);
+        self.length_counter.save_state(w);
+        self.volume_envelope.save_state(w);
+        w.u8(self.base_divisor);
+        w.u8(self.shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.enabled = r.bool()?;
+        self.lsfr.reg = r.u16()?;
+        self.lsfr.width_mode = r.bool()?;
+        self.timer.load_state(r)?;
+        self.length_counter.load_state(r)?;
+        self.volume_envelope.load_state(r)?;
+        self.base_divisor = r.u8()?;
+        self.shift = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first outputs of the LFSR after a trigger, as a string of 0s and 1s.
+    fn outputs(width_mode: bool) -> String {
+        let mut lsfr = Lsfr::new();
+        lsfr.width_mode = width_mode;
+        lsfr.trigger();
+        (0..40)
+            .map(|_| {
+                lsfr.tick();
+                if lsfr.output() {
+                    '1'
+                } else {
+                    '0'
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lsfr() {
+        assert_eq!(outputs(false), "0000000000000011111111111111011111111111");
+        assert_eq!(outputs(true), "0000001111110111110011110101110000110111");
+
+        // The 7-bit sequence repeats every 127 clocks, the 15-bit one every 32767
+        for (width_mode, mask, period) in [(true, 0x7F, 127), (false, 0x7FFF, 32767)] {
+            let mut lsfr = Lsfr::new();
+            lsfr.width_mode = width_mode;
+            lsfr.trigger();
+            let mut n = 0;
+            loop {
+                lsfr.tick();
+                n += 1;
+                if lsfr.reg & mask == LSFR_TRIGGER_VALUE & mask {
+                    break;
+                }
+            }
+            assert_eq!(n, period);
+        }
+    }
+
+    #[test]
+    fn test_trigger_keeps_width_mode() {
+        let mut channel = NoiseChannel::new();
+        channel.set_nr42(0xF0);
+        channel.set_nr43(0x08);
+        channel.set_nr44(0x80, &FrameSequencer::default());
+        assert_eq!(channel.nr43(), 0x08);
+        assert_ne!(channel.lsfr.reg, 0);
+    }
+}
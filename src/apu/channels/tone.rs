@@ -1,9 +1,12 @@
 use bitvec::{field::BitField, order::Lsb0, view::BitView};
 use log::trace;
 
-use crate::apu::{frame_sequencer::FrameSequencer, Timer};
+use crate::apu::{frame_sequencer::FrameSequencer, ChannelSnapshot, Timer};
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter, Stateful};
+use crate::timing::CYCLES_PER_SECOND;
 
-use super::{LengthCounter, VolumeEnvelope};
+use super::{dac, LengthCounter, VolumeEnvelope};
 #[derive(Debug)]
 pub(crate) struct ToneChannel {
     enabled: bool,
@@ -53,21 +56,28 @@ impl ToneChannel {
         }
         if frame_sequencer.sweep_triggered() {
             if let Some(ref mut sweep) = self.frequency_sweep {
-                match sweep.tick() {
-                    FrequencySweepResult::NewFreq(f) => self.freq_timer.period = (2048 - f) * 4,
-                    FrequencySweepResult::Disable => self.enabled = false,
-                    FrequencySweepResult::Nop => (),
+                let result = sweep.tick();
+                if let Some(freq) = result.new_freq {
+                    self.set_frequency(freq);
+                }
+                if result.overflow {
+                    self.enabled = false;
                 }
             }
         }
     }
 
+    /// Write back a frequency computed by the sweep unit to NRx3/NRx4.
+    fn set_frequency(&mut self, freq: u16) {
+        self.freq_lo = freq as u8;
+        self.freq_hi = (freq >> 8) as u8 & 0x07;
+        self.freq_timer.period = (2048 - freq) * 4;
+    }
+
     pub(crate) fn nrx0(&self) -> u8 {
-        let mut res = 0xFF;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
         if let Some(ref sweep) = self.frequency_sweep {
-            // bit 7 is always set
-            bits.set(7, true);
             bits[4..=6].store(sweep.timer.period as u8);
             bits.set(3, sweep.should_negate);
             bits[0..=2].store(sweep.shift);
@@ -82,12 +92,15 @@ impl ToneChannel {
             let sweep_time = bits[4..=6].load::<u8>();
             let negate = bits[3];
             let shift = bits[0..=2].load::<u8>();
-            sweep.load(sweep_time as u16, negate, shift);
+            if sweep.load(sweep_time as u16, negate, shift) {
+                // Leaving negate mode after a subtraction was made disables the channel
+                self.enabled = false;
+            }
         }
     }
 
     pub(crate) fn nrx1(&self) -> u8 {
-        let mut res = 0xFF;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
         bits[6..=7].store(self.wave_generator.duty as u8);
 
@@ -108,7 +121,7 @@ impl ToneChannel {
     }
 
     pub(crate) fn nrx2(&self) -> u8 {
-        let mut res = 0xFF;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
 
         bits[4..=7].store(self.volume_envelope.start_volume);
@@ -138,7 +151,7 @@ impl ToneChannel {
 
     pub(crate) fn nrx3(&self) -> u8 {
         // NRx3 is write-only
-        0xFF
+        0
     }
 
     pub(crate) fn set_nrx3(&mut self, b: u8) {
@@ -147,29 +160,27 @@ impl ToneChannel {
     }
 
     pub(crate) fn nrx4(&self) -> u8 {
-        let mut res = 0xFF;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
-        // only bit 6 can be read back
+        // only bit 6 can be read back, the rest is masked by the APU
         bits.set(6, self.length_counter.length_enabled);
         trace!("Returning {:08b} for NRx4", res);
         res
     }
 
-    pub(crate) fn set_nrx4(&mut self, b: u8) {
+    pub(crate) fn set_nrx4(&mut self, b: u8, frame_sequencer: &FrameSequencer) {
         trace!("setting NRx4 to {:08b}", b);
         let bits = b.view_bits::<Lsb0>();
 
-        if bits[6] {
-            self.length_counter.enable();
-        } else {
-            self.length_counter.reset();
+        if self.length_counter.set_enabled(bits[6], frame_sequencer) && !bits[7] {
+            self.enabled = false;
         }
         self.freq_hi = bits[0..=2].load::<u8>();
 
         if bits[7] {
             // Trigger
             self.enabled = true;
-            self.length_counter.trigger();
+            self.length_counter.trigger(frame_sequencer);
             let freq = ((self.freq_hi as u16) << 8) + self.freq_lo as u16;
             if freq == 0 {
                 // should we do this?
@@ -177,10 +188,14 @@ impl ToneChannel {
             }
             self.freq_timer.period = (2048 - freq) * 4;
             self.freq_timer.reset();
+            // The duty step is not reset on trigger, only when the APU is powered off
             // Reset volume envelope
             self.volume_envelope.trigger();
             if let Some(ref mut sweep) = self.frequency_sweep {
-                sweep.trigger(freq);
+                if sweep.trigger(freq) {
+                    // The initial overflow check failed
+                    self.enabled = false;
+                }
             }
             if !self.is_dac_on() {
                 // If DAC is off, disable the channel
@@ -191,15 +206,30 @@ impl ToneChannel {
         }
     }
 
-    pub(crate) fn output(&self) -> i16 {
-        if self.enabled && self.is_dac_on() && self.wave_generator.output() {
-            self.volume_envelope.volume() as i16
+    /// Analog output of the channel, or 0.0 if its DAC is off.
+    pub(crate) fn output(&self) -> f32 {
+        if !self.is_dac_on() {
+            return 0.0;
+        }
+        if self.enabled && self.wave_generator.output() {
+            dac(self.volume_envelope.volume())
         } else {
-            0
+            dac(0)
         }
     }
 
-    fn is_dac_on(&self) -> bool {
+    pub(crate) fn snapshot(&self) -> ChannelSnapshot {
+        ChannelSnapshot {
+            enabled: self.enabled,
+            dac_enabled: self.is_dac_on(),
+            volume: self.volume_envelope.volume(),
+            // 8 steps per period of the square wave
+            frequency: CYCLES_PER_SECOND as f32 / (self.freq_timer.period.max(1) as f32 * 8.0),
+            samples: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_dac_on(&self) -> bool {
         self.volume_envelope.is_dac_on()
     }
 
@@ -222,6 +252,14 @@ impl ToneChannel {
     }
 }
 
+/// Square waveforms for the 4 duty cycles (12.5%, 25%, 50% and 75%), indexed by step.
+const DUTY_TABLES: [[bool; 8]; 4] = [
+    [false, false, false, false, false, false, false, true],
+    [true, false, false, false, false, false, false, true],
+    [true, false, false, false, false, true, true, true],
+    [false, true, true, true, true, true, true, false],
+];
+
 #[derive(Debug)]
 struct SquareWaveGenerator {
     duty: Duty,
@@ -245,12 +283,7 @@ impl SquareWaveGenerator {
     }
 
     pub fn output(&self) -> bool {
-        match self.duty {
-            Duty::Duty0 => self.step == 7,
-            Duty::Duty1 => self.step == 0 || self.step == 7,
-            Duty::Duty2 => self.step == 0 || self.step == 5 || self.step == 6 || self.step == 7,
-            Duty::Duty3 => self.step != 0 && self.step != 7,
-        }
+        DUTY_TABLES[self.duty as usize][self.step as usize]
     }
 
     pub fn reset(&mut self) {
@@ -275,16 +308,18 @@ impl From<u8> for Duty {
             1 => Duty::Duty1,
             2 => Duty::Duty2,
             3 => Duty::Duty3,
-            _ => panic!("Unsupported value for Duty enum: {}", d),
+            _ => unreachable!("Unsupported value for Duty enum: {}", d),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum FrequencySweepResult {
-    NewFreq(u16),
-    Disable,
-    Nop,
+/// Outcome of a sweep clock.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct FrequencySweepResult {
+    /// New frequency to write back to the channel, if any.
+    new_freq: Option<u16>,
+    /// Whether an overflow check failed, which disables the channel.
+    overflow: bool,
 }
 
 #[derive(Debug)]
@@ -292,6 +327,8 @@ struct FrequencySweep {
     enabled: bool,
     shadow_register: u16,
     should_negate: bool,
+    /// Whether a frequency was calculated in negate mode since the last trigger.
+    negate_used: bool,
     timer: Timer,
     shift: u8,
 }
@@ -302,43 +339,71 @@ impl FrequencySweep {
             enabled: false,
             shadow_register: 0,
             should_negate: false,
+            negate_used: false,
             timer: Timer::new(0),
             shift: 0,
         }
     }
 
     fn tick(&mut self) -> FrequencySweepResult {
-        if self.timer.tick() && self.enabled && self.shift != 0 {
-            let delta = self.shadow_register >> self.shift as u16;
-            let new_freq = if self.should_negate {
-                self.shadow_register.wrapping_sub(delta)
-            } else {
-                self.shadow_register.wrapping_add(delta)
-            };
-            let overflow = self.shadow_register > 2047;
-            if overflow {
-                return FrequencySweepResult::Disable;
-            } else {
-                self.shadow_register = new_freq;
-                return FrequencySweepResult::NewFreq(new_freq);
-            }
+        let mut result = FrequencySweepResult::default();
+        if !self.timer.tick() {
+            return result;
+        }
+        self.reload_timer();
+        if !self.enabled || self.timer.period == 0 {
+            return result;
+        }
+
+        let new_freq = self.calculate();
+        if new_freq > 2047 {
+            result.overflow = true;
+        } else if self.shift != 0 {
+            self.shadow_register = new_freq;
+            result.new_freq = Some(new_freq);
+            // The new frequency is immediately checked again, but not written back
+            result.overflow = self.calculate() > 2047;
         }
 
-        FrequencySweepResult::Nop
+        result
+    }
+
+    /// Compute the next frequency from the shadow register.
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow_register >> self.shift as u16;
+        if self.should_negate {
+            self.negate_used = true;
+            self.shadow_register.wrapping_sub(delta)
+        } else {
+            self.shadow_register + delta
+        }
+    }
+
+    /// Reload the sweep timer. A period of 0 is treated as 8.
+    fn reload_timer(&mut self) {
+        self.timer.counter = if self.timer.period == 0 {
+            8
+        } else {
+            self.timer.period
+        };
     }
 
-    fn load(&mut self, sweep_time: u16, negate: bool, shift: u8) {
+    /// Update the sweep parameters from NR10. Returns `true` if the channel should be disabled.
+    fn load(&mut self, sweep_time: u16, negate: bool, shift: u8) -> bool {
+        let disable = self.negate_used && self.should_negate && !negate;
         self.timer.period = sweep_time;
         self.should_negate = negate;
         self.shift = shift;
+        disable
     }
 
-    fn trigger(&mut self, current_frequency: u16) {
+    /// Restart the sweep. Returns `true` if the initial overflow check disables the channel.
+    fn trigger(&mut self, current_frequency: u16) -> bool {
         self.shadow_register = current_frequency;
-        self.timer.reset();
-        if self.timer.period != 0 || self.shift != 0 {
-            self.enabled = true;
-        }
+        self.negate_used = false;
+        self.reload_timer();
+        self.enabled = self.timer.period != 0 || self.shift != 0;
+        self.shift != 0 && self.calculate() > 2047
     }
 
     fn reset(&mut self) {
@@ -346,6 +411,149 @@ impl FrequencySweep {
         self.shadow_register = 0;
         self.shift = 0;
         self.should_negate = false;
+        self.negate_used = false;
         self.timer.period = 0;
     }
 }
+
+impl Stateful for ToneChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        self.length_counter.save_state(w);
+        self.volume_envelope.save_state(w);
+        w.u8(self.freq_hi);
+        w.u8(self.freq_lo);
+        self.freq_timer.save_state(w);
+        // Only channel 1 has a sweep, so whether there is one isn't part of the state
+        if let Some(sweep) = &self.frequency_sweep {
+            w.bool(sweep.enabled);
+            w.u16(sweep.shadow_register);
+            w.bool(sweep.should_negate);
+            w.bool(sweep.negate_used);
+            sweep.timer.save_state(w);
+            w.u8(sweep.shift);
+        }
+        w.u8(self.wave_generator.duty as u8);
+        w.u8(self.wave_generator.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.enabled = r.bool()?;
+        self.length_counter.load_state(r)?;
+        self.volume_envelope.load_state(r)?;
+        self.freq_hi = r.u8()?;
+        self.freq_lo = r.u8()?;
+        self.freq_timer.load_state(r)?;
+        if let Some(sweep) = &mut self.frequency_sweep {
+            sweep.enabled = r.bool()?;
+            sweep.shadow_register = r.u16()?;
+            sweep.should_negate = r.bool()?;
+            sweep.negate_used = r.bool()?;
+            sweep.timer.load_state(r)?;
+            sweep.shift = r.u8()?;
+        }
+        self.wave_generator.duty = Duty::from(r.u8_below(4)?);
+        self.wave_generator.step = r.u8_below(8)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waveform(duty: Duty) -> String {
+        let mut generator = SquareWaveGenerator::new();
+        generator.set_duty(duty);
+        (0..8)
+            .map(|_| {
+                let bit = if generator.output() { '1' } else { '0' };
+                generator.tick();
+                bit
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_duty_waveforms() {
+        assert_eq!("00000001", waveform(Duty::Duty0));
+        assert_eq!("10000001", waveform(Duty::Duty1));
+        assert_eq!("10000111", waveform(Duty::Duty2));
+        assert_eq!("01111110", waveform(Duty::Duty3));
+    }
+
+    #[test]
+    fn test_trigger_keeps_duty_step() {
+        let fs = FrameSequencer::default();
+        let mut channel = ToneChannel::new(false);
+        channel.set_nrx2(0xF0);
+        channel.set_nrx3(0xFF);
+        channel.set_nrx4(0x87, &fs);
+        for _ in 0..3 {
+            channel.wave_generator.tick();
+        }
+        channel.set_nrx4(0x87, &fs);
+        assert_eq!(3, channel.wave_generator.step);
+
+        channel.reset();
+        assert_eq!(0, channel.wave_generator.step);
+    }
+
+    fn sweep_channel(nr10: u8, freq: u16) -> (ToneChannel, FrameSequencer) {
+        let mut fs = FrameSequencer::default();
+        let mut channel = ToneChannel::new(true);
+        // DAC on
+        channel.set_nrx2(0xF0);
+        channel.set_nrx0(nr10);
+        channel.set_nrx3(freq as u8);
+        channel.set_nrx4(0x80 | (freq >> 8) as u8, &fs);
+        // Move the frame sequencer to the step that clocks the sweep
+        fs.tick();
+        fs.tick();
+        assert!(fs.sweep_triggered());
+        (channel, fs)
+    }
+
+    #[test]
+    fn test_sweep_overflow_on_trigger() {
+        // add mode, shift 1: 0x7FF + 0x3FF overflows straight away
+        let (channel, _) = sweep_channel(0x11, 0x7FF);
+        assert!(!channel.enabled);
+        let (channel, _) = sweep_channel(0x11, 0x400);
+        assert!(channel.enabled);
+        // no calculation is made when the shift is 0
+        let (channel, _) = sweep_channel(0x10, 0x7FF);
+        assert!(channel.enabled);
+    }
+
+    #[test]
+    fn test_sweep_writes_back_frequency() {
+        let (mut channel, fs) = sweep_channel(0x11, 0x100);
+        channel.tick_frame(&fs);
+        assert!(channel.enabled);
+        assert_eq!(0x80, channel.freq_lo);
+        assert_eq!(0x01, channel.freq_hi);
+        assert_eq!((2048 - 0x180) * 4, channel.freq_timer.period);
+
+        // 0x600 is fine, but the second check (0x600 + 0x300) overflows
+        let (mut channel, fs) = sweep_channel(0x11, 0x400);
+        channel.tick_frame(&fs);
+        assert!(!channel.enabled);
+        assert_eq!(0x06, channel.freq_hi);
+    }
+
+    #[test]
+    fn test_sweep_negate_quirk() {
+        // negate mode, shift 1: the trigger makes a subtraction
+        let (mut channel, _) = sweep_channel(0x19, 0x400);
+        assert!(channel.enabled);
+        // clearing negate now disables the channel
+        channel.set_nrx0(0x11);
+        assert!(!channel.enabled);
+
+        // without a prior calculation in negate mode, nothing happens
+        let (mut channel, _) = sweep_channel(0x18, 0x400);
+        channel.set_nrx0(0x10);
+        assert!(channel.enabled);
+    }
+}
@@ -1,19 +1,33 @@
 use bitvec::{field::BitField, order::Lsb0, view::BitView};
 
-use crate::apu::{frame_sequencer::FrameSequencer, Timer};
+use crate::apu::{frame_sequencer::FrameSequencer, ChannelSnapshot, Timer};
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter, Stateful};
+use crate::timing::CYCLES_PER_SECOND;
 
-use super::LengthCounter;
+use super::{dac, LengthCounter};
+
+/// Number of T-cycles after the channel fetched a byte of wave RAM during which the CPU can
+/// access that byte while the channel is playing.
+const WAVE_RAM_ACCESS_WINDOW: u16 = 2;
+/// Number of T-cycles before a sample fetch during which triggering the channel corrupts the wave
+/// RAM.
+const TRIGGER_CORRUPTION_WINDOW: u16 = 2;
 
 #[derive(Debug)]
 pub(crate) struct WaveChannel {
     // Wave table containing 32 4-bit samples
     wav: [u8; 16],
     enabled: bool,
+    /// Whether the DAC is powered on (NR30 bit 7). The channel can only be enabled if it is.
+    dac_enabled: bool,
     length_counter: LengthCounter,
     output_level: OutputLevel,
     freq: u16,
     position: u8,
     freq_timer: Timer,
+    /// Number of T-cycles since the last time a byte was fetched from wave RAM
+    cycles_since_fetch: u16,
 }
 
 impl WaveChannel {
@@ -21,20 +35,24 @@ impl WaveChannel {
         Self {
             wav: [0; 16],
             enabled: false,
+            dac_enabled: false,
             length_counter: LengthCounter::new(256),
             output_level: OutputLevel::Mute,
             freq: 0,
             position: 0,
             freq_timer: Timer::new(4096),
+            cycles_since_fetch: u16::MAX,
         }
     }
 
     pub(crate) fn tick(&mut self) {
+        self.cycles_since_fetch = self.cycles_since_fetch.saturating_add(1);
         if self.freq_timer.tick() {
             self.position += 1;
             if self.position == 32 {
                 self.position = 0;
             }
+            self.cycles_since_fetch = 0;
         }
     }
 
@@ -46,25 +64,24 @@ impl WaveChannel {
     }
 
     pub(crate) fn nr30(&self) -> u8 {
-        let mut res = 0xFF_u8;
+        let mut res = 0_u8;
         let bits = res.view_bits_mut::<Lsb0>();
-        bits.set(7, self.enabled);
+        bits.set(7, self.dac_enabled);
 
         res
     }
 
     pub(crate) fn set_nr30(&mut self, b: u8) {
-        if b.view_bits::<Lsb0>()[7] {
-            self.enabled = true;
-            self.position = 0;
-        } else {
+        self.dac_enabled = b.view_bits::<Lsb0>()[7];
+        if !self.dac_enabled {
+            // turning the DAC off disables the channel, but turning it on doesn't enable it
             self.enabled = false;
         }
     }
 
     pub(crate) fn nr31(&self) -> u8 {
         // NR31 is write-only
-        0xFF
+        0
     }
 
     pub(crate) fn set_nr31(&mut self, b: u8) {
@@ -72,7 +89,7 @@ impl WaveChannel {
     }
 
     pub(crate) fn nr32(&self) -> u8 {
-        let mut res = 0xFF_u8;
+        let mut res = 0_u8;
         let bits = res.view_bits_mut::<Lsb0>();
         bits[5..=6].store(self.output_level as u8);
 
@@ -90,7 +107,8 @@ impl WaveChannel {
     }
 
     pub(crate) fn nr33(&self) -> u8 {
-        0xFF
+        // NR33 is write-only
+        0
     }
 
     pub(crate) fn set_nr33(&mut self, b: u8) {
@@ -98,42 +116,81 @@ impl WaveChannel {
     }
 
     pub(crate) fn nr34(&self) -> u8 {
-        let mut res = 0xff;
+        let mut res = 0;
         let bits = res.view_bits_mut::<Lsb0>();
         bits.set(6, self.length_counter.length_enabled);
 
         res
     }
 
-    pub(crate) fn set_nr34(&mut self, b: u8) {
+    pub(crate) fn set_nr34(&mut self, b: u8, frame_sequencer: &FrameSequencer) {
         let bits = b.view_bits::<Lsb0>();
         self.freq.view_bits_mut::<Lsb0>()[8..=10].store::<u8>(bits[0..=2].load::<u8>());
 
-        if bits[6] {
-            self.length_counter.enable();
-        } else {
-            self.length_counter.reset();
+        if self.length_counter.set_enabled(bits[6], frame_sequencer) && !bits[7] {
+            self.enabled = false;
         }
 
         if bits[7] {
             // trigger
+            if self.enabled && self.freq_timer.counter <= TRIGGER_CORRUPTION_WINDOW {
+                self.corrupt_wave_ram();
+            }
+            self.enabled = self.dac_enabled;
             self.position = 0;
             self.freq_timer.period = (2048 - self.freq) * 2;
-            self.length_counter.trigger();
+            self.freq_timer.reset();
+            self.length_counter.trigger(frame_sequencer);
         }
     }
 
+    /// On the DMG, triggering the channel while it's about to fetch a byte of wave RAM overwrites
+    /// the beginning of wave RAM: if the byte is one of the first 4, only the first byte is
+    /// overwritten with it, otherwise the first 4 bytes are overwritten with the 4-byte aligned
+    /// block containing it.
+    fn corrupt_wave_ram(&mut self) {
+        let idx = ((self.position as usize + 1) % 32) / 2;
+        if idx < 4 {
+            self.wav[0] = self.wav[idx];
+        } else {
+            let block = idx & !0x03;
+            self.wav.copy_within(block..block + 4, 0);
+        }
+    }
+
+    /// Whether the CPU can access the wave RAM.
+    ///
+    /// While the channel is playing, the CPU can only access the byte that the channel is
+    /// currently reading, and only right after the channel fetched it.
+    fn wave_ram_accessible(&self) -> bool {
+        self.cycles_since_fetch < WAVE_RAM_ACCESS_WINDOW
+    }
+
     pub(crate) fn read_wav(&self, idx: usize) -> u8 {
-        self.wav[idx]
+        if !self.enabled {
+            self.wav[idx]
+        } else if self.wave_ram_accessible() {
+            self.wav[self.position as usize / 2]
+        } else {
+            0xFF
+        }
     }
 
     pub(crate) fn write_wav(&mut self, idx: usize, b: u8) {
-        self.wav[idx] = b;
+        if !self.enabled {
+            self.wav[idx] = b;
+        } else if self.wave_ram_accessible() {
+            self.wav[self.position as usize / 2] = b;
+        }
     }
 
-    pub(crate) fn output(&self) -> i16 {
+    /// Analog output of the channel, or 0.0 if its DAC is off.
+    pub(crate) fn output(&self) -> f32 {
+        if !self.dac_enabled {
+            return 0.0;
+        }
         if !self.enabled {
-            return 0;
+            return dac(0);
         }
 
         let byte = self.wav[self.position as usize / 2];
@@ -146,12 +203,27 @@ impl WaveChannel {
         };
         let adjusted_value = self.output_level.apply(value);
 
-        adjusted_value as i16
-        // dac(adjusted_value)
+        dac(adjusted_value)
+    }
+
+    pub(crate) fn snapshot(&self) -> ChannelSnapshot {
+        ChannelSnapshot {
+            enabled: self.enabled,
+            dac_enabled: self.dac_enabled,
+            volume: self.output_level.apply(15),
+            // 32 samples per period of the wave
+            frequency: CYCLES_PER_SECOND as f32 / (self.freq_timer.period.max(1) as f32 * 32.0),
+            samples: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_dac_on(&self) -> bool {
+        self.dac_enabled
     }
 
     pub(crate) fn reset(&mut self) {
         self.enabled = false;
+        self.dac_enabled = false;
         self.length_counter.reset();
         self.position = 0;
         self.output_level = OutputLevel::Mute;
@@ -181,3 +253,103 @@ impl OutputLevel {
         }
     }
 }
+
+impl Stateful for WaveChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wav);
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        self.length_counter.save_state(w);
+        w.u8(self.output_level as u8);
+        w.u16(self.freq);
+        w.u8(self.position);
+        self.freq_timer.save_state(w);
+        w.u16(self.cycles_since_fetch);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.wav)?;
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.length_counter.load_state(r)?;
+        self.output_level = match r.u8_below(4)? {
+            0 => OutputLevel::Mute,
+            1 => OutputLevel::Full,
+            2 => OutputLevel::Half,
+            _ => OutputLevel::Quarter,
+        };
+        self.freq = r.u16()?;
+        self.position = r.u8_below(32)?;
+        self.freq_timer.load_state(r)?;
+        self.cycles_since_fetch = r.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Set up a playing channel with a period of 8 T-cycles, with wave RAM filled with 0..=15
+    fn playing_channel() -> WaveChannel {
+        let mut channel = WaveChannel::new();
+        for i in 0..16 {
+            channel.write_wav(i, i as u8);
+        }
+        channel.set_nr30(0x80);
+        channel.set_nr33(0xFC);
+        channel.set_nr34(0x87, &FrameSequencer::default());
+        channel
+    }
+
+    #[test]
+    fn test_wave_ram_access_while_playing() {
+        let mut channel = playing_channel();
+
+        // Not accessible until the channel fetches a byte
+        assert_eq!(channel.read_wav(5), 0xFF);
+        channel.write_wav(5, 0x42);
+        for _ in 0..8 {
+            channel.tick();
+        }
+        // Position is now 1, i.e. byte 0, whatever the address
+        assert_eq!(channel.read_wav(5), 0x00);
+        channel.tick();
+        channel.write_wav(5, 0x42);
+        assert_eq!(channel.wav[0], 0x42);
+        assert_eq!(channel.wav[5], 5);
+        channel.tick();
+        assert_eq!(channel.read_wav(0), 0xFF);
+
+        channel.set_nr30(0x00);
+        assert_eq!(channel.read_wav(5), 5);
+    }
+
+    #[test]
+    fn test_trigger_corrupts_wave_ram() {
+        // Trigger right before fetching the byte at position 1 (i.e. byte 0)
+        let mut channel = playing_channel();
+        for _ in 0..6 {
+            channel.tick();
+        }
+        channel.set_nr34(0x87, &FrameSequencer::default());
+        assert_eq!(channel.wav[..4], [0, 1, 2, 3]);
+
+        // Trigger right before fetching byte 5
+        let mut channel = playing_channel();
+        for _ in 0..(8 * 10 - 2) {
+            channel.tick();
+        }
+        assert_eq!(channel.position, 9);
+        channel.set_nr34(0x87, &FrameSequencer::default());
+        assert_eq!(channel.wav[..8], [4, 5, 6, 7, 4, 5, 6, 7]);
+
+        // Triggering away from a fetch doesn't corrupt anything
+        let mut channel = playing_channel();
+        for _ in 0..(8 * 10 - 4) {
+            channel.tick();
+        }
+        channel.set_nr34(0x87, &FrameSequencer::default());
+        assert_eq!(channel.wav[..4], [0, 1, 2, 3]);
+    }
+}
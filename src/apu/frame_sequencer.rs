@@ -1,3 +1,6 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter, Stateful};
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) struct FrameSequencer(u8);
 
@@ -6,10 +9,21 @@ impl FrameSequencer {
         self.0 = (self.0 + 1) % 8;
     }
 
+    /// Reset the frame sequencer so that the next step is step 0, which happens when the APU is
+    /// turned on.
+    pub fn reset(&mut self) {
+        self.0 = 7;
+    }
+
     pub fn length_triggered(&self) -> bool {
         self.0 == 0 || self.0 == 2 || self.0 == 4 || self.0 == 6
     }
 
+    /// Whether the next step will clock the length counters.
+    pub fn next_step_clocks_length(&self) -> bool {
+        self.0 % 2 == 1
+    }
+
     pub fn vol_envelope_trigged(&self) -> bool {
         self.0 == 7
     }
@@ -18,3 +32,14 @@ impl FrameSequencer {
         self.0 == 2 || self.0 == 6
     }
 }
+
+impl Stateful for FrameSequencer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.0);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.0 = r.u8_below(8)?;
+        Ok(())
+    }
+}
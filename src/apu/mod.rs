@@ -1,51 +1,41 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, num::NonZeroU32};
 
 use bitvec::{field::BitField, order::Lsb0, view::BitView};
 use log::debug;
 
-use crate::AudioSink;
+use crate::error::Result;
+use crate::io_regs::{
+    Nr52, NR10, NR11, NR12, NR13, NR14, NR21, NR22, NR23, NR24, NR30, NR31, NR32, NR33, NR34, NR41,
+    NR42, NR43, NR44, NR50, NR51, NR52, WAVE_RAM,
+};
+use crate::state::{StateReader, StateWriter, Stateful};
+use crate::{anomaly::AnomalyLog, timing::CYCLES_PER_SECOND, AudioSink};
 
 mod channels;
 mod frame_sequencer;
+mod snapshot;
 
 use frame_sequencer::FrameSequencer;
+pub use snapshot::{ApuSnapshot, ChannelSnapshot};
 
 use self::channels::{NoiseChannel, ToneChannel, WaveChannel};
 
-// Channel 1
-const REG_NR10: u16 = 0xFF10;
-const REG_NR11: u16 = 0xFF11;
-const REG_NR12: u16 = 0xFF12;
-const REG_NR13: u16 = 0xFF13;
-const REG_NR14: u16 = 0xFF14;
-// Channel 2
-const REG_NR21: u16 = 0xFF16;
-const REG_NR22: u16 = 0xFF17;
-const REG_NR23: u16 = 0xFF18;
-const REG_NR24: u16 = 0xFF19;
-// Channel 3
-const REG_NR30: u16 = 0xFF1A;
-const REG_NR31: u16 = 0xFF1B;
-const REG_NR32: u16 = 0xFF1C;
-const REG_NR33: u16 = 0xFF1D;
-const REG_NR34: u16 = 0xFF1E;
-// Channel 4
-const REG_NR41: u16 = 0xFF20;
-const REG_NR42: u16 = 0xFF21;
-const REG_NR43: u16 = 0xFF22;
-const REG_NR44: u16 = 0xFF23;
-// sound control
-const REG_NR50: u16 = 0xFF24;
-const REG_NR51: u16 = 0xFF25;
-const REG_NR52: u16 = 0xFF26;
-
-const WAV_RAM_START: u16 = 0xFF30;
-
-const CPU_CYCLES_PER_SECOND: u32 = 4194304;
 // Period for the main 512Hz timer
 const TIMER_PERIOD: u16 = 8192;
-const TARGET_SAMPLE_RATE: u32 = 44100;
-// const SAMPLE_CYCLES: f32 = CPU_CYCLES_PER_SECOND as f32 / 44100.0;
+/// Sample rate used until the frontend tells us what the audio device actually wants
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// Default cutoff frequency (in Hz) of the low-pass filter applied before downsampling
+pub const DEFAULT_LOW_PASS_CUTOFF: u32 = 16000;
+
+/// Bits that always read back as 1 for each sound register, from NR10 to NR52. Write-only bits
+/// and unused registers (0xFF15 and 0xFF1F) read as 1.
+const READ_MASKS: [u8; (NR52 - NR10 + 1) as usize] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
 
 #[derive(Debug)]
 pub struct Apu {
@@ -62,10 +52,20 @@ pub struct Apu {
     /// Enable Vin into right output (comes from NR50)
     right_vin_enabled: bool,
 
-    #[allow(dead_code)]
-    sample_rate: u32,
-    sample_period: f32,
-    sample_counter: f32,
+    /// When to produce the output samples
+    sample_clock: SampleClock,
+    /// Sum of the left/right outputs since the last emitted sample, used to average (i.e. box
+    /// filter) the 4MHz signal down to the output rate instead of just picking one value.
+    left_acc: f32,
+    right_acc: f32,
+    acc_count: u32,
+    /// Filters taking the edge off the square waves before downsampling, which would otherwise
+    /// alias into audible noise
+    left_low_pass: LowPassFilter,
+    right_low_pass: LowPassFilter,
+    /// Filters removing the DC offset of the DACs from the left/right outputs
+    left_filter: HighPassFilter,
+    right_filter: HighPassFilter,
     timer: Timer,
     frame_sequencer: FrameSequencer,
 
@@ -74,7 +74,11 @@ pub struct Apu {
     channel3: WaveChannel,
     channel4: NoiseChannel,
 
-    buf: VecDeque<i16>,
+    /// Samples produced since the last `flush()`, interleaved left/right
+    samples: Vec<i16>,
+    /// The most recent outputs of each channel, for `snapshot()`
+    channel_samples: [VecDeque<f32>; 4],
+    pub(crate) anomalies: AnomalyLog,
 }
 
 impl Apu {
@@ -86,20 +90,27 @@ impl Apu {
             left_volume: 0,
             right_vin_enabled: false,
             right_volume: 0,
-            sample_rate: TARGET_SAMPLE_RATE,
-            sample_period: CPU_CYCLES_PER_SECOND as f32 / TARGET_SAMPLE_RATE as f32,
-            sample_counter: 0.0,
+            sample_clock: SampleClock::new(DEFAULT_SAMPLE_RATE),
+            left_acc: 0.0,
+            right_acc: 0.0,
+            acc_count: 0,
+            left_low_pass: LowPassFilter::new(Some(DEFAULT_LOW_PASS_CUTOFF)),
+            right_low_pass: LowPassFilter::new(Some(DEFAULT_LOW_PASS_CUTOFF)),
+            left_filter: HighPassFilter::new(DEFAULT_SAMPLE_RATE),
+            right_filter: HighPassFilter::new(DEFAULT_SAMPLE_RATE),
             timer: Timer::new(TIMER_PERIOD),
             frame_sequencer: FrameSequencer::default(),
             channel1: ToneChannel::new(true),
             channel2: ToneChannel::new(false),
             channel3: WaveChannel::new(),
             channel4: NoiseChannel::new(),
-            buf: VecDeque::new(),
+            samples: Vec::new(),
+            channel_samples: Default::default(),
+            anomalies: AnomalyLog::default(),
         }
     }
 
-    pub fn step(&mut self, cycles: u8, sink: &mut dyn AudioSink) {
+    pub fn step(&mut self, cycles: u32) {
         for _ in 0..cycles {
             self.channel1.tick();
             self.channel2.tick();
@@ -114,93 +125,192 @@ impl Apu {
                 self.channel4.tick_frame(&self.frame_sequencer);
             }
 
-            self.sample_counter += 1.0;
-            if self.sample_counter >= self.sample_period {
-                self.sample_counter -= self.sample_period;
-                let (left, right) = self.output();
-                if self.buf.len() < 400 {
-                    self.buf.push_back(left);
-                    self.buf.push_back(right);
-                }
-
-                if self.buf.len() > 200 {
-                    sink.push_samples(&mut self.buf);
-                }
+            let (left, right) = self.output();
+            self.left_acc += self.left_low_pass.filter(left);
+            self.right_acc += self.right_low_pass.filter(right);
+            self.acc_count += 1;
+
+            if self.sample_clock.tick() {
+                self.record_channel_samples();
+                let (left_dacs_on, right_dacs_on) = self.dacs_on();
+                let left = self
+                    .left_filter
+                    .filter(self.left_acc / self.acc_count as f32, left_dacs_on);
+                let right = self
+                    .right_filter
+                    .filter(self.right_acc / self.acc_count as f32, right_dacs_on);
+                let left = to_sample(left);
+                let right = to_sample(right);
+                self.left_acc = 0.0;
+                self.right_acc = 0.0;
+                self.acc_count = 0;
+                self.samples.push(left);
+                self.samples.push(right);
             }
         }
     }
 
-    // Outputs a pair of left/right samples
-    fn output(&self) -> (i16, i16) {
-        let mut left = 0;
-        let mut right = 0;
+    /// Send the samples produced since the last call to the sink.
+    pub fn flush(&mut self, sink: &mut dyn AudioSink) {
+        if !self.samples.is_empty() {
+            sink.push_samples(&self.samples);
+            self.samples.clear();
+        }
+    }
+
+    /// Set the rate (in Hz) at which samples are pushed to the `AudioSink`.
+    ///
+    /// This should match the sample rate of the audio device, otherwise the pitch will be off.
+    pub fn set_sample_rate(&mut self, sample_rate: NonZeroU32) {
+        let sample_rate = sample_rate.get();
+        debug!("Setting APU sample rate to {}Hz", sample_rate);
+        self.sample_clock = SampleClock::new(sample_rate);
+        self.left_acc = 0.0;
+        self.right_acc = 0.0;
+        self.acc_count = 0;
+        self.left_filter = HighPassFilter::new(sample_rate);
+        self.right_filter = HighPassFilter::new(sample_rate);
+        self.samples.clear();
+    }
+
+    /// Set the cutoff frequency (in Hz) of the low-pass filter applied to the outputs before
+    /// they're downsampled, or disable it with `None`.
+    pub fn set_low_pass_cutoff(&mut self, cutoff: Option<u32>) {
+        debug!("Setting APU low-pass cutoff to {:?}Hz", cutoff);
+        self.left_low_pass = LowPassFilter::new(cutoff);
+        self.right_low_pass = LowPassFilter::new(cutoff);
+    }
 
+    /// Outputs a pair of left/right analog samples, between -1.0 and 1.0.
+    fn output(&self) -> (f32, f32) {
         if !self.apu_enabled {
-            return (left, right);
+            return (0.0, 0.0);
         }
 
+        let outputs = [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(),
+            self.channel4.output(),
+        ];
         let nr51 = self.sound_output_selection.view_bits::<Lsb0>();
-
-        if nr51[7] {
-            left += self.channel4.output();
-        }
-        if nr51[6] {
-            left += self.channel3.output();
-        }
-        if nr51[5] {
-            left += self.channel2.output();
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            if nr51[4 + i] {
+                left += output;
+            }
+            if nr51[i] {
+                right += output;
+            }
         }
-        if nr51[4] {
-            left += self.channel1.output();
+        if self.left_vin_enabled {
+            left += self.vin();
         }
-        if nr51[3] {
-            right += self.channel4.output();
+        if self.right_vin_enabled {
+            right += self.vin();
         }
-        if nr51[2] {
-            right += self.channel3.output();
+
+        // Each side can mix up to 4 channels (Vin is ignored here as it's always silent), and the
+        // NR50 volumes go from 1/8 to 8/8.
+        let left = left / 4.0 * (self.left_volume + 1) as f32 / 8.0;
+        let right = right / 4.0 * (self.right_volume + 1) as f32 / 8.0;
+
+        (left, right)
+    }
+
+    /// What the APU and each of its channels are doing right now.
+    pub fn snapshot(&self) -> ApuSnapshot {
+        let mut channels = [
+            self.channel1.snapshot(),
+            self.channel2.snapshot(),
+            self.channel3.snapshot(),
+            self.channel4.snapshot(),
+        ];
+        for (channel, samples) in channels.iter_mut().zip(&self.channel_samples) {
+            channel.samples = samples.iter().copied().collect();
         }
-        if nr51[1] {
-            right += self.channel2.output();
+        ApuSnapshot {
+            enabled: self.apu_enabled,
+            channels,
         }
-        if nr51[0] {
-            right += self.channel1.output();
+    }
+
+    fn record_channel_samples(&mut self) {
+        let outputs = [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(),
+            self.channel4.output(),
+        ];
+        for (samples, output) in self.channel_samples.iter_mut().zip(outputs) {
+            if samples.len() == snapshot::SAMPLE_WINDOW {
+                samples.pop_front();
+            }
+            samples.push_back(output);
         }
+    }
 
-        left *= self.left_volume as i16 + 1;
-        right *= self.right_volume as i16 + 1;
+    /// Analog signal coming from the cartridge through the Vin pin, which can be mixed into the
+    /// outputs through NR50. No emulated cartridge drives it, so it's always silent.
+    fn vin(&self) -> f32 {
+        0.0
+    }
 
+    /// Whether any of the DACs of the channels mixed into the left/right outputs is on.
+    fn dacs_on(&self) -> (bool, bool) {
+        let dacs = [
+            self.channel1.is_dac_on(),
+            self.channel2.is_dac_on(),
+            self.channel3.is_dac_on(),
+            self.channel4.is_dac_on(),
+        ];
+        let nr51 = self.sound_output_selection.view_bits::<Lsb0>();
+        let left = (0..4).any(|i| dacs[i] && nr51[4 + i]);
+        let right = (0..4).any(|i| dacs[i] && nr51[i]);
         (left, right)
     }
 
     pub fn read_io(&self, addr: u16) -> u8 {
+        match addr
+            .checked_sub(NR10)
+            .and_then(|i| READ_MASKS.get(i as usize))
+        {
+            Some(mask) => self.register(addr) | mask,
+            None => anomaly!(self.anomalies, 0xFF, "Invalid sound register {:04x}", addr),
+        }
+    }
+
+    /// Value of the bits of a sound register that can actually be read back.
+    fn register(&self, addr: u16) -> u8 {
         match addr {
             // Channel 1
-            REG_NR10 => self.channel1.nrx0(),
-            REG_NR11 => self.channel1.nrx1(),
-            REG_NR12 => self.channel1.nrx2(),
-            REG_NR13 => self.channel1.nrx3(),
-            REG_NR14 => self.channel1.nrx4(),
-            0xFF15 => 0xFF, // NR15/NR20 doesn't really exist
+            NR10 => self.channel1.nrx0(),
+            NR11 => self.channel1.nrx1(),
+            NR12 => self.channel1.nrx2(),
+            NR13 => self.channel1.nrx3(),
+            NR14 => self.channel1.nrx4(),
+            0xFF15 => 0, // NR15/NR20 doesn't really exist
             // Channel 2
-            REG_NR21 => self.channel2.nrx1(),
-            REG_NR22 => self.channel2.nrx2(),
-            REG_NR23 => self.channel2.nrx3(),
-            REG_NR24 => self.channel2.nrx4(),
+            NR21 => self.channel2.nrx1(),
+            NR22 => self.channel2.nrx2(),
+            NR23 => self.channel2.nrx3(),
+            NR24 => self.channel2.nrx4(),
             // Channel 3
-            REG_NR30 => self.channel3.nr30(),
-            REG_NR31 => self.channel3.nr31(),
-            REG_NR32 => self.channel3.nr32(),
-            REG_NR33 => self.channel3.nr33(),
-            REG_NR34 => self.channel3.nr34(),
-            0xFF1F => 0xFF,
+            NR30 => self.channel3.nr30(),
+            NR31 => self.channel3.nr31(),
+            NR32 => self.channel3.nr32(),
+            NR33 => self.channel3.nr33(),
+            NR34 => self.channel3.nr34(),
+            0xFF1F => 0,
             // Channel 4
-            REG_NR41 => self.channel4.nr41(),
-            REG_NR42 => self.channel4.nr42(),
-            REG_NR43 => self.channel4.nr43(),
-            REG_NR44 => self.channel4.nr44(),
+            NR41 => self.channel4.nr41(),
+            NR42 => self.channel4.nr42(),
+            NR43 => self.channel4.nr43(),
+            NR44 => self.channel4.nr44(),
             // sound control
-            REG_NR50 => {
-                let mut res = 0xFF;
+            NR50 => {
+                let mut res = 0;
                 let bits = res.view_bits_mut::<Lsb0>();
                 bits.set(7, self.left_vin_enabled);
                 bits[4..=6].store::<u8>(self.left_volume);
@@ -208,69 +318,71 @@ impl Apu {
                 bits[0..=2].store::<u8>(self.right_volume);
                 res
             }
-            REG_NR51 => self.sound_output_selection,
-            REG_NR52 => {
-                let mut byte = 0xff;
-                let bits = byte.view_bits_mut::<Lsb0>();
-                bits.set(7, self.apu_enabled);
-                bits.set(3, self.channel4.enabled());
-                bits.set(2, self.channel3.enabled());
-                bits.set(1, self.channel2.enabled());
-                bits.set(0, self.channel1.enabled());
-                byte
+            NR51 => self.sound_output_selection,
+            NR52 => {
+                let mut nr52 = Nr52::empty();
+                nr52.set(Nr52::APU_ENABLE, self.apu_enabled);
+                nr52.set(Nr52::CH4_ON, self.channel4.enabled());
+                nr52.set(Nr52::CH3_ON, self.channel3.enabled());
+                nr52.set(Nr52::CH2_ON, self.channel2.enabled());
+                nr52.set(Nr52::CH1_ON, self.channel1.enabled());
+                nr52.bits()
             }
-            _ => panic!("Invalid sound register {:04x}", addr),
+            _ => unreachable!(),
         }
     }
 
     pub fn write_io(&mut self, addr: u16, b: u8) {
         // If the APU is disabled, all writes are ignored, except for NR52
-        if addr != REG_NR52 && !self.apu_enabled {
+        if addr != NR52 && !self.apu_enabled {
             return;
         }
 
         match addr {
             // Channel 1
-            REG_NR10 => self.channel1.set_nrx0(b),
-            REG_NR11 => self.channel1.set_nrx1(b),
-            REG_NR12 => self.channel1.set_nrx2(b),
-            REG_NR13 => self.channel1.set_nrx3(b),
-            REG_NR14 => self.channel1.set_nrx4(b),
+            NR10 => self.channel1.set_nrx0(b),
+            NR11 => self.channel1.set_nrx1(b),
+            NR12 => self.channel1.set_nrx2(b),
+            NR13 => self.channel1.set_nrx3(b),
+            NR14 => self.channel1.set_nrx4(b, &self.frame_sequencer),
             0xFF15 => (), // nop
             // Channel 2
-            REG_NR21 => self.channel2.set_nrx1(b),
-            REG_NR22 => self.channel2.set_nrx2(b),
-            REG_NR23 => self.channel2.set_nrx3(b),
-            REG_NR24 => self.channel2.set_nrx4(b),
+            NR21 => self.channel2.set_nrx1(b),
+            NR22 => self.channel2.set_nrx2(b),
+            NR23 => self.channel2.set_nrx3(b),
+            NR24 => self.channel2.set_nrx4(b, &self.frame_sequencer),
             // Channel 3
-            REG_NR30 => self.channel3.set_nr30(b),
-            REG_NR31 => self.channel3.set_nr31(b),
-            REG_NR32 => self.channel3.set_nr32(b),
-            REG_NR33 => self.channel3.set_nr33(b),
-            REG_NR34 => self.channel3.set_nr34(b),
+            NR30 => self.channel3.set_nr30(b),
+            NR31 => self.channel3.set_nr31(b),
+            NR32 => self.channel3.set_nr32(b),
+            NR33 => self.channel3.set_nr33(b),
+            NR34 => self.channel3.set_nr34(b, &self.frame_sequencer),
             0xFF1F => (), // nop
             // Channel 4
-            REG_NR41 => self.channel4.set_nr41(b),
-            REG_NR42 => self.channel4.set_nr42(b),
-            REG_NR43 => self.channel4.set_nr43(b),
-            REG_NR44 => self.channel4.set_nr44(b),
+            NR41 => self.channel4.set_nr41(b),
+            NR42 => self.channel4.set_nr42(b),
+            NR43 => self.channel4.set_nr43(b),
+            NR44 => self.channel4.set_nr44(b, &self.frame_sequencer),
             // sound control
-            REG_NR50 => {
+            NR50 => {
                 let bits = b.view_bits::<Lsb0>();
                 self.left_vin_enabled = bits[7];
                 self.left_volume = bits[4..=6].load::<u8>();
                 self.right_vin_enabled = bits[3];
                 self.right_volume = bits[0..=2].load::<u8>();
             }
-            REG_NR51 => self.sound_output_selection = b,
-            REG_NR52 => {
-                self.apu_enabled = b.view_bits::<Lsb0>()[7];
+            NR51 => self.sound_output_selection = b,
+            NR52 => {
+                let was_enabled = self.apu_enabled;
+                self.apu_enabled = Nr52::from_bits_truncate(b).contains(Nr52::APU_ENABLE);
                 if self.apu_enabled {
                     debug!("Turning APU ON!");
+                    if !was_enabled {
+                        self.frame_sequencer.reset();
+                    }
                     self.channel1.reset();
                 } else {
                     debug!("Turning APU OFF!");
-                    self.buf.clear();
                     self.left_vin_enabled = false;
                     self.right_vin_enabled = false;
                     self.timer.reset();
@@ -283,20 +395,125 @@ impl Apu {
                     self.channel4.reset();
                 }
             }
-            _ => panic!("Invalid sound register {:04x}", addr),
+            _ => anomaly!(self.anomalies, (), "Invalid sound register {:04x}", addr),
         };
     }
 
     pub fn read_wav(&self, addr: u16) -> u8 {
-        let index = addr - WAV_RAM_START;
-        assert!(index <= 0x0F);
-        self.channel3.read_wav(index as usize)
+        let index = addr.wrapping_sub(WAVE_RAM);
+        if index <= 0x0F {
+            self.channel3.read_wav(index as usize)
+        } else {
+            anomaly!(
+                self.anomalies,
+                0xFF,
+                "Invalid wave RAM address {:04x}",
+                addr
+            )
+        }
     }
 
     pub fn write_wav(&mut self, addr: u16, value: u8) {
-        let index = addr - WAV_RAM_START;
-        assert!(index <= 0x0F);
-        self.channel3.write_wav(index as usize, value);
+        let index = addr.wrapping_sub(WAVE_RAM);
+        if index <= 0x0F {
+            self.channel3.write_wav(index as usize, value);
+        } else {
+            anomaly!(self.anomalies, (), "Invalid wave RAM address {:04x}", addr);
+        }
+    }
+}
+
+/// Fixed-rate clock deciding on which T-cycles an output sample is produced.
+///
+/// This is a fractional-step accumulator: it's incremented by the sample rate every cycle, and
+/// ticks every time it goes over `CYCLES_PER_SECOND`. Using integers means there is no drift.
+#[derive(Debug)]
+struct SampleClock {
+    /// Output sample rate, in Hz
+    rate: u32,
+    counter: u32,
+}
+
+impl SampleClock {
+    fn new(rate: u32) -> Self {
+        Self { rate, counter: 0 }
+    }
+
+    /// Advance by one T-cycle. Returns `true` if a sample is due.
+    fn tick(&mut self) -> bool {
+        self.counter += self.rate;
+        if self.counter >= CYCLES_PER_SECOND {
+            self.counter -= CYCLES_PER_SECOND;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Convert an analog output (between -1.0 and 1.0) to a sample for the `AudioSink`.
+fn to_sample(value: f32) -> i16 {
+    (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// The capacitor on each output of the Game Boy, which removes the DC offset of the DACs so that
+/// silence is centered on 0.
+#[derive(Debug)]
+struct HighPassFilter {
+    /// How much of its charge the capacitor keeps from one sample to the next
+    charge_factor: f32,
+    capacitor: f32,
+}
+
+impl HighPassFilter {
+    /// Create a filter for the given sample rate. The DMG's capacitor keeps 0.999958 of its
+    /// charge every T-cycle.
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            charge_factor: 0.999958_f32.powf(CYCLES_PER_SECOND as f32 / sample_rate as f32),
+            capacitor: 0.0,
+        }
+    }
+
+    /// Filter one sample. When all the DACs are off the output is silent, and the capacitor is
+    /// left alone.
+    fn filter(&mut self, input: f32, dacs_on: bool) -> f32 {
+        if !dacs_on {
+            return 0.0;
+        }
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
+}
+
+/// One-pole low-pass filter running at the APU's 4MHz rate.
+#[derive(Debug)]
+struct LowPassFilter {
+    /// How far the output moves towards the input every T-cycle, or `None` to let everything
+    /// through
+    alpha: Option<f32>,
+    output: f32,
+}
+
+impl LowPassFilter {
+    /// Create a filter with the given cutoff frequency (in Hz), or a pass-through one.
+    fn new(cutoff: Option<u32>) -> Self {
+        let alpha = cutoff.map(|cutoff| {
+            let omega = 2.0 * std::f32::consts::PI * cutoff as f32 / CYCLES_PER_SECOND as f32;
+            1.0 - (-omega).exp()
+        });
+        Self { alpha, output: 0.0 }
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        match self.alpha {
+            Some(alpha) => {
+                self.output += (input - self.output) * alpha;
+                self.output
+            }
+            None => input,
+        }
     }
 }
 
@@ -330,3 +547,301 @@ impl Timer {
         self.counter = self.period;
     }
 }
+
+impl Stateful for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.period);
+        w.u16(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.period = r.u16()?;
+        self.counter = r.u16()?;
+        Ok(())
+    }
+}
+
+/// The filters and the samples not sent to the audio sink yet belong to the host's audio output,
+/// so they're left as they are.
+impl Stateful for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.apu_enabled);
+        w.u8(self.sound_output_selection);
+        w.u8(self.left_volume);
+        w.u8(self.right_volume);
+        w.bool(self.left_vin_enabled);
+        w.bool(self.right_vin_enabled);
+        self.timer.save_state(w);
+        self.frame_sequencer.save_state(w);
+        self.channel1.save_state(w);
+        self.channel2.save_state(w);
+        self.channel3.save_state(w);
+        self.channel4.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.apu_enabled = r.bool()?;
+        self.sound_output_selection = r.u8()?;
+        self.left_volume = r.u8()?;
+        self.right_volume = r.u8()?;
+        self.left_vin_enabled = r.bool()?;
+        self.right_vin_enabled = r.bool()?;
+        self.timer.load_state(r)?;
+        self.frame_sequencer.load_state(r)?;
+        self.channel1.load_state(r)?;
+        self.channel2.load_state(r)?;
+        self.channel3.load_state(r)?;
+        self.channel4.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingSink {
+        samples: usize,
+    }
+
+    impl AudioSink for CountingSink {
+        fn push_samples(&mut self, samples: &[i16]) {
+            self.samples += samples.len();
+        }
+    }
+
+    /// Number of samples pushed in 1/64th of a second
+    fn samples_in_a_64th_of_a_second(sample_rate: Option<u32>) -> usize {
+        let mut apu = Apu::new();
+        if let Some(rate) = sample_rate {
+            apu.set_sample_rate(NonZeroU32::new(rate).unwrap());
+        }
+        let mut sink = CountingSink::default();
+        for _ in 0..CYCLES_PER_SECOND / 64 / 4 {
+            apu.step(4);
+            apu.flush(&mut sink);
+        }
+        sink.samples
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        // 2 channels, and the samples of the last 1/64th of the period aren't due yet
+        let expected = |rate: u32| 2 * (rate / 64) as usize;
+        assert_eq!(
+            expected(DEFAULT_SAMPLE_RATE),
+            samples_in_a_64th_of_a_second(None)
+        );
+        assert_eq!(expected(48000), samples_in_a_64th_of_a_second(Some(48000)));
+        assert_eq!(expected(22050), samples_in_a_64th_of_a_second(Some(22050)));
+    }
+
+    #[test]
+    fn test_sample_clock() {
+        let mut clock = SampleClock::new(48000);
+        let ticks = (0..CYCLES_PER_SECOND).filter(|_| clock.tick()).count();
+        assert_eq!(48000, ticks);
+        // the samples are evenly spaced
+        let mut clock = SampleClock::new(CYCLES_PER_SECOND / 4);
+        let ticks: Vec<bool> = (0..8).map(|_| clock.tick()).collect();
+        assert_eq!(
+            vec![false, false, false, true, false, false, false, true],
+            ticks
+        );
+    }
+
+    #[test]
+    fn test_flush_pushes_every_sample() {
+        let mut apu = Apu::new();
+        let mut sink = CountingSink::default();
+        // One cycle short of the first sample
+        let cycles_per_sample = CYCLES_PER_SECOND.div_ceil(DEFAULT_SAMPLE_RATE);
+        apu.step(cycles_per_sample - 1);
+        apu.flush(&mut sink);
+        assert_eq!(0, sink.samples);
+        apu.step(1);
+        apu.flush(&mut sink);
+        assert_eq!(2, sink.samples);
+        assert!(apu.samples.is_empty());
+    }
+
+    /// Run the APU until the frame sequencer moves on to its next step
+    fn step_frame_sequencer(apu: &mut Apu) {
+        for _ in 0..TIMER_PERIOD / 4 {
+            apu.step(4);
+        }
+    }
+
+    #[test]
+    fn test_length_extra_clocking() {
+        let mut apu = Apu::new();
+        apu.write_io(NR52, 0x00);
+        apu.write_io(NR52, 0x80);
+        // Run step 0, which clocks the length counters: the next step (1) doesn't
+        step_frame_sequencer(&mut apu);
+        apu.write_io(NR12, 0xF0);
+        apu.write_io(NR13, 0xFF);
+        // length = 2
+        apu.write_io(NR11, 0x3E);
+        apu.write_io(NR14, 0x80);
+        assert_eq!(apu.read_io(NR52) & 0x01, 0x01);
+
+        // Enabling the length counter clocks it once (length = 1)...
+        apu.write_io(NR14, 0x40);
+        // ...but only if it was disabled
+        apu.write_io(NR14, 0x40);
+        assert_eq!(apu.read_io(NR52) & 0x01, 0x01);
+        // Step 1 doesn't clock the length counter, step 2 does and disables the channel
+        step_frame_sequencer(&mut apu);
+        assert_eq!(apu.read_io(NR52) & 0x01, 0x01);
+        step_frame_sequencer(&mut apu);
+        assert_eq!(apu.read_io(NR52) & 0x01, 0x00);
+
+        // length = 1, and the next step (3) doesn't clock it: the extra clock disables the channel
+        apu.write_io(NR11, 0x3F);
+        apu.write_io(NR14, 0x80);
+        apu.write_io(NR14, 0x00);
+        apu.write_io(NR14, 0x40);
+        assert_eq!(apu.read_io(NR52) & 0x01, 0x00);
+
+        // Triggering with an expired length counter reloads it with 64 - 1, as step 3 is still
+        // next. Every other step clocks it, so the channel lasts 62 clocks and stops on the 63rd.
+        apu.write_io(NR14, 0xC0);
+        for _ in 0..62 {
+            step_frame_sequencer(&mut apu);
+            step_frame_sequencer(&mut apu);
+        }
+        assert_eq!(apu.read_io(NR52) & 0x01, 0x01);
+        step_frame_sequencer(&mut apu);
+        step_frame_sequencer(&mut apu);
+        assert_eq!(apu.read_io(NR52) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_register_read_back() {
+        let mut apu = Apu::new();
+        for addr in NR10..NR52 {
+            let mask = READ_MASKS[(addr - NR10) as usize];
+            apu.write_io(addr, 0x00);
+            assert_eq!(apu.read_io(addr), mask, "Writing 0x00 to {:04X}", addr);
+            apu.write_io(addr, 0xFF);
+            assert_eq!(apu.read_io(addr), 0xFF, "Writing 0xFF to {:04X}", addr);
+        }
+
+        // NR30 reflects the DAC, not whether the channel is playing
+        apu.write_io(NR30, 0x00);
+        apu.write_io(NR30, 0x80);
+        apu.write_io(NR34, 0x00);
+        assert_eq!(apu.read_io(NR52) & 0x04, 0x00);
+        assert_eq!(apu.read_io(NR30), 0xFF);
+        apu.write_io(NR34, 0x80);
+        assert_eq!(apu.read_io(NR52) & 0x04, 0x04);
+        apu.write_io(NR30, 0x00);
+        assert_eq!(apu.read_io(NR52) & 0x04, 0x00);
+        assert_eq!(apu.read_io(NR30), 0x7F);
+
+        apu.write_io(NR52, 0x00);
+        assert_eq!(apu.read_io(NR52), 0x70);
+        apu.write_io(NR52, 0xFF);
+        assert_eq!(apu.read_io(NR52), 0xF0);
+    }
+
+    #[test]
+    fn test_mixer() {
+        let mut apu = Apu::new();
+        // Channel 1 at full volume, on the left only
+        apu.write_io(NR50, 0x77);
+        apu.write_io(NR51, 0x10);
+        apu.write_io(NR11, 0x80);
+        apu.write_io(NR12, 0xF0);
+        apu.write_io(NR14, 0x87);
+        let (mut min, mut max) = (0.0_f32, 0.0_f32);
+        // One period of the wave is 8 * 1024 cycles
+        for _ in 0..8 * 1024 {
+            apu.step(1);
+            let (left, right) = apu.output();
+            assert_eq!(right, 0.0);
+            min = min.min(left);
+            max = max.max(left);
+        }
+        assert_eq!((min, max), (-0.25, 0.25));
+
+        // Halving the volume halves the output
+        apu.write_io(NR50, 0x33);
+        assert_eq!(apu.output().0.abs(), 0.125);
+    }
+
+    #[test]
+    fn test_high_pass_filter() {
+        let mut filter = HighPassFilter::new(DEFAULT_SAMPLE_RATE);
+        // A constant signal decays to 0...
+        assert_eq!(filter.filter(1.0, true), 1.0);
+        for _ in 0..DEFAULT_SAMPLE_RATE {
+            filter.filter(1.0, true);
+        }
+        assert!(filter.filter(1.0, true).abs() < 0.01);
+        // ...and nothing comes out when all the DACs are off
+        assert_eq!(filter.filter(1.0, false), 0.0);
+    }
+
+    #[test]
+    fn test_low_pass_filter() {
+        let mut filter = LowPassFilter::new(None);
+        assert_eq!(filter.filter(1.0), 1.0);
+
+        // A constant signal goes through...
+        let mut filter = LowPassFilter::new(Some(1000));
+        for _ in 0..CYCLES_PER_SECOND / 100 {
+            filter.filter(1.0);
+        }
+        assert!((filter.filter(1.0) - 1.0).abs() < 0.01);
+        // ...but a signal switching every cycle (2MHz) is flattened
+        let square = |i| if i % 2 == 0 { -1.0 } else { 1.0 };
+        for i in 0..CYCLES_PER_SECOND / 100 {
+            filter.filter(square(i));
+        }
+        let (mut min, mut max) = (1.0_f32, -1.0_f32);
+        for i in 0..1000 {
+            let output = filter.filter(square(i));
+            min = min.min(output);
+            max = max.max(output);
+        }
+        assert!(max - min < 0.01);
+    }
+
+    #[test]
+    fn test_dacs_on() {
+        let mut apu = Apu::new();
+        assert_eq!(apu.dacs_on(), (false, false));
+        // Channel 2's DAC is on, but it's only mixed into the right output
+        apu.write_io(NR22, 0xF0);
+        apu.write_io(NR51, 0x02);
+        assert_eq!(apu.dacs_on(), (false, true));
+        apu.write_io(NR51, 0x22);
+        assert_eq!(apu.dacs_on(), (true, true));
+        apu.write_io(NR22, 0x00);
+        assert_eq!(apu.dacs_on(), (false, false));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut apu = Apu::new();
+        // Volume 15, going up (i.e. staying at 15)
+        apu.write_io(NR12, 0xF8);
+        apu.write_io(NR13, 0x00);
+        apu.write_io(NR14, 0x87);
+        apu.step(CYCLES_PER_SECOND / 10);
+
+        let snapshot = apu.snapshot();
+        assert!(snapshot.enabled);
+        let channel = &snapshot.channels[0];
+        assert!(channel.enabled && channel.dac_enabled);
+        assert_eq!(channel.volume, 15);
+        assert_eq!(channel.frequency, 512.0);
+        assert_eq!(channel.samples.len(), snapshot::SAMPLE_WINDOW);
+        assert!(channel.samples.contains(&-1.0) && channel.samples.contains(&1.0));
+        // Channel 2 is silent, with its DAC off
+        assert!(!snapshot.channels[1].dac_enabled);
+        assert!(snapshot.channels[1].samples.iter().all(|s| *s == 0.0));
+    }
+}
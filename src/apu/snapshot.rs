@@ -0,0 +1,29 @@
+/// Number of samples kept for each channel in an `ApuSnapshot`
+pub(crate) const SAMPLE_WINDOW: usize = 512;
+
+/// A snapshot of what the APU is doing, e.g. to visualize the music.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ApuSnapshot {
+    /// Whether the APU is switched on (NR52 bit 7)
+    pub enabled: bool,
+    /// Channel 1 (square with sweep), 2 (square), 3 (wave) and 4 (noise)
+    pub channels: [ChannelSnapshot; 4],
+}
+
+/// The state of one sound channel.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChannelSnapshot {
+    /// Whether the channel is playing
+    pub enabled: bool,
+    /// Whether the channel's DAC is on. If it's off, the channel is silent even if it's enabled.
+    pub dac_enabled: bool,
+    /// Current volume, from 0 to 15. For the wave channel, this is the output level (15, 7, 3 or
+    /// 0).
+    pub volume: u8,
+    /// Frequency of the sound, in Hz. For the noise channel, this is the rate at which the LFSR
+    /// is clocked.
+    pub frequency: f32,
+    /// The most recent analog outputs of the channel (oldest first, between -1.0 and 1.0), one
+    /// per sample sent to the `AudioSink`.
+    pub samples: Vec<f32>,
+}
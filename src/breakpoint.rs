@@ -0,0 +1,724 @@
+use std::{fmt, num::ParseIntError, ops::RangeInclusive, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::{cpu::CpuState, interrupt::InterruptFlag};
+
+/// A set of addresses, optimised for fast lookups.
+///
+/// Addresses are grouped in 256-byte pages: a bitmap of the pages that contain at least one
+/// address means that most lookups (and all of them when the set is empty) are answered with a
+/// single bit test. Only when the page matches do we binary-search the (sorted) addresses.
+#[derive(Debug, Default, Clone)]
+pub struct AddressSet {
+    /// One bit per 256-byte page
+    pages: [u64; 4],
+    /// Sorted list of addresses
+    addrs: Vec<u16>,
+}
+
+impl AddressSet {
+    /// Add an address to the set. Returns `false` if it was already present.
+    pub fn insert(&mut self, addr: u16) -> bool {
+        match self.addrs.binary_search(&addr) {
+            Ok(_) => false,
+            Err(idx) => {
+                self.addrs.insert(idx, addr);
+                let page = (addr >> 8) as usize;
+                self.pages[page / 64] |= 1 << (page % 64);
+                true
+            }
+        }
+    }
+
+    /// Add all the addresses of the given range to the set.
+    pub fn insert_range(&mut self, range: RangeInclusive<u16>) {
+        for page in (*range.start() >> 8)..=(*range.end() >> 8) {
+            let page = page as usize;
+            self.pages[page / 64] |= 1 << (page % 64);
+        }
+        self.addrs.extend(range);
+        self.addrs.sort_unstable();
+        self.addrs.dedup();
+    }
+
+    /// Remove an address from the set. Returns `false` if it wasn't present.
+    pub fn remove(&mut self, addr: u16) -> bool {
+        match self.addrs.binary_search(&addr) {
+            Ok(idx) => {
+                self.addrs.remove(idx);
+                let page = addr >> 8;
+                // clear the page bit if it was the last address in that page
+                if !self.addrs.iter().any(|a| a >> 8 == page) {
+                    let page = page as usize;
+                    self.pages[page / 64] &= !(1 << (page % 64));
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, addr: u16) -> bool {
+        let page = (addr >> 8) as usize;
+        if self.pages[page / 64] & (1 << (page % 64)) == 0 {
+            return false;
+        }
+        self.addrs.binary_search(&addr).is_ok()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.pages = [0; 4];
+        self.addrs.clear();
+    }
+
+    /// Iterate over the addresses in the set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.addrs.iter().copied()
+    }
+}
+
+/// A PC breakpoint, optionally qualified with a ROM bank and a condition.
+///
+/// Addresses in the switchable ROM area (4000-7FFF) are ambiguous, as they point to different code
+/// depending on the bank that is mapped there. A bank-qualified breakpoint only triggers when its
+/// bank is the current one. It is written `<bank>:<address>` in hexadecimal, e.g. `03:4F20`.
+///
+/// A conditional breakpoint only triggers when its [`Condition`] on the CPU registers holds, e.g.
+/// `4123 if a==3e`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub bank: Option<u16>,
+    pub addr: u16,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16) -> Self {
+        Self {
+            bank: None,
+            addr,
+            condition: None,
+        }
+    }
+
+    pub fn in_bank(bank: u16, addr: u16) -> Self {
+        Self {
+            bank: Some(bank),
+            addr,
+            condition: None,
+        }
+    }
+
+    /// Only trigger the breakpoint when the given condition holds.
+    pub fn with_condition(self, condition: Condition) -> Self {
+        Self {
+            condition: Some(condition),
+            ..self
+        }
+    }
+}
+
+impl From<u16> for Breakpoint {
+    fn from(addr: u16) -> Self {
+        Self::new(addr)
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (location, condition) = match s.split_once(" if ") {
+            Some((location, condition)) => (location.trim(), Some(condition.parse()?)),
+            None => (s.trim(), None),
+        };
+        let parse_hex = |s| parse_hex(s).with_context(|| format!("Invalid address {s}"));
+        let breakpoint = match location.split_once(':') {
+            Some((bank, addr)) => Self::in_bank(parse_hex(bank)?, parse_hex(addr)?),
+            None => Self::new(parse_hex(location)?),
+        };
+        Ok(Self {
+            condition,
+            ..breakpoint
+        })
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr)?,
+            None => write!(f, "{:04X}", self.addr)?,
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// A condition on the CPU registers, such as `a==3e && zf==1`.
+///
+/// It's made of comparisons between a register (`a`, `f`, `b`... `l`, `af`, `bc`, `de`, `hl`,
+/// `sp`, `pc`) or flag (`zf`, `nf`, `hf`, `cf`) and an hexadecimal value, with `==`, `!=`, `<`,
+/// `<=`, `>` or `>=`. Comparisons can be combined with `&&` and `||`, `&&` taking precedence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    /// The condition holds if all the comparisons of one of the groups hold
+    any_of: Vec<Vec<Comparison>>,
+}
+
+impl Condition {
+    pub fn eval(&self, state: &CpuState) -> bool {
+        self.any_of
+            .iter()
+            .any(|all_of| all_of.iter().all(|cmp| cmp.eval(state)))
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let any_of = s
+            .split("||")
+            .map(|group| group.split("&&").map(str::parse).collect())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { any_of })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, all_of) in self.any_of.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" || ")?;
+            }
+            for (j, cmp) in all_of.iter().enumerate() {
+                if j > 0 {
+                    f.write_str(" && ")?;
+                }
+                write!(f, "{}", cmp)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A comparison between a register or flag and a value, e.g. `a==3e`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Comparison {
+    operand: Operand,
+    op: CmpOp,
+    value: u16,
+}
+
+impl Comparison {
+    fn eval(&self, state: &CpuState) -> bool {
+        let lhs = self.operand.value(state);
+        match self.op {
+            CmpOp::Eq => lhs == self.value,
+            CmpOp::Ne => lhs != self.value,
+            CmpOp::Lt => lhs < self.value,
+            CmpOp::Le => lhs <= self.value,
+            CmpOp::Gt => lhs > self.value,
+            CmpOp::Ge => lhs >= self.value,
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // The 2-character operators need to be looked for first, as `<` is a prefix of `<=`
+        let (op, (lhs, rhs)) = CmpOp::ALL
+            .iter()
+            .find_map(|op| s.split_once(op.symbol()).map(|parts| (*op, parts)))
+            .ok_or_else(|| anyhow!("Invalid comparison `{s}`"))?;
+        let operand = lhs.trim().parse()?;
+        let rhs = rhs.trim();
+        let value = parse_hex(rhs).with_context(|| format!("Invalid value `{rhs}`"))?;
+        Ok(Self { operand, op, value })
+    }
+}
+
+/// Parse an hexadecimal number, with an optional `0x` or `$` prefix.
+fn parse_hex(s: &str) -> Result<u16, ParseIntError> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix('$'))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16)
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{:X}", self.operand, self.op.symbol(), self.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    /// All the operators, the 2-character ones first
+    const ALL: [CmpOp; 6] = [
+        CmpOp::Eq,
+        CmpOp::Ne,
+        CmpOp::Le,
+        CmpOp::Ge,
+        CmpOp::Lt,
+        CmpOp::Gt,
+    ];
+
+    fn symbol(self) -> &'static str {
+        match self {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        }
+    }
+}
+
+/// A register or flag that a condition can look at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    FlagZ,
+    FlagN,
+    FlagH,
+    FlagC,
+}
+
+impl Operand {
+    const ALL: [Operand; 18] = [
+        Operand::A,
+        Operand::F,
+        Operand::B,
+        Operand::C,
+        Operand::D,
+        Operand::E,
+        Operand::H,
+        Operand::L,
+        Operand::AF,
+        Operand::BC,
+        Operand::DE,
+        Operand::HL,
+        Operand::SP,
+        Operand::PC,
+        Operand::FlagZ,
+        Operand::FlagN,
+        Operand::FlagH,
+        Operand::FlagC,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operand::A => "a",
+            Operand::F => "f",
+            Operand::B => "b",
+            Operand::C => "c",
+            Operand::D => "d",
+            Operand::E => "e",
+            Operand::H => "h",
+            Operand::L => "l",
+            Operand::AF => "af",
+            Operand::BC => "bc",
+            Operand::DE => "de",
+            Operand::HL => "hl",
+            Operand::SP => "sp",
+            Operand::PC => "pc",
+            Operand::FlagZ => "zf",
+            Operand::FlagN => "nf",
+            Operand::FlagH => "hf",
+            Operand::FlagC => "cf",
+        }
+    }
+
+    fn value(self, state: &CpuState) -> u16 {
+        match self {
+            Operand::A => state.a as u16,
+            Operand::F => state.f as u16,
+            Operand::B => state.b as u16,
+            Operand::C => state.c as u16,
+            Operand::D => state.d as u16,
+            Operand::E => state.e as u16,
+            Operand::H => state.h as u16,
+            Operand::L => state.l as u16,
+            Operand::AF => state.af(),
+            Operand::BC => state.bc(),
+            Operand::DE => state.de(),
+            Operand::HL => state.hl(),
+            Operand::SP => state.sp,
+            Operand::PC => state.pc,
+            Operand::FlagZ => state.flag_z() as u16,
+            Operand::FlagN => state.flag_n() as u16,
+            Operand::FlagH => state.flag_h() as u16,
+            Operand::FlagC => state.flag_c() as u16,
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Operand::ALL
+            .into_iter()
+            .find(|op| op.name().eq_ignore_ascii_case(s))
+        {
+            Some(op) => Ok(op),
+            None => bail!("Unknown register `{s}`"),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+}
+
+/// A memory access that triggered a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub addr: u16,
+    /// Value read or written
+    pub value: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.kind {
+            WatchKind::Read => "Read",
+            WatchKind::Write => "Write",
+        };
+        write!(f, "{} ${:04X} = ${:02X}", access, self.addr, self.value)
+    }
+}
+
+/// All the conditions that can cause the execution to be paused.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    /// PC breakpoints
+    pub exec: AddressSet,
+    /// Addresses of the bank-qualified PC breakpoints, for fast lookups
+    banked_addrs: AddressSet,
+    /// Bank-qualified PC breakpoints, as sorted `(bank, address)` pairs
+    banked: Vec<(u16, u16)>,
+    /// Addresses of the conditional PC breakpoints, for fast lookups
+    conditional_addrs: AddressSet,
+    /// Conditional PC breakpoints (banked or not)
+    conditional: Vec<Breakpoint>,
+    /// Watchpoints on memory reads
+    pub read: AddressSet,
+    /// Watchpoints on memory writes
+    pub write: AddressSet,
+    /// Addresses of the value-matching watchpoints, for fast lookups
+    value_watch_addrs: AddressSet,
+    /// Watchpoints that only trigger when a given value is read or written
+    value_watches: Vec<(WatchKind, RangeInclusive<u16>, u8)>,
+    /// Break when one of these interrupts is serviced
+    pub(crate) interrupts: InterruptFlag,
+}
+
+impl Breakpoints {
+    /// Returns `true` if execution should break before the instruction at `pc`.
+    ///
+    /// `rom_bank` returns the ROM bank currently mapped at 4000-7FFF, and `cpu_state` the CPU
+    /// registers. They are only called when there is a bank-qualified or conditional breakpoint
+    /// at `pc`.
+    #[inline]
+    pub fn is_exec_break(
+        &self,
+        pc: u16,
+        rom_bank: impl Fn() -> u16,
+        cpu_state: impl FnOnce() -> CpuState,
+    ) -> bool {
+        self.exec.contains(pc)
+            || (self.banked_addrs.contains(pc) && self.is_banked_break(pc, &rom_bank))
+            || (self.conditional_addrs.contains(pc)
+                && self.is_conditional_break(pc, &rom_bank, cpu_state))
+    }
+
+    fn is_banked_break(&self, pc: u16, rom_bank: impl Fn() -> u16) -> bool {
+        match Self::bank_at(pc, rom_bank) {
+            Some(bank) => self.banked.binary_search(&(bank, pc)).is_ok(),
+            None => true,
+        }
+    }
+
+    fn is_conditional_break(
+        &self,
+        pc: u16,
+        rom_bank: impl Fn() -> u16,
+        cpu_state: impl FnOnce() -> CpuState,
+    ) -> bool {
+        let state = cpu_state();
+        self.conditional.iter().any(|b| {
+            b.addr == pc
+                && b.bank
+                    .is_none_or(|bank| Self::bank_at(pc, &rom_bank).is_none_or(|cur| cur == bank))
+                && b.condition.as_ref().is_none_or(|c| c.eval(&state))
+        })
+    }
+
+    /// The ROM bank `pc` is in, or `None` if it's not in the ROM (where banks don't mean
+    /// anything).
+    fn bank_at(pc: u16, rom_bank: impl Fn() -> u16) -> Option<u16> {
+        match pc {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(rom_bank()),
+            _ => None,
+        }
+    }
+
+    /// Add a PC breakpoint.
+    pub fn add(&mut self, breakpoint: Breakpoint) {
+        if breakpoint.condition.is_some() {
+            self.conditional_addrs.insert(breakpoint.addr);
+            if !self.conditional.contains(&breakpoint) {
+                self.conditional.push(breakpoint);
+            }
+            return;
+        }
+        match breakpoint.bank {
+            None => {
+                self.exec.insert(breakpoint.addr);
+            }
+            Some(bank) => {
+                let key = (bank, breakpoint.addr);
+                if let Err(idx) = self.banked.binary_search(&key) {
+                    self.banked.insert(idx, key);
+                    self.banked_addrs.insert(breakpoint.addr);
+                }
+            }
+        }
+    }
+
+    /// Iterate over the PC breakpoints.
+    pub fn exec_breakpoints(&self) -> impl Iterator<Item = Breakpoint> + '_ {
+        self.exec
+            .iter()
+            .map(Breakpoint::new)
+            .chain(
+                self.banked
+                    .iter()
+                    .map(|&(bank, addr)| Breakpoint::in_bank(bank, addr)),
+            )
+            .chain(self.conditional.iter().cloned())
+    }
+
+    #[inline]
+    pub fn is_read_watch(&self, addr: u16, value: u8) -> bool {
+        self.read.contains(addr) || self.is_value_watch(WatchKind::Read, addr, value)
+    }
+
+    #[inline]
+    pub fn is_write_watch(&self, addr: u16, value: u8) -> bool {
+        self.write.contains(addr) || self.is_value_watch(WatchKind::Write, addr, value)
+    }
+
+    fn is_value_watch(&self, kind: WatchKind, addr: u16, value: u8) -> bool {
+        self.value_watch_addrs.contains(addr)
+            && self
+                .value_watches
+                .iter()
+                .any(|(k, range, v)| *k == kind && range.contains(&addr) && *v == value)
+    }
+
+    #[inline]
+    pub fn is_interrupt_break(&self, flag: InterruptFlag) -> bool {
+        self.interrupts.intersects(flag)
+    }
+
+    /// Break when one of the given interrupts is serviced.
+    pub fn break_on_interrupt(&mut self, flag: InterruptFlag) {
+        self.interrupts.insert(flag);
+    }
+
+    /// Add a watchpoint on the given range of addresses, which only triggers when `value` is read
+    /// or written if it's set.
+    pub fn watch(&mut self, range: RangeInclusive<u16>, kind: WatchKind, value: Option<u8>) {
+        match (kind, value) {
+            (WatchKind::Read, None) => self.read.insert_range(range),
+            (WatchKind::Write, None) => self.write.insert_range(range),
+            (_, Some(value)) => {
+                self.value_watch_addrs.insert_range(range.clone());
+                self.value_watches.push((kind, range, value));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exec.is_empty()
+            && self.banked.is_empty()
+            && self.conditional.is_empty()
+            && self.read.is_empty()
+            && self.write.is_empty()
+            && self.value_watches.is_empty()
+            && self.interrupts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_set() {
+        let mut set = AddressSet::default();
+        assert!(set.is_empty());
+        assert!(!set.contains(0x0100));
+
+        assert!(set.insert(0x4F20));
+        assert!(set.insert(0x0100));
+        assert!(set.insert(0x4F00));
+        assert!(!set.insert(0x0100));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0x0100, 0x4F00, 0x4F20]);
+
+        assert!(set.contains(0x0100));
+        assert!(set.contains(0x4F20));
+        assert!(!set.contains(0x4F21));
+        assert!(!set.contains(0x0101));
+
+        // removing one address from a page keeps the others
+        assert!(set.remove(0x4F00));
+        assert!(!set.remove(0x4F00));
+        assert!(set.contains(0x4F20));
+        assert!(!set.contains(0x4F00));
+
+        assert!(set.remove(0x4F20));
+        assert!(!set.contains(0x4F20));
+        assert_eq!(set.pages, [1 << 1, 0, 0, 0]);
+
+        set.clear();
+        assert!(set.is_empty());
+        assert!(!set.contains(0x0100));
+
+        set.insert(0x80FF);
+        set.insert_range(0x80F0..=0x8110);
+        assert_eq!(set.iter().count(), 0x21);
+        assert!(set.contains(0x80F0));
+        assert!(set.contains(0x8110));
+        assert!(!set.contains(0x8111));
+        assert_eq!(set.pages, [0, 0, 1 << 0 | 1 << 1, 0]);
+    }
+
+    #[test]
+    fn test_banked_breakpoints() {
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.add("0150".parse().unwrap());
+        breakpoints.add("03:4F20".parse().unwrap());
+        breakpoints.add(Breakpoint::in_bank(0, 0x0200));
+        breakpoints.add(Breakpoint::in_bank(1, 0xC000));
+        assert!(!breakpoints.is_empty());
+
+        assert!(breakpoints.is_exec_break(0x0150, || 5, CpuState::default));
+        assert!(breakpoints.is_exec_break(0x4F20, || 3, CpuState::default));
+        assert!(!breakpoints.is_exec_break(0x4F20, || 4, CpuState::default));
+        assert!(!breakpoints.is_exec_break(0x4F21, || 3, CpuState::default));
+        // the bank isn't even looked up without a banked breakpoint at that address
+        assert!(!breakpoints.is_exec_break(0x4000, || unreachable!(), CpuState::default));
+        // the fixed bank is bank 0, and banks are ignored outside of the ROM
+        assert!(breakpoints.is_exec_break(0x0200, || 3, CpuState::default));
+        assert!(breakpoints.is_exec_break(0xC000, || 3, CpuState::default));
+
+        assert_eq!(
+            breakpoints
+                .exec_breakpoints()
+                .map(|b| b.to_string())
+                .collect::<Vec<_>>(),
+            vec!["0150", "00:0200", "01:C000", "03:4F20"]
+        );
+        assert!("xx:4F20".parse::<Breakpoint>().is_err());
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let breakpoint: Breakpoint = "03:4123 if a==0x3e && zf == 1 || hl>=$C000"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            breakpoint.to_string(),
+            "03:4123 if a==3E && zf==1 || hl>=C000"
+        );
+        assert_eq!(
+            breakpoint.to_string().parse::<Breakpoint>().unwrap(),
+            breakpoint
+        );
+        assert_eq!(
+            "0x4123 if a==0x3e".parse::<Breakpoint>().unwrap(),
+            "4123 if a==3e".parse::<Breakpoint>().unwrap()
+        );
+        assert_eq!(
+            "$03:$4123".parse::<Breakpoint>().unwrap(),
+            Breakpoint::in_bank(3, 0x4123)
+        );
+        assert!("4123 if x==1".parse::<Breakpoint>().is_err());
+        assert!("4123 if a=1".parse::<Breakpoint>().is_err());
+        assert!("4123 if a==zz".parse::<Breakpoint>().is_err());
+
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.add(breakpoint);
+        breakpoints.add("0150 if bc!=0".parse().unwrap());
+        assert!(!breakpoints.is_empty());
+        assert_eq!(breakpoints.exec_breakpoints().count(), 2);
+
+        let state = |a, f, hl: u16| CpuState {
+            a,
+            f,
+            h: (hl >> 8) as u8,
+            l: hl as u8,
+            ..CpuState::default()
+        };
+        // A is 3E, but Z isn't set
+        assert!(!breakpoints.is_exec_break(0x4123, || 3, || state(0x3E, 0x00, 0)));
+        assert!(breakpoints.is_exec_break(0x4123, || 3, || state(0x3E, 0x80, 0)));
+        // wrong bank
+        assert!(!breakpoints.is_exec_break(0x4123, || 4, || state(0x3E, 0x80, 0)));
+        assert!(breakpoints.is_exec_break(0x4123, || 3, || state(0, 0, 0xC123)));
+        assert!(!breakpoints.is_exec_break(0x0150, || 3, CpuState::default));
+        // the registers are only looked at when there's a conditional breakpoint at PC
+        assert!(!breakpoints.is_exec_break(0x0151, || 3, || unreachable!()));
+    }
+}
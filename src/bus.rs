@@ -1,13 +1,31 @@
-use std::ops::RangeInclusive;
+use std::{cell::Cell, ops::RangeInclusive, time::Instant};
 
 use log::{info, trace};
 
 use crate::{
-    apu::Apu, cartridge::Cartridge, gfx::Gfx, interrupt::InterruptFlag, joypad::Joypad,
-    timer::Timer, AudioSink, FrameSink,
+    anomaly::Anomaly,
+    apu::Apu,
+    breakpoint::{Breakpoints, WatchHit, WatchKind},
+    cartridge::Cartridge,
+    cheats::{is_cheat_ram, Cheat},
+    error::{GbError, Result},
+    gfx::Gfx,
+    interrupt::InterruptFlag,
+    io_regs::{
+        BGP, BOOT, DIV, DMA, IE, IF, LCDC, NR10, NR11, NR12, NR50, NR51, NR52, P1, SB, SC, TAC,
+        TIMA, TMA, WAVE_RAM,
+    },
+    joypad::Joypad,
+    state::{StateReader, StateWriter, Stateful},
+    timer::Timer,
+    AudioSink, FrameSink, Stats,
 };
 
+#[cfg(feature = "bundled-boot-rom")]
 const BOOT_ROM_DATA: &[u8] = include_bytes!("../assets/dmg_boot.bin");
+/// Without a bundled boot ROM, the host must provide one or skip the boot sequence
+#[cfg(not(feature = "bundled-boot-rom"))]
+const BOOT_ROM_DATA: &[u8] = &[0xFF; 256];
 
 // Memory Map
 const BOOT_ROM: RangeInclusive<u16> = 0x0000..=0x00FF;
@@ -22,36 +40,99 @@ const INVALID_AREA: RangeInclusive<u16> = 0xFEA0..=0xFEFF;
 const IO_REGISTERS: RangeInclusive<u16> = 0xFF00..=0xFF7F;
 const HRAM: RangeInclusive<u16> = 0xFF80..=0xFFFE;
 
-//
-// IO registers ranges (TODO CGB registers)
-//
-/// Joypad controller
-const IO_RANGE_JPD: RangeInclusive<u16> = 0xFF00..=0xFF00;
-/// Communication
-const IO_RANGE_COM: RangeInclusive<u16> = 0xFF01..=0xFF02;
-/// Divider and Timer
-const IO_RANGE_TIM: RangeInclusive<u16> = 0xFF04..=0xFF07;
-/// IF - Interrupt Flag
-const IO_RANGE_INT: RangeInclusive<u16> = 0xFF0F..=0xFF0F;
-/// Sound (APU)
-const IO_RANGE_APU: RangeInclusive<u16> = 0xFF10..=0xFF26;
-/// Waveform RAM
-const IO_RANGE_WAV: RangeInclusive<u16> = 0xFF30..=0xFF3F;
-/// LCD
-const IO_RANGE_LCD: RangeInclusive<u16> = 0xFF40..=0xFF4F;
-/// Disable Boot ROM
-const IO_RANGE_DBR: RangeInclusive<u16> = 0xFF50..=0xFF50;
+/// Maximum number of bytes of serial output kept until they are retrieved
+const MAX_SERIAL_OUTPUT: usize = 64 * 1024;
+
+/// Peripheral handling an IO register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoHandler {
+    Joypad,
+    /// Serial transfer data and control
+    Serial,
+    /// Divider and timer
+    Timer,
+    InterruptFlag,
+    /// Sound registers
+    Apu,
+    WaveRam,
+    /// LCD registers
+    Lcd,
+    /// OAM DMA transfer
+    Dma,
+    /// Disable the boot ROM
+    BootRom,
+    /// CGB-only registers, ignored on the DMG
+    Cgb,
+    /// Nothing: reads as 0xFF, and writes are ignored. Accesses are recorded, see
+    /// [`Bus::unmapped_io_accesses()`].
+    Unmapped,
+}
+
+/// An entry of the IO register map
+#[derive(Debug, Clone, Copy)]
+struct IoPort {
+    handler: IoHandler,
+    /// Bits that always read back as 1
+    read_mask: u8,
+}
+
+/// Handler of each IO register, from 0xFF00 to 0xFF7F
+const IO_MAP: [IoPort; 0x80] = {
+    let map = [IoPort {
+        handler: IoHandler::Unmapped,
+        read_mask: 0xFF,
+    }; 0x80];
+    let map = map_io(map, P1, P1, IoHandler::Joypad, 0x00);
+    let map = map_io(map, SB, SB, IoHandler::Serial, 0x00);
+    // FIXME: the serial control register isn't implemented
+    let map = map_io(map, SC, SC, IoHandler::Serial, 0x7E);
+    let map = map_io(map, DIV, TAC, IoHandler::Timer, 0x00);
+    // Unused bits of IF are always 1
+    let map = map_io(map, IF, IF, IoHandler::InterruptFlag, 0xE0);
+    // The APU has its own read masks
+    let map = map_io(map, NR10, NR52, IoHandler::Apu, 0x00);
+    let map = map_io(map, WAVE_RAM, WAVE_RAM + 0xF, IoHandler::WaveRam, 0x00);
+    let map = map_io(map, LCDC, 0xFF4F, IoHandler::Lcd, 0x00);
+    let map = map_io(map, DMA, DMA, IoHandler::Dma, 0x00);
+    let map = map_io(map, BOOT, BOOT, IoHandler::BootRom, 0xFF);
+    map_io(map, 0xFF68, 0xFF69, IoHandler::Cgb, 0xFF)
+};
+
+/// Route the IO registers from `start` to `end` (inclusive) to the given handler.
+const fn map_io(
+    mut map: [IoPort; 0x80],
+    start: u16,
+    end: u16,
+    handler: IoHandler,
+    read_mask: u8,
+) -> [IoPort; 0x80] {
+    let mut addr = start;
+    while addr <= end {
+        map[(addr - *IO_REGISTERS.start()) as usize] = IoPort { handler, read_mask };
+        addr += 1;
+    }
+    map
+}
 
 pub struct Bus {
     ram: Box<[u8]>,
     hram: Box<[u8]>,
-    apu: Apu,
+    pub(crate) apu: Apu,
     pub(crate) gfx: Gfx,
     pub(crate) cartridge: Cartridge,
     /// P1/JOYP Joypad contoller
     joypad: Joypad,
-    input_has_changed: bool,
+    /// Set when a joypad interrupt should be requested on the next cycle
+    joypad_interrupt: bool,
+
+    /// Clock cycles the CPU has run that the peripherals haven't caught up with yet
+    pending_cycles: u32,
+    /// Number of pending cycles at which the peripherals must be run, because one of them will do
+    /// something the CPU can notice (e.g. request an interrupt)
+    next_event: u32,
 
+    /// Boot ROM mapped at 0x0000-0x00FF until the boot sequence is complete
+    boot_rom: Box<[u8]>,
     has_booted: bool,
 
     /// IE - Interrupt Enable register
@@ -62,6 +143,23 @@ pub struct Bus {
     timer: Timer,
     /// SB - serial byte
     sb: u8,
+    /// Bytes sent over the serial port (e.g. test results printed by test ROMs)
+    serial_output: Vec<u8>,
+    /// Unmapped IO registers accessed so far, one bit per register from 0xFF00
+    unmapped_io: Cell<u128>,
+
+    /// Breakpoints and watchpoints set by the debugger
+    pub(crate) breakpoints: Breakpoints,
+    /// Last CPU memory access that triggered a watchpoint
+    pub(crate) watch_hit: Option<WatchHit>,
+
+    /// Active cheat codes
+    cheats: Vec<Cheat>,
+
+    /// Performance counters
+    pub(crate) stats: Stats,
+    /// Whether the time spent in the PPU and APU is measured
+    pub(crate) profiling: bool,
 }
 
 impl Bus {
@@ -75,37 +173,183 @@ impl Bus {
             gfx: Gfx::new(),
             cartridge,
             joypad: Joypad::default(),
-            input_has_changed: false,
+            joypad_interrupt: false,
+            pending_cycles: 0,
+            next_event: 0,
+            boot_rom: BOOT_ROM_DATA.into(),
             has_booted: false,
             interrupt_enable: InterruptFlag::empty(),
             interrupt_flag: InterruptFlag::empty(),
             timer: Timer::new(),
             sb: 0,
+            serial_output: Vec::new(),
+            unmapped_io: Cell::new(0),
+            breakpoints: Breakpoints::default(),
+            watch_hit: None,
+            cheats: Vec::new(),
+            stats: Stats::default(),
+            profiling: false,
+        }
+    }
+
+    /// Use the given boot ROM instead of the bundled one.
+    pub fn set_boot_rom(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() != BOOT_ROM.len() {
+            return Err(GbError::InvalidBootRom { size: data.len() });
         }
+        self.boot_rom = data.into();
+        Ok(())
     }
 
-    /// Run the different peripherals for the given number of clock cycles
-    pub fn cycle(
-        &mut self,
-        cycles: u8,
-        frame_sink: &mut dyn FrameSink,
-        audio_sink: &mut dyn AudioSink,
-    ) {
-        self.interrupt_flag |= self.gfx.dots(cycles, frame_sink);
-        self.apu.step(cycles, audio_sink);
-        if self.timer.cycle(cycles) {
-            self.interrupt_flag |= InterruptFlag::TIMER;
+    /// Put the IO registers in the state the DMG boot ROM leaves them in, and unmap the boot ROM.
+    pub fn skip_boot(&mut self) {
+        // Sound
+        // NR52 needs to be first as it powers the APU on
+        self.write_io(NR52, 0x80);
+        self.write_io(NR10, 0x80);
+        self.write_io(NR11, 0xBF);
+        self.write_io(NR12, 0xF3);
+        self.write_io(NR50, 0x77);
+        self.write_io(NR51, 0xF3);
+
+        // LCD
+        self.write_io(LCDC, 0x91);
+        self.write_io(BGP, 0xFC);
+
+        // Timer
+        self.timer.set_div_counter(0xABCC);
+        self.interrupt_flag = InterruptFlag::from_bits_truncate(0xE1);
+
+        self.has_booted = true;
+        info!("Skipping boot sequence");
+    }
+
+    /// Advance the clock by the given number of clock cycles.
+    ///
+    /// The peripherals aren't run straight away: they catch up in one batch when the next of them
+    /// is due to do something visible from the CPU (see [`sync()`](Self::sync)), or when the CPU
+    /// accesses them. This is much faster than running them one M-cycle at a time, but the CPU
+    /// still sees the same timings.
+    pub fn cycle(&mut self, cycles: u8) {
+        self.pending_cycles += cycles as u32;
+        if self.pending_cycles >= self.next_event {
+            self.sync();
         }
-        if self.input_has_changed {
+        if self.joypad_interrupt {
             self.interrupt_flag |= InterruptFlag::JOYPAD;
-            self.input_has_changed = false;
+            self.joypad_interrupt = false;
+        }
+    }
+
+    /// Run the peripherals for the pending cycles, and schedule the next time they need to be run.
+    pub(crate) fn sync(&mut self) {
+        let cycles = std::mem::take(&mut self.pending_cycles);
+        if cycles > 0 {
+            let start = self.profiling.then(Instant::now);
+            let interrupts = self.gfx.dots(cycles);
+            let ppu_done = self.profiling.then(Instant::now);
+            self.apu.step(cycles);
+            if let (Some(start), Some(ppu_done)) = (start, ppu_done) {
+                self.stats.ppu_time += ppu_done - start;
+                self.stats.apu_time += ppu_done.elapsed();
+            }
+            if interrupts.contains(InterruptFlag::VBLANK) {
+                self.stats.frames += 1;
+                self.apply_ram_cheats();
+            }
+            self.interrupt_flag |= interrupts;
+            self.cartridge.step(cycles);
+            if self.timer.cycle(cycles) {
+                self.interrupt_flag |= InterruptFlag::TIMER;
+            }
+        }
+        self.schedule_next_event();
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+        self.cartridge.set_rom_patches(&self.cheats);
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+        self.cartridge.set_rom_patches(&self.cheats);
+    }
+
+    /// Apply the GameShark codes, which is done at the start of each VBlank.
+    fn apply_ram_cheats(&mut self) {
+        for i in 0..self.cheats.len() {
+            match self.cheats[i] {
+                Cheat::RamWrite { addr, value } if is_cheat_ram(addr) => {
+                    self.write_byte_unsynced(addr, value)
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Work out when the peripherals next need to be run. Only the PPU and the timer request
+    /// interrupts: the APU and the cartridge's clock can only be observed by reading their
+    /// registers, which syncs them anyway.
+    fn schedule_next_event(&mut self) {
+        let mut next_event = self.gfx.dots_until_event();
+        if let Some(timer) = self.timer.cycles_until_interrupt() {
+            next_event = next_event.min(timer);
+        }
+        self.next_event = next_event;
+    }
+
+    /// Whether an access to the given address can't be done without running the peripherals
+    /// first, i.e. anything but RAM, and reads from the ROM.
+    fn needs_sync(addr: u16, write: bool) -> bool {
+        !(WRAM.contains(&addr)
+            || ECHO_RAM.contains(&addr)
+            || HRAM.contains(&addr)
+            || (!write && (CART_BANK_00.contains(&addr) || CART_BANK_MAPPED.contains(&addr))))
+    }
+
+    /// Send the video and audio output produced so far to the sinks
+    pub fn flush(&mut self, frame_sink: &mut dyn FrameSink, audio_sink: &mut dyn AudioSink) {
+        self.gfx.flush(frame_sink);
+        self.apu.flush(audio_sink);
+    }
+
+    /// Read a byte on behalf of the CPU, checking for read watchpoints.
+    pub(crate) fn cpu_read_byte(&mut self, addr: u16) -> u8 {
+        if Self::needs_sync(addr, false) {
+            self.sync();
         }
+        let value = self.read_byte(addr);
+        if self.breakpoints.is_read_watch(addr, value) {
+            self.watch_hit = Some(WatchHit {
+                kind: WatchKind::Read,
+                addr,
+                value,
+            });
+        }
+        value
+    }
+
+    /// Write a byte on behalf of the CPU, checking for write watchpoints.
+    pub(crate) fn cpu_write_byte(&mut self, addr: u16, value: u8) {
+        if self.breakpoints.is_write_watch(addr, value) {
+            self.watch_hit = Some(WatchHit {
+                kind: WatchKind::Write,
+                addr,
+                value,
+            });
+        }
+        self.write_byte(addr, value);
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
         if BOOT_ROM.contains(&addr) && !self.has_booted {
             // read from boot rom
-            BOOT_ROM_DATA[addr as usize]
+            self.boot_rom[addr as usize]
         } else if CART_BANK_00.contains(&addr) || CART_BANK_MAPPED.contains(&addr) {
             self.cartridge.read_rom(addr)
         } else if VRAM.contains(&addr) {
@@ -118,18 +362,25 @@ impl Bus {
         } else if ECHO_RAM.contains(&addr) {
             // ECHO RAM: mirror of C000-DDFF
             trace!("Accessing ECHO RAM!");
-            self.read_byte(addr - 0x2000)
+            self.ram[(addr - ECHO_RAM.start()) as usize]
         } else if OAM.contains(&addr) {
             // debug!("Reading Sprite attribute table (OAM): 0x{:04x}", addr);
             self.gfx.read_oam(addr)
         } else if INVALID_AREA.contains(&addr) {
             trace!("Invalid access to address 0x{:04x}", addr);
-            0x00
+            // On the DMG, this area reads as 0x00, except while the PPU is using OAM where it
+            // reads as 0xFF like OAM itself. (The OAM corruption these reads cause then isn't
+            // emulated.)
+            if self.gfx.oam_accessible() {
+                0x00
+            } else {
+                0xFF
+            }
         } else if IO_REGISTERS.contains(&addr) {
             self.read_io(addr)
         } else if HRAM.contains(&addr) {
             self.hram[(addr - HRAM.start()) as usize]
-        } else if addr == 0xFFFF {
+        } else if addr == IE {
             trace!("Reading IE register: {:?}", self.interrupt_enable);
             self.interrupt_enable.bits()
         } else {
@@ -137,39 +388,22 @@ impl Bus {
         }
     }
 
-    pub fn read_word(&self, addr: u16) -> u16 {
-        // memory access is little-endian (i.e lsb comes first)
-        let lsb = self.read_byte(addr);
-        let msb = self.read_byte(addr + 1);
-
-        u16::from_le_bytes([lsb, msb])
+    pub fn write_byte(&mut self, addr: u16, b: u8) {
+        let sync = Self::needs_sync(addr, true);
+        if sync {
+            self.sync();
+        }
+        self.write_byte_unsynced(addr, b);
+        if sync {
+            // The write may have moved the next event, e.g. by enabling the timer
+            self.schedule_next_event();
+        }
     }
 
-    pub fn write_byte(&mut self, addr: u16, b: u8) {
-        if BOOT_ROM.contains(&addr) && !self.has_booted {
-            panic!("Tried to write into boot ROM during the boot sequence!");
-        } else if CART_BANK_00.contains(&addr) {
-            if (0x0000..=0x1FFF).contains(&addr) {
-                if b & 0x0A == 0x0A {
-                    trace!("Enabling external RAM");
-                } else {
-                    trace!("Disabling external RAM");
-                }
-            } else if (0x2000..0x3FFF).contains(&addr) {
-                self.cartridge.select_rom_bank(b);
-                // } else {
-                //     // ROM Bank Number register
-                //     // self.cartridge.select_rom_bank(b);
-                //     debug!("Not implemented: select 9th bit of ROM bank number");
-            }
-        } else if CART_BANK_MAPPED.contains(&addr) {
-            if (0x4000..=0x5FFF).contains(&addr) {
-                trace!("Selecting external RAM bank {:02X}", b);
-                self.cartridge.set_secondary_bank_register(b);
-            } else {
-                // Select banking mode
-                self.cartridge.select_banking_mode(b);
-            }
+    fn write_byte_unsynced(&mut self, addr: u16, b: u8) {
+        if CART_BANK_00.contains(&addr) || CART_BANK_MAPPED.contains(&addr) {
+            // Writes to the ROM go to the mapper's registers, even while the boot ROM is mapped
+            self.cartridge.write_rom(addr, b);
         } else if VRAM.contains(&addr) {
             self.gfx.write_vram(addr, b);
         } else if EXT_RAM.contains(&addr) {
@@ -178,7 +412,7 @@ impl Bus {
             self.ram[(addr - WRAM.start()) as usize] = b;
         } else if ECHO_RAM.contains(&addr) {
             // ECHO RAM: mirror of C000-DDFF
-            self.write_byte(addr - 0x2000, b);
+            self.ram[(addr - ECHO_RAM.start()) as usize] = b;
         } else if OAM.contains(&addr) {
             // debug!("Writing Sprite attribute table (OAM): 0x{:04x}", addr);
             self.gfx.write_oam(addr, b);
@@ -189,7 +423,7 @@ impl Bus {
             self.write_io(addr, b);
         } else if HRAM.contains(&addr) {
             self.hram[(addr - HRAM.start()) as usize] = b;
-        } else if addr == 0xFFFF {
+        } else if addr == IE {
             trace!("Setting Interrupt Enable Register with 0b{:08b}", b);
             unsafe {
                 // FIXME: that kind of sucks...
@@ -200,14 +434,6 @@ impl Bus {
         }
     }
 
-    pub(crate) fn write_word(&mut self, addr: u16, word: u16) {
-        // memory access is little-endian, so write the lsb first...
-        let [lsb, msb] = word.to_le_bytes();
-        self.write_byte(addr, lsb);
-        // then the msb
-        self.write_byte(addr + 1, msb);
-    }
-
     pub fn interrupt_enable(&self) -> InterruptFlag {
         self.interrupt_enable
     }
@@ -225,123 +451,332 @@ impl Bus {
         );
     }
 
-    pub fn interrupt_pending(&self) -> bool {
-        !(self.interrupt_enable & self.interrupt_flag).is_empty()
+    /// The IO register map entry of the given address.
+    fn io_port(addr: u16) -> IoPort {
+        IO_MAP[(addr - IO_REGISTERS.start()) as usize]
     }
 
     /// Read access to IO registers
     fn read_io(&self, addr: u16) -> u8 {
-        if IO_RANGE_JPD.contains(&addr) {
-            // Joypad controller register
-            trace!("Read Joypad controller register 0x{:04x}", addr);
-            self.joypad.read()
-        } else if IO_RANGE_COM.contains(&addr) {
-            // Communication controller
-            // FIXME: implement properly
-            if addr == 0xFF01 {
-                self.sb
-            } else {
-                0x7E
+        let port = Self::io_port(addr);
+        let value = match port.handler {
+            IoHandler::Joypad => {
+                trace!("Read Joypad controller register 0x{:04x}", addr);
+                self.joypad.read()
             }
-        } else if IO_RANGE_TIM.contains(&addr) {
-            match addr {
-                0xff04 => self.timer.div_timer(),
-                0xff05 => self.timer.tima(),
-                0xff06 => self.timer.tma(),
-                0xff07 => self.timer.tac(),
+            IoHandler::Serial => match addr {
+                SB => self.sb,
+                _ => 0x00,
+            },
+            IoHandler::Timer => match addr {
+                DIV => self.timer.div_timer(),
+                TIMA => self.timer.tima(),
+                TMA => self.timer.tma(),
+                TAC => self.timer.tac(),
                 _ => unreachable!(),
+            },
+            IoHandler::InterruptFlag => self.interrupt_flag.bits(),
+            IoHandler::Apu => self.apu.read_io(addr),
+            IoHandler::WaveRam => self.apu.read_wav(addr),
+            IoHandler::Lcd | IoHandler::Dma => self.gfx.read_reg(addr),
+            IoHandler::BootRom | IoHandler::Cgb => 0xFF,
+            IoHandler::Unmapped => {
+                // some games access unknown registers for some reason, so just return FF
+                self.record_unmapped_io(addr);
+                0xFF
             }
-        } else if IO_RANGE_INT.contains(&addr) {
-            // IF - interrupt flag
-            // Note: unused bits are always 1
-            self.interrupt_flag.bits() | 0b11100000
-        } else if IO_RANGE_APU.contains(&addr) {
-            // Sound
-            self.apu.read_io(addr)
-        } else if (0xFF27..=0xFF2F).contains(&addr) {
-            // Technically part of the APU range, but not used
-            0xff
-        } else if IO_RANGE_WAV.contains(&addr) {
-            // Waveform ram
-            self.apu.read_wav(addr)
-        } else if IO_RANGE_LCD.contains(&addr) {
-            // LCD
-            // debug!("Read LCD controller 0x{:04x}", addr);
-            self.gfx.read_reg(addr)
-        } else {
-            // some games access unknown registers for some reason, so just return FF
-            0xFF
-        }
+        };
+        value | port.read_mask
     }
 
     /// Write access to IO registers.
     fn write_io(&mut self, addr: u16, b: u8) {
-        if IO_RANGE_JPD.contains(&addr) {
-            // Joypad controller register
-            self.joypad.write(b);
-            trace!(
-                "Write Joypad controller register 0x{:04x}<-0x{:02X}. Register is now {:08b}",
-                addr,
-                b,
-                self.joypad.read()
-            );
-        } else if IO_RANGE_COM.contains(&addr) {
-            // Communication controller
-            if addr == 0xFF01 {
-                self.sb = b;
+        match Self::io_port(addr).handler {
+            IoHandler::Joypad => {
+                self.joypad_interrupt |= self.joypad.write(b);
+                trace!(
+                    "Write Joypad controller register 0x{:04x}<-0x{:02X}. Register is now {:08b}",
+                    addr,
+                    b,
+                    self.joypad.read()
+                );
+            }
+            IoHandler::Serial => {
+                if addr == SB {
+                    self.sb = b;
+                } else if b & 0x81 == 0x81 {
+                    // Transfer requested using the internal clock: there's nothing on the other
+                    // end of the link cable, but keep the byte so that the output of test ROMs can
+                    // be shown.
+                    if self.serial_output.len() >= MAX_SERIAL_OUTPUT {
+                        self.serial_output.drain(..MAX_SERIAL_OUTPUT / 2);
+                    }
+                    self.serial_output.push(self.sb);
+                }
             }
-        } else if IO_RANGE_TIM.contains(&addr) {
-            match addr {
-                0xff04 => self.timer.reset_div_timer(),
-                0xff05 => self.timer.set_tima(b),
-                0xff06 => self.timer.set_tma(b),
-                0xff07 => self.timer.set_tac(b),
+            IoHandler::Timer => match addr {
+                DIV => self.timer.reset_div_timer(),
+                TIMA => self.timer.set_tima(b),
+                TMA => self.timer.set_tma(b),
+                TAC => self.timer.set_tac(b),
                 _ => unreachable!(),
+            },
+            IoHandler::InterruptFlag => {
+                trace!("Setting IF with {:08b}", b);
+                self.interrupt_flag = InterruptFlag::from_bits_truncate(b);
             }
-        } else if IO_RANGE_INT.contains(&addr) {
-            // IF - interrupt flag
-            trace!("Setting IF with {:08b}", b);
-            self.interrupt_flag = InterruptFlag::from_bits_truncate(b);
-        } else if IO_RANGE_APU.contains(&addr) {
-            // Sound
-            trace!("Write sound register 0x{:04x}<-0x{:02X}", addr, b);
-            self.apu.write_io(addr, b);
-        } else if IO_RANGE_WAV.contains(&addr) {
-            // Waveform ram
-            trace!("Write waveform RAM 0x{:04x}<-0x{:02X}", addr, b);
-            self.apu.write_wav(addr, b);
-        } else if IO_RANGE_LCD.contains(&addr) {
-            // LCD
-            // debug!("Write LCD controller 0x{:04x}<-0x{:02X}", addr, b);
-            if addr == 0xff46 {
-                // DMA transfer
+            IoHandler::Apu => {
+                trace!("Write sound register 0x{:04x}<-0x{:02X}", addr, b);
+                self.apu.write_io(addr, b);
+            }
+            IoHandler::WaveRam => {
+                trace!("Write waveform RAM 0x{:04x}<-0x{:02X}", addr, b);
+                self.apu.write_wav(addr, b);
+            }
+            IoHandler::Lcd => self.gfx.write_reg(addr, b),
+            IoHandler::Dma => {
                 let base_addr = (b as u16) * 0x100;
-                // debug!("Starting DMA transfer from 0x{:04x} to OAM", base_addr);
                 for i in 0..=0x9Fu16 {
                     self.gfx
                         .write_oam(OAM.start() + i, self.read_byte(base_addr + i));
                 }
-            } else {
-                self.gfx.write_reg(addr, b);
             }
-        } else if IO_RANGE_DBR.contains(&addr) {
-            if b != 0 {
-                self.has_booted = true;
-                // Disable boot rom
-                info!("Boot sequence complete. Disabling boot ROM.");
+            IoHandler::BootRom => {
+                if b != 0 {
+                    self.has_booted = true;
+                    // Disable boot rom
+                    info!("Boot sequence complete. Disabling boot ROM.");
+                }
+            }
+            IoHandler::Cgb => (),
+            IoHandler::Unmapped => {
+                trace!("Write I/O Register 0x{:04x}<-0x{:02X} (unmapped)", addr, b);
+                self.record_unmapped_io(addr);
             }
-        } else if (0xff68..=0xff69).contains(&addr) {
-            // CGB-only registers, just ignore for now
-        } else {
-            trace!(
-                "Write I/O Register 0x{:04x}<-0x{:02X} (NOT IMPLEMENTED)",
-                addr,
-                b
-            );
         }
     }
 
+    fn record_unmapped_io(&self, addr: u16) {
+        let bit = 1 << (addr - IO_REGISTERS.start());
+        self.unmapped_io.set(self.unmapped_io.get() | bit);
+    }
+
+    /// The unmapped IO registers that have been read or written since power on.
+    pub(crate) fn unmapped_io_accesses(&self) -> Vec<u16> {
+        let accessed = self.unmapped_io.get();
+        IO_REGISTERS
+            .filter(|addr| accessed & (1 << (addr - IO_REGISTERS.start())) != 0)
+            .collect()
+    }
+
     pub(crate) fn set_button_pressed(&mut self, button: crate::joypad::Button, is_pressed: bool) {
-        self.input_has_changed = self.joypad.set_button(button, is_pressed);
+        self.joypad_interrupt |= self.joypad.set_button(button, is_pressed);
+    }
+
+    pub(crate) fn serial_output(&self) -> &[u8] {
+        &self.serial_output
+    }
+
+    pub(crate) fn take_serial_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.serial_output)
+    }
+
+    /// Return the anomalies recorded by the peripherals since the last call, grouped by
+    /// peripheral.
+    pub(crate) fn take_anomalies(&self) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        self.apu.anomalies.drain_into(&mut anomalies);
+        self.cartridge.anomalies.drain_into(&mut anomalies);
+        anomalies
+    }
+}
+
+impl Stateful for Bus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.bytes(&self.hram);
+        self.joypad.save_state(w);
+        w.bool(self.joypad_interrupt);
+        w.u32(self.pending_cycles);
+        w.u32(self.next_event);
+        w.bool(self.has_booted);
+        w.u8(self.interrupt_enable.bits());
+        w.u8(self.interrupt_flag.bits());
+        self.timer.save_state(w);
+        w.u8(self.sb);
+        self.gfx.save_state(w);
+        self.apu.save_state(w);
+        self.cartridge.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.ram)?;
+        r.bytes_into(&mut self.hram)?;
+        self.joypad.load_state(r)?;
+        self.joypad_interrupt = r.bool()?;
+        self.pending_cycles = r.u32()?;
+        self.next_event = r.u32()?;
+        self.has_booted = r.bool()?;
+        let ie = r.u8()?;
+        // IE keeps its unused bits, as when it's written
+        self.interrupt_enable = unsafe { InterruptFlag::from_bits_unchecked(ie) };
+        self.interrupt_flag = InterruptFlag::from_bits_truncate(r.u8()?);
+        self.timer.load_state(r)?;
+        self.sb = r.u8()?;
+        self.gfx.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.cartridge.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_regs::{LY, NR14};
+
+    #[test]
+    fn test_batched_peripherals() {
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        bus.write_byte(LCDC, 0x80);
+        bus.write_byte(IE, 0xFF);
+        // TIMA overflows after 2 ticks of 16 cycles, i.e. 9 M-cycles with the reload
+        bus.write_byte(TIMA, 0xFE);
+        bus.write_byte(TAC, 0x05);
+
+        let mut timer_at = None;
+        let mut vblank_at = None;
+        for m_cycle in 1..=(144 * 456 / 4) {
+            bus.cycle(4);
+            if timer_at.is_none() && bus.interrupt_flag().contains(InterruptFlag::TIMER) {
+                timer_at = Some(m_cycle);
+            }
+            if vblank_at.is_none() && bus.interrupt_flag().contains(InterruptFlag::VBLANK) {
+                vblank_at = Some(m_cycle);
+            }
+            // The peripherals are only run in batches, but they're up to date when accessed
+            if m_cycle % 1000 == 0 {
+                assert_eq!(bus.cpu_read_byte(LY) as u32, m_cycle * 4 / 456);
+            }
+        }
+        assert_eq!(timer_at, Some(9));
+        assert_eq!(vblank_at, Some(144 * 456 / 4));
+        // ...and they weren't run every M-cycle
+        assert!(bus.next_event > 4);
+    }
+
+    #[test]
+    fn test_io_map() {
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        // Read masks
+        bus.write_byte(IF, 0x01);
+        assert_eq!(bus.read_byte(IF), 0xE1);
+        bus.write_byte(SB, 0x42);
+        assert_eq!(bus.read_byte(SB), 0x42);
+        assert_eq!(bus.read_byte(SC), 0x7E);
+        bus.write_byte(TMA, 0x12);
+        assert_eq!(bus.read_byte(TMA), 0x12);
+        assert_eq!(bus.read_byte(BOOT), 0xFF);
+
+        // Unmapped registers read as 0xFF, and the accesses are recorded
+        assert!(bus.unmapped_io_accesses().is_empty());
+        assert_eq!(bus.read_byte(0xFF03), 0xFF);
+        bus.write_byte(0xFF7F, 0x12);
+        assert_eq!(bus.read_byte(0xFF7F), 0xFF);
+        // ...but not the CGB registers, which are ignored
+        bus.write_byte(0xFF68, 0x12);
+        assert_eq!(bus.unmapped_io_accesses(), vec![0xFF03, 0xFF7F]);
+    }
+
+    #[test]
+    fn test_apu_registers() {
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        // Channel 1 at full volume, triggered
+        bus.write_byte(NR12, 0xF0);
+        bus.write_byte(NR14, 0x87);
+        assert_eq!(bus.read_byte(NR12), 0xF0);
+        assert_eq!(bus.read_byte(NR52), 0xF1);
+        bus.write_byte(WAVE_RAM, 0x12);
+
+        // Powering the APU off clears the registers, which then read back as their masks only,
+        // and ignore writes...
+        bus.write_byte(NR52, 0x00);
+        assert_eq!(bus.read_byte(NR52), 0x70);
+        assert_eq!(bus.read_byte(NR12), 0x00);
+        assert_eq!(bus.read_byte(NR51), 0x00);
+        bus.write_byte(NR50, 0x77);
+        assert_eq!(bus.read_byte(NR50), 0x00);
+        // ...except for the wave RAM
+        assert_eq!(bus.read_byte(WAVE_RAM), 0x12);
+        bus.write_byte(WAVE_RAM, 0x34);
+        assert_eq!(bus.read_byte(WAVE_RAM), 0x34);
+
+        bus.write_byte(NR52, 0x80);
+        bus.write_byte(NR50, 0x77);
+        assert_eq!(bus.read_byte(NR50), 0x77);
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4AEF] = 0x03;
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(rom));
+        bus.write_byte(LCDC, 0x80);
+
+        bus.add_cheat("01A-EFB".parse().unwrap());
+        bus.add_cheat("010238CD".parse().unwrap());
+        assert_eq!(bus.read_byte(0x4AEF), 0x01);
+        // the RAM is only written at the start of VBlank
+        assert_eq!(bus.read_byte(0xCD38), 0x00);
+        for _ in 0..(144 * 456 / 4) {
+            bus.cycle(4);
+        }
+        assert_eq!(bus.read_byte(0xCD38), 0x02);
+
+        bus.clear_cheats();
+        assert_eq!(bus.read_byte(0x4AEF), 0x03);
+    }
+
+    #[test]
+    fn test_boot_rom() {
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        let err = bus.set_boot_rom(&[0; 100]).unwrap_err();
+        assert!(matches!(err, GbError::InvalidBootRom { size: 100 }));
+        assert_eq!(
+            err.to_string(),
+            "Invalid boot ROM size: expected 256 bytes, got 100"
+        );
+
+        bus.set_boot_rom(&[0x31; 256]).unwrap();
+        // writes go to the mapper and don't affect the boot ROM
+        bus.write_byte(0x0010, 0x0A);
+        assert_eq!(bus.read_byte(0x0010), 0x31);
+        assert!(bus.take_anomalies().is_empty());
+    }
+
+    #[test]
+    fn test_echo_ram_and_invalid_area() {
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        bus.write_byte(0xC000, 0x12);
+        bus.write_byte(0xFDFF, 0x34);
+        bus.write_byte(0xFE00, 0x56);
+        assert_eq!(bus.cpu_read_byte(0xE000), 0x12);
+        assert_eq!(bus.cpu_read_byte(0xDDFF), 0x34);
+        // OAM isn't part of the mirror
+        assert_eq!(bus.cpu_read_byte(0xDE00), 0x00);
+        assert_eq!(bus.cpu_read_byte(0xFE00), 0x56);
+
+        // The invalid area reads as 0x00 and ignores writes...
+        bus.write_byte(0xFEA0, 0x78);
+        assert_eq!(bus.cpu_read_byte(0xFEA0), 0x00);
+        assert_eq!(bus.cpu_read_byte(0xFEFF), 0x00);
+        // ...except while the PPU is reading OAM (mode 2 at the start of the second line)
+        bus.write_byte(LCDC, 0x80);
+        for _ in 0..(456 + 8) / 4 {
+            bus.cycle(4);
+        }
+        assert_eq!(bus.cpu_read_byte(0xFE00), 0xFF);
+        assert_eq!(bus.cpu_read_byte(0xFEA0), 0xFF);
+        assert_eq!(bus.cpu_read_byte(0xFEFF), 0xFF);
     }
 }
@@ -0,0 +1,323 @@
+//! The cartridge header (0100-014F), which describes the game and the hardware of the cartridge.
+//!
+//! See <https://gbdev.io/pandocs/The_Cartridge_Header.html>.
+use std::fmt;
+
+/// Kind of cartridge, i.e. its mapper and the other hardware it has, from the byte at 0147.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CartridgeType {
+    RomOnly,
+    Mbc1,
+    Mbc1Ram,
+    Mbc1RamBattery,
+    Mbc2,
+    Mbc2Battery,
+    RomRam,
+    RomRamBattery,
+    Mmm01,
+    Mmm01Ram,
+    Mmm01RamBattery,
+    Mbc3TimerBattery,
+    Mbc3TimerRamBattery,
+    Mbc3,
+    Mbc3Ram,
+    Mbc3RamBattery,
+    Mbc5,
+    Mbc5Ram,
+    Mbc5RamBattery,
+    Mbc5Rumble,
+    Mbc5RumbleRam,
+    Mbc5RumbleRamBattery,
+    Mbc6,
+    Mbc7SensorRumbleRamBattery,
+    PocketCamera,
+    BandaiTama5,
+    HuC3,
+    HuC1RamBattery,
+    Unknown(u8),
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => CartridgeType::RomOnly,
+            0x01 => CartridgeType::Mbc1,
+            0x02 => CartridgeType::Mbc1Ram,
+            0x03 => CartridgeType::Mbc1RamBattery,
+            0x05 => CartridgeType::Mbc2,
+            0x06 => CartridgeType::Mbc2Battery,
+            0x08 => CartridgeType::RomRam,
+            0x09 => CartridgeType::RomRamBattery,
+            0x0B => CartridgeType::Mmm01,
+            0x0C => CartridgeType::Mmm01Ram,
+            0x0D => CartridgeType::Mmm01RamBattery,
+            0x0F => CartridgeType::Mbc3TimerBattery,
+            0x10 => CartridgeType::Mbc3TimerRamBattery,
+            0x11 => CartridgeType::Mbc3,
+            0x12 => CartridgeType::Mbc3Ram,
+            0x13 => CartridgeType::Mbc3RamBattery,
+            0x19 => CartridgeType::Mbc5,
+            0x1A => CartridgeType::Mbc5Ram,
+            0x1B => CartridgeType::Mbc5RamBattery,
+            0x1C => CartridgeType::Mbc5Rumble,
+            0x1D => CartridgeType::Mbc5RumbleRam,
+            0x1E => CartridgeType::Mbc5RumbleRamBattery,
+            0x20 => CartridgeType::Mbc6,
+            0x22 => CartridgeType::Mbc7SensorRumbleRamBattery,
+            0xFC => CartridgeType::PocketCamera,
+            0xFD => CartridgeType::BandaiTama5,
+            0xFE => CartridgeType::HuC3,
+            0xFF => CartridgeType::HuC1RamBattery,
+            code => CartridgeType::Unknown(code),
+        }
+    }
+
+    /// The byte at 0147 for this type.
+    pub fn code(&self) -> u8 {
+        match self {
+            CartridgeType::RomOnly => 0x00,
+            CartridgeType::Mbc1 => 0x01,
+            CartridgeType::Mbc1Ram => 0x02,
+            CartridgeType::Mbc1RamBattery => 0x03,
+            CartridgeType::Mbc2 => 0x05,
+            CartridgeType::Mbc2Battery => 0x06,
+            CartridgeType::RomRam => 0x08,
+            CartridgeType::RomRamBattery => 0x09,
+            CartridgeType::Mmm01 => 0x0B,
+            CartridgeType::Mmm01Ram => 0x0C,
+            CartridgeType::Mmm01RamBattery => 0x0D,
+            CartridgeType::Mbc3TimerBattery => 0x0F,
+            CartridgeType::Mbc3TimerRamBattery => 0x10,
+            CartridgeType::Mbc3 => 0x11,
+            CartridgeType::Mbc3Ram => 0x12,
+            CartridgeType::Mbc3RamBattery => 0x13,
+            CartridgeType::Mbc5 => 0x19,
+            CartridgeType::Mbc5Ram => 0x1A,
+            CartridgeType::Mbc5RamBattery => 0x1B,
+            CartridgeType::Mbc5Rumble => 0x1C,
+            CartridgeType::Mbc5RumbleRam => 0x1D,
+            CartridgeType::Mbc5RumbleRamBattery => 0x1E,
+            CartridgeType::Mbc6 => 0x20,
+            CartridgeType::Mbc7SensorRumbleRamBattery => 0x22,
+            CartridgeType::PocketCamera => 0xFC,
+            CartridgeType::BandaiTama5 => 0xFD,
+            CartridgeType::HuC3 => 0xFE,
+            CartridgeType::HuC1RamBattery => 0xFF,
+            CartridgeType::Unknown(code) => *code,
+        }
+    }
+
+    /// Name of the type as listed in the Pan Docs (e.g. `MBC3+RAM+BATTERY`), or `None` if it's
+    /// unknown.
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self {
+            CartridgeType::RomOnly => "ROM ONLY",
+            CartridgeType::Mbc1 => "MBC1",
+            CartridgeType::Mbc1Ram => "MBC1+RAM",
+            CartridgeType::Mbc1RamBattery => "MBC1+RAM+BATTERY",
+            CartridgeType::Mbc2 => "MBC2",
+            CartridgeType::Mbc2Battery => "MBC2+BATTERY",
+            CartridgeType::RomRam => "ROM+RAM",
+            CartridgeType::RomRamBattery => "ROM+RAM+BATTERY",
+            CartridgeType::Mmm01 => "MMM01",
+            CartridgeType::Mmm01Ram => "MMM01+RAM",
+            CartridgeType::Mmm01RamBattery => "MMM01+RAM+BATTERY",
+            CartridgeType::Mbc3TimerBattery => "MBC3+TIMER+BATTERY",
+            CartridgeType::Mbc3TimerRamBattery => "MBC3+TIMER+RAM+BATTERY",
+            CartridgeType::Mbc3 => "MBC3",
+            CartridgeType::Mbc3Ram => "MBC3+RAM",
+            CartridgeType::Mbc3RamBattery => "MBC3+RAM+BATTERY",
+            CartridgeType::Mbc5 => "MBC5",
+            CartridgeType::Mbc5Ram => "MBC5+RAM",
+            CartridgeType::Mbc5RamBattery => "MBC5+RAM+BATTERY",
+            CartridgeType::Mbc5Rumble => "MBC5+RUMBLE",
+            CartridgeType::Mbc5RumbleRam => "MBC5+RUMBLE+RAM",
+            CartridgeType::Mbc5RumbleRamBattery => "MBC5+RUMBLE+RAM+BATTERY",
+            CartridgeType::Mbc6 => "MBC6",
+            CartridgeType::Mbc7SensorRumbleRamBattery => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            CartridgeType::PocketCamera => "POCKET CAMERA",
+            CartridgeType::BandaiTama5 => "BANDAI TAMA5",
+            CartridgeType::HuC3 => "HuC3",
+            CartridgeType::HuC1RamBattery => "HuC1+RAM+BATTERY",
+            CartridgeType::Unknown(_) => return None,
+        };
+        Some(name)
+    }
+
+    /// Whether the cartridge has external RAM, whose size is given by the header.
+    ///
+    /// The MBC2's built-in RAM doesn't count, as the header says it has no RAM.
+    pub fn has_ram(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc1Ram
+                | CartridgeType::Mbc1RamBattery
+                | CartridgeType::RomRam
+                | CartridgeType::RomRamBattery
+                | CartridgeType::Mmm01Ram
+                | CartridgeType::Mmm01RamBattery
+                | CartridgeType::Mbc3TimerRamBattery
+                | CartridgeType::Mbc3Ram
+                | CartridgeType::Mbc3RamBattery
+                | CartridgeType::Mbc5Ram
+                | CartridgeType::Mbc5RamBattery
+                | CartridgeType::Mbc5RumbleRam
+                | CartridgeType::Mbc5RumbleRamBattery
+                | CartridgeType::Mbc7SensorRumbleRamBattery
+                | CartridgeType::HuC1RamBattery
+        )
+    }
+
+    /// Whether the cartridge has a battery to keep the contents of its RAM (or its clock).
+    pub fn has_battery(&self) -> bool {
+        self.name().is_some_and(|name| name.ends_with("BATTERY"))
+    }
+
+    /// Whether the cartridge has a real-time clock (MBC3+TIMER).
+    pub fn has_timer(&self) -> bool {
+        self.name().is_some_and(|name| name.contains("TIMER"))
+    }
+}
+
+impl fmt::Display for CartridgeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "UNKNOWN ({:02x})", self.code()),
+        }
+    }
+}
+
+/// The information in the header of a cartridge.
+///
+/// Nothing is validated: see [`Cartridge::check_header()`](super::Cartridge::check_header) for
+/// that. Bytes missing from a ROM that's too small to have a header read as 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    /// Title of the game, in upper case ASCII
+    pub title: String,
+    /// Code of the publisher: the 2 characters of the new licensee code, or the old one in hex
+    /// followed by `(OLD)`
+    pub licensee: String,
+    pub cartridge_type: CartridgeType,
+    /// ROM size byte (0148)
+    pub rom_size: u8,
+    /// Number of 16KB ROM banks, or `None` if the ROM size is invalid
+    pub rom_banks: Option<u16>,
+    /// RAM size byte (0149)
+    pub ram_size: u8,
+    /// Number of 8KB RAM banks, or `None` if the RAM size is invalid
+    pub ram_banks: Option<u16>,
+    /// Whether the game supports the Game Boy Color's features
+    pub cgb: bool,
+    /// Whether the game supports the Super Game Boy's features
+    pub sgb: bool,
+    /// Checksum of 0134-014C, verified by the boot ROM
+    pub header_checksum: u8,
+    /// Sum of all the bytes of the ROM except itself, which nothing verifies
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    /// Parse the header of the given ROM.
+    pub fn parse(rom: &[u8]) -> Self {
+        let byte = |addr: usize| rom.get(addr).copied().unwrap_or(0);
+        let cgb = byte(0x0143) & 0x80 != 0;
+        // The last byte of the title became the CGB flag
+        let title_end = if cgb { 0x0142 } else { 0x0143 };
+        let title = rom.get(0x0134..=title_end).unwrap_or(&[]);
+        let title = &title[..title.iter().position(|b| *b == 0).unwrap_or(title.len())];
+
+        let licensee = match byte(0x014B) {
+            // Uses the new licensee code instead
+            0x33 => String::from_utf8_lossy(rom.get(0x0144..=0x0145).unwrap_or(&[])).to_string(),
+            code => format!("{:02x} (OLD)", code),
+        };
+        let rom_size = byte(0x0148);
+        let ram_size = byte(0x0149);
+
+        Self {
+            title: String::from_utf8_lossy(title).to_string(),
+            licensee,
+            cartridge_type: CartridgeType::from_code(byte(0x0147)),
+            rom_size,
+            rom_banks: (rom_size <= 0x08).then(|| 2 << rom_size),
+            ram_size,
+            ram_banks: match ram_size {
+                0x00 => Some(0),
+                0x02 => Some(1),
+                0x03 => Some(4),
+                0x04 => Some(16),
+                0x05 => Some(8),
+                _ => None,
+            },
+            cgb,
+            sgb: byte(0x0146) == 0x03,
+            header_checksum: byte(0x014D),
+            global_checksum: u16::from_be_bytes([byte(0x014E), byte(0x014F)]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x013F].copy_from_slice(b"POKEMON RED");
+        rom[0x0144..=0x0145].copy_from_slice(b"01");
+        rom[0x0146] = 0x03;
+        rom[0x0147] = 0x13;
+        rom[0x0148] = 0x05;
+        rom[0x0149] = 0x03;
+        rom[0x014B] = 0x33;
+        rom[0x014D] = 0x20;
+        rom[0x014E..=0x014F].copy_from_slice(&[0x91, 0xE6]);
+
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(
+            header,
+            CartridgeHeader {
+                title: "POKEMON RED".to_string(),
+                licensee: "01".to_string(),
+                cartridge_type: CartridgeType::Mbc3RamBattery,
+                rom_size: 0x05,
+                rom_banks: Some(64),
+                ram_size: 0x03,
+                ram_banks: Some(4),
+                cgb: false,
+                sgb: true,
+                header_checksum: 0x20,
+                global_checksum: 0x91E6,
+            }
+        );
+        assert_eq!(header.cartridge_type.to_string(), "MBC3+RAM+BATTERY");
+        assert!(header.cartridge_type.has_ram());
+        assert!(header.cartridge_type.has_battery());
+
+        // The CGB flag isn't part of the title
+        rom[0x0134..=0x0143].copy_from_slice(b"LONG CGB TITLE!\x80");
+        rom[0x0147] = 0x42;
+        rom[0x0148] = 0x09;
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(header.title, "LONG CGB TITLE!");
+        assert!(header.cgb);
+        assert_eq!(header.cartridge_type, CartridgeType::Unknown(0x42));
+        assert_eq!(header.cartridge_type.to_string(), "UNKNOWN (42)");
+        assert_eq!(header.rom_banks, None);
+
+        // Too small to have a header
+        let header = CartridgeHeader::parse(&[]);
+        assert_eq!(header.title, "");
+        assert_eq!(header.cartridge_type, CartridgeType::RomOnly);
+    }
+
+    #[test]
+    fn test_cartridge_type_codes() {
+        for code in 0..=0xFF {
+            assert_eq!(CartridgeType::from_code(code).code(), code);
+        }
+    }
+}
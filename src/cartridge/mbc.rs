@@ -0,0 +1,761 @@
+//! Memory bank controllers (i.e. mappers), which map the cartridge's ROM and RAM banks into the
+//! address space.
+use log::{trace, warn};
+
+use super::{CartridgeType, MapperState};
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter, Stateful};
+use crate::timing::CYCLES_PER_SECOND;
+
+/// Size of a ROM bank
+const ROM_BANK_SIZE: usize = 0x4000;
+/// Size of a RAM bank
+const RAM_BANK_SIZE: usize = 0x2000;
+
+/// A memory bank controller.
+///
+/// The mapper only holds the state of its registers: the ROM and RAM are owned by the cartridge
+/// and passed in on each access. RAM addresses are relative to the start of the external RAM
+/// area, i.e. in the range 0000-1FFF.
+pub(crate) trait Mbc {
+    /// Read a byte from 0000-7FFF.
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8;
+
+    /// Handle a write to 0000-7FFF, which is where the mapper's registers are.
+    fn write_rom(&mut self, addr: u16, b: u8);
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8;
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, b: u8);
+
+    /// Whether the RAM (and anything else mapped at A000-BFFF) is enabled. While it's disabled,
+    /// writes are ignored and reads return open bus.
+    fn ram_enabled(&self) -> bool {
+        true
+    }
+
+    /// Advance the mapper's own clock (if it has one) by the given number of clock cycles.
+    fn step(&mut self, _cycles: u32) {}
+
+    /// Number of the ROM bank currently mapped at 4000-7FFF.
+    fn rom_bank(&self) -> u16;
+
+    /// Number of the RAM bank currently mapped at A000-BFFF.
+    fn ram_bank(&self) -> u8;
+
+    fn state(&self) -> MapperState;
+
+    fn set_state(&mut self, state: MapperState);
+
+    /// Save the mapper's registers in a savestate. Mappers with more state than the registers in
+    /// [`MapperState`] must save it too.
+    fn save_state(&self, w: &mut StateWriter) {
+        let state = self.state();
+        w.bool(state.ram_enabled);
+        w.u16(state.rom_bank_register);
+        w.u8(state.ram_bank_register);
+        w.bool(state.banking_mode_1);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.set_state(MapperState {
+            ram_enabled: r.bool()?,
+            rom_bank_register: r.u16()?,
+            ram_bank_register: r.u8()?,
+            banking_mode_1: r.bool()?,
+            ..Default::default()
+        });
+        Ok(())
+    }
+}
+
+/// Create the mapper for the given cartridge type.
+///
+/// The masks are the number of ROM and RAM banks minus one: the bank registers are wider than
+/// needed for most cartridges, and the bits that aren't needed aren't connected to anything.
+pub(crate) fn new_mbc(
+    cartridge_type: CartridgeType,
+    rom_bank_mask: u16,
+    ram_bank_mask: u8,
+) -> Box<dyn Mbc> {
+    let masks = BankMasks {
+        rom: rom_bank_mask,
+        ram: ram_bank_mask,
+    };
+    match cartridge_type {
+        CartridgeType::RomOnly | CartridgeType::RomRam | CartridgeType::RomRamBattery => {
+            Box::<NoMbc>::default()
+        }
+        CartridgeType::Mbc1 | CartridgeType::Mbc1Ram | CartridgeType::Mbc1RamBattery => {
+            Box::new(Mbc1::new(masks))
+        }
+        CartridgeType::Mbc2 | CartridgeType::Mbc2Battery => Box::new(Mbc2::new(masks)),
+        CartridgeType::Mbc3TimerBattery
+        | CartridgeType::Mbc3TimerRamBattery
+        | CartridgeType::Mbc3
+        | CartridgeType::Mbc3Ram
+        | CartridgeType::Mbc3RamBattery => Box::new(Mbc3::new(masks)),
+        CartridgeType::Mbc5
+        | CartridgeType::Mbc5Ram
+        | CartridgeType::Mbc5RamBattery
+        | CartridgeType::Mbc5Rumble
+        | CartridgeType::Mbc5RumbleRam
+        | CartridgeType::Mbc5RumbleRamBattery => Box::new(Mbc5::new(masks)),
+        t => {
+            warn!("Unsupported cartridge type {}, ignoring its mapper", t);
+            Box::<NoMbc>::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BankMasks {
+    rom: u16,
+    ram: u8,
+}
+
+/// Read a byte of the given bank. Reading past the end of the data (e.g. a truncated ROM dump)
+/// returns open bus.
+fn read_bank(data: &[u8], bank_size: usize, bank: usize, offset: u16) -> u8 {
+    data.get(bank * bank_size + offset as usize)
+        .copied()
+        .unwrap_or(0xFF)
+}
+
+/// Offset in the RAM of the given address of the given bank.
+///
+/// RAM chips smaller than the area they're mapped to are mirrored over it, as the address lines
+/// they don't have aren't connected. Returns `None` if there's no RAM at all.
+fn ram_offset(ram: &[u8], bank: usize, offset: u16) -> Option<usize> {
+    (!ram.is_empty()).then(|| (bank * RAM_BANK_SIZE + offset as usize) % ram.len())
+}
+
+/// Read a byte of the given RAM bank, or open bus if there's no RAM.
+fn read_ram_bank(ram: &[u8], bank: usize, offset: u16) -> u8 {
+    ram_offset(ram, bank, offset).map_or(0xFF, |i| ram[i])
+}
+
+/// Write a byte to the given RAM bank, unless there's no RAM.
+fn write_ram_bank(ram: &mut [u8], bank: usize, offset: u16, b: u8) {
+    if let Some(i) = ram_offset(ram, bank, offset) {
+        ram[i] = b;
+    }
+}
+
+/// Read from the fixed bank at 0000-3FFF or the switchable bank at 4000-7FFF.
+fn read_rom_banks(rom: &[u8], addr: u16, low_bank: u16, high_bank: u16) -> u8 {
+    if addr < 0x4000 {
+        read_bank(rom, ROM_BANK_SIZE, low_bank as usize, addr)
+    } else {
+        read_bank(rom, ROM_BANK_SIZE, high_bank as usize, addr - 0x4000)
+    }
+}
+
+/// The RAM is enabled by writing a value with $A in its lower 4 bits to 0000-1FFF.
+fn is_ram_enable(b: u8) -> bool {
+    let enabled = b & 0x0F == 0x0A;
+    trace!(
+        "{} external RAM",
+        if enabled { "Enabling" } else { "Disabling" }
+    );
+    enabled
+}
+
+/// 32KB ROM (and optionally 8KB of RAM) without any banking.
+#[derive(Debug, Default)]
+struct NoMbc;
+
+impl Mbc for NoMbc {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        read_rom_banks(rom, addr, 0, 1)
+    }
+
+    fn write_rom(&mut self, addr: u16, b: u8) {
+        trace!("Ignoring write to ROM 0x{:04x}<-0x{:02x}", addr, b);
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        read_ram_bank(ram, 0, addr)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, b: u8) {
+        write_ram_bank(ram, 0, addr, b);
+    }
+
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    fn ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState::default()
+    }
+
+    fn set_state(&mut self, _state: MapperState) {}
+}
+
+/// Up to 2MB of ROM and 32KB of RAM.
+#[derive(Debug)]
+struct Mbc1 {
+    masks: BankMasks,
+    ram_enabled: bool,
+    /// Bank register (lower 5 bits of the ROM bank number)
+    selected_rom_bank: u8,
+    /// Secondary bank register (RAM bank number, or upper bits of the ROM bank number)
+    secondary_bank_register: u8,
+    banking_mode_1: bool,
+}
+
+impl Mbc1 {
+    fn new(masks: BankMasks) -> Self {
+        Self {
+            masks,
+            ram_enabled: false,
+            selected_rom_bank: 0x01,
+            secondary_bank_register: 0x00,
+            banking_mode_1: false,
+        }
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        // In mode 1, the secondary bank register also applies to 0000-3FFF
+        let low_bank = if self.banking_mode_1 {
+            ((self.secondary_bank_register as u16) << 5) & self.masks.rom
+        } else {
+            0
+        };
+        read_rom_banks(rom, addr, low_bank, self.rom_bank())
+    }
+
+    fn write_rom(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = is_ram_enable(b),
+            0x2000..=0x3FFF => {
+                // Bank 0 can't be selected here: it's turned into bank 1
+                self.selected_rom_bank = (b & 0x1F).max(1);
+                trace!("Selected ROM bank {}", self.selected_rom_bank);
+            }
+            0x4000..=0x5FFF => {
+                self.secondary_bank_register = b & 0x03;
+                trace!(
+                    "Secondary bank register: {:02x}",
+                    self.secondary_bank_register
+                );
+            }
+            _ => {
+                self.banking_mode_1 = b & 0x01 != 0;
+                trace!("Banking mode select {}", self.banking_mode_1 as u8);
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        read_ram_bank(ram, self.ram_bank() as usize, addr)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, b: u8) {
+        write_ram_bank(ram, self.ram_bank() as usize, addr, b);
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rom_bank(&self) -> u16 {
+        let bank = ((self.secondary_bank_register as u16) << 5) | self.selected_rom_bank as u16;
+        bank & self.masks.rom
+    }
+
+    fn ram_bank(&self) -> u8 {
+        if self.banking_mode_1 {
+            self.secondary_bank_register & self.masks.ram
+        } else {
+            0
+        }
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState {
+            ram_enabled: self.ram_enabled,
+            rom_bank_register: self.selected_rom_bank as u16,
+            ram_bank_register: self.secondary_bank_register,
+            banking_mode_1: self.banking_mode_1,
+            ..Default::default()
+        }
+    }
+
+    fn set_state(&mut self, state: MapperState) {
+        self.ram_enabled = state.ram_enabled;
+        self.selected_rom_bank = state.rom_bank_register as u8;
+        self.secondary_bank_register = state.ram_bank_register;
+        self.banking_mode_1 = state.banking_mode_1;
+    }
+}
+
+/// Up to 256KB of ROM, and 512 half-bytes of built-in RAM.
+#[derive(Debug)]
+struct Mbc2 {
+    masks: BankMasks,
+    ram_enabled: bool,
+    selected_rom_bank: u8,
+}
+
+impl Mbc2 {
+    fn new(masks: BankMasks) -> Self {
+        Self {
+            masks,
+            ram_enabled: false,
+            selected_rom_bank: 0x01,
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        read_rom_banks(rom, addr, 0, self.rom_bank())
+    }
+
+    fn write_rom(&mut self, addr: u16, b: u8) {
+        // Only 0000-3FFF is used, and bit 8 of the address selects the register
+        match addr {
+            0x0000..=0x3FFF if addr & 0x0100 == 0 => self.ram_enabled = is_ram_enable(b),
+            0x0000..=0x3FFF => {
+                self.selected_rom_bank = (b & 0x0F).max(1);
+                trace!("Selected ROM bank {}", self.selected_rom_bank);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        // Only the lower 4 bits are actually stored, and the 512 bytes are mirrored over the
+        // whole area
+        0xF0 | read_ram_bank(ram, 0, addr & 0x01FF)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, b: u8) {
+        write_ram_bank(ram, 0, addr & 0x01FF, b & 0x0F);
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.selected_rom_bank as u16 & self.masks.rom
+    }
+
+    fn ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState {
+            ram_enabled: self.ram_enabled,
+            rom_bank_register: self.selected_rom_bank as u16,
+            ..Default::default()
+        }
+    }
+
+    fn set_state(&mut self, state: MapperState) {
+        self.ram_enabled = state.ram_enabled;
+        self.selected_rom_bank = state.rom_bank_register as u8;
+    }
+}
+
+/// Up to 2MB of ROM, 32KB of RAM, and a real-time clock.
+#[derive(Debug)]
+struct Mbc3 {
+    masks: BankMasks,
+    ram_enabled: bool,
+    selected_rom_bank: u8,
+    /// RAM bank (00-03) or RTC register (08-0C) mapped at A000-BFFF
+    ram_bank_register: u8,
+    rtc: Rtc,
+    /// The RTC registers are latched by writing 00 then 01 to 6000-7FFF
+    latched: Rtc,
+    last_latch_write: u8,
+}
+
+impl Mbc3 {
+    fn new(masks: BankMasks) -> Self {
+        Self {
+            masks,
+            ram_enabled: false,
+            selected_rom_bank: 0x01,
+            ram_bank_register: 0x00,
+            rtc: Rtc::default(),
+            latched: Rtc::default(),
+            last_latch_write: 0xFF,
+        }
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        read_rom_banks(rom, addr, 0, self.rom_bank())
+    }
+
+    fn write_rom(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = is_ram_enable(b),
+            0x2000..=0x3FFF => {
+                self.selected_rom_bank = (b & 0x7F).max(1);
+                trace!("Selected ROM bank {}", self.selected_rom_bank);
+            }
+            0x4000..=0x5FFF => self.ram_bank_register = b,
+            _ => {
+                if self.last_latch_write == 0x00 && b == 0x01 {
+                    self.latched = self.rtc.clone();
+                }
+                self.last_latch_write = b;
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        match self.ram_bank_register {
+            0x00..=0x03 => read_ram_bank(ram, self.ram_bank() as usize, addr),
+            reg @ 0x08..=0x0C => self.latched.read(reg),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, b: u8) {
+        match self.ram_bank_register {
+            0x00..=0x03 => write_ram_bank(ram, self.ram_bank() as usize, addr, b),
+            reg @ 0x08..=0x0C => self.rtc.write(reg, b),
+            _ => {}
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn step(&mut self, cycles: u32) {
+        self.rtc.step(cycles);
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.selected_rom_bank as u16 & self.masks.rom
+    }
+
+    fn ram_bank(&self) -> u8 {
+        self.ram_bank_register & self.masks.ram
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState {
+            ram_enabled: self.ram_enabled,
+            rom_bank_register: self.selected_rom_bank as u16,
+            ram_bank_register: self.ram_bank_register,
+            banking_mode_1: false,
+            rtc_latched: [0x08, 0x09, 0x0A, 0x0B, 0x0C].map(|reg| self.latched.read(reg)),
+            rtc_latch_write: self.last_latch_write,
+        }
+    }
+
+    fn set_state(&mut self, state: MapperState) {
+        self.ram_enabled = state.ram_enabled;
+        self.selected_rom_bank = state.rom_bank_register as u8;
+        self.ram_bank_register = state.ram_bank_register;
+        for (reg, b) in (0x08..=0x0C).zip(state.rtc_latched) {
+            self.latched.write(reg, b);
+        }
+        self.last_latch_write = state.rtc_latch_write;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.u8(self.selected_rom_bank);
+        w.u8(self.ram_bank_register);
+        self.rtc.save_state(w);
+        self.latched.save_state(w);
+        w.u8(self.last_latch_write);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.ram_enabled = r.bool()?;
+        self.selected_rom_bank = r.u8()?;
+        self.ram_bank_register = r.u8()?;
+        self.rtc.load_state(r)?;
+        self.latched.load_state(r)?;
+        self.last_latch_write = r.u8()?;
+        Ok(())
+    }
+}
+
+/// The MBC3's real-time clock.
+///
+/// It counts emulated time rather than the host's time, so that it's deterministic.
+#[derive(Debug, Default, Clone)]
+struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    /// 9-bit day counter
+    days: u16,
+    halted: bool,
+    /// Set when the day counter overflows, until cleared by the game
+    day_carry: bool,
+    /// Clock cycles since the last second
+    cycles: u32,
+}
+
+impl Rtc {
+    fn step(&mut self, cycles: u32) {
+        if self.halted {
+            return;
+        }
+        self.cycles += cycles;
+        if self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+
+    /// Advance the clock by one second. Out of range values (which can be written by the game)
+    /// keep counting until the register overflows.
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            _ => {
+                ((self.day_carry as u8) << 7)
+                    | ((self.halted as u8) << 6)
+                    | ((self.days >> 8) as u8 & 0x01)
+            }
+        }
+    }
+
+    fn write(&mut self, reg: u8, b: u8) {
+        match reg {
+            0x08 => {
+                self.seconds = b & 0x3F;
+                // writing the seconds resets the sub-second counter
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = b & 0x3F,
+            0x0A => self.hours = b & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | b as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | ((b as u16 & 0x01) << 8);
+                self.halted = b & 0x40 != 0;
+                self.day_carry = b & 0x80 != 0;
+            }
+        }
+    }
+}
+
+impl Stateful for Rtc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.seconds);
+        w.u8(self.minutes);
+        w.u8(self.hours);
+        w.u16(self.days);
+        w.bool(self.halted);
+        w.bool(self.day_carry);
+        w.u32(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.seconds = r.u8()?;
+        self.minutes = r.u8()?;
+        self.hours = r.u8()?;
+        self.days = r.u16()?;
+        self.halted = r.bool()?;
+        self.day_carry = r.bool()?;
+        self.cycles = r.u32()?;
+        Ok(())
+    }
+}
+
+/// Up to 8MB of ROM and 128KB of RAM.
+#[derive(Debug)]
+struct Mbc5 {
+    masks: BankMasks,
+    ram_enabled: bool,
+    /// 9-bit ROM bank number. Unlike the other mappers, bank 0 can be mapped at 4000-7FFF.
+    selected_rom_bank: u16,
+    selected_ram_bank: u8,
+}
+
+impl Mbc5 {
+    fn new(masks: BankMasks) -> Self {
+        Self {
+            masks,
+            ram_enabled: false,
+            selected_rom_bank: 0x01,
+            selected_ram_bank: 0x00,
+        }
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        read_rom_banks(rom, addr, 0, self.rom_bank())
+    }
+
+    fn write_rom(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = is_ram_enable(b),
+            0x2000..=0x2FFF => self.selected_rom_bank = (self.selected_rom_bank & 0x100) | b as u16,
+            0x3000..=0x3FFF => {
+                self.selected_rom_bank = (self.selected_rom_bank & 0xFF) | ((b as u16 & 0x01) << 8)
+            }
+            // Bit 3 drives the rumble motor on cartridges that have one
+            0x4000..=0x5FFF => self.selected_ram_bank = b & 0x0F,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        read_ram_bank(ram, self.ram_bank() as usize, addr)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, b: u8) {
+        write_ram_bank(ram, self.ram_bank() as usize, addr, b);
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.selected_rom_bank & self.masks.rom
+    }
+
+    fn ram_bank(&self) -> u8 {
+        self.selected_ram_bank & self.masks.ram
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState {
+            ram_enabled: self.ram_enabled,
+            rom_bank_register: self.selected_rom_bank,
+            ram_bank_register: self.selected_ram_bank,
+            ..Default::default()
+        }
+    }
+
+    fn set_state(&mut self, state: MapperState) {
+        self.ram_enabled = state.ram_enabled;
+        self.selected_rom_bank = state.rom_bank_register;
+        self.selected_ram_bank = state.ram_bank_register;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ROM where each byte is the number of its bank
+    fn numbered_rom(num_banks: usize) -> Vec<u8> {
+        (0..num_banks)
+            .flat_map(|bank| [bank as u8; ROM_BANK_SIZE])
+            .collect()
+    }
+
+    #[test]
+    fn test_mbc2() {
+        let rom = numbered_rom(16);
+        let mut ram = vec![0; RAM_BANK_SIZE];
+        let mut mbc = new_mbc(CartridgeType::Mbc2Battery, 15, 0);
+
+        // bit 8 of the address selects the ROM bank register
+        mbc.write_rom(0x2100, 0x05);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x05);
+        mbc.write_rom(0x2000, 0x03);
+        assert_eq!(mbc.rom_bank(), 0x05);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x01);
+
+        // half-byte RAM, mirrored every 512 bytes
+        mbc.write_ram(&mut ram, 0x0010, 0xAB);
+        assert_eq!(mbc.read_ram(&ram, 0x0010), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, 0x0210), 0xFB);
+    }
+
+    #[test]
+    fn test_mbc3_rtc() {
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = new_mbc(CartridgeType::Mbc3TimerRamBattery, 127, 3);
+
+        mbc.write_rom(0x4000, 0x08);
+        mbc.write_ram(&mut ram, 0x0000, 59);
+        mbc.write_rom(0x4000, 0x09);
+        mbc.write_ram(&mut ram, 0x0000, 59);
+        let latch = |mbc: &mut Box<dyn Mbc>| {
+            mbc.write_rom(0x6000, 0x00);
+            mbc.write_rom(0x6000, 0x01);
+        };
+        let read_reg = |mbc: &mut Box<dyn Mbc>, reg: u8| {
+            mbc.write_rom(0x4000, reg);
+            mbc.read_ram(&[], 0x0000)
+        };
+        latch(&mut mbc);
+        assert_eq!(read_reg(&mut mbc, 0x08), 59);
+
+        for _ in 0..CYCLES_PER_SECOND / 4 {
+            mbc.step(4);
+        }
+        // the registers don't change until they're latched again
+        assert_eq!(read_reg(&mut mbc, 0x08), 59);
+        latch(&mut mbc);
+        assert_eq!(read_reg(&mut mbc, 0x08), 0);
+        assert_eq!(read_reg(&mut mbc, 0x09), 0);
+        assert_eq!(read_reg(&mut mbc, 0x0A), 1);
+
+        // RAM banks are still accessible
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(&mut ram, 0x0000, 0x42);
+        assert_eq!(ram[2 * RAM_BANK_SIZE], 0x42);
+    }
+
+    #[test]
+    fn test_mbc5() {
+        let rom = numbered_rom(512);
+        let mut mbc = new_mbc(CartridgeType::Mbc5, 511, 0);
+
+        // bank 0 can be mapped
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x00);
+        // 9th bit
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.rom_bank(), 0x105);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x05);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x00);
+    }
+}
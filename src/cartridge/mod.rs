@@ -0,0 +1,947 @@
+use std::{
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+
+use crate::anomaly::AnomalyLog;
+use crate::cheats::Cheat;
+use crate::error::{GbError, Result};
+use crate::platform::{DeterministicPlatform, Platform};
+use crate::state::{StateReader, StateWriter, Stateful};
+pub use header::{CartridgeHeader, CartridgeType};
+use mbc::{new_mbc, Mbc};
+pub use storage::{FileStorage, SaveStorage};
+
+mod header;
+mod mbc;
+mod storage;
+
+/// Nintendo logo, which must be present at 0104-0133 for the boot ROM to accept the cartridge
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+/// Size of the cartridge header, including the entry point and everything before it
+const HEADER_SIZE: usize = 0x0150;
+
+/// A problem with a cartridge's header or ROM file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// The ROM is too small to contain a header
+    TooSmall {
+        size: usize,
+    },
+    LogoMismatch,
+    HeaderChecksum {
+        expected: u8,
+        computed: u8,
+    },
+    GlobalChecksum {
+        expected: u16,
+        computed: u16,
+    },
+    UnknownType(u8),
+    InvalidRomSize(u8),
+    /// The size of the ROM file doesn't match the ROM size in the header
+    RomSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidRamSize(u8),
+}
+
+impl CartridgeError {
+    /// Whether the cartridge can't be run because of this problem.
+    ///
+    /// The other problems are worth a warning, but they don't prevent the emulation: the logo is
+    /// only checked by the boot ROM, nothing checks the global checksum, and extra data at the
+    /// end of the ROM file is never mapped.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            CartridgeError::LogoMismatch | CartridgeError::GlobalChecksum { .. } => false,
+            CartridgeError::RomSizeMismatch { expected, actual } => actual < expected,
+            _ => true,
+        }
+    }
+}
+
+impl std::fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartridgeError::TooSmall { size } => {
+                write!(f, "ROM is too small to contain a header ({} bytes)", size)
+            }
+            CartridgeError::LogoMismatch => write!(f, "Nintendo logo mismatch"),
+            CartridgeError::HeaderChecksum { expected, computed } => write!(
+                f,
+                "Header checksum mismatch: expected {:02x}, computed {:02x}",
+                expected, computed
+            ),
+            CartridgeError::GlobalChecksum { expected, computed } => write!(
+                f,
+                "Global checksum mismatch: expected {:04x}, computed {:04x}",
+                expected, computed
+            ),
+            CartridgeError::UnknownType(t) => write!(f, "Unknown cartridge type {:02x}", t),
+            CartridgeError::InvalidRomSize(s) => write!(f, "Invalid ROM size {:02x}", s),
+            CartridgeError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "ROM file has size {}, expected {} from the header",
+                actual, expected
+            ),
+            CartridgeError::InvalidRamSize(s) => write!(f, "Invalid RAM size {:02x}", s),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+/// State of the memory bank controller (i.e. the mapper) of a cartridge.
+///
+/// Together with the content of the RAM, this is everything needed to restore a cartridge to a
+/// given point in time (apart from the running MBC3 real-time clock, which only the savestates
+/// hold). Registers that the mapper doesn't have are left at 0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MapperState {
+    /// Whether the external RAM is enabled
+    pub ram_enabled: bool,
+    /// ROM bank register (only the lower 5 bits of the ROM bank number on MBC1)
+    pub rom_bank_register: u16,
+    /// RAM bank register (on MBC1, the secondary bank register which can also hold the upper
+    /// bits of the ROM bank number)
+    pub ram_bank_register: u8,
+    /// Banking mode select (MBC1 only)
+    pub banking_mode_1: bool,
+    /// RTC registers 08-0C as last latched, which are what the game reads at A000-BFFF (MBC3 only)
+    pub rtc_latched: [u8; 5],
+    /// Last value written to 6000-7FFF: writing 00 then 01 latches the RTC (MBC3 only)
+    pub rtc_latch_write: u8,
+}
+
+/// What the cartridge RAM contains at power on, when there's no save file.
+///
+/// On real cartridges the SRAM starts with more or less random contents, and some games look for
+/// specific garbage to detect that they're booting for the first time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    /// All bytes set to FF
+    Ones,
+    /// Pseudo-random bytes generated from the given seed, or from the
+    /// [`Platform`](crate::platform::Platform)'s entropy if there's no seed
+    Random(Option<u64>),
+}
+
+impl FromStr for RamInit {
+    type Err = anyhow::Error;
+
+    /// Parse `zeros`, `ff`, `random` or `random:<seed>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zeros" => Ok(RamInit::Zeros),
+            "ff" => Ok(RamInit::Ones),
+            "random" => Ok(RamInit::Random(None)),
+            _ => {
+                let seed = s
+                    .strip_prefix("random:")
+                    .ok_or_else(|| anyhow!("Unknown RAM init pattern {}", s))?;
+                let seed = seed
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid seed {}", seed))?;
+                Ok(RamInit::Random(Some(seed)))
+            }
+        }
+    }
+}
+
+pub struct Cartridge {
+    data: Box<[u8]>,
+    header: CartridgeHeader,
+    ram: Box<[u8]>,
+    mbc: Box<dyn Mbc>,
+    /// Where the battery-backed RAM is persisted, if anywhere
+    storage: Option<Box<dyn SaveStorage>>,
+    /// Whether the RAM was loaded from the save storage
+    ram_loaded: bool,
+    /// Game Genie codes applied to the reads of the ROM
+    rom_patches: Vec<Cheat>,
+    pub(crate) anomalies: AnomalyLog,
+}
+
+impl Cartridge {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with_save_profile(path, None)
+    }
+
+    /// Load a cartridge, using the battery RAM of the given save profile.
+    ///
+    /// Each profile has its own save file next to the ROM, so that several people can play the
+    /// same game without overwriting each other's saves.
+    ///
+    /// Fails with [`GbError::Cartridge`] if the header is corrupted or the ROM file is truncated,
+    /// or [`GbError::Io`] if the ROM or the save file can't be read.
+    pub fn load_with_save_profile<P: AsRef<Path>>(path: P, profile: Option<&str>) -> Result<Self> {
+        let content = read_file(path.as_ref())?;
+        info!("Loaded {} bytes from rom file", content.len());
+
+        let save_file = save_file_path(path.as_ref(), profile)?;
+        let mut cart = Self::parse(content)?;
+        cart.set_save_storage(FileStorage::new(save_file))?;
+
+        Ok(cart)
+    }
+
+    /// Create a cartridge from the content of a ROM file, checking its header like
+    /// [`load()`](Self::load) does, but without any save file.
+    ///
+    /// Fails with [`GbError::Cartridge`] if the header is corrupted or the ROM is truncated. Less
+    /// serious problems, like a wrong global checksum, are only logged.
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let cart = Self::from_bytes(data);
+        for problem in cart.check_header() {
+            if problem.is_fatal() {
+                return Err(problem.into());
+            }
+            warn!("{}", problem);
+        }
+        let global_checksum = cart.compute_global_checksum();
+        if global_checksum != cart.header.global_checksum {
+            warn!(
+                "{}",
+                CartridgeError::GlobalChecksum {
+                    expected: cart.header.global_checksum,
+                    computed: global_checksum,
+                }
+            );
+        }
+        Ok(cart)
+    }
+
+    /// Persist the battery-backed RAM in the given storage, and load it from there if something
+    /// was saved before.
+    ///
+    /// Cartridges created with [`load()`](Self::load) already use a [`FileStorage`] next to the
+    /// ROM.
+    pub fn set_save_storage(&mut self, storage: impl SaveStorage + 'static) -> Result<()> {
+        if let Some(expected_size) = self.ram_save_size() {
+            match storage.load()? {
+                Some(ram) if ram.len() != expected_size => warn!(
+                    "Save {} has size {}, expected {}. Ignoring...",
+                    storage,
+                    ram.len(),
+                    expected_size
+                ),
+                Some(ram) => {
+                    info!("Loading save {}...", storage);
+                    self.ram[..expected_size].copy_from_slice(&ram[..]);
+                    self.ram_loaded = true;
+                }
+                None => info!("No save found."),
+            }
+        }
+        self.storage = Some(Box::new(storage));
+        Ok(())
+    }
+
+    /// Create a cartridge from the given ROM data, without any save file.
+    ///
+    /// The data doesn't need to contain a valid header (or any header at all): missing header
+    /// bytes read as 0.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        let mut cart = Self {
+            header: CartridgeHeader::parse(&data),
+            data: data.into_boxed_slice(),
+            // Allocated below, once the header can be read
+            ram: Box::default(),
+            // Replaced below, once the header can be read
+            mbc: new_mbc(CartridgeType::RomOnly, 1, 0),
+            storage: None,
+            ram_loaded: false,
+            rom_patches: Vec::new(),
+            anomalies: AnomalyLog::default(),
+        };
+        cart.ram = vec![0; cart.ram_size()].into_boxed_slice();
+        let ram_bank_mask = cart.get_num_ram_banks().unwrap_or(1) - 1;
+        cart.mbc = new_mbc(
+            cart.header.cartridge_type,
+            cart.rom_bank_mask(),
+            ram_bank_mask as u8,
+        );
+        cart
+    }
+
+    /// Whether the ROM is big enough to contain a cartridge header.
+    pub fn has_header(&self) -> bool {
+        self.data.len() >= HEADER_SIZE
+    }
+
+    /// Raw ROM data
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The parsed header of the cartridge.
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    /// Read a range of the header, or an empty slice if the ROM is too small.
+    fn header_bytes(&self, range: RangeInclusive<usize>) -> &[u8] {
+        self.data.get(range).unwrap_or(&[])
+    }
+
+    fn has_ram(&self) -> bool {
+        self.header.cartridge_type.has_ram()
+    }
+
+    /// Compute the header checksum over 0134-014C, as done by the boot ROM.
+    pub fn compute_header_checksum(&self) -> u8 {
+        self.header_bytes(0x0134..=0x014C)
+            .iter()
+            .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1))
+    }
+
+    /// Compute the global checksum (sum of all the bytes of the ROM except the checksum itself).
+    ///
+    /// This isn't checked by the hardware.
+    pub fn compute_global_checksum(&self) -> u16 {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x014E && *i != 0x014F)
+            .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16))
+    }
+
+    /// Check the cartridge header for errors.
+    ///
+    /// Returns each problem found, so an empty list means the header is valid. The global
+    /// checksum isn't checked, as nothing on the hardware does.
+    pub fn check_header(&self) -> Vec<CartridgeError> {
+        if !self.has_header() {
+            return vec![CartridgeError::TooSmall {
+                size: self.data.len(),
+            }];
+        }
+        let mut problems = vec![];
+        if self.header_bytes(0x0104..=0x0133) != NINTENDO_LOGO {
+            problems.push(CartridgeError::LogoMismatch);
+        }
+        let checksum = self.compute_header_checksum();
+        if checksum != self.header.header_checksum {
+            problems.push(CartridgeError::HeaderChecksum {
+                expected: self.header.header_checksum,
+                computed: checksum,
+            });
+        }
+        if let CartridgeType::Unknown(code) = self.header.cartridge_type {
+            problems.push(CartridgeError::UnknownType(code));
+        }
+        match self.header.rom_banks {
+            None => problems.push(CartridgeError::InvalidRomSize(self.header.rom_size)),
+            Some(banks) => {
+                let expected_size = banks as usize * 0x4000;
+                if self.data.len() != expected_size {
+                    problems.push(CartridgeError::RomSizeMismatch {
+                        expected: expected_size,
+                        actual: self.data.len(),
+                    });
+                }
+            }
+        }
+        if self.has_ram() && self.get_num_ram_banks().is_none() {
+            problems.push(CartridgeError::InvalidRamSize(self.header.ram_size));
+        }
+
+        problems
+    }
+
+    /// Fill the RAM with the given pattern, unless it was loaded from a save file.
+    pub(crate) fn init_ram(&mut self, init: RamInit, platform: &mut dyn Platform) {
+        if self.ram_loaded {
+            return;
+        }
+        match init {
+            RamInit::Zeros => self.ram.fill(0x00),
+            RamInit::Ones => self.ram.fill(0xFF),
+            RamInit::Random(Some(seed)) => {
+                DeterministicPlatform::new(seed, 0).fill_random(&mut self.ram)
+            }
+            RamInit::Random(None) => platform.fill_random(&mut self.ram),
+        }
+    }
+
+    /// Current state of the mapper.
+    pub fn mapper_state(&self) -> MapperState {
+        self.mbc.state()
+    }
+
+    /// Restore the mapper to the given state.
+    pub fn set_mapper_state(&mut self, state: MapperState) {
+        self.mbc.set_state(state);
+    }
+
+    /// Number of the ROM bank currently mapped at 4000-7FFF.
+    pub fn current_rom_bank(&self) -> u16 {
+        self.mbc.rom_bank()
+    }
+
+    /// Number of the RAM bank currently mapped at A000-BFFF.
+    pub fn current_ram_bank(&self) -> u8 {
+        self.mbc.ram_bank()
+    }
+
+    /// Mask applied to the ROM bank numbers.
+    ///
+    /// The bank registers are wider than needed for most ROMs, and the bits that aren't needed to
+    /// address the ROM's banks aren't connected to anything, so selecting a bank past the end of
+    /// the ROM wraps around.
+    fn rom_bank_mask(&self) -> u16 {
+        let num_banks = match self.header.rom_banks {
+            Some(banks) => banks,
+            // Invalid header: go by the size of the data instead
+            None => self
+                .data
+                .len()
+                .div_ceil(0x4000)
+                .clamp(2, 512)
+                .next_power_of_two() as u16,
+        };
+        num_banks - 1
+    }
+
+    /// Read a byte from the ROM area (0000-7FFF), through the mapper.
+    pub fn read_rom(&self, addr: u16) -> u8 {
+        let value = self.mbc.read_rom(&self.data, addr);
+        if self.rom_patches.is_empty() {
+            return value;
+        }
+        self.rom_patches
+            .iter()
+            .find_map(|patch| patch.patch_rom(addr, value))
+            .unwrap_or(value)
+    }
+
+    /// Patch the reads of the ROM with the given Game Genie codes (any other cheat is ignored).
+    pub(crate) fn set_rom_patches(&mut self, cheats: &[Cheat]) {
+        self.rom_patches = cheats
+            .iter()
+            .filter(|cheat| matches!(cheat, Cheat::RomPatch { .. }))
+            .copied()
+            .collect();
+    }
+
+    /// Write to the ROM area (0000-7FFF), which is where the mapper's registers are.
+    pub fn write_rom(&mut self, addr: u16, b: u8) {
+        self.mbc.write_rom(addr, b);
+    }
+
+    /// Read a byte from the selected bank of this cartridge's external RAM.
+    ///
+    /// The given address should be relative to the selected bank, i.e. in the range 0000-1FFF.
+    /// Reads return open bus while the RAM is disabled, or if the cartridge has no RAM.
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        if addr >= 0x2000 {
+            anomaly!(
+                self.anomalies,
+                0xFF,
+                "Invalid external RAM address 0x{:04x}",
+                addr
+            )
+        } else if !self.mbc.ram_enabled() {
+            0xFF
+        } else {
+            self.mbc.read_ram(&self.ram, addr)
+        }
+    }
+
+    /// Write a byte into the selected bank of this cartridge's external RAM
+    ///
+    /// The given address should be relative to the selected bank, i.e. in the range 0000-1FFF.
+    /// Writes are ignored while the RAM is disabled.
+    pub fn write_ram(&mut self, addr: u16, b: u8) {
+        if addr >= 0x2000 {
+            anomaly!(
+                self.anomalies,
+                (),
+                "Invalid external RAM address 0x{:04x}",
+                addr
+            );
+        } else if self.mbc.ram_enabled() {
+            self.mbc.write_ram(&mut self.ram, addr, b);
+        }
+    }
+
+    /// Advance the mapper's clock (for the MBC3's real-time clock).
+    pub(crate) fn step(&mut self, cycles: u32) {
+        self.mbc.step(cycles);
+    }
+
+    pub fn save(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        if let Some(ram_size) = self.ram_save_size() {
+            if let Err(e) = storage.store(&self.ram[..ram_size]) {
+                warn!("Failed to save {}: {}", storage, e);
+            }
+        }
+    }
+
+    /// Size of the cartridge's RAM: the MBC2's built-in RAM, or the external RAM from the header
+    fn ram_size(&self) -> usize {
+        match self.header.cartridge_type {
+            CartridgeType::Mbc2 | CartridgeType::Mbc2Battery => 512,
+            _ => self
+                .get_num_ram_banks()
+                .map_or(0, |banks| banks as usize * 8192),
+        }
+    }
+
+    /// Size of the RAM to persist, if the cartridge has any
+    fn ram_save_size(&self) -> Option<usize> {
+        if self.header.cartridge_type == CartridgeType::Mbc2Battery {
+            // MBC2+BATTERY has 512 half-bytes of RAM built in, but the header says there's no RAM
+            Some(512)
+        } else {
+            self.get_num_ram_banks().map(|s| s as usize * 8192)
+        }
+    }
+
+    /// Number of banks of external RAM, if the cartridge has any
+    fn get_num_ram_banks(&self) -> Option<u16> {
+        if self.has_ram() {
+            self.header.ram_banks.filter(|banks| *banks > 0)
+        } else {
+            None
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|source| GbError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Return the path of the file used to persist the cartridge's RAM.
+///
+/// This is the ROM's path with a `.sav` extension, or `.<profile>.sav` when using a save profile.
+fn save_file_path(rom: &Path, profile: Option<&str>) -> Result<PathBuf> {
+    let mut path = PathBuf::from(rom);
+    match profile {
+        None => {
+            path.set_extension("sav");
+        }
+        Some(profile) => {
+            if profile.is_empty()
+                || !profile
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(GbError::InvalidSaveProfile(profile.to_string()));
+            }
+            path.set_extension(format!("{}.sav", profile));
+        }
+    }
+
+    Ok(path)
+}
+
+/// The RAM is restored along with the mapper, so it's written to the save file like any RAM
+/// written by the game.
+impl Stateful for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        self.mbc.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.ram)?;
+        self.mbc.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapper_state_round_trip() {
+        // 1MB MBC1 cartridge with 32KB of RAM, where each bank starts with its number
+        let mut data = vec![0; 64 * 0x4000];
+        for bank in 0..64 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x0147] = 0x03;
+        data[0x0148] = 0x05;
+        data[0x0149] = 0x03;
+        let mut cart = Cartridge::from_bytes(data);
+
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x2000, 0x03);
+        cart.write_rom(0x4000, 0x01);
+        cart.write_rom(0x6000, 1);
+        cart.write_ram(0x0000, 0x42);
+        assert_eq!(cart.read_rom(0x4000), 0x23);
+        assert_eq!(cart.current_ram_bank(), 1);
+
+        let state = cart.mapper_state();
+        cart.write_rom(0x0000, 0x00);
+        cart.write_rom(0x2000, 0x05);
+        cart.write_rom(0x4000, 0x00);
+        cart.write_rom(0x6000, 0);
+        assert_eq!(cart.read_rom(0x4000), 0x05);
+        // the RAM is disabled
+        assert_eq!(cart.read_ram(0x0000), 0xFF);
+
+        cart.set_mapper_state(state);
+        assert_eq!(cart.mapper_state(), state);
+        assert!(state.ram_enabled);
+        assert_eq!(cart.read_rom(0x4000), 0x23);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+    }
+
+    /// ROM with the given header ROM size, where each bank is filled with its number
+    fn numbered_rom(num_banks: usize, rom_size: u8) -> Cartridge {
+        let mut data = vec![0; num_banks * 0x4000];
+        for (bank, chunk) in data.chunks_mut(0x4000).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        data[0x0147] = 0x01;
+        data[0x0148] = rom_size;
+        Cartridge::from_bytes(data)
+    }
+
+    #[test]
+    fn test_rom_bank_wraps_around() {
+        // 64KB ROM: only 2 bits of the bank number are used
+        let mut cart = numbered_rom(4, 0x01);
+        cart.write_rom(0x2000, 0x06);
+        assert_eq!(cart.current_rom_bank(), 0x02);
+        assert_eq!(cart.read_rom(0x4000), 0x02);
+        // bank 4 is bank 0, which MBC1 doesn't turn into bank 1
+        cart.write_rom(0x2000, 0x04);
+        assert_eq!(cart.read_rom(0x7FFF), 0x00);
+        // the secondary bank register isn't connected either
+        cart.write_rom(0x2000, 0x01);
+        cart.write_rom(0x4000, 0x03);
+        cart.write_rom(0x6000, 1);
+        assert_eq!(cart.read_rom(0x4000), 0x01);
+        assert_eq!(cart.read_rom(0x1000), 0x00);
+    }
+
+    #[test]
+    fn test_rom_open_bus() {
+        // the header says 256KB, but the file is only 32KB
+        let mut cart = numbered_rom(2, 0x03);
+        cart.write_rom(0x2000, 0x01);
+        assert_eq!(cart.read_rom(0x4000), 0x01);
+        cart.write_rom(0x2000, 0x0F);
+        assert_eq!(cart.current_rom_bank(), 0x0F);
+        assert_eq!(cart.read_rom(0x4000), 0xFF);
+        assert_eq!(cart.read_rom(0x7FFF), 0xFF);
+
+        // tiny ROM without a header (so without a mapper): everything past the data is open bus,
+        // and nothing panics
+        let mut cart = Cartridge::from_bytes(vec![0x42; 0x100]);
+        for bank in 0..=0x1F {
+            cart.write_rom(0x2000, bank);
+            assert_eq!(cart.current_rom_bank(), 1);
+            for addr in (0x0000..=0x7FFF).step_by(0x80) {
+                let expected = if addr < 0x100 { 0x42 } else { 0xFF };
+                assert_eq!(cart.read_rom(addr), expected, "bank {bank} addr {addr:04x}");
+            }
+        }
+    }
+
+    /// 32KB ROM with a valid header
+    fn valid_rom() -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        data[0x0104..=0x0133].copy_from_slice(&NINTENDO_LOGO);
+        data[0x0134..0x0138].copy_from_slice(b"TEST");
+        data[0x014D] = Cartridge::from_bytes(data.clone()).compute_header_checksum();
+        data
+    }
+
+    #[test]
+    fn test_check_header() {
+        assert_eq!(Cartridge::from_bytes(valid_rom()).check_header(), vec![]);
+
+        let mut data = valid_rom();
+        data[0x0134] = b'X';
+        let problems = Cartridge::from_bytes(data).check_header();
+        assert!(matches!(
+            problems[..],
+            [CartridgeError::HeaderChecksum { .. }]
+        ));
+        assert!(problems[0].is_fatal());
+
+        let mut data = valid_rom();
+        data.truncate(0x4000);
+        let problems = Cartridge::from_bytes(data).check_header();
+        assert_eq!(
+            problems,
+            vec![CartridgeError::RomSizeMismatch {
+                expected: 0x8000,
+                actual: 0x4000
+            }]
+        );
+        assert!(problems[0].is_fatal());
+
+        // trailing garbage is harmless
+        let mut data = valid_rom();
+        data.extend_from_slice(&[0; 16]);
+        let problems = Cartridge::from_bytes(data).check_header();
+        assert!(!problems[0].is_fatal());
+
+        let problems = Cartridge::from_bytes(vec![0; 0x100]).check_header();
+        assert_eq!(problems, vec![CartridgeError::TooSmall { size: 0x100 }]);
+
+        // Only the fatal problems are errors when parsing a ROM
+        let mut data = valid_rom();
+        data.extend_from_slice(&[0; 16]);
+        assert!(Cartridge::parse(data).is_ok());
+        assert!(Cartridge::parse(vec![0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn test_savestate() {
+        // 1MB MBC1 cartridge with 32KB of RAM, where each bank starts with its number
+        let mut data = vec![0; 64 * 0x4000];
+        for bank in 0..64 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x0147] = 0x03;
+        data[0x0148] = 0x05;
+        data[0x0149] = 0x03;
+        let mut cart = Cartridge::from_bytes(data.clone());
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x2000, 0x03);
+        cart.write_rom(0x4000, 0x01);
+        cart.write_rom(0x6000, 1);
+        cart.write_ram(0x0000, 0x42);
+        let mut w = StateWriter::new(0);
+        cart.save_state(&mut w);
+        let state = w.finish();
+
+        // The game goes on with the same banks after the state is loaded
+        let mut restored = Cartridge::from_bytes(data);
+        let mut r = StateReader::new(&state, 0).unwrap();
+        restored.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(restored.mapper_state(), cart.mapper_state());
+        assert_eq!(restored.read_rom(0x4000), 0x23);
+        assert_eq!(restored.read_ram(0x0000), 0x42);
+        restored.write_ram(0x0001, 0x43);
+        restored.write_rom(0x2000, 0x04);
+        assert_eq!(restored.read_rom(0x4000), 0x24);
+        restored.write_rom(0x4000, 0x00);
+        assert_eq!(restored.read_ram(0x0000), 0x00);
+        restored.write_rom(0x4000, 0x01);
+        assert_eq!(restored.read_ram(0x0001), 0x43);
+    }
+
+    #[test]
+    fn test_mbc3_latch_state() {
+        // 2MB MBC3+TIMER+RAM+BATTERY cartridge with 32KB of RAM, where each bank starts with its
+        // number
+        let mut data = vec![0; 128 * 0x4000];
+        for bank in 0..128 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x0147] = 0x10;
+        data[0x0148] = 0x06;
+        data[0x0149] = 0x03;
+        let mut cart = Cartridge::from_bytes(data.clone());
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x2000, 0x03);
+        // Set the seconds to 21 and latch them
+        cart.write_rom(0x4000, 0x08);
+        cart.write_ram(0x0000, 21);
+        cart.write_rom(0x6000, 0x00);
+        cart.write_rom(0x6000, 0x01);
+        let state = cart.mapper_state();
+        assert_eq!(state.rtc_latched[0], 21);
+        assert_eq!(state.rtc_latch_write, 0x01);
+        let mut w = StateWriter::new(0);
+        cart.save_state(&mut w);
+        let saved = w.finish();
+
+        // Another ROM bank and another time, with a latch half done
+        cart.write_rom(0x2000, 0x05);
+        cart.write_ram(0x0000, 42);
+        cart.write_rom(0x6000, 0x00);
+        cart.write_rom(0x6000, 0x01);
+        cart.write_rom(0x6000, 0x00);
+        assert_eq!(cart.read_rom(0x4000), 0x05);
+        assert_eq!(cart.read_ram(0x0000), 42);
+
+        let check = |cart: &mut Cartridge| {
+            assert_eq!(cart.read_rom(0x4000), 0x03);
+            assert_eq!(cart.read_ram(0x0000), 21);
+            // The latch isn't half done anymore, so this doesn't latch the time again
+            cart.write_rom(0x6000, 0x01);
+            assert_eq!(cart.read_ram(0x0000), 21);
+        };
+        cart.set_mapper_state(state);
+        assert_eq!(cart.mapper_state(), state);
+        check(&mut cart);
+
+        let mut restored = Cartridge::from_bytes(data);
+        let mut r = StateReader::new(&saved, 0).unwrap();
+        restored.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(restored.mapper_state(), state);
+        check(&mut restored);
+    }
+
+    #[test]
+    fn test_ram_init() {
+        assert_eq!("ff".parse::<RamInit>().unwrap(), RamInit::Ones);
+        assert_eq!(
+            "random:42".parse::<RamInit>().unwrap(),
+            RamInit::Random(Some(42))
+        );
+        assert!("random:foo".parse::<RamInit>().is_err());
+
+        // ROM+RAM with 8KB of RAM, which is always enabled
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x08;
+        rom[0x0149] = 0x02;
+        let mut platform = DeterministicPlatform::default();
+        let mut cart = Cartridge::from_bytes(rom.clone());
+        cart.init_ram(RamInit::Ones, &mut platform);
+        assert_eq!(cart.read_ram(0x1000), 0xFF);
+
+        // the same seed always gives the same contents
+        let mut other = Cartridge::from_bytes(rom);
+        cart.init_ram(RamInit::Random(Some(42)), &mut platform);
+        other.init_ram(RamInit::Random(Some(42)), &mut platform);
+        assert_eq!(cart.ram, other.ram);
+        assert!(cart.ram.iter().any(|b| *b != cart.ram[0]));
+
+        // the contents of the save file are kept
+        cart.ram_loaded = true;
+        cart.init_ram(RamInit::Zeros, &mut platform);
+        assert_eq!(cart.ram, other.ram);
+    }
+
+    /// Saves kept in memory, shared between the cartridges of a test
+    #[derive(Default, Clone)]
+    struct MemoryStorage(std::rc::Rc<std::cell::RefCell<Option<Vec<u8>>>>);
+
+    impl std::fmt::Display for MemoryStorage {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "memory")
+        }
+    }
+
+    impl SaveStorage for MemoryStorage {
+        fn load(&self) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.borrow().clone())
+        }
+
+        fn store(&self, ram: &[u8]) -> Result<()> {
+            *self.0.borrow_mut() = Some(ram.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_save_storage() {
+        // MBC1+RAM+BATTERY with 8KB of RAM
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let storage = MemoryStorage::default();
+
+        let mut cart = Cartridge::from_bytes(rom.clone());
+        cart.set_save_storage(storage.clone()).unwrap();
+        assert!(!cart.ram_loaded);
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_ram(0x0123, 0x42);
+        cart.save();
+        assert_eq!(storage.0.borrow().as_ref().map(Vec::len), Some(8192));
+
+        let mut cart = Cartridge::from_bytes(rom);
+        cart.set_save_storage(storage).unwrap();
+        assert!(cart.ram_loaded);
+        cart.write_rom(0x0000, 0x0A);
+        assert_eq!(cart.read_ram(0x0123), 0x42);
+    }
+
+    #[test]
+    fn test_external_ram() {
+        // MBC1+RAM with 8KB of RAM
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x02;
+        rom[0x0149] = 0x02;
+        let mut cart = Cartridge::from_bytes(rom.clone());
+        assert_eq!(cart.ram.len(), 8192);
+
+        // disabled at power on
+        cart.write_ram(0x0123, 0x42);
+        assert_eq!(cart.read_ram(0x0123), 0xFF);
+        cart.write_rom(0x0000, 0x0A);
+        assert_eq!(cart.read_ram(0x0123), 0x00);
+        cart.write_ram(0x0123, 0x42);
+        assert_eq!(cart.read_ram(0x0123), 0x42);
+
+        // there's only one bank, which is mirrored over the others
+        cart.write_rom(0x6000, 1);
+        cart.write_rom(0x4000, 0x02);
+        assert_eq!(cart.read_ram(0x0123), 0x42);
+
+        // disabling the RAM again
+        cart.write_rom(0x0000, 0x00);
+        cart.write_ram(0x0123, 0x17);
+        assert_eq!(cart.read_ram(0x0123), 0xFF);
+        cart.write_rom(0x0000, 0x0A);
+        assert_eq!(cart.read_ram(0x0123), 0x42);
+
+        // the size comes from the header
+        rom[0x0147] = 0x1B;
+        rom[0x0149] = 0x04;
+        assert_eq!(Cartridge::from_bytes(rom.clone()).ram.len(), 128 * 1024);
+        rom[0x0147] = 0x06;
+        assert_eq!(Cartridge::from_bytes(rom.clone()).ram.len(), 512);
+
+        // without any RAM, everything reads as open bus
+        rom[0x0147] = 0x01;
+        let mut cart = Cartridge::from_bytes(rom);
+        assert!(cart.ram.is_empty());
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_ram(0x0123, 0x42);
+        assert_eq!(cart.read_ram(0x0123), 0xFF);
+    }
+
+    #[test]
+    fn test_no_cartridge() {
+        // With nothing in the slot, the whole ROM area reads as open bus
+        let cart = Cartridge::from_bytes(Vec::new());
+        assert_eq!(cart.read_rom(0x0104), 0xFF);
+        assert_eq!(cart.read_rom(0x4000), 0xFF);
+    }
+
+    #[test]
+    fn test_save_file_path() {
+        let rom = Path::new("roms/tetris.gb");
+        assert_eq!(
+            save_file_path(rom, None).unwrap(),
+            PathBuf::from("roms/tetris.sav")
+        );
+        assert_eq!(
+            save_file_path(rom, Some("playthrough2")).unwrap(),
+            PathBuf::from("roms/tetris.playthrough2.sav")
+        );
+        assert!(save_file_path(rom, Some("")).is_err());
+        assert!(save_file_path(rom, Some("../other")).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+//! Persistence of the battery-backed RAM of cartridges.
+//!
+//! The core doesn't touch the file system itself: it goes through a [`SaveStorage`], so that
+//! hosts without one (e.g. a browser) can keep the saves wherever they want.
+use std::{fmt, io, path::PathBuf};
+
+use crate::error::{GbError, Result};
+
+/// Where the battery-backed RAM of a cartridge is persisted.
+pub trait SaveStorage: fmt::Display {
+    /// Read the saved RAM, or `None` if nothing has been saved yet.
+    fn load(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Persist the RAM.
+    fn store(&self, ram: &[u8]) -> Result<()>;
+}
+
+/// Saves in a file, as used by the desktop frontend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn io_error(&self, source: io::Error) -> GbError {
+        GbError::Io {
+            path: self.path.clone(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for FileStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+impl SaveStorage for FileStorage {
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(self.io_error(e)),
+        }
+    }
+
+    fn store(&self, ram: &[u8]) -> Result<()> {
+        std::fs::write(&self.path, ram).map_err(|e| self.io_error(e))
+    }
+}
@@ -0,0 +1,182 @@
+//! Game Genie and GameShark cheat codes.
+//!
+//! Game Genie codes (`ABC-DEF` or `ABC-DEF-GHI`) patch the ROM: reads of one address return
+//! another value, optionally only when the original value matches (as the same address can hold
+//! different code depending on the ROM bank). GameShark codes (`TTVVAAAA`) write a value to the
+//! RAM at every VBlank.
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+
+/// Where GameShark codes can write: the cartridge RAM, the WRAM and the HRAM. Anything else would
+/// poke the mapper or the IO registers.
+const RAM_WRITE_RANGES: [RangeInclusive<u16>; 2] = [0xA000..=0xDFFF, 0xFF80..=0xFFFE];
+
+/// Whether a GameShark code can write to `addr`.
+pub(crate) fn is_cheat_ram(addr: u16) -> bool {
+    RAM_WRITE_RANGES.iter().any(|range| range.contains(&addr))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cheat {
+    /// A Game Genie code: reading `addr` from the ROM returns `value` instead, if the original
+    /// value is `compare`
+    RomPatch {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    /// A GameShark code: `value` is written to `addr` at every VBlank, if it's in the cartridge
+    /// RAM, the WRAM or the HRAM
+    RamWrite { addr: u16, value: u8 },
+}
+
+impl Cheat {
+    /// Return the patched value of the ROM at `addr`, if this cheat patches it.
+    #[inline]
+    pub(crate) fn patch_rom(&self, addr: u16, original: u8) -> Option<u8> {
+        match *self {
+            Cheat::RomPatch {
+                addr: a,
+                value,
+                compare,
+            } if a == addr && compare.is_none_or(|c| c == original) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Cheat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits = s
+            .chars()
+            .filter(|c| *c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<_>>>()
+            .with_context(|| format!("Invalid cheat code {s}: not hexadecimal"))?;
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+        match digits.len() {
+            // Game Genie: ABC-DEF[-GHI], where AB is the value, FCDE the address xored with F000,
+            // and GI the value to compare to, rotated and xored with BA (H is ignored)
+            6 | 9 => {
+                let addr = u16::from(digits[5] ^ 0xF) << 12
+                    | u16::from(digits[2]) << 8
+                    | u16::from(digits[3]) << 4
+                    | u16::from(digits[4]);
+                ensure!(
+                    addr < 0x8000,
+                    "Invalid cheat code {s}: Game Genie codes can only patch the ROM"
+                );
+                let compare = (digits.len() == 9)
+                    .then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+                Ok(Cheat::RomPatch {
+                    addr,
+                    value: byte(0),
+                    compare,
+                })
+            }
+            // GameShark: TTVVAAAA, where TT is the type (usually 01, and ignored), VV the value
+            // and AAAA the address in little-endian
+            8 => {
+                let addr = u16::from_le_bytes([byte(4), byte(6)]);
+                ensure!(
+                    is_cheat_ram(addr),
+                    "Invalid cheat code {s}: GameShark codes can only write to the RAM"
+                );
+                Ok(Cheat::RamWrite {
+                    addr,
+                    value: byte(2),
+                })
+            }
+            _ => bail!("Invalid cheat code {s}: expected ABC-DEF, ABC-DEF-GHI or TTVVAAAA"),
+        }
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cheat::RomPatch {
+                addr,
+                value,
+                compare: Some(compare),
+            } => write!(f, "ROM {addr:04X} = {value:02X} (if {compare:02X})"),
+            Cheat::RomPatch { addr, value, .. } => write!(f, "ROM {addr:04X} = {value:02X}"),
+            Cheat::RamWrite { addr, value } => write!(f, "RAM {addr:04X} = {value:02X}"),
+        }
+    }
+}
+
+/// Parse a cheat file: one code per line, optionally followed by a description. Empty lines and
+/// lines starting with `#` are ignored.
+pub fn parse_cheat_file(text: &str) -> Result<Vec<Cheat>> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let code = line.split_whitespace().next()?;
+            (!code.starts_with('#')).then(|| {
+                code.parse()
+                    .map_err(|e: Error| anyhow!("Line {}: {e}", i + 1))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cheats() {
+        assert_eq!(
+            "01A-EFB".parse::<Cheat>().unwrap(),
+            Cheat::RomPatch {
+                addr: 0x4AEF,
+                value: 0x01,
+                compare: None
+            }
+        );
+        // GI = 0x20, rotated right by 2 = 0x08, xored with BA = 0xB2
+        assert_eq!(
+            "3EA-44F-2A0".parse::<Cheat>().unwrap(),
+            Cheat::RomPatch {
+                addr: 0x0A44,
+                value: 0x3E,
+                compare: Some(0xB2)
+            }
+        );
+        assert_eq!(
+            "010238cd".parse::<Cheat>().unwrap(),
+            Cheat::RamWrite {
+                addr: 0xCD38,
+                value: 0x02
+            }
+        );
+        assert!("01A-EF7".parse::<Cheat>().is_err());
+        // The mapper's registers and the IO registers are off limits
+        assert!("01010020".parse::<Cheat>().is_err());
+        assert!("010040FF".parse::<Cheat>().is_err());
+        assert!("010180FF".parse::<Cheat>().is_ok());
+        assert!("01A-EFG".parse::<Cheat>().is_err());
+        assert!("01A".parse::<Cheat>().is_err());
+
+        let cheats =
+            parse_cheat_file("# Infinite lives\n010238CD lives\n\n01A-EFB  jump\n").unwrap();
+        assert_eq!(cheats.len(), 2);
+        assert_eq!(cheats[1].to_string(), "ROM 4AEF = 01");
+        assert_eq!(
+            parse_cheat_file("0102\n").unwrap_err().to_string(),
+            "Line 1: Invalid cheat code 0102: expected ABC-DEF, ABC-DEF-GHI or TTVVAAAA"
+        );
+    }
+
+    #[test]
+    fn test_patch_rom() {
+        let cheat: Cheat = "3EA-44F-2A0".parse().unwrap();
+        assert_eq!(cheat.patch_rom(0x0A44, 0xB2), Some(0x3E));
+        assert_eq!(cheat.patch_rom(0x0A44, 0x00), None);
+        assert_eq!(cheat.patch_rom(0x0A45, 0xB2), None);
+    }
+}
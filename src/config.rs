@@ -0,0 +1,170 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use log::{debug, warn};
+
+use crate::screen::ScaleMode;
+
+/// Maximum number of ROMs kept in the list of recently opened ROMs
+const MAX_RECENT_ROMS: usize = 10;
+
+/// Settings of the emulator that persist between runs.
+///
+/// They are stored as `key = value` lines in the platform's configuration directory (e.g.
+/// `~/.config/gb-rs/config` on Linux).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Initial size of the window, as a multiple of the screen size
+    pub scale: u32,
+    /// How the screen is scaled to fit the window
+    pub scale_mode: ScaleMode,
+    /// Name of the DMG palette
+    pub palette: String,
+    /// Audio volume, in percent
+    pub volume: u8,
+    /// Recently opened ROMs, most recent first
+    pub recent_roms: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scale: 1,
+            scale_mode: ScaleMode::default(),
+            palette: "green".to_string(),
+            volume: 100,
+            recent_roms: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Path of the configuration file, if the platform has a configuration directory.
+    pub fn path() -> Option<PathBuf> {
+        ProjectDirs::from("", "", "gb-rs").map(|dirs| dirs.config_dir().join("config"))
+    }
+
+    /// Load the configuration from its default location, falling back to the default
+    /// configuration if it doesn't exist or can't be read.
+    pub fn load_or_default() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        if !path.exists() {
+            debug!("No config file found at {}", path.display());
+            return Self::default();
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content),
+            Err(e) => {
+                warn!("Failed to read config file {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Save the configuration to its default location.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().context("No configuration directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&path, self.to_string())
+            .with_context(|| format!("Failed to write config file {}", path.display()))
+    }
+
+    /// Parse the content of a configuration file. Invalid lines are ignored.
+    fn parse(content: &str) -> Self {
+        let mut config = Self::default();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                warn!("Ignoring invalid config line: {}", line);
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let valid = match key {
+                "scale" => value.parse().map(|s| config.scale = s).is_ok(),
+                "scale_mode" => value.parse().map(|m| config.scale_mode = m).is_ok(),
+                "palette" => {
+                    config.palette = value.to_string();
+                    true
+                }
+                "volume" => value.parse().map(|v| config.volume = v).is_ok(),
+                "recent_rom" => {
+                    config.recent_roms.push(PathBuf::from(value));
+                    true
+                }
+                _ => false,
+            };
+            if !valid {
+                warn!("Ignoring invalid config line: {}", line);
+            }
+        }
+        config.scale = config.scale.max(1);
+        config.volume = config.volume.min(100);
+        config.recent_roms.truncate(MAX_RECENT_ROMS);
+
+        config
+    }
+
+    /// Move the given ROM to the top of the list of recent ROMs.
+    pub fn add_recent_rom(&mut self, rom: &Path) {
+        let rom = rom.canonicalize().unwrap_or_else(|_| rom.to_path_buf());
+        self.recent_roms.retain(|r| *r != rom);
+        self.recent_roms.insert(0, rom);
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "scale = {}", self.scale)?;
+        writeln!(f, "scale_mode = {}", self.scale_mode)?;
+        writeln!(f, "palette = {}", self.palette)?;
+        writeln!(f, "volume = {}", self.volume)?;
+        for rom in &self.recent_roms {
+            writeln!(f, "recent_rom = {}", rom.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "# comment\nscale = 3\nscale_mode = fill\nvolume=250\nbogus\nrecent_rom = /roms/a b.gb\nrecent_rom = /roms/c.gb\n",
+        );
+        assert_eq!(config.scale, 3);
+        assert_eq!(config.scale_mode, ScaleMode::Fill);
+        assert_eq!(config.palette, "green");
+        assert_eq!(config.volume, 100);
+        assert_eq!(
+            config.recent_roms,
+            vec![PathBuf::from("/roms/a b.gb"), PathBuf::from("/roms/c.gb")]
+        );
+
+        // round-trip
+        assert_eq!(Config::parse(&config.to_string()), config);
+    }
+
+    #[test]
+    fn test_recent_roms() {
+        let mut config = Config::default();
+        for i in 0..12 {
+            config.add_recent_rom(Path::new(&format!("/roms/{}.gb", i)));
+        }
+        config.add_recent_rom(Path::new("/roms/5.gb"));
+        assert_eq!(config.recent_roms.len(), MAX_RECENT_ROMS);
+        assert_eq!(config.recent_roms[0], PathBuf::from("/roms/5.gb"));
+        assert_eq!(config.recent_roms[1], PathBuf::from("/roms/11.gb"));
+    }
+}
@@ -1,10 +1,21 @@
 mod register;
+mod state;
+
+use std::collections::VecDeque;
 
 use bitvec::{order::Lsb0, view::BitView};
 use log::{debug, info, trace, warn};
 
-use self::register::{Reg, RegPair, Registers};
-use crate::{bus::Bus, interrupt::InterruptFlag};
+use self::register::Registers;
+pub use self::register::{Reg, RegPair};
+pub use self::state::{CallFrame, CallKind, CpuState};
+use crate::{
+    breakpoint::WatchHit,
+    error::Result,
+    interrupt::InterruptFlag,
+    memory::Memory,
+    state::{StateReader, StateWriter, Stateful},
+};
 
 const ITR_VBLANK: u16 = 0x0040;
 const ITR_STAT: u16 = 0x0048;
@@ -12,6 +23,14 @@ const ITR_TIMER: u16 = 0x0050;
 const ITR_SERIAL: u16 = 0x0058;
 const ITR_JOYP: u16 = 0x0060;
 
+/// Number of clock cycles it takes to dispatch an interrupt
+const ITR_DISPATCH_CYCLES: u8 = 20;
+/// Maximum number of clock cycles run at once while halted
+const HALT_BATCH_CYCLES: u8 = 228;
+/// Maximum depth of the shadow call stack: code that never returns from its subroutines (e.g.
+/// by resetting SP) would otherwise make it grow forever
+const MAX_CALL_STACK: usize = 256;
+
 pub struct Cpu {
     regs: Registers,
 
@@ -21,15 +40,27 @@ pub struct Cpu {
 
     /// IME - Interrupt Master Enable Flag
     ime: bool,
+    /// Number of instructions left (including the current one) before EI takes effect
+    ime_delay: u8,
 
     // for debugging
-    breakpoint: u16,
     paused: bool,
     // Pause cpu if LD B,B is encountered
     enable_soft_break: bool,
 
     // Flag for the HALT bug
     halt_bug: bool,
+
+    /// Number of CALLs (including RSTs and interrupts) minus number of RETs, for the debugger
+    call_depth: i32,
+    /// The subroutines being executed, innermost last, for the debugger
+    call_stack: VecDeque<CallFrame>,
+
+    /// Number of clock cycles spent on the bus by the instruction being executed
+    step_cycles: u8,
+
+    /// Last watchpoint hit, with the address of the instruction that triggered it
+    watch_hit: Option<(u16, WatchHit)>,
 }
 
 impl Default for Cpu {
@@ -40,62 +71,53 @@ impl Default for Cpu {
             pc: Default::default(),
             halted: false,
             ime: true, // is this correct?
-            // breakpoint: 0x0100,
-            breakpoint: 0xffff,
+            ime_delay: 0,
             paused: Default::default(),
             enable_soft_break: false,
             halt_bug: false,
+            call_depth: 0,
+            call_stack: VecDeque::new(),
+            step_cycles: 0,
+            watch_hit: None,
         }
     }
 }
 
 impl Cpu {
-    pub fn with_breakpoint(breakpoint: Option<u16>, enable_soft_break: bool) -> Self {
+    pub fn new(enable_soft_break: bool) -> Self {
         Self {
-            breakpoint: breakpoint.unwrap_or(0xffff),
             enable_soft_break,
             ..Self::default()
         }
     }
 
-    pub fn handle_interrupt(&mut self, bus: &mut Bus) {
-        let interrupt_flag = bus.interrupt_flag();
-        let interrupt_enable = bus.interrupt_enable();
-        let pending_interrupts = bus.interrupt_pending();
-
-        // if there are pending interrupts, we need to wake the cpu (even if IME=0)
-        if pending_interrupts && self.halted {
-            self.halted = false;
-        }
-
-        if !self.ime || !pending_interrupts {
+    /// Dispatch the highest priority pending interrupt, if interrupts are enabled.
+    ///
+    /// Return the number of clock cycles used (0 if no interrupt was dispatched)
+    fn handle_interrupt(&mut self, bus: &mut impl Memory) -> u8 {
+        // The CPU is woken up by `execute()`, which takes an extra M-cycle
+        if self.halted || !self.ime || !bus.interrupt_pending() {
             // If interrupts are disabled, or no pending interrupts, just return
-            // debug!("interrupts are disabled, ignoring");
-            return;
+            return 0;
         }
-        // debug!("Handling interrupts: pending: {:?} / enabled: {:?}", interrupt_flag, interrupt_enable);
 
-        let should_handle = |f: InterruptFlag| -> bool {
-            interrupt_flag.contains(f) && interrupt_enable.contains(f)
-        };
+        self.call_interrupt(bus);
+        ITR_DISPATCH_CYCLES
+    }
 
+    /// The interrupt with the highest priority among the pending and enabled ones, if any.
+    fn highest_priority_interrupt(bus: &impl Memory) -> Option<InterruptFlag> {
+        let pending = bus.interrupt_flag() & bus.interrupt_enable();
         // These need to be ordered by priority:
-        if should_handle(InterruptFlag::VBLANK) {
-            trace!("Handling VBLANK interrupt");
-            self.call_interrupt(bus, InterruptFlag::VBLANK);
-        } else if should_handle(InterruptFlag::STAT) {
-            trace!("Handling STAT interrupt");
-            self.call_interrupt(bus, InterruptFlag::STAT);
-        } else if should_handle(InterruptFlag::TIMER) {
-            trace!("Handling TIMER interrupt");
-            self.call_interrupt(bus, InterruptFlag::TIMER);
-        } else if should_handle(InterruptFlag::SERIAL) {
-            trace!("Handling SERIAL interrupt");
-            self.call_interrupt(bus, InterruptFlag::SERIAL);
-        } else if should_handle(InterruptFlag::JOYPAD) {
-            trace!("Handling JOYPAD interrupt");
-            self.call_interrupt(bus, InterruptFlag::JOYPAD);
-        }
+        [
+            InterruptFlag::VBLANK,
+            InterruptFlag::STAT,
+            InterruptFlag::TIMER,
+            InterruptFlag::SERIAL,
+            InterruptFlag::JOYPAD,
+        ]
+        .into_iter()
+        .find(|&f| pending.contains(f))
     }
 
     fn get_itr_vector(&self, flag: InterruptFlag) -> u16 {
@@ -109,16 +131,41 @@ impl Cpu {
         }
     }
 
-    /// Fetch and execute the next instructions.
+    /// Fetch and execute the next instruction, then dispatch the highest priority pending
+    /// interrupt, if any.
+    ///
+    /// Interrupts are only checked at this instruction boundary, so at most one is dispatched per
+    /// step, and its 20 clock cycles are included in the cycles returned. The peripherals are run
+    /// as the CPU accesses the bus, so that memory accesses happen at the right M-cycle.
     ///
     /// Return the number of clock cycles used
-    pub fn step(&mut self, bus: &mut Bus) -> u8 {
+    pub fn step(&mut self, bus: &mut impl Memory) -> u8 {
+        let cycles = self.execute(bus);
+        cycles + self.handle_interrupt(bus)
+    }
+
+    /// Fetch and execute the next instruction, or wait for an interrupt while halted.
+    ///
+    /// Return the number of clock cycles used
+    fn execute(&mut self, bus: &mut impl Memory) -> u8 {
+        self.step_cycles = 0;
         // for debugging
-        if self.pc == self.breakpoint {
+        if bus.is_exec_break(self.pc, &|| self.state()) {
             self.paused = true;
         }
         if self.halted {
-            return 4;
+            // Nothing happens until an interrupt is requested, so run the peripherals for a while
+            // instead of returning after each M-cycle
+            while !bus.interrupt_pending() && self.step_cycles < HALT_BATCH_CYCLES {
+                self.tick(bus);
+            }
+            if bus.interrupt_pending() {
+                // The CPU wakes up (even if IME=0), which takes one more M-cycle. The interrupt is
+                // then dispatched by `step()` if interrupts are enabled.
+                self.halted = false;
+                self.tick(bus);
+            }
+            return self.step_cycles;
         }
 
         let orig_pc = self.pc;
@@ -144,7 +191,9 @@ impl Cpu {
             // LD (a16),SP
             0x08 => {
                 let addr = self.fetch_word(bus);
-                bus.write_word(addr, self.sp);
+                let [lsb, msb] = self.sp.to_le_bytes();
+                self.write(bus, addr, lsb);
+                self.write(bus, addr.wrapping_add(1), msb);
                 20
             }
             // ADD HL,BC
@@ -207,7 +256,7 @@ impl Cpu {
             0x21 => self.ld_rr_d16(bus, RegPair::HL),
             // LD (HL+),A
             0x22 => {
-                bus.write_byte(*self.regs.hl, self.regs.get(Reg::A));
+                self.write(bus, *self.regs.hl, self.regs.get(Reg::A));
                 *self.regs.hl = self.regs.hl.wrapping_add(1);
                 8
             }
@@ -258,7 +307,7 @@ impl Cpu {
             }
             // LD (HL-),A
             0x32 => {
-                bus.write_byte(self.regs.get_pair(RegPair::HL), self.regs.get(Reg::A));
+                self.write(bus, self.regs.get_pair(RegPair::HL), self.regs.get(Reg::A));
                 *self.regs.hl = self.regs.hl.wrapping_sub(1);
                 8
             }
@@ -564,7 +613,7 @@ impl Cpu {
             }
             // RET
             0xc9 => {
-                self.ret_if(bus, true);
+                self.ret(bus);
                 // debug!("Returning from subroutine to 0x{:04x}", self.pc);
                 16
             }
@@ -616,10 +665,10 @@ impl Cpu {
             }
             // RETI
             0xd9 => {
-                self.ret_if(bus, true);
-                // Re-enable interrupts
+                self.ret(bus);
+                // Re-enable interrupts (unlike EI, this takes effect immediately)
                 self.ime = true;
-                trace!("Returning from interrupt handler to 0x{:04x}", self.pc);
+                self.ime_delay = 0;
                 16
             }
             // JP C,a16
@@ -640,7 +689,7 @@ impl Cpu {
             0xe0 => {
                 let a8 = self.fetch(bus);
                 let addr = 0xFF00 + a8 as u16;
-                bus.write_byte(addr, self.regs.get(Reg::A));
+                self.write(bus, addr, self.regs.get(Reg::A));
                 12
             }
             // POP HL
@@ -648,7 +697,7 @@ impl Cpu {
             // LD (C),A
             0xe2 => {
                 let addr = 0xFF00 + self.regs.get(Reg::C) as u16;
-                bus.write_byte(addr, self.regs.get(Reg::A));
+                self.write(bus, addr, self.regs.get(Reg::A));
                 8
             }
             // PUSH HL
@@ -671,7 +720,8 @@ impl Cpu {
             0xf0 => {
                 let a8 = self.fetch(bus);
                 let addr = 0xFF00 + a8 as u16;
-                self.regs.set(Reg::A, bus.read_byte(addr));
+                let a = self.read(bus, addr);
+                self.regs.set(Reg::A, a);
                 12
             }
             // POP AF
@@ -679,13 +729,16 @@ impl Cpu {
             // LD A,(C)
             0xf2 => {
                 let addr = 0xFF00 + self.regs.get(Reg::C) as u16;
-                self.regs.set(Reg::A, bus.read_byte(addr));
+                let a = self.read(bus, addr);
+                self.regs.set(Reg::A, a);
                 8
             }
             // DI
             0xf3 => {
                 trace!("Disabling interrupts");
                 self.ime = false;
+                // also cancels a preceding EI
+                self.ime_delay = 0;
                 4
             }
             // PUSH AF
@@ -706,8 +759,11 @@ impl Cpu {
             // EI
             0xfb => {
                 trace!("Enabling interrupts");
-                // TODO the effect needs to be delayed by one instruction...
-                self.ime = true;
+                // The effect is delayed by one instruction: IME is set at the end of the next
+                // instruction
+                if !self.ime && self.ime_delay == 0 {
+                    self.ime_delay = 2;
+                }
                 4
             }
             // CP d8
@@ -719,14 +775,38 @@ impl Cpu {
                 // self.dump_cpu();
                 // unimplemented!("op=0x{:02x}, orig_pc=0x{:04x}", op, orig_pc);
                 warn!("Unimplemented op=0x{:02x}, orig_pc=0x{:04x}", op, orig_pc);
-                0
+                4
             }
         };
-        cycles
+
+        // Run the peripherals for the remaining internal cycles of the instruction
+        while self.step_cycles < cycles {
+            self.tick(bus);
+        }
+        debug_assert_eq!(
+            self.step_cycles, cycles,
+            "op=0x{:02x} accessed the bus on more M-cycles than it takes",
+            op
+        );
+
+        if let Some(hit) = bus.take_watch_hit() {
+            self.watch_hit = Some((orig_pc, hit));
+            self.paused = true;
+        }
+
+        // EI only takes effect after the instruction following it
+        if self.ime_delay > 0 {
+            self.ime_delay -= 1;
+            if self.ime_delay == 0 {
+                self.ime = true;
+            }
+        }
+
+        self.step_cycles
     }
 
     /// CB-prefixed instruction
-    fn step_cb(&mut self, bus: &mut Bus) -> u8 {
+    fn step_cb(&mut self, bus: &mut impl Memory) -> u8 {
         let cb_op = self.fetch(bus);
         match cb_op {
             // RLC B
@@ -1078,26 +1158,37 @@ impl Cpu {
     }
 
     // TODO probably should implement Debug instead...
-    pub fn dump_cpu(&self) {
-        println!(
-            "PC=${:04X}, SP=${:04X}, regs={:?}, IME={}",
-            self.pc, self.sp, self.regs, self.ime
-        );
+    /// Run the peripherals for one M-cycle (4 clock cycles)
+    fn tick(&mut self, bus: &mut impl Memory) {
+        bus.tick(4);
+        self.step_cycles += 4;
     }
 
-    fn fetch(&mut self, bus: &mut Bus) -> u8 {
-        let byte = bus.read_byte(self.pc);
+    /// Read a byte from the bus, which takes one M-cycle
+    fn read(&mut self, bus: &mut impl Memory, addr: u16) -> u8 {
+        self.tick(bus);
+        bus.read_byte(addr)
+    }
+
+    /// Write a byte to the bus, which takes one M-cycle
+    fn write(&mut self, bus: &mut impl Memory, addr: u16, b: u8) {
+        self.tick(bus);
+        bus.write_byte(addr, b);
+    }
+
+    fn fetch(&mut self, bus: &mut impl Memory) -> u8 {
+        let byte = self.read(bus, self.pc);
         if self.halt_bug {
             // Don't increment PC so the same byte is read again
             // See https://gbdev.io/pandocs/halt.html#halt-bug
             self.halt_bug = false;
         } else {
-            self.pc += 1;
+            self.pc = self.pc.wrapping_add(1);
         }
         byte
     }
 
-    fn fetch_word(&mut self, bus: &mut Bus) -> u16 {
+    fn fetch_word(&mut self, bus: &mut impl Memory) -> u16 {
         let lsb = self.fetch(bus);
         let msb = self.fetch(bus);
 
@@ -1105,16 +1196,16 @@ impl Cpu {
     }
 
     /// LD r,d8
-    fn ld_r_d8(&mut self, bus: &mut Bus, reg: Reg) -> u8 {
+    fn ld_r_d8(&mut self, bus: &mut impl Memory, reg: Reg) -> u8 {
         let d8 = self.fetch(bus);
         self.regs.set(reg, d8);
         8
     }
 
     /// LD (HL),d8
-    fn ld_hl_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn ld_hl_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let d8 = self.fetch(bus);
-        bus.write_byte(*self.regs.hl, d8);
+        self.write(bus, *self.regs.hl, d8);
         12
     }
 
@@ -1129,33 +1220,34 @@ impl Cpu {
     }
 
     /// LD rr,d16
-    fn ld_rr_d16(&mut self, bus: &mut Bus, reg: RegPair) -> u8 {
+    fn ld_rr_d16(&mut self, bus: &mut impl Memory, reg: RegPair) -> u8 {
         let d16 = self.fetch_word(bus);
         self.regs.set_pair(reg, d16);
         12
     }
 
-    fn ld_r_addr(&mut self, bus: &mut Bus, r: Reg, rr: RegPair) -> u8 {
+    fn ld_r_addr(&mut self, bus: &mut impl Memory, r: Reg, rr: RegPair) -> u8 {
         let addr = self.regs.get_pair(rr);
-        self.regs.set(r, bus.read_byte(addr));
+        let value = self.read(bus, addr);
+        self.regs.set(r, value);
         8
     }
 
-    fn ld_addr_r(&mut self, bus: &mut Bus, rr: RegPair, r: Reg) -> u8 {
+    fn ld_addr_r(&mut self, bus: &mut impl Memory, rr: RegPair, r: Reg) -> u8 {
         let addr = self.regs.get_pair(rr);
-        bus.write_byte(addr, self.regs.get(r));
+        self.write(bus, addr, self.regs.get(r));
         8
     }
 
-    fn ld_a16_r(&mut self, bus: &mut Bus, r: Reg) -> u8 {
+    fn ld_a16_r(&mut self, bus: &mut impl Memory, r: Reg) -> u8 {
         let addr = self.fetch_word(bus);
-        bus.write_byte(addr, self.regs.get(r));
+        self.write(bus, addr, self.regs.get(r));
         16
     }
 
-    fn ld_r_a16(&mut self, bus: &mut Bus, r: Reg) -> u8 {
+    fn ld_r_a16(&mut self, bus: &mut impl Memory, r: Reg) -> u8 {
         let addr = self.fetch_word(bus);
-        let byte = bus.read_byte(addr);
+        let byte = self.read(bus, addr);
         self.regs.set(r, byte);
         16
     }
@@ -1164,7 +1256,7 @@ impl Cpu {
     ///
     /// This is basically the same as `ADD SP,r8` except that the result is stored in `HL` and `SP`
     /// is not modified.
-    fn ld_hl_sp_r8(&mut self, bus: &mut Bus) -> u8 {
+    fn ld_hl_sp_r8(&mut self, bus: &mut impl Memory) -> u8 {
         // save SP
         let sp = self.sp;
         self.add_sp_r8(bus);
@@ -1175,7 +1267,7 @@ impl Cpu {
         12
     }
 
-    fn add_sp_r8(&mut self, bus: &mut Bus) -> u8 {
+    fn add_sp_r8(&mut self, bus: &mut impl Memory) -> u8 {
         // sign extend r8 to 16 bits
         let r8 = self.fetch(bus) as i8 as i16 as u16;
         let sp = self.sp;
@@ -1198,14 +1290,14 @@ impl Cpu {
         self.xor(r)
     }
 
-    fn xor_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn xor_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let v = self.fetch(bus);
         self.xor(v);
         8
     }
 
-    fn xor_hl(&mut self, bus: &mut Bus) -> u8 {
-        let v = bus.read_byte(*self.regs.hl);
+    fn xor_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let v = self.read(bus, *self.regs.hl);
         self.xor(v);
         8
     }
@@ -1227,15 +1319,15 @@ impl Cpu {
     }
 
     /// AND d8
-    fn and_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn and_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let d8 = self.fetch(bus);
         self.and(d8);
         8
     }
 
     // AND (HL)
-    fn and_hl(&mut self, bus: &mut Bus) -> u8 {
-        let hl = bus.read_byte(*self.regs.hl);
+    fn and_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let hl = self.read(bus, *self.regs.hl);
         self.and(hl);
         8
     }
@@ -1257,13 +1349,13 @@ impl Cpu {
     }
 
     /// OR (HL)
-    fn or_hl(&mut self, bus: &mut Bus) -> u8 {
-        let v = bus.read_byte(*self.regs.hl);
+    fn or_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let v = self.read(bus, *self.regs.hl);
         self.or(v);
         8
     }
 
-    fn or_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn or_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let v = self.fetch(bus);
         self.or(v);
         8
@@ -1289,9 +1381,10 @@ impl Cpu {
         8
     }
 
-    fn srl_hl(&mut self, bus: &mut Bus) -> u8 {
-        let hl = bus.read_byte(*self.regs.hl);
-        bus.write_byte(*self.regs.hl, self.srl_value_and_set_flags(hl));
+    fn srl_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let hl = self.read(bus, *self.regs.hl);
+        let new_hl = self.srl_value_and_set_flags(hl);
+        self.write(bus, *self.regs.hl, new_hl);
         16
     }
 
@@ -1306,10 +1399,10 @@ impl Cpu {
     }
 
     // SRA r (Shift Right Arithmetically)
-    fn sra_hl(&mut self, bus: &mut Bus) -> u8 {
-        let r = bus.read_byte(*self.regs.hl);
+    fn sra_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
         let new_r = self.sra(r);
-        bus.write_byte(*self.regs.hl, new_r);
+        self.write(bus, *self.regs.hl, new_r);
         16
     }
 
@@ -1331,10 +1424,10 @@ impl Cpu {
     }
 
     // SLA r (Shift Left Arithmetically)
-    fn sla_hl(&mut self, bus: &mut Bus) -> u8 {
-        let r = bus.read_byte(*self.regs.hl);
+    fn sla_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
         let new_r = self.sla(r);
-        bus.write_byte(*self.regs.hl, new_r);
+        self.write(bus, *self.regs.hl, new_r);
         16
     }
 
@@ -1357,9 +1450,10 @@ impl Cpu {
     }
 
     /// DEC (HL)
-    fn dec_hl(&mut self, bus: &mut Bus) -> u8 {
-        let new_r = self.dec_value_and_set_flags(bus.read_byte(*self.regs.hl));
-        bus.write_byte(*self.regs.hl, new_r);
+    fn dec_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
+        let new_r = self.dec_value_and_set_flags(r);
+        self.write(bus, *self.regs.hl, new_r);
 
         12
     }
@@ -1383,9 +1477,10 @@ impl Cpu {
     }
 
     /// INC (HL)
-    fn inc_hl(&mut self, bus: &mut Bus) -> u8 {
-        let new_r = self.inc_value_and_set_flags(bus.read_byte(*self.regs.hl));
-        bus.write_byte(*self.regs.hl, new_r);
+    fn inc_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
+        let new_r = self.inc_value_and_set_flags(r);
+        self.write(bus, *self.regs.hl, new_r);
         12
     }
 
@@ -1434,9 +1529,10 @@ impl Cpu {
     }
 
     /// Test bit n of register r
-    fn bit_n_hl(&mut self, n: u8, bus: &mut Bus) -> u8 {
-        self.bit_n_value(n, bus.read_byte(*self.regs.hl));
-        16
+    fn bit_n_hl(&mut self, n: u8, bus: &mut impl Memory) -> u8 {
+        let hl = self.read(bus, *self.regs.hl);
+        self.bit_n_value(n, hl);
+        12
     }
 
     fn bit_n_value(&mut self, n: u8, value: u8) -> u8 {
@@ -1451,7 +1547,7 @@ impl Cpu {
     }
 
     /// Conditional relative jump
-    fn jr_if_r8(&mut self, bus: &mut Bus, flag: bool) -> u8 {
+    fn jr_if_r8(&mut self, bus: &mut impl Memory, flag: bool) -> u8 {
         let r8 = self.fetch(bus) as i8;
         if flag {
             self.pc = self.pc.wrapping_add(r8 as i16 as u16);
@@ -1462,7 +1558,7 @@ impl Cpu {
     }
 
     /// conditional absolute jump
-    fn jp_if_a16(&mut self, bus: &mut Bus, flag: bool) -> u8 {
+    fn jp_if_a16(&mut self, bus: &mut impl Memory, flag: bool) -> u8 {
         let a16 = self.fetch_word(bus);
         if flag {
             self.pc = a16;
@@ -1478,65 +1574,124 @@ impl Cpu {
     }
 
     /// conditional CALL
-    fn call_if_a16(&mut self, bus: &mut Bus, flag: bool) -> u8 {
+    fn call_if_a16(&mut self, bus: &mut impl Memory, flag: bool) -> u8 {
         let addr = self.fetch_word(bus);
         if flag {
-            self.call(bus, addr);
+            self.call(bus, addr, CallKind::Call);
             24
         } else {
             12
         }
     }
 
-    fn call_interrupt(&mut self, bus: &mut Bus, itr_flag: InterruptFlag) {
-        let addr = self.get_itr_vector(itr_flag);
-        trace!("Calling ITR 0x{:02X}", addr);
+    fn call_interrupt(&mut self, bus: &mut impl Memory) {
         // disable interrupts
         self.ime = false;
-        bus.ack_interrupt(itr_flag);
-        self.push_word(bus, self.pc);
-        self.pc = addr;
+        self.tick(bus);
+        self.tick(bus);
+        let [lsb, msb] = self.pc.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, msb);
+        // The interrupt is only picked after the high byte of PC has been pushed: if that write
+        // went to IE (i.e. SP was 0x0000) and disabled the pending interrupts, the dispatch is
+        // cancelled and jumps to 0x0000 instead (mooneye's ie_push test relies on this)
+        let itr_flag = Self::highest_priority_interrupt(bus);
+        if let Some(itr_flag) = itr_flag {
+            bus.ack_interrupt(itr_flag);
+        }
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, lsb);
+        self.tick(bus);
+
+        match itr_flag {
+            Some(itr_flag) => {
+                let addr = self.get_itr_vector(itr_flag);
+                self.push_call_frame(CallKind::Interrupt, addr);
+                trace!("Dispatching interrupt {:?}", itr_flag);
+                if bus.is_interrupt_break(itr_flag) {
+                    info!("Breaking on interrupt {:?}", itr_flag);
+                    self.paused = true;
+                }
+                self.pc = addr;
+            }
+            None => {
+                trace!("Interrupt dispatch cancelled by a write to IE");
+                self.push_call_frame(CallKind::Interrupt, 0x0000);
+                self.pc = 0x0000;
+            }
+        }
+        self.call_depth = self.call_depth.wrapping_add(1);
     }
 
-    fn call(&mut self, bus: &mut Bus, addr: u16) {
-        trace!("Calling subroutine at 0x{:04x}", addr);
+    fn call(&mut self, bus: &mut impl Memory, addr: u16, kind: CallKind) {
         self.push_word(bus, self.pc);
+        self.push_call_frame(kind, addr);
         self.pc = addr;
+        self.call_depth = self.call_depth.wrapping_add(1);
     }
 
-    fn ret_if(&mut self, bus: &mut Bus, flag: bool) -> u8 {
+    /// Record a call to `target` (from the current PC) in the shadow call stack.
+    fn push_call_frame(&mut self, kind: CallKind, target: u16) {
+        if self.call_stack.len() == MAX_CALL_STACK {
+            self.call_stack.pop_front();
+        }
+        self.call_stack.push_back(CallFrame {
+            kind,
+            target,
+            return_addr: self.pc,
+        });
+    }
+
+    /// RET cc
+    fn ret_if(&mut self, bus: &mut impl Memory, flag: bool) -> u8 {
+        // The condition is checked on an internal M-cycle, before the address is popped
+        self.tick(bus);
         if flag {
-            self.pc = self.pop_word(bus);
+            self.ret(bus);
             20
         } else {
             8
         }
     }
 
+    /// Pop the return address into PC (the internal M-cycle that follows is left to the caller)
+    fn ret(&mut self, bus: &mut impl Memory) {
+        self.pc = self.pop_word(bus);
+        self.call_stack.pop_back();
+        self.call_depth = self.call_depth.wrapping_sub(1);
+    }
+
     /// PUSH rr
-    fn push_rr(&mut self, bus: &mut Bus, rr: RegPair) -> u8 {
+    fn push_rr(&mut self, bus: &mut impl Memory, rr: RegPair) -> u8 {
         self.push_word(bus, self.regs.get_pair(rr));
         16
     }
 
     /// POP rr
-    fn pop_rr(&mut self, bus: &mut Bus, rr: RegPair) -> u8 {
+    fn pop_rr(&mut self, bus: &mut impl Memory, rr: RegPair) -> u8 {
         let word = self.pop_word(bus);
         self.regs.set_pair(rr, word);
         12
     }
 
     /// PUSH a16
-    fn push_word(&mut self, bus: &mut Bus, word: u16) {
-        self.sp = self.sp.wrapping_sub(2);
-        bus.write_word(self.sp, word);
+    fn push_word(&mut self, bus: &mut impl Memory, word: u16) {
+        // There's an internal delay before the actual writes
+        self.tick(bus);
+        let [lsb, msb] = word.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, msb);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, lsb);
     }
 
     /// POP a16
-    fn pop_word(&mut self, bus: &mut Bus) -> u16 {
-        let word = bus.read_word(self.sp);
-        self.sp = self.sp.wrapping_add(2);
-        word
+    fn pop_word(&mut self, bus: &mut impl Memory) -> u16 {
+        let lsb = self.read(bus, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let msb = self.read(bus, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        u16::from_le_bytes([lsb, msb])
     }
 
     /// RL r ;rotate left through carry
@@ -1548,10 +1703,10 @@ impl Cpu {
     }
 
     /// RL r ;rotate left through carry
-    fn rl_hl(&mut self, bus: &mut Bus) -> u8 {
-        let v = bus.read_byte(*self.regs.hl);
+    fn rl_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let v = self.read(bus, *self.regs.hl);
         let res = self.rl(v);
-        bus.write_byte(*self.regs.hl, res);
+        self.write(bus, *self.regs.hl, res);
         16
     }
 
@@ -1586,10 +1741,10 @@ impl Cpu {
         8
     }
 
-    fn rr_hl(&mut self, bus: &mut Bus) -> u8 {
-        let r = bus.read_byte(*self.regs.hl);
+    fn rr_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
         let new_r = self.rr(r);
-        bus.write_byte(*self.regs.hl, new_r);
+        self.write(bus, *self.regs.hl, new_r);
 
         16
     }
@@ -1642,10 +1797,10 @@ impl Cpu {
         8
     }
 
-    fn rlc_hl(&mut self, bus: &mut Bus) -> u8 {
-        let r = bus.read_byte(*self.regs.hl);
+    fn rlc_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
         let rotated = self.rlc(r);
-        bus.write_byte(*self.regs.hl, rotated);
+        self.write(bus, *self.regs.hl, rotated);
         16
     }
 
@@ -1675,10 +1830,10 @@ impl Cpu {
         8
     }
 
-    fn rrc_hl(&mut self, bus: &mut Bus) -> u8 {
-        let r = bus.read_byte(*self.regs.hl);
+    fn rrc_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
         let rotated = self.rrc(r);
-        bus.write_byte(*self.regs.hl, rotated);
+        self.write(bus, *self.regs.hl, rotated);
         16
     }
 
@@ -1701,8 +1856,8 @@ impl Cpu {
     }
 
     /// ADD (HL)
-    fn add_hl_addr(&mut self, bus: &mut Bus) -> u8 {
-        let hl = bus.read_byte(*self.regs.hl);
+    fn add_hl_addr(&mut self, bus: &mut impl Memory) -> u8 {
+        let hl = self.read(bus, *self.regs.hl);
         self.add(hl);
         8
     }
@@ -1713,10 +1868,10 @@ impl Cpu {
     }
 
     /// ADD d8
-    fn add_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn add_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let d8 = self.fetch(bus);
         self.add(d8);
-        4
+        8
     }
 
     fn add(&mut self, value: u8) -> u8 {
@@ -1737,8 +1892,8 @@ impl Cpu {
     }
 
     /// ADD (HL)
-    fn adc_hl(&mut self, bus: &mut Bus) -> u8 {
-        let hl = bus.read_byte(*self.regs.hl);
+    fn adc_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let hl = self.read(bus, *self.regs.hl);
         self.adc(hl, true);
         8
     }
@@ -1749,10 +1904,10 @@ impl Cpu {
     }
 
     /// ADD d8
-    fn adc_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn adc_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let d8 = self.fetch(bus);
         self.adc(d8, true);
-        4
+        8
     }
 
     fn adc(&mut self, value: u8, with_carry: bool) -> u8 {
@@ -1768,12 +1923,12 @@ impl Cpu {
         self.regs
             .flag_h()
             .set_value((reg_a & 0x0f) + (value & 0x0f) + c > 0x0f);
-        8
+        4
     }
 
     /// SBC (HL)
-    fn sbc_hl(&mut self, bus: &mut Bus) -> u8 {
-        let hl = bus.read_byte(*self.regs.hl);
+    fn sbc_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let hl = self.read(bus, *self.regs.hl);
         self.sbc(hl, true);
         8
     }
@@ -1784,21 +1939,21 @@ impl Cpu {
     }
 
     /// SBC d8
-    fn sbc_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn sbc_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let d8 = self.fetch(bus);
         self.sbc(d8, true);
-        4
+        8
     }
 
     /// SUB (HL)
-    fn sub_hl_addr(&mut self, bus: &mut Bus) -> u8 {
-        let hl = bus.read_byte(*self.regs.hl);
+    fn sub_hl_addr(&mut self, bus: &mut impl Memory) -> u8 {
+        let hl = self.read(bus, *self.regs.hl);
         self.sub(hl);
         8
     }
 
     /// SUB d8
-    fn sub_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn sub_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let d8 = self.fetch(bus);
         self.sub(d8);
         8
@@ -1827,16 +1982,16 @@ impl Cpu {
         self.regs
             .flag_h()
             .set_value((reg_a & 0x0f) < (value & 0x0f) + c);
-        8
+        4
     }
 
-    fn cp_hl(&mut self, bus: &mut Bus) -> u8 {
-        let d8 = bus.read_byte(self.regs.get_pair(RegPair::HL));
+    fn cp_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let d8 = self.read(bus, self.regs.get_pair(RegPair::HL));
         self.cp(d8);
         8
     }
 
-    fn cp_d8(&mut self, bus: &mut Bus) -> u8 {
+    fn cp_d8(&mut self, bus: &mut impl Memory) -> u8 {
         let d8 = self.fetch(bus);
         self.cp(d8);
         8
@@ -1859,8 +2014,8 @@ impl Cpu {
             .set_value((reg_a & 0x0f) < (value & 0x0f));
     }
 
-    fn rst(&mut self, bus: &mut Bus, vec: u8) -> u8 {
-        self.call(bus, vec as u16);
+    fn rst(&mut self, bus: &mut impl Memory, vec: u8) -> u8 {
+        self.call(bus, vec as u16, CallKind::Rst);
         16
     }
 
@@ -1869,13 +2024,13 @@ impl Cpu {
         self.regs.set(Reg::A, !self.regs.get(Reg::A));
         self.regs.flag_n().set();
         self.regs.flag_h().set();
-        8
+        4
     }
 
-    fn swap_hl(&mut self, bus: &mut Bus) -> u8 {
-        let r = bus.read_byte(*self.regs.hl);
+    fn swap_hl(&mut self, bus: &mut impl Memory) -> u8 {
+        let r = self.read(bus, *self.regs.hl);
         let new_r = self.swap(r);
-        bus.write_byte(*self.regs.hl, new_r);
+        self.write(bus, *self.regs.hl, new_r);
 
         16
     }
@@ -1904,10 +2059,10 @@ impl Cpu {
         8
     }
 
-    fn res_hl(&mut self, n: u8, bus: &mut Bus) -> u8 {
-        let mut hl = bus.read_byte(*self.regs.hl);
+    fn res_hl(&mut self, n: u8, bus: &mut impl Memory) -> u8 {
+        let mut hl = self.read(bus, *self.regs.hl);
         hl.view_bits_mut::<Lsb0>().set(n as usize, false);
-        bus.write_byte(*self.regs.hl, hl);
+        self.write(bus, *self.regs.hl, hl);
 
         16
     }
@@ -1920,10 +2075,10 @@ impl Cpu {
         8
     }
 
-    fn set_hl(&mut self, n: u8, bus: &mut Bus) -> u8 {
-        let mut hl = bus.read_byte(*self.regs.hl);
+    fn set_hl(&mut self, n: u8, bus: &mut impl Memory) -> u8 {
+        let mut hl = self.read(bus, *self.regs.hl);
         hl.view_bits_mut::<Lsb0>().set(n as usize, true);
-        bus.write_byte(*self.regs.hl, hl);
+        self.write(bus, *self.regs.hl, hl);
 
         16
     }
@@ -1957,67 +2112,101 @@ impl Cpu {
         4
     }
 
-    // fn daa(&mut self) -> u8 {
-    //     let reg_a = self.regs.get(Reg::A);
-    //     let hi = (reg_a & 0xf0) >> 4;
-    //     let lo = reg_a & 0x0f;
-
-    //     if self.regs.flag_n().is_set() {
-    //         // Last operation was subtraction
-    //         match (self.regs.flag_c().is_set(), self.regs.flag_h().is_set()) {
-    //             (false, false) => (),
-    //             (false, true) => {
-    //                 if hi <= 8 && lo >= 6 {
-    //                     self.add(0xfa);
-    //                 }
-    //             }
-    //             (true, false) => {
-    //                 if hi >= 7 && lo <= 9 {
-    //                     self.add(0xa0);
-    //                 }
-    //             }
-    //             (true, true) => {
-    //                 if hi >= 6 && lo >= 6 {
-    //                     self.add(0x9a);
-    //                 }
-    //             }
-    //         }
-    //     } else {
-    //         // Last operation was an addition
-    //         match (self.regs.flag_c().is_set(), self.regs.flag_h().is_set()) {
-    //             (false, false) => {
-    //                 if hi <= 8 && lo >= 0x0a {
-    //                     self.add(0x06);
-    //                 } else if hi >= 0x0a && lo <= 9 {
-    //                     self.add(0x60);
-    //                 } else if hi >= 0x09 && lo >= 0x0a {
-    //                     self.add(0x66);
-    //                 }
-    //             }
-    //             (false, true) => {
-    //                 if hi <= 9 && lo <= 3 {
-    //                     self.add(0x06);
-    //                 } else if hi >= 0x0a && lo <= 3 {
-    //                     self.add(0x66);
-    //                 }
-    //             }
-    //             (true, false) => {
-    //                 if hi <= 2 && lo <= 9 {
-    //                     self.add(0x60);
-    //                 } else if hi <= 2 && lo >= 0x0a {
-    //                     self.add(0x66);
-    //                 }
-    //             }
-    //             (true, true) => {
-    //                 if hi <= 3 && lo <= 3 {
-    //                     self.add(0x66);
-    //                 }
-    //             }
-    //         }
-    //     }
-
-    //     4
-    // }
+    /// Set the registers to the values the DMG boot ROM leaves them in, and jump to the cartridge
+    /// entry point.
+    pub fn skip_boot(&mut self) {
+        self.regs.set_pair(RegPair::AF, 0x01B0);
+        self.regs.set_pair(RegPair::BC, 0x0013);
+        self.regs.set_pair(RegPair::DE, 0x00D8);
+        self.regs.set_pair(RegPair::HL, 0x014D);
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+    }
+
+    pub fn reg(&self, reg: Reg) -> u8 {
+        self.regs.get(reg)
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, value: u8) {
+        self.regs.set(reg, value);
+    }
+
+    pub fn reg_pair(&self, pair: RegPair) -> u16 {
+        self.regs.get_pair(pair)
+    }
+
+    /// Set a register pair. Note that the lower 4 bits of F always read back as 0.
+    pub fn set_reg_pair(&mut self, pair: RegPair, value: u16) {
+        self.regs.set_pair(pair, value);
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.regs.get(Reg::A),
+            f: self.regs.get_pair(RegPair::AF) as u8,
+            b: self.regs.get(Reg::B),
+            c: self.regs.get(Reg::C),
+            d: self.regs.get(Reg::D),
+            e: self.regs.get(Reg::E),
+            h: self.regs.get(Reg::H),
+            l: self.regs.get(Reg::L),
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.ime,
+            halted: self.halted,
+        }
+    }
+
+    /// Restore the registers from a snapshot. Any pending EI is cancelled.
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.regs.set_pair(RegPair::AF, state.af());
+        self.regs.set_pair(RegPair::BC, state.bc());
+        self.regs.set_pair(RegPair::DE, state.de());
+        self.regs.set_pair(RegPair::HL, state.hl());
+        self.sp = state.sp;
+        self.pc = state.pc;
+        self.set_ime(state.ime);
+        self.halted = state.halted;
+        self.halt_bug = false;
+    }
+
+    /// Set IME immediately, cancelling any pending EI.
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+        self.ime_delay = 0;
+    }
+
+    /// Current depth of the call stack, as tracked by CALL/RST/interrupts and RET/RETI.
+    ///
+    /// This is only an estimate as code can manipulate the stack directly.
+    pub fn call_depth(&self) -> i32 {
+        self.call_depth
+    }
+
+    /// The subroutines being executed, innermost last.
+    pub fn call_stack(&self) -> &VecDeque<CallFrame> {
+        &self.call_stack
+    }
 
     pub fn is_paused(&self) -> bool {
         self.paused
@@ -2027,9 +2216,10 @@ impl Cpu {
         self.paused = pause;
     }
 
-    /// Set the cpu's breakpoint.
-    pub fn set_breakpoint(&mut self, breakpoint: u16) {
-        self.breakpoint = breakpoint;
+    /// Return (and forget) the last watchpoint hit, with the address of the instruction that
+    /// triggered it.
+    pub fn take_watch_hit(&mut self) -> Option<(u16, WatchHit)> {
+        self.watch_hit.take()
     }
 
     /// Get the cpu's halted.
@@ -2038,9 +2228,413 @@ impl Cpu {
     }
 }
 
+impl Stateful for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        for pair in [RegPair::AF, RegPair::BC, RegPair::DE, RegPair::HL] {
+            w.u16(self.regs.get_pair(pair));
+        }
+        w.u16(self.sp);
+        w.u16(self.pc);
+        w.bool(self.ime);
+        w.bool(self.halted);
+        w.u8(self.ime_delay);
+        w.bool(self.halt_bug);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        for pair in [RegPair::AF, RegPair::BC, RegPair::DE, RegPair::HL] {
+            self.regs.set_pair(pair, r.u16()?);
+        }
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        self.ime = r.bool()?;
+        self.halted = r.bool()?;
+        self.ime_delay = r.u8()?;
+        self.halt_bug = r.bool()?;
+        // The subroutines called before the state was saved are unknown
+        self.call_depth = 0;
+        self.call_stack.clear();
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        bus::Bus,
+        cartridge::Cartridge,
+        testing::{cb_opcode_cycles, opcode_cycles},
+    };
+
+    /// 64KB of plain RAM, without any peripheral
+    struct FlatRam(Vec<u8>);
+
+    impl Memory for FlatRam {
+        fn read_byte(&mut self, addr: u16) -> u8 {
+            self.0[addr as usize]
+        }
+
+        fn write_byte(&mut self, addr: u16, value: u8) {
+            self.0[addr as usize] = value;
+        }
+
+        fn tick(&mut self, _cycles: u8) {}
+    }
+
+    #[test]
+    fn test_flat_ram() {
+        // LD A,$42; LD ($C000),A
+        let mut ram = FlatRam(vec![0; 0x10000]);
+        ram.0[0..5].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0]);
+        let mut cpu = Cpu::new(false);
+        assert_eq!(cpu.step(&mut ram), 8);
+        assert_eq!(cpu.step(&mut ram), 16);
+        assert_eq!(ram.0[0xC000], 0x42);
+        assert_eq!(cpu.pc(), 5);
+
+        // The trait is object-safe
+        let ram: &dyn Memory = &ram;
+        assert!(!ram.is_exec_break(0, &|| cpu.state()));
+    }
+
+    #[test]
+    fn test_halt_timing() {
+        // HALT at 0150, followed by NOPs
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150] = 0x76;
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(rom));
+        bus.write_byte(0xFFFF, InterruptFlag::TIMER.bits());
+
+        for ime in [false, true] {
+            let mut cpu = Cpu {
+                pc: 0x0150,
+                sp: 0xFFFE,
+                ime,
+                ..Cpu::default()
+            };
+            let step = |cpu: &mut Cpu, bus: &mut Bus| cpu.step(bus) as u32;
+            assert_eq!(step(&mut cpu, &mut bus), 4);
+            assert!(cpu.halted);
+            // nothing to do: the peripherals are run in batches
+            assert_eq!(step(&mut cpu, &mut bus), HALT_BATCH_CYCLES as u32);
+            assert!(cpu.halted);
+
+            bus.write_byte(0xFF0F, InterruptFlag::TIMER.bits());
+            if ime {
+                // wake up, then dispatch the interrupt
+                assert_eq!(step(&mut cpu, &mut bus), 4 + ITR_DISPATCH_CYCLES as u32);
+                assert_eq!(cpu.pc, ITR_TIMER);
+            } else {
+                // wake up and carry on after the HALT
+                assert_eq!(step(&mut cpu, &mut bus), 4);
+                assert_eq!(cpu.pc, 0x0151);
+                bus.write_byte(0xFF0F, 0);
+            }
+            assert!(!cpu.halted);
+        }
+    }
+
+    #[test]
+    fn test_call_stack() {
+        // CALL $0200 at 0150, RST $28 at 0200, and RET at 0028
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150..0x0153].copy_from_slice(&[0xCD, 0x00, 0x02]);
+        rom[0x0200] = 0xEF;
+        rom[0x0028] = 0xC9;
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(rom));
+        // unmap the boot ROM
+        bus.write_byte(0xFF50, 1);
+        let mut cpu = Cpu {
+            pc: 0x0150,
+            sp: 0xFFFE,
+            ..Cpu::default()
+        };
+
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0x0028);
+        let stack = cpu.call_stack();
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0].call_site(), 0x0150);
+        assert_eq!(stack[0].to_string(), "$0200 called from $0150");
+        assert_eq!(stack[1].to_string(), "$0028 (RST) from $0200");
+
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0x0201);
+        assert_eq!(cpu.call_stack().len(), 1);
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        // NOPs everywhere, once the boot ROM is unmapped
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        bus.write_byte(0xFF50, 0x01);
+        bus.write_byte(0xFFFF, 0xFF);
+        bus.write_byte(
+            0xFF0F,
+            (InterruptFlag::TIMER | InterruptFlag::VBLANK).bits(),
+        );
+        let mut cpu = Cpu {
+            pc: 0x0150,
+            sp: 0xFFFE,
+            ime: true,
+            ..Cpu::default()
+        };
+        // The NOP, then only the VBlank interrupt, which has the highest priority
+        assert_eq!(cpu.step(&mut bus), 4 + ITR_DISPATCH_CYCLES);
+        assert_eq!(cpu.pc, ITR_VBLANK);
+        assert_eq!(cpu.call_stack().len(), 1);
+        // The timer interrupt stays pending, as IME is now off
+        assert_eq!(cpu.step(&mut bus), 4);
+        assert_eq!(cpu.pc, ITR_VBLANK + 1);
+        assert_eq!(
+            bus.interrupt_flag() & InterruptFlag::TIMER,
+            InterruptFlag::TIMER
+        );
+    }
+
+    /// Flat memory that records on which cycle of the instruction each access happens
+    struct AccessRecorder {
+        mem: Vec<u8>,
+        cycles: u32,
+        accesses: Vec<(u32, char, u16)>,
+    }
+
+    impl AccessRecorder {
+        fn new(code: &[u8]) -> Self {
+            let mut mem = vec![0; 0x10000];
+            mem[..code.len()].copy_from_slice(code);
+            Self {
+                mem,
+                cycles: 0,
+                accesses: Vec::new(),
+            }
+        }
+    }
+
+    impl Memory for AccessRecorder {
+        fn read_byte(&mut self, addr: u16) -> u8 {
+            self.accesses.push((self.cycles, 'R', addr));
+            self.mem[addr as usize]
+        }
+
+        fn write_byte(&mut self, addr: u16, value: u8) {
+            self.accesses.push((self.cycles, 'W', addr));
+            self.mem[addr as usize] = value;
+        }
+
+        fn tick(&mut self, cycles: u8) {
+            self.cycles += cycles as u32;
+        }
+    }
+
+    #[test]
+    fn test_access_cycles() {
+        // LD A,($C000); PUSH BC; LD ($C001),A; RET NZ
+        let mut mem = AccessRecorder::new(&[0xFA, 0x00, 0xC0, 0xC5, 0xEA, 0x01, 0xC0, 0xC0]);
+        let mut cpu = Cpu {
+            sp: 0xFFFE,
+            ime: false,
+            ..Cpu::default()
+        };
+        let mut step = |mem: &mut AccessRecorder| {
+            mem.cycles = 0;
+            mem.accesses.clear();
+            let cycles = cpu.step(mem);
+            (cycles, std::mem::take(&mut mem.accesses))
+        };
+
+        // Each access happens at the end of its M-cycle
+        assert_eq!(
+            step(&mut mem),
+            (
+                16,
+                vec![
+                    (4, 'R', 0x0000),
+                    (8, 'R', 0x0001),
+                    (12, 'R', 0x0002),
+                    (16, 'R', 0xC000)
+                ]
+            )
+        );
+        // An internal M-cycle before the writes
+        assert_eq!(
+            step(&mut mem),
+            (
+                16,
+                vec![(4, 'R', 0x0003), (12, 'W', 0xFFFD), (16, 'W', 0xFFFC)]
+            )
+        );
+        assert_eq!(step(&mut mem).1.last(), Some(&(16, 'W', 0xC001)));
+        // The condition is checked on an internal M-cycle before the pops, and PC is set on another
+        assert_eq!(
+            step(&mut mem),
+            (
+                20,
+                vec![(4, 'R', 0x0007), (12, 'R', 0xFFFC), (16, 'R', 0xFFFD)]
+            )
+        );
+    }
+
+    #[test]
+    fn test_opcode_cycles() {
+        // Run `code` from 0000 with the given flags, and return the cycles it took according to
+        // the CPU and to the memory
+        let run = |code: &[u8], f: u8| {
+            let mut mem = AccessRecorder::new(code);
+            let mut cpu = Cpu {
+                sp: 0xFFFE,
+                ime: false,
+                ..Cpu::default()
+            };
+            cpu.regs.set_pair(RegPair::AF, f as u16);
+            let cycles = cpu.step(&mut mem);
+            (cycles as u32, mem.cycles)
+        };
+
+        for op in 0..=0xFFu8 {
+            // Z and C clear, so that NZ and NC hold, then both set, so that Z and C hold
+            for (f, nz) in [(0x00, true), (0xF0, false)] {
+                // bit 3 of the opcode tells NZ/NC (0) from Z/C (1)
+                let Some(expected) = opcode_cycles(op, (op & 0x08 == 0) == nz) else {
+                    continue;
+                };
+                assert_eq!(
+                    run(&[op], f),
+                    (expected as u32, expected as u32),
+                    "opcode {op:02X}, F={f:02X}"
+                );
+            }
+        }
+
+        for op in 0..=0xFFu8 {
+            let expected = cb_opcode_cycles(op) as u32;
+            assert_eq!(
+                run(&[0xCB, op], 0),
+                (expected, expected),
+                "opcode CB {op:02X}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_timer_read_cycle() {
+        // NOPs, then LDH A,(DIV)
+        let read_div = |nops: usize| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x0100 + nops..0x0102 + nops].copy_from_slice(&[0xF0, 0x04]);
+            let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(rom));
+            // DIV=$AB, and goes up 52 cycles later
+            bus.skip_boot();
+            let mut cpu = Cpu {
+                pc: 0x0100,
+                sp: 0xFFFE,
+                ime: false,
+                ..Cpu::default()
+            };
+            for _ in 0..=nops {
+                cpu.step(&mut bus);
+            }
+            cpu.regs.get(Reg::A)
+        };
+
+        // The read happens on the last M-cycle of the LDH: cycle 40 + 12 here
+        assert_eq!(read_div(10), 0xAC);
+        assert_eq!(read_div(9), 0xAB);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        // EI; NOP; NOP at 0150, and EI; DI; NOP at 0160
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150] = 0xFB;
+        rom[0x0160..0x0162].copy_from_slice(&[0xFB, 0xF3]);
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(rom));
+        bus.write_byte(0xFF50, 1);
+        bus.write_byte(0xFFFF, InterruptFlag::TIMER.bits());
+        bus.write_byte(0xFF0F, InterruptFlag::TIMER.bits());
+
+        let mut cpu = Cpu {
+            pc: 0x0150,
+            sp: 0xFFFE,
+            ime: false,
+            ..Cpu::default()
+        };
+        // IME isn't set yet after EI, so the pending interrupt waits
+        assert_eq!(cpu.step(&mut bus), 4);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.pc, 0x0151);
+        // It is after the next instruction, and the interrupt is dispatched straight away
+        assert_eq!(cpu.step(&mut bus), 4 + ITR_DISPATCH_CYCLES);
+        assert_eq!(cpu.pc, ITR_TIMER);
+        // returning to the second NOP
+        assert_eq!(cpu.pop_word(&mut bus), 0x0152);
+
+        // DI right after EI cancels it
+        bus.write_byte(0xFF0F, InterruptFlag::TIMER.bits());
+        let mut cpu = Cpu {
+            pc: 0x0160,
+            sp: 0xFFFE,
+            ime: false,
+            ..Cpu::default()
+        };
+        for _ in 0..3 {
+            assert_eq!(cpu.step(&mut bus), 4);
+        }
+        assert!(!cpu.ime);
+        assert_eq!(cpu.pc, 0x0163);
+    }
+
+    #[test]
+    fn test_ie_push() {
+        let mut bus = Bus::new(8 * 1024, Cartridge::from_bytes(vec![0; 0x8000]));
+        let mut dispatch = |pc: u16| {
+            bus.write_byte(0xFFFF, InterruptFlag::TIMER.bits());
+            bus.write_byte(0xFF0F, InterruptFlag::TIMER.bits());
+            let mut cpu = Cpu {
+                pc,
+                sp: 0x0000,
+                ime: true,
+                ..Cpu::default()
+            };
+            assert_eq!(cpu.handle_interrupt(&mut bus), ITR_DISPATCH_CYCLES);
+            assert_eq!(cpu.sp, 0xFFFE);
+            (cpu.pc, bus.interrupt_flag())
+        };
+
+        // The high byte of PC lands in IE and keeps the timer interrupt enabled
+        assert_eq!(dispatch(0x0434), (ITR_TIMER, InterruptFlag::empty()));
+        // It disables it: the dispatch is cancelled, and the interrupt stays pending
+        assert_eq!(dispatch(0x1234), (0x0000, InterruptFlag::TIMER));
+    }
+
+    #[test]
+    fn test_cpu_state() {
+        let mut cpu = Cpu::default();
+        let state = CpuState {
+            a: 0x12,
+            f: 0xBF,
+            b: 0x34,
+            c: 0x56,
+            d: 0x78,
+            e: 0x9A,
+            h: 0xBC,
+            l: 0xDE,
+            sp: 0xFFFE,
+            pc: 0x0150,
+            ime: false,
+            halted: true,
+        };
+        cpu.set_state(&state);
+        assert_eq!(cpu.reg_pair(RegPair::HL), 0xBCDE);
+        // the lower bits of F always read back as 0
+        assert_eq!(cpu.state(), CpuState { f: 0xB0, ..state });
+        assert_eq!(
+            cpu.state().to_string(),
+            "AF=$12B0 BC=$3456 DE=$789A HL=$BCDE SP=$FFFE PC=$0150 Z-HC IME=0 HALTED"
+        );
+    }
 
     #[test]
     fn test_rl() {
@@ -2127,4 +2721,88 @@ mod tests {
         // Z is set, and C is ignored
         test_and_check_flags(0x01, 0x00, true, true, false, false);
     }
+
+    /// Reference implementation of DAA, working on a wider integer so that the carry falls out of
+    /// the result instead of being derived from the adjustment.
+    ///
+    /// Returns the new value of A and the Z/N/H/C flags (in the upper nibble, like register F).
+    fn daa_reference(a: u8, n: bool, h: bool, c: bool) -> (u8, u8) {
+        let mut result = a as i16;
+        if n {
+            if h {
+                result = (result - 0x06) & 0xFF;
+            }
+            if c {
+                result -= 0x60;
+            }
+        } else {
+            if h || (result & 0x0F) > 0x09 {
+                result += 0x06;
+            }
+            if c || result > 0x9F {
+                result += 0x60;
+            }
+        }
+        let a = (result & 0xFF) as u8;
+        let mut f = 0;
+        if a == 0 {
+            f |= 0x80;
+        }
+        if n {
+            f |= 0x40;
+        }
+        if c || result & 0x100 != 0 {
+            f |= 0x10;
+        }
+
+        (a, f)
+    }
+
+    #[test]
+    fn test_daa_exhaustive() {
+        let mut cpu = Cpu::default();
+        let mut mismatches = vec![];
+        for a in 0..=0xFFu8 {
+            for flags in 0..8u8 {
+                let (n, h, c) = (flags & 4 != 0, flags & 2 != 0, flags & 1 != 0);
+                cpu.regs.set_pair(RegPair::AF, (a as u16) << 8);
+                cpu.regs.flag_n().set_value(n);
+                cpu.regs.flag_h().set_value(h);
+                cpu.regs.flag_c().set_value(c);
+                cpu.daa();
+
+                let af = cpu.regs.get_pair(RegPair::AF);
+                let actual = ((af >> 8) as u8, af as u8);
+                let expected = daa_reference(a, n, h, c);
+                if actual != expected {
+                    mismatches.push((a, n, h, c, actual, expected));
+                }
+            }
+        }
+
+        assert!(mismatches.is_empty(), "DAA mismatches: {:02x?}", mismatches);
+    }
+
+    #[test]
+    fn test_daa_bcd() {
+        let bcd = |v: u8| (v / 10) << 4 | (v % 10);
+        let mut cpu = Cpu::default();
+        for x in 0..100 {
+            for y in 0..100 {
+                cpu.regs.set_pair(RegPair::AF, 0x0000);
+                cpu.regs.set(Reg::A, bcd(x));
+                cpu.add(bcd(y));
+                cpu.daa();
+                assert_eq!(bcd((x + y) % 100), cpu.regs.get(Reg::A), "{x} + {y}");
+                assert_eq!(x + y >= 100, cpu.regs.flag_c().is_set(), "{x} + {y}");
+
+                cpu.regs.set_pair(RegPair::AF, 0x0000);
+                cpu.regs.set(Reg::A, bcd(x));
+                cpu.sub(bcd(y));
+                cpu.daa();
+                assert_eq!(bcd((100 + x - y) % 100), cpu.regs.get(Reg::A), "{x} - {y}");
+                assert_eq!(x < y, cpu.regs.flag_c().is_set(), "{x} - {y}");
+            }
+        }
+    }
 }
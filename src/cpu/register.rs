@@ -189,8 +189,9 @@ impl<'regs> Flags<'regs> {
     }
 }
 
+/// 8-bit registers
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(super) enum Reg {
+pub enum Reg {
     A,
     B,
     C,
@@ -200,8 +201,9 @@ pub(super) enum Reg {
     L,
 }
 
+/// 16-bit register pairs
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(super) enum RegPair {
+pub enum RegPair {
     AF,
     BC,
     DE,
@@ -0,0 +1,135 @@
+use std::fmt;
+
+/// A snapshot of the CPU registers, e.g. to check them in tests or show them in a debugger.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    /// Flags: Z, N, H and C in the upper 4 bits. The lower 4 bits are always 0.
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    /// IME - Interrupt Master Enable Flag
+    pub ime: bool,
+    pub halted: bool,
+}
+
+impl CpuState {
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f])
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+
+    pub fn flag_z(&self) -> bool {
+        self.f & 0x80 != 0
+    }
+
+    pub fn flag_n(&self) -> bool {
+        self.f & 0x40 != 0
+    }
+
+    pub fn flag_h(&self) -> bool {
+        self.f & 0x20 != 0
+    }
+
+    pub fn flag_c(&self) -> bool {
+        self.f & 0x10 != 0
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        write!(
+            f,
+            "AF=${:04X} BC=${:04X} DE=${:04X} HL=${:04X} SP=${:04X} PC=${:04X} {}{}{}{} IME={}{}",
+            self.af(),
+            self.bc(),
+            self.de(),
+            self.hl(),
+            self.sp,
+            self.pc,
+            flag(self.flag_z(), 'Z'),
+            flag(self.flag_n(), 'N'),
+            flag(self.flag_h(), 'H'),
+            flag(self.flag_c(), 'C'),
+            self.ime as u8,
+            if self.halted { " HALTED" } else { "" },
+        )
+    }
+}
+
+/// How a subroutine of the call stack was entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+/// An entry of the call stack, as tracked by CALL/RST/interrupts and RET/RETI.
+///
+/// This is only a shadow of the real stack, which code can manipulate directly: a subroutine that
+/// pops its return address and jumps somewhere else won't be removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// Address of the subroutine
+    pub target: u16,
+    /// Address pushed on the stack
+    pub return_addr: u16,
+}
+
+impl CallFrame {
+    /// Address of the instruction that called the subroutine, or that was interrupted.
+    pub fn call_site(&self) -> u16 {
+        match self.kind {
+            CallKind::Call => self.return_addr.wrapping_sub(3),
+            CallKind::Rst => self.return_addr.wrapping_sub(1),
+            CallKind::Interrupt => self.return_addr,
+        }
+    }
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            CallKind::Call => write!(
+                f,
+                "${:04X} called from ${:04X}",
+                self.target,
+                self.call_site()
+            ),
+            CallKind::Rst => write!(
+                f,
+                "${:04X} (RST) from ${:04X}",
+                self.target,
+                self.call_site()
+            ),
+            CallKind::Interrupt => {
+                write!(
+                    f,
+                    "${:04X} (interrupt) at ${:04X}",
+                    self.target,
+                    self.call_site()
+                )
+            }
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, path::PathBuf};
 
 use ansi_term::Colour;
 use anyhow::Result;
+use gb_rs::{breakpoint::Breakpoint, cheats::Cheat, InterruptFlag};
+
+use crate::emulator::SPEEDS;
 use rustyline::{
     completion::{Completer, Pair},
     error::ReadlineError,
@@ -29,53 +32,12 @@ impl Debugger {
         match readline {
             Ok(line) => {
                 self.editor.add_history_entry(line.as_str());
-                match line.as_str() {
-                    s if s.starts_with("next") => {
-                        let num = s
-                            .split_whitespace()
-                            .nth(1)
-                            .and_then(|n| u16::from_str_radix(n, 16).ok())
-                            .unwrap_or(1);
-                        Command::Next(num)
-                    }
-                    "continue" => Command::Continue,
-                    "cpu" => Command::DumpCpu,
-                    "oam" => Command::DumpOam,
-                    "palettes" => Command::DumpPalettes,
-                    s if s.starts_with("mem") => {
-                        if let Some(addr_str) = s.split_whitespace().nth(1) {
-                            if let Ok(addr) = u16::from_str_radix(addr_str, 16) {
-                                return Command::DumpMem(addr);
-                            }
-                        }
-                        Command::Nop
-                    }
-                    s if s.starts_with("dis") => {
-                        if let Some(addr_str) = s.split_whitespace().nth(1) {
-                            if let Ok(addr) = u16::from_str_radix(addr_str, 16) {
-                                return Command::Disassemble(addr);
-                            }
-                        }
-                        Command::Nop
-                    }
-                    s if s.starts_with("br") => {
-                        if let Some(addr_str) = s.split_whitespace().nth(1) {
-                            if let Ok(addr) = u16::from_str_radix(addr_str, 16) {
-                                return Command::Break(addr);
-                            }
-                        }
-                        Command::Nop
-                    }
-                    s if s.starts_with("sprite ") => {
-                        if let Some(id_str) = s.split_whitespace().nth(1) {
-                            if let Ok(id) = id_str.parse::<u8>() {
-                                return Command::Sprite(id);
-                            }
-                        }
+                match parse(&line) {
+                    Input::Command(command) => command,
+                    Input::Message(message) => {
+                        println!("{}", message);
                         Command::Nop
                     }
-                    "quit" => Command::Quit,
-                    _ => Command::Nop,
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -94,17 +56,489 @@ impl Debugger {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Description of a debugger command, used for parsing, completion, hints and `help`
+struct CommandInfo {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    /// Syntax of the arguments
+    args: &'static str,
+    help: &'static str,
+}
+
+impl CommandInfo {
+    fn matches(&self, word: &str) -> bool {
+        self.name == word || self.aliases.contains(&word)
+    }
+
+    fn usage(&self) -> String {
+        format!("{} {}", self.name, self.args)
+            .trim_end()
+            .to_string()
+    }
+}
+
+const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "next",
+        aliases: &["n"],
+        args: "[<hex count>]",
+        help: "Execute the next instruction, or the given number of instructions",
+    },
+    CommandInfo {
+        name: "step-over",
+        aliases: &[],
+        args: "",
+        help: "Execute the next instruction, running through CALLs and RSTs",
+    },
+    CommandInfo {
+        name: "finish",
+        aliases: &[],
+        args: "",
+        help: "Run until the current subroutine returns",
+    },
+    CommandInfo {
+        name: "until",
+        aliases: &[],
+        args: "<hex address>",
+        help: "Run until the given address is reached",
+    },
+    CommandInfo {
+        name: "continue",
+        aliases: &["c"],
+        args: "",
+        help: "Resume the execution",
+    },
+    CommandInfo {
+        name: "cycles",
+        aliases: &[],
+        args: "",
+        help: "Show the number of clock cycles since power on, and since the last `cycles`",
+    },
+    CommandInfo {
+        name: "backtrace",
+        aliases: &["bt"],
+        args: "",
+        help: "Show the subroutines being executed, innermost first",
+    },
+    CommandInfo {
+        name: "profile",
+        aliases: &[],
+        args: "on | off | [<hex count>]",
+        help: "Start or stop counting the executed instructions, or show the 16 (or the given \
+               number of) instructions the most cycles were spent on",
+    },
+    CommandInfo {
+        name: "mem",
+        aliases: &[],
+        args: "<hex address> [<hex length>] | vram | wram | hram [<file>]",
+        help: "Show 64 bytes of memory, or the given length or region, or save them to a file",
+    },
+    CommandInfo {
+        name: "dis",
+        aliases: &["d", "disassemble"],
+        args: "<hex address>",
+        help: "Disassemble the code at the given address",
+    },
+    CommandInfo {
+        name: "cpu",
+        aliases: &[],
+        args: "",
+        help: "Show the CPU registers",
+    },
+    CommandInfo {
+        name: "reg",
+        aliases: &[],
+        args: "[read] [<register name | hex address>]",
+        help: "Show the IO registers, or only the given one",
+    },
+    CommandInfo {
+        name: "oam",
+        aliases: &[],
+        args: "",
+        help: "Show the sprite attribute table",
+    },
+    CommandInfo {
+        name: "sprite",
+        aliases: &[],
+        args: "<sprite number>",
+        help: "Show the attributes of the given sprite",
+    },
+    CommandInfo {
+        name: "palettes",
+        aliases: &[],
+        args: "",
+        help: "Show the palettes",
+    },
+    CommandInfo {
+        name: "vram",
+        aliases: &[],
+        args: "",
+        help: "Save images of the tiles, tilemaps and palettes",
+    },
+    CommandInfo {
+        name: "banks",
+        aliases: &[],
+        args: "",
+        help: "Show the state of the cartridge's mapper",
+    },
+    CommandInfo {
+        name: "br",
+        aliases: &["b", "break"],
+        args: "[<hex bank>:]<hex address> [if <condition>]",
+        help: "Set a breakpoint at the given address, optionally only in the given ROM bank or \
+               when a condition on the registers holds (e.g. `a==3e && zf==1`)",
+    },
+    CommandInfo {
+        name: "watch",
+        aliases: &[],
+        args: "<hex address>[-<hex address>] [<hex value>]",
+        help: "Break when the given address or range of addresses is written to, optionally only \
+               with the given value",
+    },
+    CommandInfo {
+        name: "rwatch",
+        aliases: &[],
+        args: "<hex address>[-<hex address>] [<hex value>]",
+        help: "Break when the given address or range of addresses is read from, optionally only \
+               when the given value is read",
+    },
+    CommandInfo {
+        name: "break-int",
+        aliases: &[],
+        args: "vblank | stat | timer | serial | joypad",
+        help: "Break when the CPU jumps to the handler of the given interrupt",
+    },
+    CommandInfo {
+        name: "cheat",
+        aliases: &[],
+        args: "[<code> | clear]",
+        help: "Activate a Game Genie or GameShark code, or list (or clear) the active ones",
+    },
+    CommandInfo {
+        name: "sym",
+        aliases: &[],
+        args: "<file.sym>",
+        help: "Load the labels of an RGBDS symbol file, to show addresses as `label+offset`",
+    },
+    CommandInfo {
+        name: "hash",
+        aliases: &[],
+        args: "",
+        help: "Show the hash of the screen, to compare it with a golden image's",
+    },
+    CommandInfo {
+        name: "speed",
+        aliases: &[],
+        args: "25 | 50 | 100 | 200 | 400",
+        help: "Set the emulation speed, in percent of the real hardware's",
+    },
+    CommandInfo {
+        name: "screenshot",
+        aliases: &[],
+        args: "[<scale>]",
+        help: "Save the screen to a PNG, scaled by the given factor (1 for the native 160x144)",
+    },
+    CommandInfo {
+        name: "record",
+        aliases: &[],
+        args: "[<file.png>]",
+        help: "Start recording the screen to an animated PNG, or stop the current recording",
+    },
+    CommandInfo {
+        name: "savestate",
+        aliases: &[],
+        args: "<file>",
+        help: "Save the state of the whole machine to the given file",
+    },
+    CommandInfo {
+        name: "loadstate",
+        aliases: &[],
+        args: "<file>",
+        help: "Restore a state saved with `savestate` (or `--load-state`) for the same game",
+    },
+    CommandInfo {
+        name: "help",
+        aliases: &["h", "?"],
+        args: "[<command>]",
+        help: "Show the list of commands, or the help of the given command",
+    },
+    CommandInfo {
+        name: "quit",
+        aliases: &[],
+        args: "",
+        help: "Exit the emulator",
+    },
+];
+
+/// What to do with a line typed in the debugger
+#[derive(Debug, PartialEq, Eq)]
+enum Input {
+    Command(Command),
+    /// Show a message (e.g. help or an error) instead of running a command
+    Message(String),
+}
+
+/// Parse a line typed in the debugger.
+fn parse(line: &str) -> Input {
+    let mut words = line.split_whitespace();
+    let Some(word) = words.next() else {
+        return Input::Command(Command::Nop);
+    };
+    let Some(info) = COMMANDS.iter().find(|c| c.matches(word)) else {
+        return Input::Message(unknown_command(word));
+    };
+    let args = words.collect::<Vec<_>>();
+    let addr = || args.first().and_then(|a| u16::from_str_radix(a, 16).ok());
+    let range = || args.first().and_then(|a| parse_range(a));
+    // optional value of `watch` and `rwatch`
+    let watch_value = || match args.get(1) {
+        None => Some(None),
+        Some(v) => u8::from_str_radix(v, 16).ok().map(Some),
+    };
+
+    let command = match info.name {
+        "next" => match args.first() {
+            None => Some(Command::Next(1)),
+            Some(n) => u16::from_str_radix(n, 16).ok().map(Command::Next),
+        },
+        "step-over" => Some(Command::StepOver),
+        "finish" => Some(Command::Finish),
+        "until" => addr().map(Command::Until),
+        "continue" => Some(Command::Continue),
+        "cycles" => Some(Command::Cycles),
+        "backtrace" => Some(Command::Backtrace),
+        "profile" => match args.first() {
+            Some(&"on") => Some(Command::Profile(true)),
+            Some(&"off") => Some(Command::Profile(false)),
+            None => Some(Command::Hotspots(DEFAULT_HOTSPOTS)),
+            Some(n) => usize::from_str_radix(n, 16).ok().map(Command::Hotspots),
+        },
+        "mem" => parse_mem(&args),
+        "dis" => addr().map(Command::Disassemble),
+        "cpu" => Some(Command::DumpCpu),
+        "reg" => {
+            // `reg`, `reg LCDC` or `reg read ff40`
+            match args.iter().find(|a| **a != "read") {
+                None => Some(Command::DumpIoRegs(None)),
+                Some(name) => match parse_register(name) {
+                    Some(addr) => Some(Command::DumpIoRegs(Some(addr))),
+                    None => return Input::Message(format!("Unknown register {}", name)),
+                },
+            }
+        }
+        "oam" => Some(Command::DumpOam),
+        "sprite" => args
+            .first()
+            .and_then(|id| id.parse::<u8>().ok())
+            .map(Command::Sprite),
+        "palettes" => Some(Command::DumpPalettes),
+        "vram" => Some(Command::DumpVram),
+        "banks" => Some(Command::DumpBanks),
+        "br" => match args.join(" ").parse::<Breakpoint>() {
+            Ok(breakpoint) => Some(Command::Break(breakpoint)),
+            Err(e) if args.contains(&"if") => return Input::Message(format!("{:#}", e)),
+            Err(_) => None,
+        },
+        "watch" => range()
+            .zip(watch_value())
+            .map(|((start, end), value)| Command::Watch(start, end, value)),
+        "rwatch" => range()
+            .zip(watch_value())
+            .map(|((start, end), value)| Command::ReadWatch(start, end, value)),
+        "break-int" => args
+            .first()
+            .and_then(|name| InterruptFlag::from_name(name))
+            .map(Command::BreakInterrupt),
+        "cheat" => match args.first() {
+            None => Some(Command::ListCheats),
+            Some(&"clear") => Some(Command::ClearCheats),
+            Some(code) => match code.parse() {
+                Ok(cheat) => Some(Command::Cheat(cheat)),
+                Err(e) => return Input::Message(e.to_string()),
+            },
+        },
+        "sym" => args.first().map(|f| Command::LoadSymbols(PathBuf::from(f))),
+        "hash" => Some(Command::FrameHash),
+        "speed" => args
+            .first()
+            .and_then(|speed| speed.trim_end_matches('%').parse::<u32>().ok())
+            .filter(|speed| SPEEDS.contains(speed))
+            .map(Command::Speed),
+        "screenshot" => match args.first() {
+            None => Some(Command::Screenshot(None)),
+            Some(scale) => scale
+                .parse::<u32>()
+                .ok()
+                .filter(|s| *s > 0)
+                .map(|s| Command::Screenshot(Some(s))),
+        },
+        "record" => Some(Command::Record(args.first().map(PathBuf::from))),
+        "savestate" => args.first().map(|f| Command::SaveState(PathBuf::from(f))),
+        "loadstate" => args.first().map(|f| Command::LoadState(PathBuf::from(f))),
+        "help" => return Input::Message(help(args.first().copied())),
+        "quit" => Some(Command::Quit),
+        _ => unreachable!("unhandled command {}", info.name),
+    };
+
+    match command {
+        Some(command) => Input::Command(command),
+        None => Input::Message(format!("Usage: {}", info.usage())),
+    }
+}
+
+/// Error message for an unknown command, suggesting the commands it's a prefix of.
+fn unknown_command(word: &str) -> String {
+    let suggestions = COMMANDS
+        .iter()
+        .filter(|c| c.name.starts_with(word))
+        .map(|c| c.name)
+        .collect::<Vec<_>>();
+    if suggestions.is_empty() {
+        format!(
+            "Unknown command `{}` (type `help` for the list of commands)",
+            word
+        )
+    } else {
+        format!(
+            "Unknown command `{}`. Did you mean: {}?",
+            word,
+            suggestions.join(", ")
+        )
+    }
+}
+
+/// Help about the given command, or about all the commands.
+fn help(command: Option<&str>) -> String {
+    match command {
+        Some(word) => match COMMANDS.iter().find(|c| c.matches(word)) {
+            Some(info) => {
+                let mut help = format!("{}\n    {}", info.usage(), info.help);
+                if !info.aliases.is_empty() {
+                    help.push_str(&format!("\n    Aliases: {}", info.aliases.join(", ")));
+                }
+                help
+            }
+            None => unknown_command(word),
+        },
+        None => {
+            let width = COMMANDS.iter().map(|c| c.usage().len()).max().unwrap_or(0);
+            COMMANDS
+                .iter()
+                .map(|c| format!("{:<width$}  {}", c.usage(), c.help, width = width))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+/// Parse the arguments of `mem`: `c000`, `c000 200`, `c000 200 dump.bin`, `wram` or
+/// `wram dump.bin`
+fn parse_mem(args: &[&str]) -> Option<Command> {
+    let first = *args.first()?;
+    let (addr, len, rest) = match MEMORY_REGIONS.iter().find(|r| r.0 == first) {
+        Some(&(_, addr, len)) => (addr, len, &args[1..]),
+        None => {
+            let addr = u16::from_str_radix(first, 16).ok()?;
+            match args.get(1) {
+                Some(len) => (addr, usize::from_str_radix(len, 16).ok()?, &args[2..]),
+                None => (addr, DEFAULT_MEM_LENGTH, &args[1..]),
+            }
+        }
+    };
+    let file = match rest {
+        [] => None,
+        [file] => Some(PathBuf::from(file)),
+        _ => return None,
+    };
+    Some(Command::DumpMem { addr, len, file })
+}
+
+/// Number of hotspots shown by `profile` when no count is given
+const DEFAULT_HOTSPOTS: usize = 0x10;
+
+/// Number of bytes shown by `mem` when no length is given
+const DEFAULT_MEM_LENGTH: usize = 0x40;
+
+/// Regions of memory that `mem` knows by name, with their start address and length
+const MEMORY_REGIONS: [(&str, u16, usize); 3] = [
+    ("vram", 0x8000, 0x2000),
+    ("wram", 0xC000, 0x2000),
+    ("hram", 0xFF80, 0x7F),
+];
+
+/// Parse an hex address (`c000`) or range of addresses (`c000-c0ff`)
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let start = u16::from_str_radix(start, 16).ok()?;
+    let end = u16::from_str_radix(end, 16).ok()?;
+    (start <= end).then_some((start, end))
+}
+
+/// Parse a register name (`LCDC`) or an hex address (`ff40`)
+fn parse_register(s: &str) -> Option<u16> {
+    gb_rs::io_regs::address_of(s).or_else(|| u16::from_str_radix(s, 16).ok())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     Next(u16),
+    /// Execute the next instruction, running through CALLs and RSTs
+    StepOver,
+    /// Run until the current subroutine returns
+    Finish,
+    /// Run until the given address is reached
+    Until(u16),
     Continue,
-    DumpMem(u16),
+    /// Show the number of clock cycles since power on
+    Cycles,
+    /// Show the call stack
+    Backtrace,
+    /// Start or stop profiling the game's code
+    Profile(bool),
+    /// Show the given number of hotspots of the code profile
+    Hotspots(usize),
+    /// Show `len` bytes of memory starting at `addr`, or save them to `file`
+    DumpMem {
+        addr: u16,
+        len: usize,
+        file: Option<PathBuf>,
+    },
     Disassemble(u16),
     DumpCpu,
     DumpOam,
     Sprite(u8),
     DumpPalettes,
-    Break(u16),
+    /// Save images of the tiles, tilemaps and palettes
+    DumpVram,
+    /// Show the state of the cartridge's mapper
+    DumpBanks,
+    /// Show the IO registers, or only the given one
+    DumpIoRegs(Option<u16>),
+    Break(Breakpoint),
+    /// Break when the given range of addresses is written to (with the given value, if any)
+    Watch(u16, u16, Option<u8>),
+    /// Break when the given range of addresses is read from (with the given value, if any)
+    ReadWatch(u16, u16, Option<u8>),
+    /// Break when the given interrupt is serviced
+    BreakInterrupt(InterruptFlag),
+    /// Activate a cheat code
+    Cheat(Cheat),
+    ListCheats,
+    ClearCheats,
+    /// Load a symbol file
+    LoadSymbols(PathBuf),
+    /// Show the hash of the screen
+    FrameHash,
+    /// Run at the given speed, in percent
+    Speed(u32),
+    /// Save the screen to a PNG, at the given scale or the default one
+    Screenshot(Option<u32>),
+    /// Start recording the screen to the given file, or stop the current recording
+    Record(Option<PathBuf>),
+    /// Save the state of the machine to the given file
+    SaveState(PathBuf),
+    /// Restore the state of the machine from the given file
+    LoadState(PathBuf),
     Quit,
     Nop,
 }
@@ -143,13 +577,12 @@ impl Hinter for DebuggerHelper {
     type Hint = String;
 
     fn hint(&self, line: &str, _pos: usize, _ctx: &rustyline::Context<'_>) -> Option<Self::Hint> {
-        if line == "br " {
-            Some("<hex address>".to_string())
-        } else if line == "sprite " {
-            Some("<sprite number>".to_string())
-        } else {
-            None
-        }
+        // Show the syntax of the arguments after the command name
+        let word = line.strip_suffix(' ')?;
+        COMMANDS
+            .iter()
+            .find(|c| c.matches(word) && !c.args.is_empty())
+            .map(|c| c.args.to_string())
     }
 }
 
@@ -192,9 +625,167 @@ impl Validator for DebuggerHelper {}
 impl Default for DebuggerHelper {
     fn default() -> DebuggerHelper {
         DebuggerHelper {
-            commands: vec![
-                "mem", "cpu", "oam", "sprite", "palettes", "br", "next", "continue", "quit", "dis",
-            ],
+            commands: COMMANDS.iter().map(|c| c.name).collect(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("next"), Input::Command(Command::Next(1)));
+        assert_eq!(parse("n 10"), Input::Command(Command::Next(0x10)));
+        assert_eq!(
+            parse("b 4f20"),
+            Input::Command(Command::Break(0x4f20.into()))
+        );
+        assert_eq!(
+            parse("break 4f20"),
+            Input::Command(Command::Break(0x4f20.into()))
+        );
+        assert_eq!(
+            parse("br 03:4f20"),
+            Input::Command(Command::Break(Breakpoint::in_bank(3, 0x4f20)))
+        );
+        assert_eq!(
+            parse("br 0x4123 if a==0x3e"),
+            Input::Command(Command::Break("4123 if a==3e".parse().unwrap()))
+        );
+        assert_eq!(parse("d 150"), Input::Command(Command::Disassemble(0x150)));
+        assert_eq!(parse("c"), Input::Command(Command::Continue));
+        assert_eq!(parse("bt"), Input::Command(Command::Backtrace));
+        assert_eq!(parse("hash"), Input::Command(Command::FrameHash));
+        assert_eq!(
+            parse("break-int vblank"),
+            Input::Command(Command::BreakInterrupt(InterruptFlag::VBLANK))
+        );
+        assert_eq!(
+            parse("screenshot"),
+            Input::Command(Command::Screenshot(None))
+        );
+        assert_eq!(
+            parse("screenshot 3"),
+            Input::Command(Command::Screenshot(Some(3)))
+        );
+        assert_eq!(
+            parse("screenshot 0"),
+            Input::Message("Usage: screenshot [<scale>]".to_string())
+        );
+        assert_eq!(parse("speed 200"), Input::Command(Command::Speed(200)));
+        assert_eq!(parse("speed 25%"), Input::Command(Command::Speed(25)));
+        assert_eq!(
+            parse("speed 300"),
+            Input::Message("Usage: speed 25 | 50 | 100 | 200 | 400".to_string())
+        );
+        assert_eq!(parse("profile on"), Input::Command(Command::Profile(true)));
+        assert_eq!(
+            parse("sym game.sym"),
+            Input::Command(Command::LoadSymbols(PathBuf::from("game.sym")))
+        );
+        assert_eq!(parse("profile"), Input::Command(Command::Hotspots(16)));
+        assert_eq!(parse("profile 20"), Input::Command(Command::Hotspots(32)));
+        assert_eq!(
+            parse("watch c000-c0ff"),
+            Input::Command(Command::Watch(0xc000, 0xc0ff, None))
+        );
+        assert_eq!(
+            parse("rwatch ff44 90"),
+            Input::Command(Command::ReadWatch(0xff44, 0xff44, Some(0x90)))
+        );
+        assert_eq!(
+            parse("watch c000 100"),
+            Input::Message("Usage: watch <hex address>[-<hex address>] [<hex value>]".to_string())
+        );
+        assert_eq!(
+            parse("reg read ff40"),
+            Input::Command(Command::DumpIoRegs(Some(0xff40)))
+        );
+        assert_eq!(
+            parse("savestate level2.state"),
+            Input::Command(Command::SaveState(PathBuf::from("level2.state")))
+        );
+        assert_eq!(
+            parse("loadstate"),
+            Input::Message("Usage: loadstate <file>".to_string())
+        );
+        assert_eq!(
+            parse("mem c000"),
+            Input::Command(Command::DumpMem {
+                addr: 0xc000,
+                len: 0x40,
+                file: None
+            })
+        );
+        assert_eq!(
+            parse("mem c000 200 dump.bin"),
+            Input::Command(Command::DumpMem {
+                addr: 0xc000,
+                len: 0x200,
+                file: Some("dump.bin".into())
+            })
+        );
+        assert_eq!(
+            parse("mem hram"),
+            Input::Command(Command::DumpMem {
+                addr: 0xff80,
+                len: 0x7f,
+                file: None
+            })
+        );
+        assert_eq!(
+            parse("mem vram tiles.bin"),
+            Input::Command(Command::DumpMem {
+                addr: 0x8000,
+                len: 0x2000,
+                file: Some("tiles.bin".into())
+            })
+        );
+        assert_eq!(
+            parse("mem c000 200 dump.bin extra"),
+            Input::Message(
+                "Usage: mem <hex address> [<hex length>] | vram | wram | hram [<file>]".to_string()
+            )
+        );
+        assert_eq!(parse("  "), Input::Command(Command::Nop));
+        assert_eq!(parse("cheat"), Input::Command(Command::ListCheats));
+        assert_eq!(
+            parse("cheat 010238CD"),
+            Input::Command(Command::Cheat("010238CD".parse().unwrap()))
+        );
+
+        assert_eq!(
+            parse("br xyz"),
+            Input::Message("Usage: br [<hex bank>:]<hex address> [if <condition>]".to_string())
+        );
+        assert_eq!(
+            parse("br 4123 if a == 3e"),
+            Input::Command(Command::Break(
+                Breakpoint::new(0x4123).with_condition("a==3e".parse().unwrap())
+            ))
+        );
+        assert_eq!(
+            parse("br 4123 if q==3e"),
+            Input::Message("Unknown register `q`".to_string())
+        );
+        assert_eq!(
+            parse("wat"),
+            Input::Message("Unknown command `wat`. Did you mean: watch?".to_string())
+        );
+        assert!(matches!(parse("foo"), Input::Message(m) if m.contains("type `help`")));
+    }
+
+    #[test]
+    fn test_help() {
+        let Input::Message(help) = parse("help") else {
+            panic!("no help");
+        };
+        assert_eq!(help.lines().count(), COMMANDS.len());
+        assert_eq!(
+            parse("? c"),
+            Input::Message("continue\n    Resume the execution\n    Aliases: c".to_string())
+        );
+    }
+}
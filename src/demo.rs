@@ -0,0 +1,128 @@
+//! Demo (or attract) mode: when nobody has played for a while, an input movie is replayed on a
+//! loop from power on, until a key is pressed.
+//!
+//! The movie is replayed from the same state every time, so it gives the same run on every loop
+//! as long as the emulation is deterministic.
+use gb_rs::{joypad::Button, timing::FRAMES_PER_SECOND};
+
+use crate::{input::InputSource, movie::Movie};
+
+/// Time without any key pressed before the demo starts
+pub const DEMO_IDLE_SECONDS: u64 = 30;
+
+/// What the emulator must do after [`Demo::update()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DemoEvent {
+    /// Put the game aside, and play the movie from power on
+    Start,
+    /// The movie is over: play it again from power on
+    Restart,
+    /// A key was pressed: give the game back to the player, as they left it
+    Stop,
+}
+
+#[derive(Debug)]
+pub struct Demo {
+    movie: Movie,
+    /// Savestate of the Game Boy at power on, from which the movie is played
+    power_on: Vec<u8>,
+    /// Number of frames without any key pressed before the demo starts
+    idle_frames: u64,
+    /// Frame at which a key was last pressed (or the demo last stopped)
+    last_activity: u64,
+    /// Frame at which the movie started, while the demo is playing
+    started: Option<u64>,
+}
+
+impl Demo {
+    pub fn new(movie: Movie, power_on: Vec<u8>) -> Self {
+        Self {
+            movie,
+            power_on,
+            idle_frames: (DEMO_IDLE_SECONDS as f64 * FRAMES_PER_SECOND) as u64,
+            last_activity: 0,
+            started: None,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// The savestate to load when the movie starts.
+    pub fn power_on_state(&self) -> &[u8] {
+        &self.power_on
+    }
+
+    /// Called at the given frame, with whether a key is held. Returns what the emulator should
+    /// do, if anything.
+    pub fn update(&mut self, frame: u64, key_held: bool) -> Option<DemoEvent> {
+        if key_held {
+            self.last_activity = frame;
+            return self.started.take().map(|_| DemoEvent::Stop);
+        }
+        match self.started {
+            // Play the last line of the movie for a frame, so that an empty movie still loops
+            Some(start) if frame - start > self.movie.last_frame() => {
+                self.started = Some(frame);
+                Some(DemoEvent::Restart)
+            }
+            Some(_) => None,
+            None if frame - self.last_activity >= self.idle_frames => {
+                self.started = Some(frame);
+                Some(DemoEvent::Start)
+            }
+            None => None,
+        }
+    }
+}
+
+/// The buttons of the movie while the demo is playing, counting the frames from the start of the
+/// movie. Nothing is held otherwise.
+impl InputSource for Demo {
+    fn is_pressed(&self, button: Button, frame: u64) -> bool {
+        self.started
+            .filter(|start| frame >= *start)
+            .is_some_and(|start| self.movie.is_pressed(button, frame - start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demo() {
+        let movie = Movie::parse("10 start\n20 a\n100 -\n").unwrap();
+        let mut demo = Demo::new(movie, Vec::new());
+        let idle = demo.idle_frames;
+        assert_eq!(demo.update(idle - 1, false), None);
+        assert!(!demo.is_playing());
+        // A key press restarts the idle timer
+        assert_eq!(demo.update(idle - 1, true), None);
+        assert_eq!(demo.update(2 * idle - 2, false), None);
+
+        let start = 2 * idle - 1;
+        assert_eq!(demo.update(start, false), Some(DemoEvent::Start));
+        assert!(demo.is_playing());
+        assert!(!demo.is_pressed(Button::Start, start + 9));
+        assert!(demo.is_pressed(Button::Start, start + 10));
+        assert!(demo.is_pressed(Button::A, start + 20));
+        assert_eq!(demo.update(start + 100, false), None);
+
+        // The movie loops once it's over
+        let start = start + 101;
+        assert_eq!(demo.update(start, false), Some(DemoEvent::Restart));
+        assert!(demo.is_pressed(Button::Start, start + 10));
+
+        // Until a key is pressed
+        assert_eq!(demo.update(start + 50, true), Some(DemoEvent::Stop));
+        assert!(!demo.is_playing());
+        assert!(!demo.is_pressed(Button::Start, start + 60));
+        assert_eq!(demo.update(start + 51, true), None);
+        assert_eq!(
+            demo.update(start + 51 + idle, false),
+            Some(DemoEvent::Start)
+        );
+    }
+}
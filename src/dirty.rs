@@ -0,0 +1,96 @@
+//! Tracking of the lines of the screen that changed between two frames.
+use std::ops::Range;
+
+use crate::SCREEN_HEIGHT;
+
+/// A set of screen lines, e.g. the lines that changed since the previous frame.
+///
+/// Frontends that upload the frame to a texture can use [`ranges()`](Self::ranges) to only upload
+/// the bands of the screen that changed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyLines([u64; 3]);
+
+impl DirtyLines {
+    /// All the lines of the screen
+    pub fn all() -> Self {
+        let mut lines = Self::default();
+        for line in 0..SCREEN_HEIGHT {
+            lines.insert(line);
+        }
+        lines
+    }
+
+    #[inline]
+    pub fn insert(&mut self, line: usize) {
+        self.0[line / 64] |= 1 << (line % 64);
+    }
+
+    #[inline]
+    pub fn contains(&self, line: usize) -> bool {
+        line < SCREEN_HEIGHT && self.0[line / 64] & (1 << (line % 64)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == [0; 3]
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = [0; 3];
+    }
+
+    /// Number of lines in the set
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|bits| bits.count_ones() as usize).sum()
+    }
+
+    /// Iterate over the lines in the set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..SCREEN_HEIGHT).filter(|&line| self.contains(line))
+    }
+
+    /// Iterate over the runs of consecutive lines in the set, i.e. the dirty rectangles spanning
+    /// the width of the screen.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let mut line = 0;
+        std::iter::from_fn(move || {
+            let start = (line..SCREEN_HEIGHT).find(|&l| self.contains(l))?;
+            let end = (start..SCREEN_HEIGHT)
+                .find(|&l| !self.contains(l))
+                .unwrap_or(SCREEN_HEIGHT);
+            line = end;
+            Some(start..end)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_lines() {
+        let mut lines = DirtyLines::default();
+        assert!(lines.is_empty());
+        assert_eq!(lines.ranges().count(), 0);
+
+        for line in [0, 1, 2, 63, 64, 100, 143] {
+            lines.insert(line);
+        }
+        assert_eq!(lines.count(), 7);
+        assert!(lines.contains(64));
+        assert!(!lines.contains(65));
+        assert!(!lines.contains(SCREEN_HEIGHT));
+        assert_eq!(
+            lines.ranges().collect::<Vec<_>>(),
+            vec![0..3, 63..65, 100..101, 143..144]
+        );
+
+        assert_eq!(DirtyLines::all().count(), SCREEN_HEIGHT);
+        assert_eq!(
+            DirtyLines::all().ranges().collect::<Vec<_>>(),
+            vec![0..SCREEN_HEIGHT]
+        );
+        lines.clear();
+        assert!(lines.is_empty());
+    }
+}
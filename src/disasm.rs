@@ -223,11 +223,14 @@ impl<'a> Disassembler<'a> {
                             _ => unreachable!(),
                         }
                     }
-                    4 => {
-                        let cc = cc(y);
-                        let nn = self.read_word()?;
-                        self.push(format!("CALL {cc},${nn:04X}"), 3);
-                    }
+                    4 => match y {
+                        0..=3 => {
+                            let cc = cc(y);
+                            let nn = self.read_word()?;
+                            self.push(format!("CALL {cc},${nn:04X}"), 3);
+                        }
+                        _ => self.push(format!("<unknown> {op}"), 1),
+                    },
                     5 => {
                         if q {
                             if p == 0 {
@@ -386,4 +389,23 @@ mod tests {
         println!("{}", output);
         panic!("");
     }
+
+    #[test]
+    fn test_unknown_opcodes() {
+        // The unused opcodes in the CALL cc,nn and PUSH rr columns
+        let ops = Disassembler::new(&[0xE4, 0xDD, 0xFC, 0xC4, 0x34, 0x12])
+            .run()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            [
+                "<unknown> 228",
+                "<unknown> 221",
+                "<unknown> 252",
+                "CALL NZ,$1234"
+            ]
+        );
+    }
 }
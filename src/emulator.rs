@@ -1,182 +1,1095 @@
 use std::{
-    collections::VecDeque,
-    fs::File,
-    io::BufWriter,
-    path::Path,
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    mem,
+    num::NonZeroU32,
+    ops::ControlFlow,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use log::{debug, info};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn};
 
 use gb_rs::{
-    cartridge::Cartridge, gameboy::GameBoy, joypad::Button, AudioSink, FrameSink, SCREEN_HEIGHT,
-    SCREEN_WIDTH,
+    breakpoint::WatchKind,
+    cartridge::Cartridge,
+    frontend::{self, lcd_off_color},
+    gameboy::{GameBoy, StepResult, MAX_STEP_CYCLES},
+    machine::MachineConfig,
+    platform::DeterministicPlatform,
+    symbols::Symbols,
+    timing::{CYCLES_PER_SECOND, FRAME_DURATION},
+    AudioSink, DirtyLines, DmgPalette, FrameSink, Stats, TileMap, DEFAULT_LOW_PASS_CUTOFF,
+    FRAME_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH,
 };
 use ringbuf::{HeapRb, Producer};
-use winit::event::VirtualKeyCode;
 use winit_input_helper::WinitInputHelper;
 
-use crate::debugger::{Command, Debugger};
+use crate::{
+    debugger::{Command, Debugger},
+    demo::{Demo, DemoEvent},
+    input::{apply_inputs, Keyboard, PLAYER1, PLAYER2},
+    movie::Movie,
+    osd::Osd,
+    recorder::Recorder,
+    scope,
+    stats::AudioStats,
+};
+
+/// Maximum speed adjustment when synchronising to the audio device (0.5%)
+const AUDIO_SYNC_MAX_ADJUSTMENT: f64 = 0.005;
+
+/// Longest time the frame clock advances by at once, so that the emulator doesn't try to catch
+/// up with e.g. the time the window was being dragged around
+const MAX_ELAPSED: Duration = Duration::from_millis(100);
 
-// 4.194304MHZ -> 4194304 cycles per seconds
-// const CPU_CYCLE_PER_SEC: u64 = 4194304;
-// 1/4194304 seconds per cycle -> 238 nanoseconds per cycle
-const CPU_CYCLE_TIME_NS: u64 = 238;
+/// Speeds the emulation can run at, in percent of the real hardware's
+pub const SPEEDS: [u32; 5] = [25, 50, 100, 200, 400];
+
+/// What the emulation speed is synchronised to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SyncMode {
+    /// Run at exactly the speed of the real hardware, according to the system clock
+    #[default]
+    Time,
+    /// Slightly speed up or slow down (by up to 0.5%) to keep the audio buffer half full, which
+    /// avoids crackling caused by the audio device's clock drifting from the system clock
+    Audio,
+}
 
 /// The object that pulls everything together and drives the emulation engine while interfacing
 /// with actual input/outputs.
+///
+/// This is the desktop side of things (the debugger, recordings, movies...): the emulation itself
+/// is driven by the same facade as the web frontend.
 pub struct Emulator {
-    gb: GameBoy,
-    start_time_ns: Instant,
-    emulated_cycles: u64,
+    core: frontend::Emulator<MostRecentFrameSink, CpalAudioSink>,
+    /// Time of the last call to `update()`
+    last_update: Instant,
+    sync_mode: SyncMode,
+    /// Print the bytes sent over the serial port to stdout
+    serial_stdout: bool,
     debugger: Debugger,
-    sink: MostRecentFrameSink,
-    audio_sink: CpalAudioSink,
+    /// If set, the demo played when nobody has pressed a key for a while
+    demo: Option<Demo>,
+    /// The game put aside while the demo is playing
+    suspended_game: Option<Vec<u8>>,
+    /// Whether any of the keys bound to a button is held
+    key_held: bool,
+    /// Clock cycle count at the last `cycles` debugger command
+    cycles_mark: u64,
+    /// If set, the buttons are driven by this movie instead of the keyboard
+    movie: Option<Movie>,
+    /// The keyboard bindings of each player. Without a link cable, both players drive the same
+    /// Game Boy.
+    keyboards: [Keyboard; 2],
+    /// Quit after this many frames
+    max_frames: Option<u64>,
+    /// The ROM inserted, if any
+    rom: Option<PathBuf>,
+    /// Configuration of the Game Boy, to create a new one when another ROM is loaded. The cheats
+    /// and breakpoints are left out: they belong to the game, so they live in the Game Boy
+    config: MachineConfig,
+    save_profile: Option<String>,
+    sample_rate: Option<NonZeroU32>,
+    profiling: bool,
+    /// Whether the emulation is paused by the user (as opposed to by the debugger)
+    paused: bool,
+    osd: Osd,
+    /// Whether to show the oscilloscope over the screen
+    show_scope: bool,
+    /// Factor by which screenshots are scaled by default
+    screenshot_scale: u32,
+    /// Emulation speed, in percent (one of `SPEEDS`)
+    speed: u32,
+    /// Cutoff frequency of the audio low-pass filter, if enabled
+    low_pass_cutoff: Option<u32>,
+    /// In deterministic mode, the emulation runs whole frames paced by this clock rather than
+    /// following the host's clock
+    frame_clock: Option<FrameClock>,
 }
 
 impl Emulator {
+    /// Create the emulator with the given ROM inserted, or without any cartridge (which, as on the
+    /// real hardware, doesn't get past the boot ROM).
+    ///
+    /// In deterministic mode, nothing depends on the host's clock or entropy, so that the same
+    /// inputs always give the same run, cycle for cycle.
     pub fn new(
-        rom: impl AsRef<Path>,
+        rom: Option<&Path>,
         producer: Producer<i16, Arc<HeapRb<i16>>>,
-        breakpoint: Option<u16>,
-        enable_soft_break: bool,
+        config: MachineConfig,
+        save_profile: Option<String>,
+        deterministic: bool,
     ) -> Result<Self> {
-        let cartridge = Cartridge::load(rom)?;
-        info!("Title is {}", cartridge.title());
-        info!("Licensee code is {}", cartridge.licensee_code());
-        info!("Cartridge type is {}", cartridge.cartridge_type());
-        info!("ROM size is ${:02x}", cartridge.get_rom_size());
-        info!("RAM size is ${:02x}", cartridge.get_ram_size());
-        info!("CGB flag: {}", cartridge.cgb_flag());
-        info!("SGB flag: {}", cartridge.sgb_flag());
-        let gb = GameBoy::new(cartridge, breakpoint, enable_soft_break);
+        let cartridge = match rom {
+            Some(rom) => load_cartridge(rom, save_profile.as_deref())?,
+            None => Cartridge::from_bytes(Vec::new()),
+        };
+        let sink = MostRecentFrameSink {
+            lcd_off_color: lcd_off_color(config.palette),
+            ..Default::default()
+        };
+        let mut gb = new_gameboy(cartridge, config.clone(), deterministic)?;
+        if let Some(rom) = rom {
+            load_rom_symbols(&mut gb, rom);
+        }
+        // The cheats and breakpoints given on the command line are for the first game only
+        let config = MachineConfig {
+            cheats: Vec::new(),
+            breakpoints: Vec::new(),
+            ..config
+        };
 
         Ok(Self {
-            gb,
-            start_time_ns: Instant::now(),
-            emulated_cycles: 0,
+            core: frontend::Emulator::new(gb, sink, CpalAudioSink::new(producer)),
+            last_update: Instant::now(),
+            sync_mode: SyncMode::default(),
+            serial_stdout: false,
             debugger: Debugger::new()?,
-            sink: MostRecentFrameSink::default(),
-            audio_sink: CpalAudioSink::new(producer),
+            demo: None,
+            suspended_game: None,
+            key_held: false,
+            cycles_mark: 0,
+            movie: None,
+            keyboards: [Keyboard::new(PLAYER1), Keyboard::new(PLAYER2)],
+            max_frames: None,
+            rom: rom.map(Path::to_path_buf),
+            config,
+            save_profile,
+            sample_rate: None,
+            profiling: false,
+            paused: false,
+            osd: Osd::default(),
+            show_scope: false,
+            screenshot_scale: 1,
+            speed: 100,
+            low_pass_cutoff: Some(DEFAULT_LOW_PASS_CUTOFF),
+            frame_clock: deterministic.then(FrameClock::default),
         })
     }
 
+    /// Power off the Game Boy (saving the battery RAM of the current cartridge), insert the
+    /// given ROM and power it back on.
+    ///
+    /// Nothing changes if the ROM can't be loaded.
+    pub fn load_rom(&mut self, rom: &Path) -> Result<()> {
+        self.power_cycle(Some(rom))?;
+        // The demo was recorded with the previous game
+        self.demo = None;
+        self.rom = Some(rom.to_path_buf());
+        info!("Loaded {}", rom.display());
+        if let Some(name) = rom.file_stem() {
+            self.osd.show(format!("Loaded {}", name.to_string_lossy()));
+        }
+        Ok(())
+    }
+
+    /// Power the Game Boy off and back on, with the same cartridge.
+    pub fn reset(&mut self) -> Result<()> {
+        let rom = self.rom.clone();
+        self.power_cycle(rom.as_deref())?;
+        info!("Reset");
+        self.osd.show("Reset");
+        Ok(())
+    }
+
+    /// Replace the Game Boy with a new one, with the given ROM inserted (or none). The window
+    /// and the audio stream are kept as they are.
+    ///
+    /// The cheats and breakpoints are kept if it's the same ROM, and dropped otherwise.
+    fn power_cycle(&mut self, rom: Option<&Path>) -> Result<()> {
+        // Don't let the demo overwrite the player's save
+        if let Err(e) = self.resume_game() {
+            warn!("Failed to restore the game put aside by the demo: {:#}", e);
+        }
+        // Save first, so that the new cartridge loads the latest save if it's the same game
+        self.core.gb().save();
+        let cartridge = match rom {
+            Some(rom) => load_cartridge(rom, self.save_profile.as_deref())?,
+            None => Cartridge::from_bytes(Vec::new()),
+        };
+        let mut gb = new_gameboy(cartridge, self.config.clone(), self.frame_clock.is_some())?;
+        if let Some(rom) = rom {
+            load_rom_symbols(&mut gb, rom);
+        }
+        if rom == self.rom.as_deref() {
+            move_game_settings(self.core.gb_mut(), &mut gb);
+        }
+        if let Some(sample_rate) = self.apu_sample_rate() {
+            gb.set_sample_rate(sample_rate);
+        }
+        gb.set_low_pass_cutoff(self.low_pass_cutoff);
+        gb.set_profiling(self.profiling);
+
+        self.core.replace_gb(gb);
+        Ok(())
+    }
+
+    /// Pause the emulation, or resume it. The sound is muted while paused.
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        if !self.paused {
+            // Don't try to catch up with the time spent paused
+            self.last_update = Instant::now();
+            self.core.resync();
+        }
+        let message = if self.paused { "Paused" } else { "Resumed" };
+        info!("{}", message);
+        self.osd.show(message);
+    }
+
+    /// The title and type of the cartridge inserted, e.g. `POKEMON RED [MBC3+RAM+BATTERY]`, or
+    /// `None` if there's no ROM.
+    pub fn game_info(&self) -> Option<String> {
+        self.rom.as_ref()?;
+        Some(describe_cartridge(self.core.gb().cartridge()))
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: NonZeroU32) {
+        self.sample_rate = Some(sample_rate);
+        self.core.gb_mut().set_sample_rate(sample_rate);
+    }
+
+    /// Set the cutoff frequency (in Hz) of the audio low-pass filter, or disable it with `None`.
+    pub fn set_low_pass_cutoff(&mut self, cutoff: Option<u32>) {
+        self.low_pass_cutoff = cutoff;
+        self.core.gb_mut().set_low_pass_cutoff(cutoff);
+    }
+
+    /// Rate at which the APU should produce samples so that, whatever the emulation speed, the
+    /// audio device gets them at its own rate. The pitch changes with the speed, like a tape.
+    fn apu_sample_rate(&self) -> Option<NonZeroU32> {
+        let rate = self.sample_rate?.get() as u64 * 100 / self.speed as u64;
+        NonZeroU32::new(rate.max(1) as u32)
+    }
+
+    /// Run at the given speed, in percent of the real hardware's (one of [`SPEEDS`]).
+    pub fn set_speed(&mut self, speed: u32) {
+        self.speed = speed;
+        if let Some(sample_rate) = self.apu_sample_rate() {
+            self.core.gb_mut().set_sample_rate(sample_rate);
+        }
+        info!("Speed: {}%", speed);
+        self.osd.show(format!("Speed: {}%", speed));
+    }
+
+    /// Switch to the next faster (or slower) speed, if there's one.
+    pub fn change_speed(&mut self, faster: bool) {
+        if let Some(speed) = next_speed(self.speed, faster) {
+            self.set_speed(speed);
+        }
+    }
+
+    /// Run exactly one frame while paused, then stay paused.
+    pub fn advance_frame(&mut self) {
+        if !self.paused || self.core.gb().is_paused() {
+            return;
+        }
+        self.core.run_frame();
+        if let Some(movie) = &self.movie {
+            let frames = self.core.frame_count();
+            apply_inputs(&[movie], self.core.gb_mut(), frames);
+        }
+        self.osd.show(format!("Frame {}", self.core.frame_count()));
+    }
+
+    /// Set the colours used to display the screen.
+    pub fn set_palette(&mut self, palette: DmgPalette) {
+        self.config.palette = palette;
+        self.core.gb_mut().set_dmg_palette(palette);
+        self.core.frame_sink_mut().lcd_off_color = lcd_off_color(palette);
+    }
+
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    pub fn set_serial_stdout(&mut self, serial_stdout: bool) {
+        self.serial_stdout = serial_stdout;
+    }
+
+    /// Set the audio volume, in percent.
+    pub fn set_volume(&mut self, volume: u8) {
+        self.core.audio_sink_mut().master_volume = volume.min(100) as f32 / 100.0;
+    }
+
+    /// Counters of audio buffer problems, to be shared with the audio thread.
+    pub fn audio_stats(&self) -> Arc<AudioStats> {
+        Arc::clone(&self.core.audio_sink().stats)
+    }
+
+    /// Replay the button presses of the given movie, ignoring the keyboard.
+    pub fn set_movie(&mut self, movie: Movie) {
+        self.movie = Some(movie);
+        self.apply_movie();
+    }
+
+    /// Quit after the given number of frames have been produced.
+    pub fn set_max_frames(&mut self, frames: u64) {
+        self.max_frames = Some(frames);
+    }
+
+    fn apply_movie(&mut self) {
+        if let Some(movie) = &self.movie {
+            let frame = self.core.frame_count();
+            apply_inputs(&[movie], self.core.gb_mut(), frame);
+        }
+    }
+
+    /// Play the given input movie on a loop after a while without any key pressed, from the
+    /// current state of the Game Boy. This must be called before the emulation starts, so that
+    /// the demo starts from power on.
+    pub fn set_demo(&mut self, movie: Movie) {
+        self.demo = Some(Demo::new(movie, self.core.gb().save_state()));
+    }
+
+    /// Start the demo after a while without any key pressed, play it again once it's over, and
+    /// stop it when a key is pressed, giving the game back to the player as they left it.
+    fn update_demo(&mut self) {
+        let frame = self.core.frame_count();
+        let Some(demo) = &mut self.demo else {
+            return;
+        };
+        let result = match demo.update(frame, self.key_held) {
+            Some(DemoEvent::Start) => {
+                info!("Starting the demo");
+                self.osd.show("Demo");
+                self.suspended_game = Some(self.core.gb().save_state());
+                self.core.gb_mut().load_state(demo.power_on_state())
+            }
+            Some(DemoEvent::Restart) => self.core.gb_mut().load_state(demo.power_on_state()),
+            Some(DemoEvent::Stop) => {
+                info!("Stopping the demo");
+                self.osd.show("Demo stopped");
+                self.resume_game()
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to switch between the game and the demo: {:#}", e);
+        }
+        if let Some(demo) = self.demo.as_ref().filter(|demo| demo.is_playing()) {
+            apply_inputs(&[demo], self.core.gb_mut(), frame);
+        }
+    }
+
+    /// Restore the game put aside by the demo, if any.
+    fn resume_game(&mut self) -> gb_rs::Result<()> {
+        match self.suspended_game.take() {
+            Some(state) => self.core.gb_mut().load_state(&state),
+            None => Ok(()),
+        }
+    }
+
     pub fn start_debugger(&mut self) {
-        self.gb.pause();
+        self.core.gb_mut().pause();
+    }
+
+    /// Measure the time spent in the different components of the emulator.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
+        self.core.gb_mut().set_profiling(enabled);
+    }
+
+    /// Return the performance counters accumulated since the last call, and reset them.
+    pub fn take_stats(&mut self) -> Stats {
+        let stats = self.core.gb().stats().clone();
+        self.core.gb_mut().reset_stats();
+        stats
+    }
+
+    /// Number of frames produced by the emulator so far.
+    pub fn frame_count(&self) -> u64 {
+        self.core.frame_count()
+    }
+
+    /// Show a message on the screen for a couple of seconds.
+    pub fn show_message(&mut self, text: impl Into<String>) {
+        self.osd.show(text);
+    }
+
+    /// Set the line permanently shown at the top of the screen, or hide it if `status` is empty.
+    pub fn set_status(&mut self, status: impl Into<String>) {
+        self.osd.set_status(status);
     }
 
+    /// Show or hide the oscilloscope showing the output of each sound channel.
+    pub fn toggle_scope(&mut self) {
+        self.show_scope = !self.show_scope;
+    }
+
+    /// Draw the current frame, with the oscilloscope (if shown) and the on-screen messages over
+    /// it.
     pub fn render(&mut self, buf: &mut [u8]) {
-        self.sink.draw_current_frame(buf);
+        self.core.frame_sink_mut().draw_current_frame(buf);
+        if self.show_scope {
+            scope::draw(buf, &self.core.gb().apu_snapshot());
+        }
+        self.osd.draw(buf, Instant::now());
+    }
+
+    /// Run `f` with the emulator, e.g. the handling of a window event.
+    ///
+    /// If it panics, the battery RAM is saved, and an emergency savestate and a crash report are
+    /// written before the panic goes on, so that a bug in the emulator doesn't cost the player
+    /// their progress. The report is more detailed if [`install_crash_hook()`] was called.
+    pub fn catch_crash<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                match LAST_PANIC.with(|panic| panic.borrow_mut().take()) {
+                    Some((message, backtrace)) => self.save_after_crash(&message, Some(&backtrace)),
+                    None => self.save_after_crash(panic_message(payload.as_ref()), None),
+                }
+                panic::resume_unwind(payload)
+            }
+        }
     }
 
+    /// Save the battery RAM, and write a crash report and a savestate to
+    /// `gb-rs-crash_<timestamp>.txt` and `gb-rs-crash_<timestamp>.state`.
+    fn save_after_crash(&mut self, message: &str, backtrace: Option<&Backtrace>) {
+        let report = crash_report(self.core.gb(), self.rom.as_deref(), message, backtrace);
+        // Don't let the demo overwrite the player's save
+        if let Err(e) = self.resume_game() {
+            warn!("Failed to restore the game put aside by the demo: {:#}", e);
+        }
+        self.core.gb().save();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let path = PathBuf::from(format!("gb-rs-crash_{}.txt", timestamp));
+        match std::fs::write(&path, report) {
+            Ok(()) => eprintln!(
+                "The emulator crashed. The battery RAM was saved, and a crash report written to {}",
+                path.display()
+            ),
+            Err(e) => eprintln!("Failed to write the crash report {}: {}", path.display(), e),
+        }
+        // Last, as the machine may be in too bad a shape to be saved
+        let path = path.with_extension("state");
+        match std::fs::write(&path, self.core.gb().save_state()) {
+            Ok(()) => eprintln!(
+                "The state of the machine was saved to {} (load it with --load-state)",
+                path.display()
+            ),
+            Err(e) => eprintln!("Failed to write the savestate {}: {}", path.display(), e),
+        }
+    }
+
+    /// Run the emulation (or the debugger) until now. Returns true when it's time to quit.
     pub fn update(&mut self) -> bool {
-        let target_time_ns = self.start_time_ns.elapsed();
-        let target_cycles = target_time_ns.as_nanos() as u64 / CPU_CYCLE_TIME_NS;
+        let now = Instant::now();
+        let elapsed = now - self.last_update;
+        self.last_update = now;
+
+        let paused = self.paused || self.core.gb().is_paused();
+        self.core.audio_sink().stats.set_paused(paused);
+        if self.paused {
+            return false;
+        }
 
-        if self.gb.is_paused() {
+        if self.core.gb().is_paused() {
+            if let Some((pc, hit)) = self.core.gb_mut().take_watch_hit() {
+                println!("Watchpoint: {} at PC=${:04X}", hit, pc);
+            }
             match self.debugger.debug() {
                 Command::Next(n) => {
                     for _ in 0..n {
-                        self.emulated_cycles += self.gb.step(&mut self.sink, &mut self.audio_sink);
+                        self.core.step();
                     }
-                    self.gb.dump_cpu();
+                    println!("{}", self.core.gb().dump_cpu());
+                }
+                Command::StepOver => {
+                    let result = self.core.run_with(GameBoy::step_over);
+                    report_step_result(result);
+                    println!("{}", self.core.gb().dump_cpu());
+                }
+                Command::Finish => {
+                    let result = self.core.run_with(GameBoy::finish);
+                    report_step_result(result);
+                    println!("{}", self.core.gb().dump_cpu());
+                }
+                Command::Until(addr) => {
+                    let result = self.core.run_with(|gb, frame_sink, audio_sink| {
+                        gb.run_to(addr, frame_sink, audio_sink)
+                    });
+                    report_step_result(result);
+                    println!("{}", self.core.gb().dump_cpu());
                 }
                 Command::Continue => {
-                    // Reset start time
-                    self.start_time_ns = Instant::now()
-                        - Duration::from_nanos(self.emulated_cycles * CPU_CYCLE_TIME_NS);
-                    self.gb.resume();
-                }
-                Command::DumpMem(addr) => self.gb.dump_mem(addr),
-                Command::Disassemble(addr) => self.gb.disassemble(addr),
-                Command::DumpCpu => self.gb.dump_cpu(),
-                Command::DumpOam => self.gb.dump_oam(),
-                Command::DumpPalettes => self.gb.dump_palettes(),
-                Command::Break(addr) => self.gb.set_breakpoint(addr),
-                Command::Sprite(id) => self.gb.dump_sprite(id),
+                    // Don't try to catch up with the time spent in the debugger
+                    self.last_update = Instant::now();
+                    self.core.resync();
+                    self.core.gb_mut().resume();
+                }
+                Command::Cycles => {
+                    let cycles = self.core.gb().cycles();
+                    println!(
+                        "{} cycles since power on, {} since last time",
+                        cycles,
+                        cycles.saturating_sub(self.cycles_mark)
+                    );
+                    self.cycles_mark = cycles;
+                }
+                Command::Backtrace => {
+                    let gb = self.core.gb();
+                    let pc = gb.pc();
+                    println!("#0 ${:04X}{}", pc, describe_symbol(gb.symbolize(pc)));
+                    for (i, frame) in gb.call_stack().iter().rev().enumerate() {
+                        let symbol = gb.symbolize(frame.target);
+                        println!("#{} {}{}", i + 1, frame, describe_symbol(symbol));
+                    }
+                }
+                Command::Profile(enabled) => {
+                    self.core.gb_mut().set_code_profiling(enabled);
+                    if enabled {
+                        println!("Profiling the code, use `profile` to see the hotspots");
+                    }
+                }
+                Command::Hotspots(n) => print!("{}", self.core.gb().dump_hotspots(n)),
+                Command::DumpMem { addr, len, file } => match file {
+                    Some(file) => {
+                        let bytes = self.core.gb().read_memory(addr, len);
+                        match std::fs::write(&file, &bytes) {
+                            Ok(()) => println!("Saved {} bytes to {}", bytes.len(), file.display()),
+                            Err(e) => println!("Failed to save {}: {e}", file.display()),
+                        }
+                    }
+                    None => print!("{}", self.core.gb().dump_mem(addr, len)),
+                },
+                Command::Disassemble(addr) => print!("{}", self.core.gb_mut().disassemble(addr)),
+                Command::DumpCpu => println!("{}", self.core.gb_mut().dump_cpu()),
+                Command::DumpOam => print!("{}", self.core.gb_mut().dump_oam()),
+                Command::DumpPalettes => print!("{}", self.core.gb_mut().dump_palettes()),
+                Command::DumpBanks => print!("{}", self.core.gb_mut().dump_banks()),
+                Command::DumpIoRegs(addr) => print!("{}", self.core.gb_mut().dump_io_regs(addr)),
+                Command::DumpVram => {
+                    if let Err(e) = self.dump_vram() {
+                        println!("Failed to save VRAM images: {e}");
+                    }
+                }
+                Command::Break(breakpoint) => {
+                    let gb = self.core.gb_mut();
+                    let symbol = match breakpoint.bank {
+                        Some(bank) => gb.symbols().lookup(bank, breakpoint.addr),
+                        None => gb.symbolize(breakpoint.addr),
+                    };
+                    println!("Breakpoint at {}{}", breakpoint, describe_symbol(symbol));
+                    gb.set_breakpoint(breakpoint);
+                }
+                Command::LoadSymbols(path) => match load_symbols(&path) {
+                    Ok(symbols) => {
+                        println!("Loaded {} symbols", symbols.len());
+                        self.core.gb_mut().set_symbols(symbols);
+                    }
+                    Err(e) => println!("Failed to load {}: {:#}", path.display(), e),
+                },
+                Command::Watch(start, end, value) => {
+                    self.core
+                        .gb_mut()
+                        .add_watchpoint(start..=end, WatchKind::Write, value)
+                }
+                Command::ReadWatch(start, end, value) => {
+                    self.core
+                        .gb_mut()
+                        .add_watchpoint(start..=end, WatchKind::Read, value)
+                }
+                Command::BreakInterrupt(flag) => self.core.gb_mut().set_interrupt_break(flag),
+                Command::Cheat(cheat) => self.core.gb_mut().add_cheat(cheat),
+                Command::ListCheats => {
+                    for cheat in self.core.gb_mut().cheats() {
+                        println!("{cheat}");
+                    }
+                }
+                Command::ClearCheats => self.core.gb_mut().clear_cheats(),
+                Command::FrameHash => println!("{:016x}", self.core.gb().frame_hash()),
+                Command::Speed(speed) => self.set_speed(speed),
+                Command::Screenshot(scale) => {
+                    if let Err(e) = self.screenshot(scale) {
+                        println!("Failed to save screenshot: {:#}", e);
+                    }
+                }
+                Command::Record(path) => self.toggle_recording(path),
+                Command::Sprite(id) => print!("{}", self.core.gb_mut().dump_sprite(id)),
+                Command::SaveState(path) => {
+                    if let Err(e) = self.save_state(&path) {
+                        println!("Failed to save state: {:#}", e);
+                    }
+                }
+                Command::LoadState(path) => {
+                    if let Err(e) = self.load_state(&path) {
+                        println!("Failed to load state: {:#}", e);
+                    }
+                }
                 Command::Quit => return true,
                 Command::Nop => (),
             }
         } else {
-            while self.emulated_cycles < target_cycles && !self.gb.is_paused() {
-                self.emulated_cycles += self.gb.step(&mut self.sink, &mut self.audio_sink);
+            self.update_demo();
+            let flow = match &mut self.frame_clock {
+                Some(clock) => {
+                    let frames = clock.advance(elapsed, self.speed);
+                    self.run_frames(frames)
+                }
+                None => {
+                    let sync_speed = match self.sync_mode {
+                        SyncMode::Time => 1.0,
+                        SyncMode::Audio => self
+                            .core
+                            .audio_sink()
+                            .fill_level()
+                            .map_or(1.0, audio_sync_speed),
+                    };
+                    let speed = sync_speed * self.speed as f64 / 100.0;
+                    let (movie, demo, max_frames) = (&self.movie, &self.demo, self.max_frames);
+                    self.core.run_for(elapsed, speed, |gb, frames| {
+                        start_frame(movie, demo, max_frames, gb, frames)
+                    })
+                }
+            };
+            if flow.is_break() {
+                return true;
+            }
+            if self.serial_stdout {
+                let output = self.core.gb_mut().take_serial_output();
+                if !output.is_empty() {
+                    let mut stdout = std::io::stdout();
+                    if let Err(e) = stdout.write_all(&output).and_then(|_| stdout.flush()) {
+                        warn!("Failed to write serial output: {}", e);
+                    }
+                }
             }
         }
 
         false
     }
 
+    /// Run the given number of whole frames (or until the debugger takes over), so that the
+    /// buttons held only ever change between frames.
+    fn run_frames(&mut self, frames: u32) -> ControlFlow<()> {
+        for _ in 0..frames {
+            let before = self.core.frame_count();
+            self.core.run_frame();
+            let frames = self.core.frame_count();
+            if frames != before {
+                start_frame(
+                    &self.movie,
+                    &self.demo,
+                    self.max_frames,
+                    self.core.gb_mut(),
+                    frames,
+                )?;
+            }
+            if self.core.gb().is_paused() {
+                break;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
     pub fn finish(&mut self) {
-        self.gb.save();
+        // Don't let the demo overwrite the player's save
+        if let Err(e) = self.resume_game() {
+            warn!("Failed to restore the game put aside by the demo: {:#}", e);
+        }
+        self.core.gb().save();
+        if self.core.frame_sink().recorder.is_some() {
+            self.toggle_recording(None);
+        }
+        let stats = &self.core.audio_sink().stats;
+        info!(
+            "Audio: {} underruns, {} overruns",
+            stats.underruns(),
+            stats.overruns()
+        );
     }
 
-    pub fn screenshot(&mut self) -> Result<()> {
-        let filename = format!(
-            "gb-rs-screenshot_{}.png",
-            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+    /// Set the factor by which screenshots are scaled by default (1 for the native 160x144).
+    pub fn set_screenshot_scale(&mut self, scale: u32) {
+        self.screenshot_scale = scale.max(1);
+    }
+
+    /// Save the screen as it's displayed (without the overlays) to a timestamped PNG named after
+    /// the game, scaled by the given factor or the default one.
+    pub fn screenshot(&mut self, scale: Option<u32>) -> Result<()> {
+        let scale = scale.unwrap_or(self.screenshot_scale).max(1) as usize;
+        let filename = screenshot_filename(
+            &self.core.gb().cartridge().header().title,
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
         );
-        let path = Path::new(&filename);
-        let file = File::create(path)?;
-        let mut w = BufWriter::new(file);
-
-        let mut encoder = png::Encoder::new(&mut w, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
-        encoder.set_color(png::ColorType::Rgba);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header()?;
-
-        let mut data = [0u8; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
-        self.sink.draw_current_frame(&mut data);
-        writer.write_image_data(&data)?;
+        save_png(
+            Path::new(&filename),
+            SCREEN_WIDTH * scale,
+            SCREEN_HEIGHT * scale,
+            png::ColorType::Rgba,
+            &upscale(&self.core.frame_sink().buf, SCREEN_WIDTH, scale),
+        )?;
         println!("Saved screenshot to {}", filename);
+        self.osd.show("Screenshot saved");
+        Ok(())
+    }
+
+    /// Save the state of the whole machine to the given file.
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.core.gb().save_state())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("Saved state to {}", path.display());
+        Ok(())
+    }
+
+    /// Restore the state saved in the given file, which must have been saved with the same game.
+    pub fn load_state(&mut self, path: &Path) -> Result<()> {
+        let state = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        self.core
+            .gb_mut()
+            .load_state(&state)
+            .with_context(|| format!("Failed to load {}", path.display()))
+    }
+
+    /// Stop the current recording, or start recording the screen to the given file (or a
+    /// timestamped one).
+    pub fn toggle_recording(&mut self, path: Option<PathBuf>) {
+        if let Some(recorder) = self.core.frame_sink_mut().recorder.take() {
+            let frames = recorder.frames();
+            match recorder.finish() {
+                Ok(path) => {
+                    println!("Saved {} frames to {}", frames, path.display());
+                    self.osd.show("Recording saved");
+                }
+                Err(e) => warn!("{:#}", e),
+            }
+            if path.is_none() {
+                return;
+            }
+        }
+
+        let path = path.unwrap_or_else(|| {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            PathBuf::from(format!("gb-rs-recording_{}.png", timestamp))
+        });
+        match Recorder::new(path) {
+            Ok(recorder) => {
+                println!("Recording to {}", recorder.path().display());
+                self.osd.show("Recording");
+                self.core.frame_sink_mut().recorder = Some(recorder);
+            }
+            Err(e) => warn!("Failed to start recording: {:#}", e),
+        }
+    }
+
+    /// Save the tiles, tilemaps and palettes currently in VRAM as PNG images.
+    pub fn dump_vram(&self) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let images = [
+            ("tiles", self.core.gb().render_tiles()),
+            ("bg-map", self.core.gb().render_tilemap(TileMap::Background)),
+            ("win-map", self.core.gb().render_tilemap(TileMap::Window)),
+            ("palettes", self.core.gb().render_palettes()),
+        ];
+        for (name, image) in images {
+            let filename = format!("gb-rs-vram-{}_{}.png", name, timestamp);
+            save_png(
+                Path::new(&filename),
+                image.width,
+                image.height,
+                png::ColorType::Rgb,
+                &image.to_rgb_bytes(),
+            )?;
+            println!("Saved {}", filename);
+        }
         Ok(())
     }
 
     pub fn handle_input(&mut self, input: &WinitInputHelper) {
-        self.gb
-            .set_button_pressed(Button::Start, input.key_held(VirtualKeyCode::Return));
-        self.gb
-            .set_button_pressed(Button::Select, input.key_held(VirtualKeyCode::Space));
-        self.gb
-            .set_button_pressed(Button::A, input.key_held(VirtualKeyCode::A));
-        self.gb
-            .set_button_pressed(Button::B, input.key_held(VirtualKeyCode::B));
-        self.gb
-            .set_button_pressed(Button::Up, input.key_held(VirtualKeyCode::Up));
-        self.gb
-            .set_button_pressed(Button::Down, input.key_held(VirtualKeyCode::Down));
-        self.gb
-            .set_button_pressed(Button::Left, input.key_held(VirtualKeyCode::Left));
-        self.gb
-            .set_button_pressed(Button::Right, input.key_held(VirtualKeyCode::Right));
+        for keyboard in &mut self.keyboards {
+            keyboard.update(input);
+        }
+        self.key_held = self.keyboards.iter().any(Keyboard::any_held);
+        if self.movie.is_some() || self.demo.as_ref().is_some_and(Demo::is_playing) {
+            // The keys pressed while the demo is playing stop it at the next frame
+            return;
+        }
+        let [player1, player2] = &self.keyboards;
+        let frame = self.core.frame_count();
+        apply_inputs(&[player1, player2], self.core.gb_mut(), frame);
     }
 }
 
+/// Read an RGBDS symbol file.
+fn load_symbols(path: &Path) -> Result<Symbols> {
+    let text = std::fs::read_to_string(path)?;
+    Symbols::parse(&text)
+}
+
+/// Use the symbol file next to the ROM (`game.sym` for `game.gb`), if there's one.
+fn load_rom_symbols(gb: &mut GameBoy, rom: &Path) {
+    let path = rom.with_extension("sym");
+    if !path.exists() {
+        return;
+    }
+    match load_symbols(&path) {
+        Ok(symbols) => {
+            info!("Loaded {} symbols from {}", symbols.len(), path.display());
+            gb.set_symbols(symbols);
+        }
+        Err(e) => warn!("Failed to load {}: {:#}", path.display(), e),
+    }
+}
+
+/// Tell the user when a debugger stepping command gave up.
+fn report_step_result(result: StepResult) {
+    if result == StepResult::NotReached {
+        println!(
+            "Not reached after {} seconds of emulated time",
+            MAX_STEP_CYCLES / CYCLES_PER_SECOND as u64
+        );
+    }
+}
+
+/// ` <label+offset>` to append to an address, if there's a symbol for it.
+fn describe_symbol(symbol: Option<String>) -> String {
+    symbol.map(|s| format!(" <{}>", s)).unwrap_or_default()
+}
+
+/// The speed after (or before) `current` in `SPEEDS`, if there's one.
+fn next_speed(current: u32, faster: bool) -> Option<u32> {
+    if faster {
+        SPEEDS.iter().find(|speed| **speed > current).copied()
+    } else {
+        SPEEDS.iter().rev().find(|speed| **speed < current).copied()
+    }
+}
+
+thread_local! {
+    /// The message (with its location) and the backtrace of the last panic on this thread, as
+    /// recorded by the crash hook
+    static LAST_PANIC: RefCell<Option<(String, Backtrace)>> = const { RefCell::new(None) };
+}
+
+/// Install a panic hook which, on top of what the current one does, records the location and
+/// the backtrace of the panic for the crash report written by [`Emulator::catch_crash()`].
+pub fn install_crash_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::force_capture();
+        LAST_PANIC.with(|panic| *panic.borrow_mut() = Some((info.to_string(), backtrace)));
+        previous(info);
+    }));
+}
+
+/// The message of a panic, from its payload.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// What was going on when the core panicked with the given message: the game, the state of the
+/// CPU, the call stack, the last instructions executed, and the backtrace of the panic if known.
+fn crash_report(
+    gb: &GameBoy,
+    rom: Option<&Path>,
+    message: &str,
+    backtrace: Option<&Backtrace>,
+) -> String {
+    let mut report = format!(
+        "gb-rs {} crashed: {}\n\n",
+        env!("CARGO_PKG_VERSION"),
+        message
+    );
+    if let Some(rom) = rom {
+        let _ = writeln!(report, "ROM: {}", rom.display());
+    }
+    let _ = writeln!(report, "Game: {}", describe_cartridge(gb.cartridge()));
+    let _ = writeln!(report, "Cycles: {}", gb.cycles());
+    let _ = writeln!(report, "CPU: {}", gb.dump_cpu());
+    let _ = writeln!(report, "\nCall stack:");
+    for frame in gb.call_stack().iter().rev() {
+        let _ = writeln!(
+            report,
+            "  {}{}",
+            frame,
+            describe_symbol(gb.symbolize(frame.target))
+        );
+    }
+    let _ = write!(report, "\nLast instructions:\n{}", gb.dump_history());
+    if let Some(backtrace) = backtrace {
+        let _ = write!(report, "\nBacktrace:\n{}", backtrace);
+    }
+    report
+}
+
+/// `<title> [<type>]`
+fn describe_cartridge(cartridge: &Cartridge) -> String {
+    let header = cartridge.header();
+    let title = match header.title.trim() {
+        "" => "Untitled",
+        title => title,
+    };
+    match header.cartridge_type.name() {
+        Some(kind) => format!("{} [{}]", title, kind),
+        None => title.to_string(),
+    }
+}
+
+fn load_cartridge(rom: &Path, save_profile: Option<&str>) -> Result<Cartridge> {
+    let cartridge = Cartridge::load_with_save_profile(rom, save_profile)?;
+    let header = cartridge.header();
+    info!("Title is {}", header.title);
+    info!("Licensee code is {}", header.licensee);
+    info!("Cartridge type is {}", header.cartridge_type);
+    info!("ROM size is ${:02x}", header.rom_size);
+    info!("RAM size is ${:02x}", header.ram_size);
+    info!("CGB flag: {}", header.cgb);
+    info!("SGB flag: {}", header.sgb);
+    Ok(cartridge)
+}
+
+/// Create a Game Boy, which gets its time and entropy from a fixed seed in deterministic mode
+/// instead of the host.
+fn new_gameboy(
+    cartridge: Cartridge,
+    config: MachineConfig,
+    deterministic: bool,
+) -> Result<GameBoy> {
+    let gb = if deterministic {
+        GameBoy::with_platform(cartridge, config, DeterministicPlatform::default())?
+    } else {
+        GameBoy::new(cartridge, config)?
+    };
+    Ok(gb)
+}
+
+/// Move the cheats and breakpoints (whether they come from the command line or the debugger) of
+/// a game to a new Game Boy running the same game.
+fn move_game_settings(from: &mut GameBoy, to: &mut GameBoy) {
+    *to.breakpoints_mut() = mem::take(from.breakpoints_mut());
+    for cheat in from.cheats() {
+        to.add_cheat(*cheat);
+    }
+    from.clear_cheats();
+}
+
+/// Called when a frame starts: quit after the maximum number of frames, and otherwise change
+/// the buttons exactly at the start of the frame, for reproducibility.
+fn start_frame(
+    movie: &Option<Movie>,
+    demo: &Option<Demo>,
+    max_frames: Option<u64>,
+    gb: &mut GameBoy,
+    frames: u64,
+) -> ControlFlow<()> {
+    if max_frames.is_some_and(|max| frames >= max) {
+        info!("Quitting after {} frames", frames);
+        return ControlFlow::Break(());
+    }
+    if let Some(movie) = movie {
+        apply_inputs(&[movie], gb, frames);
+    } else if let Some(demo) = demo.as_ref().filter(|demo| demo.is_playing()) {
+        apply_inputs(&[demo], gb, frames);
+    }
+    ControlFlow::Continue(())
+}
+
+/// `gb-rs-screenshot_<title>_<timestamp>.png`, keeping only the characters of the title that are
+/// safe in a filename.
+fn screenshot_filename(title: &str, timestamp: u64) -> String {
+    let title: String = title
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if title.is_empty() {
+        format!("gb-rs-screenshot_{}.png", timestamp)
+    } else {
+        format!("gb-rs-screenshot_{}_{}.png", title, timestamp)
+    }
+}
+
+/// Scale an RGBA image of the given width up by an integer factor, without any smoothing.
+fn upscale(data: &[u8], width: usize, scale: usize) -> Vec<u8> {
+    if scale == 1 {
+        return data.to_vec();
+    }
+    let mut scaled = Vec::with_capacity(data.len() * scale * scale);
+    for line in data.chunks_exact(width * 4) {
+        let start = scaled.len();
+        for pixel in line.chunks_exact(4) {
+            for _ in 0..scale {
+                scaled.extend_from_slice(pixel);
+            }
+        }
+        for _ in 1..scale {
+            scaled.extend_from_within(start..start + width * scale * 4);
+        }
+    }
+    scaled
+}
+
+fn save_png(
+    path: &Path,
+    width: usize,
+    height: usize,
+    color: png::ColorType,
+    data: &[u8],
+) -> Result<()> {
+    let file = File::create(path)?;
+    let mut w = BufWriter::new(file);
+
+    let mut encoder = png::Encoder::new(&mut w, width as u32, height as u32);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    Ok(())
+}
+
 /// Frame sink that only keeps the most recent frame
 struct MostRecentFrameSink {
-    buf: [(u8, u8, u8); SCREEN_WIDTH * SCREEN_HEIGHT],
+    /// RGBA pixels of the frame, in the same layout as the `pixels` frame buffer
+    buf: Box<[u8]>,
+    /// Lines of the next frame that differ from `buf`
+    dirty: DirtyLines,
     new_frame: bool,
+    /// Colour of the screen when the LCD is turned off
+    lcd_off_color: [u8; 4],
+    /// Receives a copy of the frames while the screen is being recorded
+    recorder: Option<Recorder>,
 }
 
 impl MostRecentFrameSink {
     pub fn new() -> Self {
         Self {
-            buf: [(0, 0, 0); SCREEN_WIDTH * SCREEN_HEIGHT],
+            buf: vec![0; FRAME_SIZE].into_boxed_slice(),
+            dirty: DirtyLines::all(),
             new_frame: true,
+            lcd_off_color: lcd_off_color(DmgPalette::default()),
+            recorder: None,
         }
     }
 
     fn draw_current_frame(&mut self, frame: &mut [u8]) {
-        self.buf
-            .iter()
-            .zip(frame.chunks_mut(4))
-            .for_each(|((r, g, b), p)| {
-                p[0] = *r;
-                p[1] = *g;
-                p[2] = *b;
-                p[3] = 255;
-            });
+        frame.copy_from_slice(&self.buf);
         self.new_frame = false;
     }
 }
@@ -188,41 +1101,220 @@ impl Default for MostRecentFrameSink {
 }
 
 impl FrameSink for MostRecentFrameSink {
-    fn push_frame(&mut self, frame: &[(u8, u8, u8)]) {
-        self.buf.copy_from_slice(frame);
+    fn push_frame(&mut self, frame: &[u8]) {
+        const LINE_SIZE: usize = SCREEN_WIDTH * 4;
+        for lines in self.dirty.ranges() {
+            let bytes = lines.start * LINE_SIZE..lines.end * LINE_SIZE;
+            self.buf[bytes.clone()].copy_from_slice(&frame[bytes]);
+        }
+        self.dirty = DirtyLines::all();
         self.new_frame = true;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.push_frame(frame);
+        }
+    }
+
+    fn dirty_lines(&mut self, lines: &DirtyLines) {
+        self.dirty = *lines;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.dirty_lines(lines);
+        }
+    }
+
+    fn lcd_power_changed(&mut self, enabled: bool) {
+        if !enabled {
+            // Display a blank screen until the LCD is turned back on
+            for pixel in self.buf.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&self.lcd_off_color);
+            }
+            self.new_frame = true;
+        }
     }
 }
 
 struct CpalAudioSink {
     buffer: Producer<i16, Arc<HeapRb<i16>>>,
-    master_volume: i16,
+    /// Factor applied to the samples produced by the APU, between 0.0 and 1.0
+    master_volume: f32,
+    stats: Arc<AudioStats>,
 }
 
 impl CpalAudioSink {
     fn new(buffer: Producer<i16, Arc<HeapRb<i16>>>) -> Self {
         Self {
             buffer,
-            master_volume: 16,
+            master_volume: 1.0,
+            stats: Arc::new(AudioStats::default()),
         }
     }
 }
 
+/// A virtual clock that counts time in whole frames, so that the emulation stops at the same
+/// points whatever the timing of the host.
+#[derive(Debug, Default)]
+struct FrameClock {
+    /// Fraction of a frame that's elapsed but wasn't emulated yet
+    pending: f64,
+}
+
+impl FrameClock {
+    /// Advance the clock by the given time (at most [`MAX_ELAPSED`]) at the given speed (in
+    /// percent), and return the number of whole frames to emulate.
+    fn advance(&mut self, elapsed: Duration, speed: u32) -> u32 {
+        let elapsed = elapsed.min(MAX_ELAPSED);
+        self.pending += elapsed.as_secs_f64() / FRAME_DURATION.as_secs_f64() * speed as f64 / 100.0;
+        let frames = self.pending.floor();
+        self.pending -= frames;
+        frames as u32
+    }
+}
+
+/// Speed factor to apply to the emulation so that the audio buffer stays half full: when it's
+/// running low we need to produce samples a bit faster, and slower when it's filling up.
+fn audio_sync_speed(fill_level: f64) -> f64 {
+    let error = (0.5 - fill_level.clamp(0.0, 1.0)) * 2.0;
+    1.0 + error * AUDIO_SYNC_MAX_ADJUSTMENT
+}
+
 impl AudioSink for CpalAudioSink {
-    fn push_sample(&mut self, sample: (i16, i16)) -> bool {
-        if self.buffer.push(sample.0 * self.master_volume).is_err()
-            || self.buffer.push(sample.1 * self.master_volume).is_err()
-        {
+    fn push_samples(&mut self, samples: &[i16]) {
+        let master_volume = self.master_volume;
+        let mut iter = samples.iter().map(|v| (*v as f32 * master_volume) as i16);
+        let n = self.buffer.push_iter(&mut iter);
+        if n < samples.len() {
+            // The audio device isn't keeping up: the rest of the samples are dropped
             debug!("Buffer overrun!");
-            return true;
+            self.stats.record_overrun();
         }
+    }
 
-        false
+    fn fill_level(&self) -> Option<f64> {
+        Some(self.buffer.len() as f64 / self.buffer.capacity() as f64)
     }
+}
 
-    fn push_samples(&mut self, samples: &mut VecDeque<i16>) {
-        let mut iter = samples.iter().map(|v| *v * self.master_volume);
-        let n = self.buffer.push_iter(&mut iter);
-        samples.drain(0..n);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_sync_speed() {
+        assert_eq!(audio_sync_speed(0.5), 1.0);
+        assert_eq!(audio_sync_speed(0.0), 1.005);
+        assert_eq!(audio_sync_speed(1.0), 0.995);
+        assert!(audio_sync_speed(0.25) > 1.0);
+        assert!(audio_sync_speed(0.75) < 1.0);
+    }
+
+    #[test]
+    fn test_screenshot_filename() {
+        assert_eq!(
+            screenshot_filename("POKEMON RED", 42),
+            "gb-rs-screenshot_POKEMON_RED_42.png"
+        );
+        assert_eq!(
+            screenshot_filename("A/B:C", 42),
+            "gb-rs-screenshot_A_B_C_42.png"
+        );
+        assert_eq!(screenshot_filename("", 42), "gb-rs-screenshot_42.png");
+    }
+
+    #[test]
+    fn test_describe_cartridge() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13F].copy_from_slice(b"POKEMON RED");
+        rom[0x147] = 0x13;
+        assert_eq!(
+            describe_cartridge(&Cartridge::from_bytes(rom.clone())),
+            "POKEMON RED [MBC3+RAM+BATTERY]"
+        );
+
+        rom[0x134..0x13F].fill(0);
+        rom[0x147] = 0x00;
+        assert_eq!(
+            describe_cartridge(&Cartridge::from_bytes(rom.clone())),
+            "Untitled [ROM ONLY]"
+        );
+
+        rom[0x147] = 0x42;
+        assert_eq!(describe_cartridge(&Cartridge::from_bytes(rom)), "Untitled");
+    }
+
+    #[test]
+    fn test_crash_report() {
+        let config = MachineConfig::default().boot_rom(gb_rs::machine::BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(vec![0; 0x8000]), config).unwrap();
+        for _ in 0..2 {
+            gb.run_frame();
+        }
+        let report = crash_report(&gb, Some(Path::new("game.gb")), "oops", None);
+        assert!(report.contains("crashed: oops\n"), "{}", report);
+        assert!(report.contains("ROM: game.gb\n"), "{}", report);
+        assert!(report.contains("Game: Untitled [ROM ONLY]\n"), "{}", report);
+        let history = report.split("Last instructions:\n").nth(1).unwrap();
+        assert_eq!(history.lines().count(), 64);
+        let backtrace = Backtrace::disabled();
+        let report = crash_report(&gb, None, "oops", Some(&backtrace));
+        assert!(
+            report.ends_with("\nBacktrace:\ndisabled backtrace"),
+            "{}",
+            report
+        );
+
+        let payload: Box<dyn Any + Send> = Box::new(format!("index {} out of range", 3));
+        assert_eq!(panic_message(payload.as_ref()), "index 3 out of range");
+        let payload: Box<dyn Any + Send> = Box::new("oops");
+        assert_eq!(panic_message(payload.as_ref()), "oops");
+    }
+
+    #[test]
+    fn test_move_game_settings() {
+        let new_gb = || {
+            let config = MachineConfig::default().boot_rom(gb_rs::machine::BootRom::Skip);
+            GameBoy::new(Cartridge::from_bytes(vec![0; 0x8000]), config).unwrap()
+        };
+        let mut old = new_gb();
+        old.set_breakpoint(0x0150);
+        old.add_cheat("010238CD".parse().unwrap());
+        let mut gb = new_gb();
+        move_game_settings(&mut old, &mut gb);
+        assert!(gb.breakpoints().exec.contains(0x0150));
+        assert_eq!(gb.cheats(), ["010238CD".parse().unwrap()]);
+        assert!(!old.breakpoints().exec.contains(0x0150));
+        assert!(old.cheats().is_empty());
+    }
+
+    #[test]
+    fn test_next_speed() {
+        assert_eq!(next_speed(100, true), Some(200));
+        assert_eq!(next_speed(100, false), Some(50));
+        assert_eq!(next_speed(400, true), None);
+        assert_eq!(next_speed(25, false), None);
+    }
+
+    #[test]
+    fn test_frame_clock() {
+        let mut clock = FrameClock::default();
+        assert_eq!(clock.advance(FRAME_DURATION / 2, 100), 0);
+        assert_eq!(clock.advance(FRAME_DURATION / 2, 100), 1);
+        assert_eq!(clock.advance(FRAME_DURATION * 3, 100), 3);
+        assert_eq!(clock.advance(FRAME_DURATION, 200), 2);
+        assert_eq!(clock.advance(FRAME_DURATION, 50), 0);
+        assert_eq!(clock.advance(FRAME_DURATION, 50), 1);
+
+        // A long pause (e.g. the window being moved) doesn't have to be caught up with
+        let mut clock = FrameClock::default();
+        assert_eq!(clock.advance(Duration::from_secs(10), 100), 5);
+    }
+
+    #[test]
+    fn test_upscale() {
+        // 2x1 image: red, blue
+        let image = [0xFF, 0, 0, 0xFF, 0, 0, 0xFF, 0xFF];
+        assert_eq!(upscale(&image, 2, 1), image);
+        let red = [0xFF, 0, 0, 0xFF];
+        let blue = [0, 0, 0xFF, 0xFF];
+        let line = [red, red, blue, blue].concat();
+        assert_eq!(upscale(&image, 2, 2), [line.clone(), line].concat());
     }
 }
@@ -0,0 +1,62 @@
+//! Errors returned by the library.
+//!
+//! Only setting up an emulator can fail (loading a cartridge, a boot ROM or a savestate): once it
+//! runs, nothing the emulated program does is an error. Invalid memory accesses behave like on the
+//! hardware (usually reading open bus, i.e. 0xFF), and the situations the emulator doesn't expect
+//! are reported as [anomalies](crate::anomaly).
+use std::{fmt, io, path::PathBuf};
+
+use crate::cartridge::CartridgeError;
+
+#[derive(Debug)]
+pub enum GbError {
+    /// The cartridge can't be run
+    Cartridge(CartridgeError),
+    /// A file (e.g. the ROM or the save file) couldn't be read or written
+    Io { path: PathBuf, source: io::Error },
+    /// A boot ROM must be exactly 256 bytes long
+    InvalidBootRom { size: usize },
+    /// Save profile names can only contain letters, digits, '-' and '_'
+    InvalidSaveProfile(String),
+    /// A savestate can't be loaded, e.g. because it was saved by another version of the emulator
+    InvalidState(String),
+}
+
+/// Result type of the library
+pub type Result<T, E = GbError> = std::result::Result<T, E>;
+
+impl fmt::Display for GbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbError::Cartridge(e) => write!(f, "{}", e),
+            GbError::Io { path, source } => {
+                write!(f, "Failed to access {}: {}", path.display(), source)
+            }
+            GbError::InvalidBootRom { size } => {
+                write!(f, "Invalid boot ROM size: expected 256 bytes, got {}", size)
+            }
+            GbError::InvalidSaveProfile(name) => write!(
+                f,
+                "Invalid save profile name '{}': only letters, digits, '-' and '_' are allowed",
+                name
+            ),
+            GbError::InvalidState(reason) => write!(f, "Invalid savestate: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for GbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GbError::Cartridge(e) => Some(e),
+            GbError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<CartridgeError> for GbError {
+    fn from(e: CartridgeError) -> Self {
+        GbError::Cartridge(e)
+    }
+}
@@ -0,0 +1,255 @@
+//! What every frontend needs to drive a [`GameBoy`] in real time, whatever it uses for video,
+//! audio and input: the desktop one (the `gb-rs` binary) and the web one (in `web/`) are both
+//! built on the [`Emulator`] facade.
+use std::{cell::Cell, ops::ControlFlow, time::Duration};
+
+use crate::{
+    gameboy::{FrameResult, GameBoy, StepResult},
+    joypad::Button,
+    timing, AudioSink, DirtyLines, DmgPalette, FrameSink, PaletteId,
+};
+
+/// Drives a [`GameBoy`] at the speed of the real hardware, feeding the given sinks.
+///
+/// The host calls [`run_for()`](Self::run_for) regularly (e.g. on each frame of its display)
+/// with the real time elapsed since the previous call, and the emulator catches up with it.
+pub struct Emulator<F, A> {
+    gb: GameBoy,
+    frame_sink: F,
+    audio_sink: A,
+    /// Number of cycles that should have been emulated by now. This is fractional because of the
+    /// speed adjustments requested by the host.
+    target_cycles: f64,
+    emulated_cycles: u64,
+    /// Number of frames pushed to the frame sink so far
+    frames: Cell<u64>,
+}
+
+impl<F: FrameSink, A: AudioSink> Emulator<F, A> {
+    pub fn new(gb: GameBoy, frame_sink: F, audio_sink: A) -> Self {
+        Self {
+            gb,
+            frame_sink,
+            audio_sink,
+            target_cycles: 0.0,
+            emulated_cycles: 0,
+            frames: Cell::new(0),
+        }
+    }
+
+    pub fn gb(&self) -> &GameBoy {
+        &self.gb
+    }
+
+    pub fn gb_mut(&mut self) -> &mut GameBoy {
+        &mut self.gb
+    }
+
+    pub fn frame_sink(&self) -> &F {
+        &self.frame_sink
+    }
+
+    pub fn frame_sink_mut(&mut self) -> &mut F {
+        &mut self.frame_sink
+    }
+
+    pub fn audio_sink(&self) -> &A {
+        &self.audio_sink
+    }
+
+    pub fn audio_sink_mut(&mut self) -> &mut A {
+        &mut self.audio_sink
+    }
+
+    /// Replace the Game Boy, e.g. to insert another cartridge, and return the previous one.
+    pub fn replace_gb(&mut self, gb: GameBoy) -> GameBoy {
+        std::mem::replace(&mut self.gb, gb)
+    }
+
+    /// Number of frames produced so far.
+    pub fn frame_count(&self) -> u64 {
+        self.frames.get()
+    }
+
+    pub fn set_button_pressed(&mut self, button: Button, pressed: bool) {
+        self.gb.set_button_pressed(button, pressed);
+    }
+
+    /// Emulate `elapsed` of real time, sped up or slowed down by the factor `speed`.
+    ///
+    /// `on_frame` is called after each frame with the number of frames produced so far, e.g. to
+    /// change the buttons at a precise frame. Returning [`ControlFlow::Break`] stops the
+    /// emulation, and is passed on to the caller.
+    ///
+    /// Nothing is emulated while the execution is paused (see [`GameBoy::pause()`]).
+    pub fn run_for(
+        &mut self,
+        elapsed: Duration,
+        speed: f64,
+        mut on_frame: impl FnMut(&mut GameBoy, u64) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.target_cycles += timing::duration_to_cycles(elapsed) * speed;
+        while (self.emulated_cycles as f64) < self.target_cycles {
+            let frames = self.frames.get();
+            let end =
+                self.gb.cycles() + (self.target_cycles - self.emulated_cycles as f64).ceil() as u64;
+            // Stop after each frame, so that `on_frame` is called right away
+            let result = self.step_until(|gb, f| f != frames || gb.cycles() >= end);
+            if self.frames.get() != frames {
+                on_frame(&mut self.gb, self.frames.get())?;
+            }
+            if result == StepResult::Paused {
+                break;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Run until the next frame, whatever the time, e.g. to advance the emulation frame by frame.
+    pub fn run_frame(&mut self) -> FrameResult {
+        self.run_with(GameBoy::step_until_frame)
+    }
+
+    /// Execute a single instruction. Returns the number of clock cycles used.
+    pub fn step(&mut self) -> u64 {
+        self.run_with(|gb, frame_sink, audio_sink| gb.step(frame_sink, audio_sink))
+    }
+
+    /// Run the Game Boy with the given function, e.g. one of the debugger's stepping functions
+    /// such as [`GameBoy::step_over()`], and return what it returns.
+    pub fn run_with<R>(
+        &mut self,
+        run: impl FnOnce(&mut GameBoy, &mut dyn FrameSink, &mut dyn AudioSink) -> R,
+    ) -> R {
+        let mut frame_sink = FrameCounter {
+            sink: &mut self.frame_sink,
+            frames: &self.frames,
+        };
+        let start = self.gb.cycles();
+        let result = run(&mut self.gb, &mut frame_sink, &mut self.audio_sink);
+        self.emulated_cycles += self.gb.cycles() - start;
+        result
+    }
+
+    /// Run with [`GameBoy::step_until()`], `done` being given the number of frames produced so
+    /// far along with the Game Boy.
+    fn step_until(&mut self, mut done: impl FnMut(&GameBoy, u64) -> bool) -> StepResult {
+        let frames = &self.frames;
+        let mut frame_sink = FrameCounter {
+            sink: &mut self.frame_sink,
+            frames,
+        };
+        let start = self.gb.cycles();
+        let result = self
+            .gb
+            .step_until(&mut frame_sink, &mut self.audio_sink, |gb| {
+                done(gb, frames.get())
+            });
+        self.emulated_cycles += self.gb.cycles() - start;
+        result
+    }
+
+    /// Forget about the time that wasn't emulated so far, e.g. after the execution has been
+    /// paused, so that the emulation doesn't try to catch up with it.
+    pub fn resync(&mut self) {
+        self.target_cycles = self.emulated_cycles as f64;
+    }
+}
+
+/// Counts the frames on their way to the frame sink.
+struct FrameCounter<'a, F> {
+    sink: &'a mut F,
+    frames: &'a Cell<u64>,
+}
+
+impl<F: FrameSink> FrameSink for FrameCounter<'_, F> {
+    fn push_frame(&mut self, frame: &[u8]) {
+        self.frames.set(self.frames.get() + 1);
+        self.sink.push_frame(frame);
+    }
+
+    fn dirty_lines(&mut self, lines: &DirtyLines) {
+        self.sink.dirty_lines(lines);
+    }
+
+    fn push_lines(&mut self, first_line: usize, pixels: &[u8]) {
+        self.sink.push_lines(first_line, pixels);
+    }
+
+    fn lcd_power_changed(&mut self, enabled: bool) {
+        self.sink.lcd_power_changed(enabled);
+    }
+
+    fn palette_changed(&mut self, palette: PaletteId, data: u8) {
+        self.sink.palette_changed(palette, data);
+    }
+}
+
+/// The colour of the screen when the LCD is turned off, for the given palette.
+pub fn lcd_off_color(palette: DmgPalette) -> [u8; 4] {
+    let (r, g, b) = palette.lightest();
+    [r, g, b, 0xFF]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cartridge::Cartridge,
+        machine::{BootRom, MachineConfig},
+        timing::FRAME_DURATION,
+    };
+
+    #[derive(Default)]
+    struct NullSink;
+
+    impl FrameSink for NullSink {
+        fn push_frame(&mut self, _frame: &[u8]) {}
+    }
+
+    impl AudioSink for NullSink {
+        fn push_samples(&mut self, _samples: &[i16]) {}
+    }
+
+    #[test]
+    fn test_run_for() {
+        // The cartridge is all NOPs, with the LCD on after the boot sequence
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let gb = GameBoy::new(Cartridge::from_bytes(vec![0; 0x8000]), config).unwrap();
+        let mut emulator = Emulator::new(gb, NullSink, NullSink);
+
+        let result = emulator.run_for(FRAME_DURATION * 3, 1.0, |_, _| ControlFlow::Continue(()));
+        assert_eq!(result, ControlFlow::Continue(()));
+        assert!((2..=3).contains(&emulator.frame_count()));
+
+        // Stop at the next frame, even though there's more time to catch up with
+        let mut seen = Vec::new();
+        let result = emulator.run_for(FRAME_DURATION * 10, 1.0, |_, frame| {
+            seen.push(frame);
+            ControlFlow::Break(())
+        });
+        assert_eq!(result, ControlFlow::Break(()));
+        assert_eq!(seen.len(), 1);
+        assert_eq!(emulator.frame_count(), seen[0]);
+
+        // Nothing runs while paused, and the lost time isn't caught up after resuming
+        emulator.gb_mut().pause();
+        let _ = emulator.run_for(FRAME_DURATION * 10, 1.0, |_, _| ControlFlow::Continue(()));
+        assert_eq!(emulator.frame_count(), seen[0]);
+        emulator.gb_mut().resume();
+        emulator.resync();
+        let _ = emulator.run_for(FRAME_DURATION, 1.0, |_, _| ControlFlow::Continue(()));
+        assert!(emulator.frame_count() <= seen[0] + 1);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let gb = GameBoy::new(Cartridge::from_bytes(vec![0; 0x8000]), config).unwrap();
+        let mut emulator = Emulator::new(gb, NullSink, NullSink);
+
+        assert_eq!(emulator.run_frame(), FrameResult::Frame);
+        assert_eq!(emulator.run_frame(), FrameResult::Frame);
+        assert_eq!(emulator.frame_count(), 2);
+    }
+}
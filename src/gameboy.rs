@@ -1,70 +1,731 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::num::NonZeroU32;
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
+use log::{log_enabled, trace, Level};
+
+use crate::anomaly::Anomaly;
+use crate::apu::ApuSnapshot;
+use crate::breakpoint::{Breakpoint, Breakpoints, WatchHit, WatchKind};
 use crate::bus::Bus;
 use crate::cartridge::Cartridge;
-use crate::cpu::Cpu;
+use crate::cheats::Cheat;
+use crate::cpu::{CallFrame, CallKind, Cpu, CpuState, Reg, RegPair};
 use crate::disasm::Disassembler;
+use crate::error::Result;
+use crate::interrupt::InterruptFlag;
+use crate::io_regs;
 use crate::joypad::Button;
-use crate::{AudioSink, FrameSink};
+use crate::machine::{BootRom, MachineConfig, Model};
+use crate::platform::{HostPlatform, Platform};
+use crate::profiling::CodeProfile;
+use crate::state::{StateReader, StateWriter, Stateful};
+use crate::symbols::Symbols;
+use crate::timing::{CYCLES_PER_FRAME, CYCLES_PER_SECOND};
+use crate::{
+    AudioSink, DirtyLines, DmgPalette, FrameSink, ModeStats, PaletteId, PpuState, RgbImage, Stats,
+    TileMap, FRAME_SIZE,
+};
+
+/// Largest sample value produced by the APU
+const MAX_SAMPLE: f32 = i16::MAX as f32;
+/// Maximum number of samples kept for `audio_drain()`, after which the oldest ones are dropped
+const MAX_BUFFERED_SAMPLES: usize = 2 * 48000;
+/// Number of executed instructions remembered for `dump_history()`
+const HISTORY_SIZE: usize = 64;
+/// Longest [`GameBoy::step_until()`] and the debugger's stepping functions run for before giving
+/// up by default (10 seconds of emulated time), so that e.g. `finish` in a function that never
+/// returns doesn't hang the host. See [`GameBoy::set_max_step_cycles()`].
+pub const MAX_STEP_CYCLES: u64 = 10 * CYCLES_PER_SECOND as u64;
+
+/// Why [`GameBoy::step_until_frame()`] returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameResult {
+    /// A frame was completed and pushed to the frame sink
+    Frame,
+    /// A frame's worth of cycles went by without a frame, because the LCD is off
+    LcdOff,
+    /// The execution was paused (e.g. by a breakpoint) before the end of the frame
+    Paused,
+}
+
+/// Why [`GameBoy::step_until()`] or one of the debugger's stepping functions, such as
+/// [`GameBoy::finish()`], returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// The target was reached
+    Done,
+    /// The execution was paused (e.g. by a breakpoint) before reaching the target
+    Paused,
+    /// The target wasn't reached within [`MAX_STEP_CYCLES`], or the limit set with
+    /// [`GameBoy::set_max_step_cycles()`]
+    NotReached,
+}
 
 pub struct GameBoy {
     cpu: Cpu,
     bus: Bus,
+    /// Number of clock cycles emulated since power on
+    cycles: u64,
+    /// Buffers used by `run_frame()`
+    frame_buffer: FrameBuffer,
+    sample_buffer: SampleBuffer,
+    /// Source of time and entropy
+    platform: Box<dyn Platform>,
+    /// Histogram of the executed instructions, while profiling the game's code
+    code_profile: Option<CodeProfile>,
+    /// Labels of the game's code, to show addresses as `label+offset`
+    symbols: Symbols,
+    /// The last instructions executed, e.g. for crash reports
+    history: History,
+    /// Longest `step_until()` runs for
+    max_step_cycles: u64,
 }
 
 impl GameBoy {
-    pub fn new(cartridge: Cartridge, breakpoint: Option<u16>, enable_soft_break: bool) -> Self {
-        Self {
-            cpu: Cpu::with_breakpoint(breakpoint, enable_soft_break),
+    /// Create a Game Boy with the given cartridge inserted.
+    ///
+    /// Fails if the configuration is invalid, e.g. if a custom boot ROM has the wrong size.
+    pub fn new(cartridge: Cartridge, config: MachineConfig) -> Result<Self> {
+        Self::with_platform(cartridge, config, HostPlatform::new())
+    }
+
+    /// Same as [`new()`](Self::new), but with the given source of time and entropy instead of
+    /// the host's, e.g. a [`DeterministicPlatform`](crate::platform::DeterministicPlatform) so
+    /// that a session can be replayed identically.
+    pub fn with_platform(
+        cartridge: Cartridge,
+        config: MachineConfig,
+        platform: impl Platform + 'static,
+    ) -> Result<Self> {
+        let MachineConfig {
+            model: Model::Dmg,
+            boot_rom,
+            palette,
+            ram_init,
+            soft_break,
+            breakpoints,
+            cheats,
+        } = config;
+        let mut gb = Self {
+            cpu: Cpu::new(soft_break),
             bus: Bus::new(8 * 1024, cartridge),
+            cycles: 0,
+            frame_buffer: FrameBuffer::default(),
+            sample_buffer: SampleBuffer::default(),
+            platform: Box::new(platform),
+            code_profile: None,
+            symbols: Symbols::default(),
+            history: History::default(),
+            max_step_cycles: MAX_STEP_CYCLES,
+        };
+        match boot_rom {
+            #[cfg(feature = "bundled-boot-rom")]
+            BootRom::Bundled => {}
+            BootRom::Custom(data) => gb.set_boot_rom(&data)?,
+            BootRom::Skip => gb.skip_boot(),
         }
+        gb.set_dmg_palette(palette);
+        gb.bus.cartridge.init_ram(ram_init, gb.platform.as_mut());
+        for breakpoint in breakpoints {
+            gb.set_breakpoint(breakpoint);
+        }
+        for cheat in cheats {
+            gb.add_cheat(cheat);
+        }
+        Ok(gb)
+    }
+
+    pub fn platform_mut(&mut self) -> &mut dyn Platform {
+        self.platform.as_mut()
+    }
+
+    /// Use the given boot ROM instead of the bundled one.
+    fn set_boot_rom(&mut self, data: &[u8]) -> Result<()> {
+        self.bus.set_boot_rom(data)
+    }
+
+    /// Don't run the boot ROM: start directly at the cartridge entry point with the CPU and IO
+    /// registers set to their post-boot values.
+    fn skip_boot(&mut self) {
+        self.cpu.skip_boot();
+        self.bus.skip_boot();
     }
 
     pub fn step(&mut self, frame_sink: &mut dyn FrameSink, audio_sink: &mut dyn AudioSink) -> u64 {
-        let cycles = self.cpu.step(&mut self.bus);
-        for _ in 0..cycles {
-            self.bus.cycle(1, frame_sink, audio_sink);
-            self.cpu.handle_interrupt(&mut self.bus);
+        let start = self.bus.profiling.then(Instant::now);
+        let pc = self.cpu.pc();
+        // The bank the instruction runs from, which it may switch
+        let bank = self.rom_bank_at(pc);
+        self.history.record(bank, pc);
+        let call_depth = self.cpu.call_depth();
+        // The CPU runs the peripherals itself as it accesses the bus, and dispatches interrupts
+        let cycles = self.cpu.step(&mut self.bus) as u64;
+        if log_enabled!(Level::Trace) {
+            self.trace_calls(call_depth);
+        }
+        if let Some(profile) = &mut self.code_profile {
+            profile.record(bank, pc, cycles);
+        }
+        if self.is_paused() {
+            // Bring the peripherals up to date for the debugger
+            self.bus.sync();
+        }
+        self.bus.flush(frame_sink, audio_sink);
+
+        self.cycles += cycles;
+        self.bus.stats.cycles += cycles;
+        if let Some(start) = start {
+            self.bus.stats.total_time += start.elapsed();
+        }
+        cycles
+    }
+
+    /// Trace the calls and returns of the instruction that was just executed, with the addresses
+    /// named after the symbols.
+    fn trace_calls(&self, call_depth: i32) {
+        let depth = self.cpu.call_depth();
+        if depth > call_depth {
+            if let Some(frame) = self.cpu.call_stack().back() {
+                let kind = match frame.kind {
+                    CallKind::Call => "subroutine",
+                    CallKind::Rst => "RST",
+                    CallKind::Interrupt => "interrupt handler",
+                };
+                trace!(
+                    "Calling {} {} from {}",
+                    kind,
+                    self.describe_addr(frame.target),
+                    self.describe_addr(frame.call_site())
+                );
+            }
+        } else if depth < call_depth {
+            trace!("Returning to {}", self.describe_addr(self.cpu.pc()));
+        }
+    }
+
+    /// `$addr`, followed by its label if there's one.
+    fn describe_addr(&self, addr: u16) -> String {
+        match self.symbolize(addr) {
+            Some(symbol) => format!("${:04X} ({})", addr, symbol),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    /// Number of clock cycles emulated since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Performance counters since the Game Boy was created, or since the last call to
+    /// [`reset_stats()`](Self::reset_stats).
+    pub fn stats(&self) -> &Stats {
+        &self.bus.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.bus.stats = Stats::default();
+    }
+
+    /// Measure the time spent in the different components, which is reported by
+    /// [`stats()`](Self::stats). This slows the emulation down a little.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.bus.profiling = enabled;
+    }
+
+    /// Count the instructions executed by the game (by address), to find the hotspots of its
+    /// code with [`code_profile()`](Self::code_profile). Enabling it starts a new profile.
+    pub fn set_code_profiling(&mut self, enabled: bool) {
+        self.code_profile = enabled.then(CodeProfile::default);
+    }
+
+    /// The instructions executed since code profiling was enabled, if it is.
+    pub fn code_profile(&self) -> Option<&CodeProfile> {
+        self.code_profile.as_ref()
+    }
+
+    /// Describe the `n` hotspots of the code profile, along with their disassembly.
+    pub fn dump_hotspots(&self, n: usize) -> String {
+        let Some(profile) = &self.code_profile else {
+            return "Code profiling is disabled\n".to_string();
+        };
+        let mut out = format!(
+            "{} instructions, {} cycles\n",
+            profile.instructions(),
+            profile.cycles()
+        );
+        for hotspot in profile.hotspots(n) {
+            let bytes = self.code_bytes(hotspot.bank, hotspot.addr, 3);
+            let instr = Disassembler::new(&bytes)
+                .run()
+                .first()
+                .map(|i| i.to_string())
+                .unwrap_or_default();
+            let _ = write!(
+                out,
+                "{:5.1}%  {}\t{}",
+                profile.percent(&hotspot),
+                hotspot,
+                instr
+            );
+            if let Some(symbol) = self.symbols.lookup(hotspot.bank.unwrap_or(0), hotspot.addr) {
+                let _ = write!(out, "\t; {symbol}");
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Disassemble the last instructions executed (up to 64), oldest first, each from the ROM
+    /// bank it ran from.
+    pub fn dump_history(&self) -> String {
+        let mut out = String::new();
+        for (bank, addr) in self.history.iter() {
+            let bytes = self.code_bytes(bank, addr, 3);
+            let instr = Disassembler::new(&bytes)
+                .run()
+                .first()
+                .map(|i| i.to_string())
+                .unwrap_or_default();
+            match bank {
+                Some(bank) => {
+                    let _ = write!(out, "{:02X}:{:04X}\t{}", bank, addr, instr);
+                }
+                None => {
+                    let _ = write!(out, "   {:04X}\t{}", addr, instr);
+                }
+            }
+            if let Some(symbol) = self.symbols.lookup(bank.unwrap_or(0), addr) {
+                let _ = write!(out, "\t; {symbol}");
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The ROM bank the given address is in, or `None` if it's not in the ROM.
+    fn rom_bank_at(&self, addr: u16) -> Option<u16> {
+        match addr {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(self.bus.cartridge.current_rom_bank()),
+            _ => None,
+        }
+    }
+
+    /// Read `len` bytes at `addr`, from the given ROM bank rather than from the one currently
+    /// mapped.
+    fn code_bytes(&self, bank: Option<u16>, addr: u16, len: u16) -> Vec<u8> {
+        match bank {
+            Some(bank) if (0x4000..=0x7FFF).contains(&addr) => {
+                let offset = bank as usize * 0x4000 + (addr - 0x4000) as usize;
+                let rom = self.bus.cartridge.data();
+                (offset..offset + len as usize)
+                    .map(|i| rom.get(i).copied().unwrap_or(0xFF))
+                    .collect()
+            }
+            _ => (0..len)
+                .map(|i| self.bus.read_byte(addr.wrapping_add(i)))
+                .collect(),
+        }
+    }
+
+    /// Run until the next frame is complete (i.e. until the next VBlank), and return it in RGBA
+    /// format.
+    ///
+    /// This is the simplest way to drive the emulator, without having to implement `FrameSink`
+    /// and `AudioSink`: the audio produced in the meantime is kept until it is retrieved with
+    /// [`audio_drain()`](Self::audio_drain).
+    ///
+    /// If the LCD is off, this returns a blank screen after a frame's worth of cycles. It also
+    /// returns early if a breakpoint is hit.
+    pub fn run_frame(&mut self) -> &[u8] {
+        let mut frame_buffer = self.frame_buffer.take();
+        let mut sample_buffer = std::mem::take(&mut self.sample_buffer);
+        self.step_until_frame(&mut frame_buffer, &mut sample_buffer);
+        self.frame_buffer = frame_buffer;
+        self.sample_buffer = sample_buffer;
+
+        &self.frame_buffer.frame
+    }
+
+    /// Run until the next frame has been pushed to the frame sink.
+    ///
+    /// If the LCD is off, this gives up after a frame's worth of cycles, so that the frontend
+    /// keeps running at the same pace. It also returns early if the execution gets paused, e.g.
+    /// by a breakpoint.
+    pub fn step_until_frame(
+        &mut self,
+        frame_sink: &mut dyn FrameSink,
+        audio_sink: &mut dyn AudioSink,
+    ) -> FrameResult {
+        let mut frame_sink = FrameWatcher {
+            sink: frame_sink,
+            frame_pushed: false,
+        };
+        let mut cycles = 0;
+        while !frame_sink.frame_pushed {
+            if self.is_paused() {
+                return FrameResult::Paused;
+            }
+            if cycles >= CYCLES_PER_FRAME as u64 {
+                return FrameResult::LcdOff;
+            }
+            cycles += self.step(&mut frame_sink, audio_sink);
+        }
+        FrameResult::Frame
+    }
+
+    /// Run until `done` returns true after an instruction, until the execution gets paused (e.g.
+    /// by a breakpoint), or for at most [`MAX_STEP_CYCLES`] (or the limit set with
+    /// [`set_max_step_cycles()`](Self::set_max_step_cycles)).
+    ///
+    /// Nothing is executed if the execution is already paused.
+    pub fn step_until(
+        &mut self,
+        frame_sink: &mut dyn FrameSink,
+        audio_sink: &mut dyn AudioSink,
+        mut done: impl FnMut(&Self) -> bool,
+    ) -> StepResult {
+        let start = self.cycles;
+        loop {
+            if self.is_paused() {
+                return StepResult::Paused;
+            }
+            self.step(frame_sink, audio_sink);
+            if done(self) {
+                return StepResult::Done;
+            }
+            if self.cycles - start >= self.max_step_cycles {
+                return StepResult::NotReached;
+            }
+        }
+    }
+
+    /// Set the number of clock cycles [`step_until()`](Self::step_until) and the debugger's
+    /// stepping functions run for at most, instead of [`MAX_STEP_CYCLES`].
+    pub fn set_max_step_cycles(&mut self, cycles: u64) {
+        self.max_step_cycles = cycles;
+    }
+
+    /// Append the audio produced by [`run_frame()`](Self::run_frame) to `samples`, as interleaved
+    /// stereo samples between -1.0 and 1.0, at the rate set with
+    /// [`set_sample_rate()`](Self::set_sample_rate).
+    pub fn audio_drain(&mut self, samples: &mut Vec<f32>) {
+        samples.extend(
+            self.sample_buffer
+                .samples
+                .drain(..)
+                .map(|s| s as f32 / MAX_SAMPLE),
+        );
+    }
+
+    /// Step over the next instruction: if it's a CALL or RST, run until it returns.
+    pub fn step_over(
+        &mut self,
+        frame_sink: &mut dyn FrameSink,
+        audio_sink: &mut dyn AudioSink,
+    ) -> StepResult {
+        let depth = self.cpu.call_depth();
+        self.run_until(frame_sink, audio_sink, |gb| gb.cpu.call_depth() <= depth)
+    }
+
+    /// Run until the current subroutine returns.
+    pub fn finish(
+        &mut self,
+        frame_sink: &mut dyn FrameSink,
+        audio_sink: &mut dyn AudioSink,
+    ) -> StepResult {
+        let depth = self.cpu.call_depth();
+        self.run_until(frame_sink, audio_sink, |gb| gb.cpu.call_depth() < depth)
+    }
+
+    /// Run until PC reaches the given address.
+    pub fn run_to(
+        &mut self,
+        addr: u16,
+        frame_sink: &mut dyn FrameSink,
+        audio_sink: &mut dyn AudioSink,
+    ) -> StepResult {
+        self.run_until(frame_sink, audio_sink, |gb| gb.cpu.pc() == addr)
+    }
+
+    /// Step at least once, then like [`step_until()`](Self::step_until).
+    ///
+    /// This is meant to be called from the debugger, i.e. while the execution is paused.
+    fn run_until(
+        &mut self,
+        frame_sink: &mut dyn FrameSink,
+        audio_sink: &mut dyn AudioSink,
+        done: impl Fn(&Self) -> bool,
+    ) -> StepResult {
+        // The first step is done while still paused, so that a breakpoint on the current
+        // instruction doesn't stop us straight away
+        self.step(frame_sink, audio_sink);
+        if done(self) {
+            return StepResult::Done;
+        }
+        self.cpu.set_pause(false);
+        let result = self.step_until(frame_sink, audio_sink, done);
+        self.cpu.set_pause(true);
+
+        result
+    }
+
+    // The dump functions return text for the host to display wherever it wants (a terminal, a
+    // debugger window...), so that the core never prints anything itself.
+
+    /// The registers, as shown by [`CpuState`]'s `Display`, followed by the label of PC if any.
+    pub fn dump_cpu(&self) -> String {
+        let state = self.cpu.state();
+        match self.symbolize(state.pc) {
+            Some(symbol) => format!("{} ({})", state, symbol),
+            None => state.to_string(),
         }
+    }
+
+    /// The cartridge currently inserted.
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.bus.cartridge
+    }
+
+    /// Use the given labels to describe addresses in the disassembly, the call stack, etc.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
 
-        cycles as u64
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
     }
 
-    pub fn dump_cpu(&self) {
-        self.cpu.dump_cpu();
+    /// Describe `addr` as `label+offset`, if there's a label before it. Addresses in the
+    /// switchable ROM and RAM banks are looked up in the banks currently mapped.
+    pub fn symbolize(&self, addr: u16) -> Option<String> {
+        self.symbols.lookup(self.bank_at(addr), addr)
     }
 
-    pub fn dump_mem(&self, addr: u16) {
-        for offset in 0..4 {
-            let addr = addr + offset * 16;
-            print!("{:04x}: ", addr);
-            for a in addr..addr + 16 {
-                print!("{:02x} ", self.bus.read_byte(a));
+    /// The bank `addr` is in, as numbered in symbol files.
+    fn bank_at(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => self.bus.cartridge.current_rom_bank(),
+            0xA000..=0xBFFF => self.bus.cartridge.current_ram_bank() as u16,
+            _ => 0,
+        }
+    }
+
+    /// Hex dump of `len` bytes of memory starting at `addr`, 16 bytes per line.
+    pub fn dump_mem(&self, addr: u16, len: usize) -> String {
+        let mut out = String::new();
+        for (line, bytes) in self.read_memory(addr, len).chunks(16).enumerate() {
+            let _ = write!(out, "{:04x}: ", addr as usize + line * 16);
+            for b in bytes {
+                let _ = write!(out, "{:02x} ", b);
             }
-            println!();
+            out.push('\n');
         }
+        out
+    }
+
+    /// Read `len` bytes of memory starting at `addr`, as the CPU would see them (but without
+    /// any side effect). The range stops at the end of the address space.
+    pub fn read_memory(&self, addr: u16, len: usize) -> Vec<u8> {
+        let end = (addr as usize + len).min(0x10000);
+        (addr as usize..end)
+            .map(|a| self.bus.read_byte(a as u16))
+            .collect()
     }
 
-    pub fn disassemble(&self, addr: u16) {
+    pub fn disassemble(&self, addr: u16) -> String {
         let bytes = (addr..addr + 100)
             .map(|a| self.bus.read_byte(a))
             .collect::<Vec<_>>();
         let instrs = Disassembler::new(&bytes).run();
+        let mut out = String::new();
         let mut pc = addr;
         for inst in instrs {
-            println!("{pc:04X}\t{inst}");
+            if let Some(label) = self.symbols.label_at(self.bank_at(pc), pc) {
+                let _ = writeln!(out, "{label}:");
+            }
+            let text = inst.to_string();
+            let _ = write!(out, "{pc:04X}\t{text}");
+            // Name the address the instruction refers to, e.g. the target of a CALL
+            if let Some(symbol) = referenced_addr(&text).and_then(|a| self.symbolize(a)) {
+                let _ = write!(out, "\t; {symbol}");
+            }
+            out.push('\n');
             pc += inst.bytes;
         }
+        out
+    }
+
+    pub fn dump_oam(&self) -> String {
+        self.bus.gfx.dump_oam()
+    }
+
+    pub fn dump_sprite(&self, id: u8) -> String {
+        self.bus.gfx.dump_sprite(id)
+    }
+
+    pub fn dump_palettes(&self) -> String {
+        self.bus.gfx.dump_palettes()
+    }
+
+    /// Describe the state of the cartridge's mapper.
+    pub fn dump_banks(&self) -> String {
+        let cartridge = &self.bus.cartridge;
+        let state = cartridge.mapper_state();
+        let mut out = format!(
+            "ROM bank:     {:02X}\n\
+             RAM bank:     {:02X}\n\
+             RAM:          {}\n\
+             ROM bank reg: {:02X}\n\
+             RAM bank reg: {:02X}\n\
+             Bank mode:    {}\n",
+            cartridge.current_rom_bank(),
+            cartridge.current_ram_bank(),
+            if state.ram_enabled {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            state.rom_bank_register,
+            state.ram_bank_register,
+            state.banking_mode_1 as u8
+        );
+        if cartridge.header().cartridge_type.has_timer() {
+            let [seconds, minutes, hours, days_lo, days_hi] = state.rtc_latched;
+            let _ = writeln!(
+                out,
+                "RTC latched:  {:02X} {:02X} {:02X} {:02X} {:02X}",
+                seconds, minutes, hours, days_lo, days_hi
+            );
+            let _ = writeln!(out, "Latch write:  {:02X}", state.rtc_latch_write);
+        }
+        out
+    }
+
+    /// Describe the value of the given IO register, or of all of them.
+    pub fn dump_io_regs(&self, addr: Option<u16>) -> String {
+        let mut out = String::new();
+        let mut print_reg = |name: &str, addr: u16| {
+            let value = self.bus.read_byte(addr);
+            let _ = writeln!(
+                out,
+                "{:<5}({:04X}) = {:02X} ({:08b})",
+                name, addr, value, value
+            );
+        };
+        match addr {
+            Some(addr) => print_reg(io_regs::name_of(addr).unwrap_or("?"), addr),
+            None => {
+                for &(name, addr) in io_regs::REGISTERS {
+                    print_reg(name, addr);
+                }
+                let unmapped = self.bus.unmapped_io_accesses();
+                if !unmapped.is_empty() {
+                    let addrs: Vec<String> =
+                        unmapped.iter().map(|a| format!("{:04X}", a)).collect();
+                    let _ = writeln!(out, "Unmapped registers accessed: {}", addrs.join(" "));
+                }
+            }
+        }
+        out
+    }
+
+    /// [Hash](crate::frame_hash) of what is on the screen at the moment, i.e. the last frame with
+    /// the lines of the current one drawn so far.
+    pub fn frame_hash(&self) -> u64 {
+        crate::frame_hash(self.bus.gfx.screen())
+    }
+
+    /// Number of dots the PPU spent in each mode on each scanline of the last complete frame.
+    pub fn ppu_mode_stats(&self) -> &ModeStats {
+        self.bus.gfx.mode_stats()
+    }
+
+    /// The PPU's position in the frame (mode, LY...) and its internal state.
+    pub fn ppu_state(&self) -> PpuState {
+        self.bus.gfx.ppu_state()
+    }
+
+    /// What is on the LCD at the moment, in RGBA format ([`FRAME_SIZE`] bytes): the last frame,
+    /// with the lines of the current one drawn so far.
+    ///
+    /// Unlike the frames sent to the [`FrameSink`], this is available at any time, e.g. in the
+    /// middle of a frame.
+    pub fn screen(&self) -> &[u8] {
+        self.bus.gfx.screen()
+    }
+
+    /// Render all the tiles in VRAM.
+    pub fn render_tiles(&self) -> RgbImage {
+        self.bus.gfx.render_tiles()
+    }
+
+    /// Render one of the tilemaps, with the visible area outlined.
+    pub fn render_tilemap(&self, map: TileMap) -> RgbImage {
+        self.bus.gfx.render_tilemap(map)
+    }
+
+    /// Render the current palettes.
+    pub fn render_palettes(&self) -> RgbImage {
+        self.bus.gfx.render_palettes()
+    }
+
+    /// A snapshot of the CPU registers.
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
     }
 
-    pub fn dump_oam(&self) {
-        self.bus.gfx.dump_oam();
+    /// A snapshot of the state of the sound channels, along with their most recent output.
+    pub fn apu_snapshot(&self) -> ApuSnapshot {
+        self.bus.apu.snapshot()
     }
 
-    pub fn dump_sprite(&self, id: u8) {
-        self.bus.gfx.dump_sprite(id);
+    /// Set all the CPU registers at once.
+    pub fn set_cpu_state(&mut self, state: &CpuState) {
+        self.cpu.set_state(state);
     }
 
-    pub fn dump_palettes(&self) {
-        self.bus.gfx.dump_palettes();
+    pub fn reg(&self, reg: Reg) -> u8 {
+        self.cpu.reg(reg)
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, value: u8) {
+        self.cpu.set_reg(reg, value);
+    }
+
+    pub fn reg_pair(&self, pair: RegPair) -> u16 {
+        self.cpu.reg_pair(pair)
+    }
+
+    pub fn set_reg_pair(&mut self, pair: RegPair, value: u16) {
+        self.cpu.set_reg_pair(pair, value);
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.cpu.sp()
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.cpu.set_sp(sp);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.cpu.set_pc(pc);
+    }
+
+    pub fn ime(&self) -> bool {
+        self.cpu.ime()
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.cpu.set_ime(ime);
     }
 
     pub fn is_halted(&self) -> bool {
@@ -85,15 +746,718 @@ impl GameBoy {
         self.cpu.set_pause(false);
     }
 
-    pub fn set_breakpoint(&mut self, addr: u16) {
-        self.cpu.set_breakpoint(addr);
+    /// Return (and forget) the watchpoint that paused the execution, if any, with the address of
+    /// the instruction that triggered it.
+    pub fn take_watch_hit(&mut self) -> Option<(u16, WatchHit)> {
+        self.cpu.take_watch_hit()
+    }
+
+    /// Pause the execution before the instruction at the given address, optionally only when the
+    /// given ROM bank is mapped.
+    pub fn set_breakpoint(&mut self, breakpoint: impl Into<Breakpoint>) {
+        self.bus.breakpoints.add(breakpoint.into());
+    }
+
+    /// Pause the execution when the CPU jumps to the handler of one of the given interrupts.
+    pub fn set_interrupt_break(&mut self, flag: InterruptFlag) {
+        self.bus.breakpoints.break_on_interrupt(flag);
+    }
+
+    /// Pause the execution when the CPU reads or writes (depending on `kind`) the given addresses.
+    pub fn add_watchpoint(
+        &mut self,
+        range: RangeInclusive<u16>,
+        kind: WatchKind,
+        value: Option<u8>,
+    ) {
+        self.bus.breakpoints.watch(range, kind, value);
+    }
+
+    /// Activate a cheat code. Game Genie codes take effect immediately, and GameShark codes at
+    /// the next VBlank.
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.bus.add_cheat(cheat);
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        self.bus.cheats()
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.bus.clear_cheats();
+    }
+
+    /// The subroutines being executed, innermost last, as far as CALL/RST/interrupts and
+    /// RET/RETI can tell.
+    pub fn call_stack(&self) -> &VecDeque<CallFrame> {
+        self.cpu.call_stack()
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.bus.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.bus.breakpoints
     }
 
     pub fn set_button_pressed(&mut self, button: Button, is_pressed: bool) {
         self.bus.set_button_pressed(button, is_pressed);
     }
 
+    /// Shorthand for [`set_button_pressed()`](Self::set_button_pressed).
+    pub fn set_button(&mut self, button: Button, is_pressed: bool) {
+        self.set_button_pressed(button, is_pressed);
+    }
+
+    /// Return everything sent over the serial port that hasn't been retrieved with
+    /// [`take_serial_output()`](Self::take_serial_output) yet.
+    ///
+    /// There's no link cable emulation, but test ROMs (e.g. Blargg's) print their results there.
+    pub fn serial_output(&self) -> String {
+        String::from_utf8_lossy(self.bus.serial_output()).into_owned()
+    }
+
+    /// Return and clear the bytes sent over the serial port since the last call.
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.bus.take_serial_output()
+    }
+
+    /// Return the anomalies recorded since the last call.
+    ///
+    /// Anomalies aren't recorded when the `strict` feature is enabled (they panic instead).
+    pub fn take_anomalies(&mut self) -> Vec<Anomaly> {
+        self.bus.take_anomalies()
+    }
+
+    /// Set the colours used to display the 4 shades of the DMG.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.bus.gfx.set_dmg_palette(palette);
+        self.frame_buffer.lcd_off_color = palette.lightest();
+    }
+
+    /// Deliver the screen to the `FrameSink` in batches of `lines` lines as soon as they are drawn,
+    /// in addition to complete frames. This allows frontends to display the picture with lower
+    /// latency, or to race the beam. `None` (the default) only delivers complete frames.
+    pub fn set_lines_per_update(&mut self, lines: Option<u8>) {
+        self.bus.gfx.set_lines_per_update(lines);
+    }
+
+    /// Set the sample rate (in Hz) of the audio pushed to the `AudioSink`.
+    pub fn set_sample_rate(&mut self, sample_rate: NonZeroU32) {
+        self.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Set the cutoff frequency (in Hz) of the low-pass filter that reduces the aliasing of the
+    /// audio, or disable it with `None`. Defaults to
+    /// [`DEFAULT_LOW_PASS_CUTOFF`](crate::DEFAULT_LOW_PASS_CUTOFF).
+    pub fn set_low_pass_cutoff(&mut self, cutoff: Option<u32>) {
+        self.bus.apu.set_low_pass_cutoff(cutoff);
+    }
+
     pub fn save(&self) {
         self.bus.cartridge.save();
     }
+
+    /// Save the whole state of the machine, to be restored later with
+    /// [`load_state()`](Self::load_state).
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.bus.cartridge.header().global_checksum);
+        self.cpu.save_state(&mut w);
+        self.bus.save_state(&mut w);
+        w.u64(self.cycles);
+        w.finish()
+    }
+
+    /// Restore a state saved by [`save_state()`](Self::save_state) with the same game.
+    ///
+    /// Fails if the state was saved with another game or another version of the emulator, or if
+    /// it is corrupted, in which case the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let backup = self.save_state();
+        let result = self.restore_state(data);
+        if result.is_err() {
+            self.restore_state(&backup)
+                .expect("Failed to restore the state saved before loading");
+        }
+        result
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let mut r = StateReader::new(data, self.bus.cartridge.header().global_checksum)?;
+        self.cpu.load_state(&mut r)?;
+        self.bus.load_state(&mut r)?;
+        self.cycles = r.u64()?;
+        r.finish()
+    }
+}
+
+/// Passes everything on to the frame sink, noting when a frame is pushed
+struct FrameWatcher<'a> {
+    sink: &'a mut dyn FrameSink,
+    frame_pushed: bool,
+}
+
+impl FrameSink for FrameWatcher<'_> {
+    fn push_frame(&mut self, frame: &[u8]) {
+        self.frame_pushed = true;
+        self.sink.push_frame(frame);
+    }
+
+    fn dirty_lines(&mut self, lines: &DirtyLines) {
+        self.sink.dirty_lines(lines);
+    }
+
+    fn push_lines(&mut self, first_line: usize, pixels: &[u8]) {
+        self.sink.push_lines(first_line, pixels);
+    }
+
+    fn lcd_power_changed(&mut self, enabled: bool) {
+        self.sink.lcd_power_changed(enabled);
+    }
+
+    fn palette_changed(&mut self, palette: PaletteId, data: u8) {
+        self.sink.palette_changed(palette, data);
+    }
+}
+
+/// Keeps the last frame, for `GameBoy::run_frame()`
+struct FrameBuffer {
+    frame: Box<[u8]>,
+    lcd_off_color: (u8, u8, u8),
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        let lcd_off_color = DmgPalette::default().lightest();
+        let (r, g, b) = lcd_off_color;
+        Self {
+            frame: [r, g, b, 0xFF].repeat(FRAME_SIZE / 4).into_boxed_slice(),
+            lcd_off_color,
+        }
+    }
+}
+
+impl FrameBuffer {
+    /// Move the frame out, leaving an empty one behind without allocating a new one.
+    fn take(&mut self) -> Self {
+        Self {
+            frame: std::mem::take(&mut self.frame),
+            lcd_off_color: self.lcd_off_color,
+        }
+    }
+}
+
+impl FrameSink for FrameBuffer {
+    fn push_frame(&mut self, frame: &[u8]) {
+        self.frame.copy_from_slice(frame);
+    }
+
+    fn lcd_power_changed(&mut self, enabled: bool) {
+        if !enabled {
+            let (r, g, b) = self.lcd_off_color;
+            for pixel in self.frame.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&[r, g, b, 0xFF]);
+            }
+        }
+    }
+}
+
+/// The 16-bit address in a disassembled instruction (`$1234`), if there's one.
+fn referenced_addr(instr: &str) -> Option<u16> {
+    let start = instr.find('$')? + 1;
+    let hex = instr.get(start..start + 4)?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u16::from_str_radix(hex, 16).ok()
+}
+
+/// Ring buffer of the addresses of the last instructions executed, with the ROM bank they were in
+/// (see `GameBoy::rom_bank_at()`)
+struct History {
+    entries: [(Option<u16>, u16); HISTORY_SIZE],
+    /// Where the next entry goes
+    next: usize,
+    len: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            entries: [(None, 0); HISTORY_SIZE],
+            next: 0,
+            len: 0,
+        }
+    }
+}
+
+impl History {
+    fn record(&mut self, bank: Option<u16>, addr: u16) {
+        self.entries[self.next] = (bank, addr);
+        self.next = (self.next + 1) % HISTORY_SIZE;
+        self.len = (self.len + 1).min(HISTORY_SIZE);
+    }
+
+    /// The entries, oldest first
+    fn iter(&self) -> impl Iterator<Item = (Option<u16>, u16)> + '_ {
+        let start = (self.next + HISTORY_SIZE - self.len) % HISTORY_SIZE;
+        (0..self.len).map(move |i| self.entries[(start + i) % HISTORY_SIZE])
+    }
+}
+
+/// Keeps the samples until they are retrieved with `GameBoy::audio_drain()`
+#[derive(Default)]
+struct SampleBuffer {
+    samples: VecDeque<i16>,
+}
+
+impl AudioSink for SampleBuffer {
+    fn push_samples(&mut self, samples: &[i16]) {
+        self.samples.extend(samples);
+        if self.samples.len() > MAX_BUFFERED_SAMPLES {
+            self.samples
+                .drain(..self.samples.len() - MAX_BUFFERED_SAMPLES);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_frame() {
+        // A ROM full of NOPs
+        let cartridge = Cartridge::from_bytes(vec![0; 0x8000]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(cartridge, config).unwrap();
+        gb.set_sample_rate(NonZeroU32::new(48000).unwrap());
+
+        let mut samples = Vec::new();
+        for _ in 0..2 {
+            assert_eq!(gb.run_frame().len(), FRAME_SIZE);
+        }
+        gb.audio_drain(&mut samples);
+        // roughly 2 frames worth of stereo samples
+        assert!(
+            samples.len() > 1400 && samples.len() < 3400,
+            "{}",
+            samples.len()
+        );
+        assert!(samples.iter().all(|s| (-1.0..=1.0).contains(s)));
+        gb.audio_drain(&mut samples);
+        assert!(samples.len() < 3400);
+    }
+
+    #[test]
+    fn test_savestate() {
+        // INC A; LDH (SCX),A; JR -5
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x105].copy_from_slice(&[0x3C, 0xE0, 0x43, 0x18, 0xFB]);
+        let config = || MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom.clone()), config()).unwrap();
+        gb.run_frame();
+        // Save in the middle of a frame
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+        for _ in 0..500 {
+            gb.step(&mut frame_buffer, &mut sample_buffer);
+        }
+        let state = gb.save_state();
+
+        let mut restored = GameBoy::new(Cartridge::from_bytes(rom), config()).unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        for gb in [&mut gb, &mut restored] {
+            gb.run_frame();
+        }
+        assert_eq!(restored.cycles(), gb.cycles());
+        assert_eq!(restored.save_state(), gb.save_state());
+
+        assert!(restored.load_state(&state[..state.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_skip_boot() {
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0x42;
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        assert_eq!(gb.pc(), 0x0100);
+        assert_eq!(gb.sp(), 0xFFFE);
+        assert_eq!(gb.reg_pair(RegPair::AF), 0x01B0);
+        assert_eq!(gb.reg_pair(RegPair::HL), 0x014D);
+        assert_eq!(gb.bus.read_byte(io_regs::LCDC), 0x91);
+        // the boot ROM is unmapped
+        assert_eq!(gb.bus.read_byte(0x0000), 0x42);
+    }
+
+    #[test]
+    fn test_custom_boot_rom() {
+        let cartridge = || Cartridge::from_bytes(vec![0; 0x8000]);
+        let config = MachineConfig::default().boot_rom(BootRom::Custom(vec![0xAA; 0x100]));
+        let gb = GameBoy::new(cartridge(), config).unwrap();
+        assert_eq!(gb.pc(), 0x0000);
+        assert_eq!(gb.bus.read_byte(0x0000), 0xAA);
+
+        let config = MachineConfig::default().boot_rom(BootRom::Custom(vec![0xAA; 0x80]));
+        assert!(GameBoy::new(cartridge(), config).is_err());
+    }
+
+    #[test]
+    fn test_ppu_state() {
+        // A ROM full of NOPs
+        let cartridge = Cartridge::from_bytes(vec![0; 0x8000]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(cartridge, config).unwrap();
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+        assert_eq!(gb.screen().len(), FRAME_SIZE);
+
+        // Window in the top-left corner
+        gb.bus.write_byte(io_regs::WY, 0);
+        gb.bus.write_byte(io_regs::WX, 7);
+        gb.bus.write_byte(io_regs::LCDC, 0xB1);
+        gb.step_until_frame(&mut frame_buffer, &mut sample_buffer);
+        let state = gb.ppu_state();
+        assert!(state.enabled);
+        assert_eq!((state.ly, state.mode), (144, 1));
+
+        gb.step_until(&mut frame_buffer, &mut sample_buffer, |gb| {
+            gb.ppu_state().ly == 10
+        });
+        let state = gb.ppu_state();
+        assert_eq!(state.mode, 2);
+        assert!(state.dot < 8);
+        assert_eq!(state.window_line, 10);
+    }
+
+    #[test]
+    fn test_step_until_frame() {
+        // A ROM full of NOPs
+        let cartridge = Cartridge::from_bytes(vec![0; 0x8000]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(cartridge, config).unwrap();
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+
+        assert_eq!(
+            gb.step_until_frame(&mut frame_buffer, &mut sample_buffer),
+            FrameResult::Frame
+        );
+        // A whole frame later, at the same point of the next frame
+        let start = gb.cycles();
+        assert_eq!(
+            gb.step_until_frame(&mut frame_buffer, &mut sample_buffer),
+            FrameResult::Frame
+        );
+        assert!((gb.cycles() - start).abs_diff(CYCLES_PER_FRAME as u64) <= 4);
+
+        // With the LCD off, one frame's worth of cycles
+        gb.bus.write_byte(0xFF40, 0x00);
+        let start = gb.cycles();
+        assert_eq!(
+            gb.step_until_frame(&mut frame_buffer, &mut sample_buffer),
+            FrameResult::LcdOff
+        );
+        assert!(gb.cycles() - start >= CYCLES_PER_FRAME as u64);
+
+        gb.pause();
+        assert_eq!(
+            gb.step_until_frame(&mut frame_buffer, &mut sample_buffer),
+            FrameResult::Paused
+        );
+        gb.resume();
+
+        let target = gb.cycles() + 1000;
+        let result = gb.step_until(&mut frame_buffer, &mut sample_buffer, |gb| {
+            gb.cycles() >= target
+        });
+        assert_eq!(result, StepResult::Done);
+        assert!(gb.cycles() >= target);
+    }
+
+    #[test]
+    fn test_register_access() {
+        // LD A,B; PUSH BC
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150..0x0152].copy_from_slice(&[0x78, 0xC5]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+
+        gb.set_reg(Reg::B, 0x12);
+        gb.set_reg(Reg::C, 0x34);
+        assert_eq!(gb.reg_pair(RegPair::BC), 0x1234);
+        // the lower bits of F can't be set
+        gb.set_reg_pair(RegPair::AF, 0xFFFF);
+        assert_eq!(gb.reg_pair(RegPair::AF), 0xFFF0);
+        gb.set_sp(0xD000);
+        gb.set_pc(0x0150);
+        gb.set_ime(false);
+        assert!(!gb.ime());
+
+        // The CPU carries on from there
+        gb.step(&mut frame_buffer, &mut sample_buffer);
+        gb.step(&mut frame_buffer, &mut sample_buffer);
+        assert_eq!(gb.reg(Reg::A), 0x12);
+        assert_eq!(gb.pc(), 0x0152);
+        assert_eq!(gb.sp(), 0xCFFE);
+        assert_eq!(gb.read_memory(0xCFFE, 2), [0x34, 0x12]);
+        assert_eq!(gb.cpu_state().b, 0x12);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // LD A,($C000); LD ($C001),A; LD A,$3E; LD ($C002),A; LD ($C003),A
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x010E].copy_from_slice(&[
+            0xFA, 0x00, 0xC0, 0xEA, 0x01, 0xC0, 0x3E, 0x3E, 0xEA, 0x02, 0xC0, 0xEA, 0x03, 0xC0,
+        ]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        gb.bus.write_byte(0xC000, 0x12);
+        gb.add_watchpoint(0xC000..=0xC000, WatchKind::Read, None);
+        gb.add_watchpoint(0xC001..=0xC001, WatchKind::Write, None);
+        // only the second write to $C003 matches
+        gb.add_watchpoint(0xC002..=0xC003, WatchKind::Write, Some(0x3F));
+        gb.add_watchpoint(0xC003..=0xC003, WatchKind::Write, Some(0x3E));
+        // never accessed that way
+        gb.add_watchpoint(0xC000..=0xC000, WatchKind::Write, None);
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+        let mut step = |gb: &mut GameBoy| {
+            gb.step(&mut frame_buffer, &mut sample_buffer);
+            let hit = gb.take_watch_hit();
+            assert_eq!(gb.is_paused(), hit.is_some());
+            gb.resume();
+            hit
+        };
+
+        let hit = |kind, addr, value| WatchHit { kind, addr, value };
+        assert_eq!(
+            step(&mut gb),
+            Some((0x0100, hit(WatchKind::Read, 0xC000, 0x12)))
+        );
+        assert_eq!(
+            step(&mut gb),
+            Some((0x0103, hit(WatchKind::Write, 0xC001, 0x12)))
+        );
+        assert_eq!(step(&mut gb), None);
+        // $3E is written to $C002, which only has a watch on $3F
+        assert_eq!(step(&mut gb), None);
+        assert_eq!(
+            step(&mut gb),
+            Some((0x010B, hit(WatchKind::Write, 0xC003, 0x3E)))
+        );
+    }
+
+    #[test]
+    fn test_interrupt_break() {
+        // EI; JR -2 at 0100
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0103].copy_from_slice(&[0xFB, 0x18, 0xFE]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        gb.bus.write_byte(0xFFFF, InterruptFlag::VBLANK.bits());
+        gb.set_interrupt_break(InterruptFlag::VBLANK);
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+
+        while !gb.is_paused() && gb.cycles() < 2 * CYCLES_PER_FRAME as u64 {
+            gb.step(&mut frame_buffer, &mut sample_buffer);
+        }
+        assert!(gb.is_paused());
+        assert_eq!(gb.pc(), 0x0040);
+    }
+
+    #[test]
+    fn test_debugger_stepping() {
+        // CALL $0200 at 0150; NOP; NOP; RET at 0200; JR -2 at 0300 (forever); CALL $0300 at 0310
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150..0x0153].copy_from_slice(&[0xCD, 0x00, 0x02]);
+        rom[0x0202] = 0xC9;
+        rom[0x0300..0x0302].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x0310..0x0313].copy_from_slice(&[0xCD, 0x00, 0x03]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+        gb.pause();
+
+        gb.set_pc(0x0150);
+        let result = gb.step_over(&mut frame_buffer, &mut sample_buffer);
+        assert_eq!((result, gb.pc()), (StepResult::Done, 0x0153));
+        assert!(gb.is_paused());
+
+        gb.set_pc(0x0150);
+        gb.step(&mut frame_buffer, &mut sample_buffer);
+        assert_eq!(gb.pc(), 0x0200);
+        let result = gb.finish(&mut frame_buffer, &mut sample_buffer);
+        assert_eq!((result, gb.pc()), (StepResult::Done, 0x0153));
+
+        gb.set_pc(0x0150);
+        let result = gb.run_to(0x0201, &mut frame_buffer, &mut sample_buffer);
+        assert_eq!((result, gb.pc()), (StepResult::Done, 0x0201));
+        // A breakpoint on the way stops it, once the instruction it's on has run
+        gb.set_pc(0x0200);
+        gb.set_breakpoint(0x0201);
+        let result = gb.run_to(0x0154, &mut frame_buffer, &mut sample_buffer);
+        assert_eq!((result, gb.pc()), (StepResult::Paused, 0x0202));
+        gb.breakpoints_mut().exec.clear();
+
+        // The subroutine never returns: give up after a while (a frame, rather than the default
+        // 10 seconds)
+        gb.set_max_step_cycles(CYCLES_PER_FRAME as u64);
+        gb.set_pc(0x0310);
+        let start = gb.cycles();
+        let result = gb.step_over(&mut frame_buffer, &mut sample_buffer);
+        assert_eq!((result, gb.pc()), (StepResult::NotReached, 0x0300));
+        let elapsed = gb.cycles() - start;
+        assert!((CYCLES_PER_FRAME as u64..MAX_STEP_CYCLES).contains(&elapsed));
+        assert!(gb.is_paused());
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::default();
+        assert_eq!(history.iter().count(), 0);
+        for addr in 0..100 {
+            history.record(Some(0), addr);
+        }
+        let addrs = history.iter().map(|(_, addr)| addr).collect::<Vec<_>>();
+        assert_eq!(addrs, (36..100).collect::<Vec<_>>());
+
+        // NOPs, then a JP to the start of the ROM
+        let mut rom = vec![0; 0x8000];
+        rom[0x0102..0x0105].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config.clone()).unwrap();
+        let mut frame_buffer = FrameBuffer::default();
+        let mut sample_buffer = SampleBuffer::default();
+        for _ in 0..3 {
+            gb.step(&mut frame_buffer, &mut sample_buffer);
+        }
+        let history = gb.dump_history();
+        let lines = history.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("00:0100\tNOP"), "{}", lines[0]);
+        assert!(lines[2].starts_with("00:0102\tJP"), "{}", lines[2]);
+
+        // JP $4000 to bank 1, which switches to bank 2 (MBC1, 64KB) with LD A,$02; LD ($2000),A
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, 0x00, 0x40]);
+        rom[0x4000..0x4005].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20]);
+        // XOR A in bank 2
+        rom[0x8005] = 0xAF;
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        for _ in 0..4 {
+            gb.step(&mut frame_buffer, &mut sample_buffer);
+        }
+        let history = gb.dump_history();
+        let lines = history.lines().collect::<Vec<_>>();
+        // Bank 1's instructions are shown from bank 1, even though bank 2 is mapped now
+        assert!(lines[1].starts_with("01:4000\tLD A"), "{}", lines[1]);
+        assert!(lines[2].starts_with("01:4002\tLD ("), "{}", lines[2]);
+        assert!(lines[3].starts_with("02:4005\tXOR"), "{}", lines[3]);
+    }
+
+    #[test]
+    fn test_frame_hash() {
+        assert_eq!(crate::frame_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(crate::frame_hash(b"a"), 0xaf63dc4c8601ec8c);
+
+        // After a complete frame, the screen is that frame
+        let cartridge = Cartridge::from_bytes(vec![0; 0x8000]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(cartridge, config).unwrap();
+        let hash = crate::frame_hash(gb.run_frame());
+        assert_eq!(gb.frame_hash(), hash);
+    }
+
+    #[test]
+    fn test_dump_mem() {
+        let mut gb = GameBoy::new(
+            Cartridge::from_bytes(vec![0; 0x8000]),
+            MachineConfig::default(),
+        )
+        .unwrap();
+        for (i, addr) in (0xFFF0..=0xFFFE).enumerate() {
+            gb.bus.write_byte(addr, i as u8);
+        }
+        assert_eq!(gb.read_memory(0xFFFC, 2), [0x0C, 0x0D]);
+        // the range stops at the end of the address space
+        assert_eq!(gb.read_memory(0xFFFE, 0x100).len(), 2);
+        assert_eq!(
+            gb.dump_mem(0xFFEE, 0x14),
+            "ffee: 00 00 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d \nfffe: 0e 00 \n"
+        );
+    }
+
+    #[test]
+    fn test_code_profile() {
+        // NOP; JR -3 (back to the NOP)
+        let mut rom = vec![0; 0x8000];
+        rom[0x101..0x103].copy_from_slice(&[0x18, 0xFD]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        let mut sink = SampleBuffer::default();
+        gb.step(&mut FrameBuffer::default(), &mut sink);
+        assert!(gb.code_profile().is_none());
+
+        gb.set_code_profiling(true);
+        for _ in 0..100 {
+            gb.step(&mut FrameBuffer::default(), &mut sink);
+        }
+        let profile = gb.code_profile().unwrap();
+        assert_eq!(profile.instructions(), 100);
+        assert_eq!(profile.cycles(), 50 * 4 + 50 * 12);
+        assert_eq!(
+            gb.dump_hotspots(1),
+            "100 instructions, 800 cycles\n 75.0%  00:0101 600 cycles (50x)\tJR -3\n"
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        // CALL $0203; NOP; NOP
+        let mut rom = vec![0; 0x8000];
+        rom[0x200..0x203].copy_from_slice(&[0xCD, 0x03, 0x02]);
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom), config).unwrap();
+        gb.set_symbols(Symbols::parse("00:0200 Start\n00:0202 Func").unwrap());
+
+        assert!(gb
+            .disassemble(0x200)
+            .starts_with("Start:\n0200\tCALL $0203\t; Func+$1\n0203\tNOP\n"));
+        gb.set_pc(0x201);
+        assert_eq!(gb.dump_cpu(), format!("{} (Start+$1)", gb.cpu_state()));
+        assert_eq!(gb.symbolize(0x4000), None);
+        assert_eq!(gb.describe_addr(0x0201), "$0201 (Start+$1)");
+        assert_eq!(gb.describe_addr(0x4000), "$4000");
+    }
+
+    #[test]
+    fn test_serial_output() {
+        let mut gb = GameBoy::new(
+            Cartridge::from_bytes(vec![0; 0x8000]),
+            MachineConfig::default(),
+        )
+        .unwrap();
+        for b in b"Passed" {
+            gb.bus.write_byte(0xFF01, *b);
+            // start a transfer using the internal clock
+            gb.bus.write_byte(0xFF02, 0x81);
+        }
+        // no transfer without the start bit
+        gb.bus.write_byte(0xFF02, 0x01);
+        assert_eq!(gb.serial_output(), "Passed");
+
+        assert_eq!(gb.take_serial_output(), b"Passed");
+        assert!(gb.take_serial_output().is_empty());
+    }
 }
@@ -1,12 +1,38 @@
 use std::{
-    fmt::Debug,
+    fmt::{Debug, Write},
     ops::{Deref, DerefMut},
+    str::FromStr,
 };
 
+use anyhow::{anyhow, ensure, Context, Result};
 use bitvec::prelude::*;
 use log::trace;
 
-use crate::{interrupt::InterruptFlag, FrameSink, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::{
+    interrupt::InterruptFlag,
+    io_regs::{Lcdc, Stat, BGP, LCDC, LY, LYC, OBP0, OBP1, SCX, SCY, STAT, WX, WY},
+    state::{StateReader, StateWriter, Stateful},
+    timing::{self, DOTS_PER_LINE, LINES_PER_FRAME, VISIBLE_LINES},
+    DirtyLines, FrameSink, PaletteId, FRAME_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+
+/// Dot of a visible line at which mode 3 (drawing) starts, after the OAM scan
+const MODE3_START: u32 = 80;
+/// Length of mode 3 without any penalty
+const MODE3_MIN_DOTS: u32 = 172;
+/// Penalty for fetching a sprite, without the wait for the background fetcher
+const SPRITE_FETCH_DOTS: u32 = 6;
+/// Penalty when the window starts on the line, as the background fetcher restarts
+const WINDOW_START_DOTS: u32 = 6;
+/// The OAM scan looks at one of the 40 OAM entries every 2 dots
+const DOTS_PER_OAM_ENTRY: u32 = 2;
+/// Maximum number of sprites selected by the OAM scan for a line
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+/// Last line of the frame, which LY only reports for a few dots
+const LAST_LINE: u32 = LINES_PER_FRAME - 1;
+/// Number of dots of the last line during which LY reads 153
+const LAST_LINE_LY_DOTS: u32 = 4;
 
 const VRAM_START: u16 = 0x8000;
 const OAM_START: u16 = 0xFE00;
@@ -15,17 +41,48 @@ const VRAM_TILE_DATA_BLOCK_0_ADDR: u16 = 0x8000;
 // const VRAM_TILE_DATA_BLOCK_1_ADDR: u16 = 0x8800;
 const VRAM_TILE_DATA_BLOCK_2_ADDR: u16 = 0x9000;
 
-const LCDC_REG: u16 = 0xFF40;
-const STAT_REG: u16 = 0xFF41;
-const SCY_REG: u16 = 0xFF42;
-const SCX_REG: u16 = 0xFF43;
-const LY_REG: u16 = 0xFF44;
-const LYC_REG: u16 = 0xFF45;
-const BGP_REG: u16 = 0xFF47;
-const OBP0_REG: u16 = 0xFF48;
-const OBP1_REG: u16 = 0xFF49;
-const WY_REG: u16 = 0xFF4A;
-const WX_REG: u16 = 0xFF4B;
+/// Colour of the viewport outline drawn on top of the tilemaps
+const VIEWPORT_COLOR: (u8, u8, u8) = (0xff, 0x00, 0x00);
+
+/// An offscreen RGB image, used to visualize the content of the VRAM
+#[derive(Debug, Clone)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    /// Pixels, row by row
+    pub pixels: Vec<(u8, u8, u8)>,
+}
+
+impl RgbImage {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![(0, 0, 0); width * height],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: (u8, u8, u8)) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    /// Pixels as packed RGB bytes
+    pub fn to_rgb_bytes(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|(r, g, b)| [*r, *g, *b])
+            .collect()
+    }
+}
+
+/// One of the two 32x32 tilemaps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileMap {
+    /// The tilemap currently used by the background (LCDC.3)
+    Background,
+    /// The tilemap currently used by the window (LCDC.6)
+    Window,
+}
 
 #[derive(Debug)]
 pub struct Gfx {
@@ -35,12 +92,19 @@ pub struct Gfx {
     /// Represents the LCD itself, i.e. where pixels are actually written.
     ///
     /// Each pixel is in RGBA format.
-    lcd: Box<[(u8, u8, u8)]>,
+    lcd: Box<[u8]>,
+    /// Lines of `lcd` that changed since the last frame sent to the frame sink
+    dirty_lines: DirtyLines,
 
     /// Number of clock cycles since we began rendering the current frame
     dots: usize,
+    /// Set when a register that affects the STAT interrupt line is written, as the next dot may
+    /// then request an interrupt
+    stat_changed: bool,
     running_mode: Mode,
     line_drawing_state: LineDrawingState,
+    /// Sprites selected by the OAM scan for the current line, in OAM order
+    line_sprites: Vec<Sprite>,
 
     // LCDC individual flags:
     /// LCDC.7
@@ -64,6 +128,12 @@ pub struct Gfx {
     scy: u8,
     /// SCX (Scroll X)
     scx: u8,
+    /// SCY as latched at the start of mode 3 for the current line
+    line_scy: u8,
+    /// SCX as latched at the start of mode 3 for the current line
+    line_scx: u8,
+    /// Length of mode 3 on the current line, which depends on SCX, the sprites and the window
+    mode3_dots: u32,
 
     /// LY (LCD Y Coordinate) == line currently being drawn
     ly: u8,
@@ -93,9 +163,24 @@ pub struct Gfx {
     obp0: Palette,
     /// OBJ Palette 1
     obp1: Palette,
+    /// Actual colours of the 4 shades
+    dmg_palette: DmgPalette,
 
     // Window internal line counter
     window_internal_line_counter: u8,
+
+    /// Events waiting to be sent to the frame sink
+    pending_events: Vec<LcdEvent>,
+    /// Whether a complete frame is waiting to be sent to the frame sink
+    frame_ready: bool,
+    /// If set, the frame sink also receives the lines as they are drawn, in batches of this many
+    /// lines
+    lines_per_update: Option<u8>,
+
+    /// Time spent in each mode during the current frame
+    current_mode_stats: Box<ModeStats>,
+    /// Time spent in each mode during the last complete frame
+    mode_stats: Box<ModeStats>,
 }
 
 impl Gfx {
@@ -103,10 +188,13 @@ impl Gfx {
         Self {
             vram: vec![0; 8 * 1024].into_boxed_slice(),
             oam_ram: vec![0; 0xA0].into_boxed_slice(),
-            lcd: vec![(0, 0, 0); SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
+            lcd: vec![0; FRAME_SIZE].into_boxed_slice(),
+            dirty_lines: DirtyLines::default(),
             dots: 0,
+            stat_changed: false,
             running_mode: Mode::Mode2,
             line_drawing_state: LineDrawingState::Idle,
+            line_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
             // TODO should it be exploded into individual flags?
             lcd_and_ppu_enabled: false,
             window_tile_map_area: false,
@@ -118,9 +206,13 @@ impl Gfx {
             bg_and_window_enable: false,
             scy: 0,
             scx: 0,
+            line_scy: 0,
+            mode3_dots: MODE3_MIN_DOTS,
+            line_scx: 0,
             bgp: Palette([Color::White; 4]),
             obp0: Palette([Color::White; 4]),
             obp1: Palette([Color::White; 4]),
+            dmg_palette: DmgPalette::default(),
             ly: 0,
             lyc: 0,
             wy: 0,
@@ -134,6 +226,11 @@ impl Gfx {
             stat_vblank_active: false,
             stat_hblank_active: false,
             window_internal_line_counter: 0,
+            pending_events: Vec::new(),
+            frame_ready: false,
+            lines_per_update: None,
+            current_mode_stats: Box::default(),
+            mode_stats: Box::default(),
         }
     }
 
@@ -160,10 +257,14 @@ impl Gfx {
         }
     }
 
-    pub fn read_oam(&self, addr: u16) -> u8 {
-        if !self.lcd_and_ppu_enabled
+    /// Whether the CPU can access OAM, i.e. the PPU isn't using it (during modes 2 and 3).
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_and_ppu_enabled
             || (self.running_mode != Mode::Mode2 && self.running_mode != Mode::Mode3)
-        {
+    }
+
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        if self.oam_accessible() {
             self.oam_ram[(addr - OAM_START) as usize]
         } else {
             0xff
@@ -171,54 +272,51 @@ impl Gfx {
     }
 
     pub fn write_oam(&mut self, addr: u16, b: u8) {
-        if !self.lcd_and_ppu_enabled
-            || (self.running_mode != Mode::Mode2 && self.running_mode != Mode::Mode3)
-        {
+        if self.oam_accessible() {
             self.oam_ram[(addr - OAM_START) as usize] = b;
         }
     }
 
     pub fn read_reg(&self, addr: u16) -> u8 {
-        if addr == LCDC_REG {
-            let mut lcdc = 0u8;
-            let bits = lcdc.view_bits_mut::<Lsb0>();
-            bits.set(7, self.lcd_and_ppu_enabled);
-            bits.set(6, self.window_tile_map_area);
-            bits.set(5, self.window_enable);
-            bits.set(4, self.bg_and_window_tile_data_area);
-            bits.set(3, self.bg_tile_map_area);
-            bits.set(2, self.obj_size);
-            bits.set(1, self.obj_enabled);
-            bits.set(0, self.bg_and_window_enable);
-
-            lcdc
-        } else if addr == STAT_REG {
+        if addr == LCDC {
+            let mut lcdc = Lcdc::empty();
+            lcdc.set(Lcdc::LCD_ENABLE, self.lcd_and_ppu_enabled);
+            lcdc.set(Lcdc::WINDOW_TILE_MAP, self.window_tile_map_area);
+            lcdc.set(Lcdc::WINDOW_ENABLE, self.window_enable);
+            lcdc.set(Lcdc::BG_WINDOW_TILE_DATA, self.bg_and_window_tile_data_area);
+            lcdc.set(Lcdc::BG_TILE_MAP, self.bg_tile_map_area);
+            lcdc.set(Lcdc::OBJ_SIZE, self.obj_size);
+            lcdc.set(Lcdc::OBJ_ENABLE, self.obj_enabled);
+            lcdc.set(Lcdc::BG_WINDOW_ENABLE, self.bg_and_window_enable);
+
+            lcdc.bits()
+        } else if addr == STAT {
             // FF41 STAT
             self.stat()
-        } else if addr == SCY_REG {
+        } else if addr == SCY {
             // FF42 SCY
             self.scy
-        } else if addr == SCX_REG {
+        } else if addr == SCX {
             // FF43 SCX
             self.scx
-        } else if addr == LY_REG {
+        } else if addr == LY {
             // FF44 LY
-            self.ly
-        } else if addr == LYC_REG {
+            self.current_ly()
+        } else if addr == LYC {
             // FF45 LYC
             self.lyc
-        } else if addr == WY_REG {
+        } else if addr == WY {
             // FF4A WY
             self.wy
-        } else if addr == WX_REG {
+        } else if addr == WX {
             // FF4B WX
             self.wx
-        } else if addr == BGP_REG {
+        } else if addr == BGP {
             // FF47 - BGP (BG Palette Data)
             get_palette_as_byte(&self.bgp)
-        } else if addr == OBP0_REG {
+        } else if addr == OBP0 {
             get_palette_as_byte(&self.obp0)
-        } else if addr == OBP1_REG {
+        } else if addr == OBP1 {
             get_palette_as_byte(&self.obp1)
         } else {
             // CGB-only registers, so just ignore for now
@@ -228,124 +326,233 @@ impl Gfx {
     }
 
     pub fn write_reg(&mut self, addr: u16, b: u8) {
-        if addr == LCDC_REG {
+        if addr == LCDC || addr == STAT || addr == LYC {
+            self.stat_changed = true;
+        }
+        if addr == LCDC {
             let orig_lcd_state = self.lcd_and_ppu_enabled;
-            let bits = b.view_bits::<Lsb0>();
-            self.lcd_and_ppu_enabled = bits[7];
-            self.window_tile_map_area = bits[6];
-            self.window_enable = bits[5];
-            self.bg_and_window_tile_data_area = bits[4];
-            self.bg_tile_map_area = bits[3];
-            self.obj_size = bits[2];
-            self.obj_enabled = bits[1];
-            self.bg_and_window_enable = bits[0];
+            let lcdc = Lcdc::from_bits_truncate(b);
+            self.lcd_and_ppu_enabled = lcdc.contains(Lcdc::LCD_ENABLE);
+            self.window_tile_map_area = lcdc.contains(Lcdc::WINDOW_TILE_MAP);
+            self.window_enable = lcdc.contains(Lcdc::WINDOW_ENABLE);
+            self.bg_and_window_tile_data_area = lcdc.contains(Lcdc::BG_WINDOW_TILE_DATA);
+            self.bg_tile_map_area = lcdc.contains(Lcdc::BG_TILE_MAP);
+            self.obj_size = lcdc.contains(Lcdc::OBJ_SIZE);
+            self.obj_enabled = lcdc.contains(Lcdc::OBJ_ENABLE);
+            self.bg_and_window_enable = lcdc.contains(Lcdc::BG_WINDOW_ENABLE);
             trace!("LCDC reg = 0b{:b}", b);
             if orig_lcd_state && !self.lcd_and_ppu_enabled {
                 trace!("LCD turned OFF!");
+                self.pending_events.push(LcdEvent::Power(false));
             } else if !orig_lcd_state && self.lcd_and_ppu_enabled {
                 trace!("LCD turned ON!");
+                self.pending_events.push(LcdEvent::Power(true));
+                // The sink displayed something else while the LCD was off
+                self.dirty_lines = DirtyLines::all();
             }
-        } else if addr == STAT_REG {
+        } else if addr == STAT {
             self.set_stat(b);
-        } else if addr == SCY_REG {
+        } else if addr == SCY {
             // FF42 SCY
             self.scy = b;
-        } else if addr == SCX_REG {
+        } else if addr == SCX {
             // FF43 SCX
             self.scx = b;
-        } else if addr == LY_REG {
-            // FF44 LY
-            self.ly = b;
-        } else if addr == LYC_REG {
+        } else if addr == LY {
+            // FF44 LY is read-only
+            trace!("Ignoring write to LY: {:02x}", b);
+        } else if addr == LYC {
             // FF45 LYC
             self.lyc = b;
-        } else if addr == WY_REG {
+        } else if addr == WY {
             // FF4A WY
             self.wy = b;
             trace!("Setting WY={}", self.wy);
-        } else if addr == WX_REG {
+        } else if addr == WX {
             // FF4B WX
             self.wx = b;
             trace!("Setting WX={}", self.wx);
-        } else if addr == BGP_REG {
+        } else if addr == BGP {
             // FF47 - BGP (BG Palette Data)
-            set_palette_data(&mut self.bgp, b);
-        } else if addr == OBP0_REG {
-            set_palette_data(&mut self.obp0, b);
-        } else if addr == OBP1_REG {
-            set_palette_data(&mut self.obp1, b);
+            self.set_palette(PaletteId::Bg, b);
+        } else if addr == OBP0 {
+            self.set_palette(PaletteId::Obj0, b);
+        } else if addr == OBP1 {
+            self.set_palette(PaletteId::Obj1, b);
         } else {
             // CGB-only registers, so just ignore for now
             // warn!("unimplemented register 0x{:04x}", addr);
         }
     }
 
+    fn set_palette(&mut self, id: PaletteId, b: u8) {
+        let palette = match id {
+            PaletteId::Bg => &mut self.bgp,
+            PaletteId::Obj0 => &mut self.obp0,
+            PaletteId::Obj1 => &mut self.obp1,
+        };
+        if get_palette_as_byte(palette) != b {
+            set_palette_data(palette, b);
+            self.pending_events.push(LcdEvent::Palette(id, b));
+        }
+    }
+
     /// Return the value of the STAT register (FF41)
     fn stat(&self) -> u8 {
-        let mut byte = 0b10000000_u8; // bit 7 is always 1
-        let bits = byte.view_bits_mut::<Lsb0>();
+        let mut stat = Stat::empty();
 
         // interrupt sources
-        bits.set(6, self.stat_lyc_eq_ly_itr_source);
-        bits.set(5, self.stat_oam_itr_source);
-        bits.set(4, self.stat_vblank_itr_source);
-        bits.set(3, self.stat_hblank_itr_source);
+        stat.set(Stat::LYC_INTERRUPT, self.stat_lyc_eq_ly_itr_source);
+        stat.set(Stat::OAM_INTERRUPT, self.stat_oam_itr_source);
+        stat.set(Stat::VBLANK_INTERRUPT, self.stat_vblank_itr_source);
+        stat.set(Stat::HBLANK_INTERRUPT, self.stat_hblank_itr_source);
 
-        bits.set(2, self.ly == self.lyc);
+        stat.set(Stat::LYC_EQ_LY, self.current_ly() == self.lyc);
 
-        let mode = self.running_mode as u8;
-        let mode_bits = mode.view_bits::<Lsb0>();
-        bits.set(1, mode_bits[1]);
-        bits.set(0, mode_bits[0]);
+        // The PPU reports mode 0 while the LCD is off
+        let mode = if self.lcd_and_ppu_enabled {
+            self.running_mode as u8
+        } else {
+            0
+        };
+
+        // bit 7 is always 1
+        0b1000_0000 | stat.bits() | (mode & Stat::MODE.bits())
+    }
 
-        bits.load()
+    /// Value of LY as seen by the CPU, which is always 0 while the LCD is off.
+    fn current_ly(&self) -> u8 {
+        if self.lcd_and_ppu_enabled {
+            self.ly
+        } else {
+            0
+        }
     }
 
+    /// Write to STAT: only the interrupt sources (bits 3-6) are writable, the LYC=LY flag and the
+    /// mode are read-only.
     fn set_stat(&mut self, stat: u8) {
-        let bits = stat.view_bits::<Lsb0>();
-        self.stat_lyc_eq_ly_itr_source = bits[6];
-        self.stat_oam_itr_source = bits[5];
-        self.stat_vblank_itr_source = bits[4];
-        self.stat_hblank_itr_source = bits[3];
+        let stat = Stat::from_bits_truncate(stat);
+        self.stat_lyc_eq_ly_itr_source = stat.contains(Stat::LYC_INTERRUPT);
+        self.stat_oam_itr_source = stat.contains(Stat::OAM_INTERRUPT);
+        self.stat_vblank_itr_source = stat.contains(Stat::VBLANK_INTERRUPT);
+        self.stat_hblank_itr_source = stat.contains(Stat::HBLANK_INTERRUPT);
     }
 
-    pub(crate) fn dots(&mut self, cycles: u8, frame_sink: &mut dyn FrameSink) -> InterruptFlag {
+    pub(crate) fn dots(&mut self, cycles: u32) -> InterruptFlag {
         let mut interrupt = InterruptFlag::empty();
         for _ in 0..cycles {
-            interrupt |= self.dot(frame_sink);
+            interrupt |= self.dot();
         }
 
         interrupt
     }
 
+    /// Number of dots until the PPU next does something the CPU could notice without reading its
+    /// registers, i.e. draw a line or request an interrupt. This is either the next change of
+    /// mode or the next line, where LY=LYC is checked.
+    pub(crate) fn dots_until_event(&self) -> u32 {
+        if self.stat_changed {
+            return 1;
+        }
+        let (line, line_dot) = timing::dots_to_line(self.dots as u32);
+        if line == LAST_LINE && line_dot < LAST_LINE_LY_DOTS {
+            // LY changes to 0, which may trigger a LYC=0 interrupt
+            return LAST_LINE_LY_DOTS - line_dot;
+        }
+        // Modes 3 and 0 start at dots 80 and (at least) 252 of the visible lines
+        [MODE3_START, MODE3_START + self.mode3_dots, DOTS_PER_LINE]
+            .into_iter()
+            .find(|&dot| dot > line_dot)
+            .unwrap_or(DOTS_PER_LINE)
+            - line_dot
+    }
+
+    /// What is on the LCD at the moment, in RGBA format: the last frame, with the lines of the
+    /// current one drawn so far.
+    pub(crate) fn screen(&self) -> &[u8] {
+        &self.lcd
+    }
+
+    /// The PPU's position in the frame and its internal state.
+    pub(crate) fn ppu_state(&self) -> PpuState {
+        let (_, dot) = timing::dots_to_line(self.dots as u32);
+        PpuState {
+            enabled: self.lcd_and_ppu_enabled,
+            mode: self.running_mode as u8,
+            ly: self.ly,
+            lyc: self.lyc,
+            dot: dot as u16,
+            window_line: self.window_internal_line_counter,
+        }
+    }
+
+    /// Send the pending events and the last complete frame (if any) to the frame sink.
+    pub(crate) fn flush(&mut self, frame_sink: &mut dyn FrameSink) {
+        for event in self.pending_events.drain(..) {
+            match event {
+                LcdEvent::Power(enabled) => frame_sink.lcd_power_changed(enabled),
+                LcdEvent::Palette(id, data) => frame_sink.palette_changed(id, data),
+                LcdEvent::Lines(first, last) => {
+                    let (first, last) = (first as usize, last as usize);
+                    frame_sink.push_lines(
+                        first,
+                        &self.lcd[first * SCREEN_WIDTH * 4..(last + 1) * SCREEN_WIDTH * 4],
+                    );
+                }
+            }
+        }
+        if self.frame_ready {
+            frame_sink.dirty_lines(&self.dirty_lines);
+            self.dirty_lines.clear();
+            frame_sink.push_frame(&self.lcd);
+            self.frame_ready = false;
+        }
+    }
+
     /// Run the graphics subsystem for one clock cycle (or _dot_)
-    fn dot(&mut self, frame_sink: &mut dyn FrameSink) -> InterruptFlag {
+    fn dot(&mut self) -> InterruptFlag {
         let mut interrupts = InterruptFlag::empty();
         let stat_line = self.stat_line();
+        self.stat_changed = false;
 
         self.dots += 1;
-        // Each scanline takes 456 dots
-        let mut scanline = (self.dots / 456) as u8;
-        let line_dot = (self.dots % 456) as u16;
+        let (scanline, line_dot) = timing::dots_to_line(self.dots as u32);
+        let (mut scanline, line_dot) = (scanline as u8, line_dot as u16);
 
-        // A whole frame (drawing + VSync) is 153 scanlines
-        if scanline > 153 {
+        // A whole frame (drawing + VSync) is 154 scanlines
+        if scanline as u32 >= LINES_PER_FRAME {
             self.dots = line_dot as usize;
             scanline = 0;
+            std::mem::swap(&mut self.current_mode_stats, &mut self.mode_stats);
+            *self.current_mode_stats = ModeStats::default();
         }
-        self.ly = scanline;
+        // LY only reads 153 at the very start of the last line, and then 0 until the end of the
+        // frame, so LY=LYC matches 0 for a whole line (and a bit) and LYC=0 interrupts are
+        // requested during line 153
+        self.ly = if scanline as u32 == LAST_LINE && line_dot as u32 >= LAST_LINE_LY_DOTS {
+            0
+        } else {
+            scanline
+        };
         self.stat_lyc_eq_ly_active = self.ly == self.lyc;
 
-        if scanline > 143 {
+        if scanline as u32 >= VISIBLE_LINES {
             self.running_mode = Mode::Mode1;
         } else {
-            self.running_mode = match line_dot {
-                0..=79 => Mode::Mode2,
-                80..=251 => Mode::Mode3,
-                252..=455 => Mode::Mode0,
-                // unreachable as we pattern match on the result of a modulo 456 operation
-                _ => unreachable!("This shouldn't happen!"),
-            }
+            // The length of mode 3 is worked out when it starts, and it's at least 172 dots
+            let line_dot = line_dot as u32;
+            self.running_mode = if line_dot < MODE3_START {
+                Mode::Mode2
+            } else if line_dot < MODE3_START + self.mode3_dots {
+                Mode::Mode3
+            } else {
+                Mode::Mode0
+            };
+        }
+
+        if self.lcd_and_ppu_enabled {
+            self.current_mode_stats.lines[scanline as usize][self.running_mode as usize] += 1;
         }
 
         self.stat_hblank_active = self.running_mode == Mode::Mode0;
@@ -362,7 +569,7 @@ impl Gfx {
             Mode::Mode1 => {
                 if self.line_drawing_state == LineDrawingState::Idle {
                     if self.lcd_and_ppu_enabled {
-                        frame_sink.push_frame(&self.lcd);
+                        self.frame_ready = true;
                     }
                     interrupts |= InterruptFlag::VBLANK;
                     self.line_drawing_state = LineDrawingState::FramePushed;
@@ -375,15 +582,22 @@ impl Gfx {
                 if self.line_drawing_state == LineDrawingState::Idle
                     || self.line_drawing_state == LineDrawingState::FramePushed
                 {
-                    // OAM scan
                     self.line_drawing_state = LineDrawingState::OamScan;
+                    self.line_sprites.clear();
+                }
+                // Each entry is checked over 2 dots
+                let line_dot = line_dot as u32;
+                if line_dot % DOTS_PER_OAM_ENTRY == DOTS_PER_OAM_ENTRY - 1 {
+                    self.scan_oam_entry((line_dot / DOTS_PER_OAM_ENTRY) as usize);
                 }
             }
             // Drawing
             Mode::Mode3 => {
                 if self.line_drawing_state == LineDrawingState::OamScan {
                     self.line_drawing_state = LineDrawingState::Drawing;
+                    self.latch_scroll();
                     self.draw_scan_line();
+                    self.queue_lines();
                 }
             }
         }
@@ -404,6 +618,48 @@ impl Gfx {
         }
     }
 
+    /// Latch the scroll registers for the line about to be drawn.
+    ///
+    /// The whole line is rendered at once at the start of mode 3, so the scroll values the CPU
+    /// writes while the line is being drawn only take effect on the next line. Games doing raster
+    /// effects (e.g. parallax scrolling) usually update SCX/SCY during HBlank or from a STAT
+    /// interrupt, which this handles correctly; mid-line changes can't be rendered until we have a
+    /// pixel FIFO.
+    fn latch_scroll(&mut self) {
+        self.line_scx = self.scx;
+        self.line_scy = self.scy;
+    }
+
+    /// Set the colours used to display the 4 shades. This takes effect from the next line drawn.
+    pub(crate) fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+    }
+
+    /// Time spent in each mode during the last complete frame.
+    pub(crate) fn mode_stats(&self) -> &ModeStats {
+        &self.mode_stats
+    }
+
+    /// Set the number of lines to batch together when delivering lines to the frame sink as they
+    /// are drawn, or `None` to only deliver complete frames.
+    pub(crate) fn set_lines_per_update(&mut self, lines: Option<u8>) {
+        self.lines_per_update = lines.map(|n| n.clamp(1, SCREEN_HEIGHT as u8));
+    }
+
+    /// Notify the frame sink of the lines drawn so far if a batch is complete.
+    fn queue_lines(&mut self) {
+        let Some(n) = self.lines_per_update else {
+            return;
+        };
+        if !self.lcd_and_ppu_enabled || self.ly as usize >= SCREEN_HEIGHT {
+            return;
+        }
+        if (self.ly + 1).is_multiple_of(n) || self.ly as usize == SCREEN_HEIGHT - 1 {
+            let first = self.ly - self.ly % n;
+            self.pending_events.push(LcdEvent::Lines(first, self.ly));
+        }
+    }
+
     fn draw_scan_line(&mut self) {
         let mut drawn_from_window = false;
         let bg_tilemap_area = if self.bg_tile_map_area {
@@ -416,91 +672,73 @@ impl Gfx {
         } else {
             0x9800
         };
-        // Get the first 10 sprites that match the current line
-        let sprites = self.get_sprites_for_scanline(self.ly);
+        // The sprites with the smallest X are drawn on top, then the first ones in OAM (the sort
+        // is stable)
+        let mut sprites = std::mem::take(&mut self.line_sprites);
+        sprites.sort_by_key(|s| s.x);
 
         // Render a line of pixels
         for x in 0..SCREEN_WIDTH as u8 {
             // Coordinates in "LCD space" (i.e 160x144)
             let (lcd_x, lcd_y) = (x, self.ly);
-            // Coordinates in "Background area" space (i.e 256x256)
-            let (bg_x, bg_y, tilemap_area) = if self.bg_and_window_enable
-                && self.window_enable
-                && lcd_x + 7 >= self.wx
-                && lcd_y >= self.wy
-            {
-                // We're in the window
-                drawn_from_window = true;
-                (
-                    lcd_x + 7 - self.wx,
-                    self.window_internal_line_counter,
-                    win_tilemap_area,
-                )
-            } else {
-                // we're in the background
 
-                (lcd_x + self.scx, lcd_y + self.scy, bg_tilemap_area)
+            // Background / window pixel. On the DMG, LCDC.0 turns off both the background and the
+            // window (regardless of LCDC.5): they're blank (white, and colour 0 as far as sprite
+            // priority is concerned), and only the sprites are displayed.
+            let (color_byte, bg_color) = if self.bg_and_window_enable {
+                let in_window = self.window_enable && lcd_x + 7 >= self.wx && lcd_y >= self.wy;
+                // Coordinates in "Background area" space (i.e 256x256)
+                let (bg_x, bg_y, tilemap_area) = if in_window {
+                    drawn_from_window = true;
+                    (
+                        lcd_x + 7 - self.wx,
+                        self.window_internal_line_counter,
+                        win_tilemap_area,
+                    )
+                } else {
+                    (
+                        lcd_x.wrapping_add(self.line_scx),
+                        lcd_y.wrapping_add(self.line_scy),
+                        bg_tilemap_area,
+                    )
+                };
+                // Coordinates in "tilemap space" (i.e. 32x32)
+                let (tilemap_x, tilemap_y) = (bg_x / 8, bg_y / 8);
+                let tile_id = self
+                    .read_vram_internal(tilemap_area + (tilemap_y as u16 * 32 + tilemap_x as u16));
+                // Coordinates in "tile space" (i.e. which pixel of an 8x8 tile to draw)
+                let color_byte =
+                    self.tile_color_index(self.bg_tile_addr(tile_id), bg_x % 8, bg_y % 8);
+
+                (color_byte, self.bgp[color_byte as usize])
+            } else {
+                (0, Color::White)
             };
-            // Coordinates in "tilemap space" (i.e. 32x32)
-            let (tilemap_x, tilemap_y) = (bg_x / 8, bg_y / 8);
-
-            let tile_id =
-                self.read_vram_internal(tilemap_area + (tilemap_y as u16 * 32 + tilemap_x as u16));
-
-            // Now that we've got the tileid, look up the tile data in the appropriate location.
-
-            // Coordinates in "tile space" (i.e. which pixel of an 8x8 tile to draw)
-            let (tile_col, tile_row) = (bg_x % 8, bg_y % 8);
 
-            let mut tile_offset: u16 = if self.bg_and_window_tile_data_area {
-                let base = VRAM_TILE_DATA_BLOCK_0_ADDR;
-                // treat tile id as unsigned
-                base + 16 * tile_id as u16
+            // The first sprite (in order of priority) with an opaque pixel here, if any. The
+            // sprites with a lower priority are hidden, even if that one ends up behind the
+            // background.
+            let sprite_pixel = if self.obj_enabled {
+                sprites.iter().find_map(|s| {
+                    self.get_sprite_pixel(s, lcd_x, lcd_y)
+                        .map(|(index, color)| (index, color, s.bg_has_priority()))
+                })
             } else {
-                let base = VRAM_TILE_DATA_BLOCK_2_ADDR;
-                // treat tile id as *signed*, so sign-extend it to 16 bits
-                let signed_id = tile_id as i8 as i16;
-                let offset = (16 * signed_id) as u16;
-
-                base.wrapping_add(offset)
-                // (base + 16 * signed_id as i16) as u16
+                None
             };
-            tile_offset += 2 * tile_row as u16;
-
-            let lo_byte = self.read_vram_internal(tile_offset);
-            let hi_byte = self.read_vram_internal(tile_offset + 1);
 
-            let mut color_byte = 0u8;
-            if self.bg_and_window_enable {
-                let color_bits = color_byte.view_bits_mut::<Lsb0>();
-                // Use Msb0 order here as pixel 0 is the leftmost bit (bit 7).
-                color_bits.set(1, hi_byte.view_bits::<Msb0>()[tile_col as usize]);
-                color_bits.set(0, lo_byte.view_bits::<Msb0>()[tile_col as usize]);
-            }
-            // Background / window pixel
-            // let color = if self.bg_and_window_enable {
-            //     self.bgp[color_byte as usize]
-            // } else {
-            //     Color::White
-            // };
-
-            // Potential sprite pixel
-            let sprite_pixel_and_bg_has_priority = sprites.iter().find_map(|s| {
-                self.get_sprite_pixel(s, lcd_x, lcd_y)
-                    .map(|p| (p, s.bg_has_priority()))
-            });
-
-            let final_color = match (self.obj_enabled, sprite_pixel_and_bg_has_priority) {
-                (true, Some((p, true))) => {
-                    if color_byte != 0 {
-                        self.bgp[color_byte as usize]
-                    } else {
-                        p
-                    }
+            let final_color = match sprite_pixel {
+                Some((sprite_index, sprite_color, behind_bg))
+                    if visible_layer(
+                        self.bg_and_window_enable,
+                        color_byte,
+                        sprite_index,
+                        behind_bg,
+                    ) == Layer::Sprite =>
+                {
+                    sprite_color
                 }
-                (true, Some((p, false))) => p,
-                (true, None) => self.bgp[color_byte as usize],
-                (false, _) => self.bgp[color_byte as usize],
+                _ => bg_color,
             };
 
             self.write_pixel(x, self.ly, final_color);
@@ -509,6 +747,163 @@ impl Gfx {
         if drawn_from_window {
             self.window_internal_line_counter += 1;
         }
+        self.mode3_dots = self.mode3_length(&sprites, drawn_from_window);
+        self.line_sprites = sprites;
+    }
+
+    /// Number of dots taken to draw the current line.
+    ///
+    /// On top of the 172 dots it takes at least, the PPU discards the first SCX % 8 pixels,
+    /// restarts the background fetcher when the window starts, and pauses to fetch each sprite. The
+    /// sprite penalty follows Pan Docs: 6 dots for the fetch itself, plus the time spent waiting
+    /// for the background fetcher, which only the first sprite over a given background tile pays.
+    fn mode3_length(&self, sprites: &[Sprite], window: bool) -> u32 {
+        let fine_scroll = (self.line_scx % 8) as u32;
+        let mut dots = MODE3_MIN_DOTS + fine_scroll;
+        if window {
+            dots += WINDOW_START_DOTS;
+        }
+        if self.obj_enabled {
+            let mut tiles_seen = Vec::with_capacity(sprites.len());
+            // Sprites off the right edge of the screen aren't fetched
+            for sprite in sprites.iter().filter(|s| s.x < 168) {
+                dots += SPRITE_FETCH_DOTS;
+                if sprite.x == 0 {
+                    dots += 5;
+                    continue;
+                }
+                let pixel = sprite.x as u32 + fine_scroll;
+                let tile = pixel / 8;
+                if !tiles_seen.contains(&tile) {
+                    tiles_seen.push(tile);
+                    dots += 5u32.saturating_sub(pixel % 8);
+                }
+            }
+        }
+        dots
+    }
+
+    /// Address of the data of the given BG/window tile, according to the addressing mode selected
+    /// by LCDC.4.
+    fn bg_tile_addr(&self, tile_id: u8) -> u16 {
+        if self.bg_and_window_tile_data_area {
+            let base = VRAM_TILE_DATA_BLOCK_0_ADDR;
+            // treat tile id as unsigned
+            base + 16 * tile_id as u16
+        } else {
+            let base = VRAM_TILE_DATA_BLOCK_2_ADDR;
+            // treat tile id as *signed*, so sign-extend it to 16 bits
+            let signed_id = tile_id as i8 as i16;
+            let offset = (16 * signed_id) as u16;
+
+            base.wrapping_add(offset)
+        }
+    }
+
+    /// Colour index (0-3) of the pixel at (x, y) in the tile whose data starts at `tile_addr`.
+    fn tile_color_index(&self, tile_addr: u16, x: u8, y: u8) -> u8 {
+        let lo_byte = self.read_vram_internal(tile_addr + 2 * y as u16);
+        let hi_byte = self.read_vram_internal(tile_addr + 2 * y as u16 + 1);
+        let bit = 7 - x;
+
+        ((hi_byte >> bit) & 1) << 1 | ((lo_byte >> bit) & 1)
+    }
+
+    /// Render the 384 tiles of the VRAM, as a grid of 16x24 tiles, using the BG palette.
+    pub fn render_tiles(&self) -> RgbImage {
+        let mut image = RgbImage::new(16 * 8, 24 * 8);
+        for tile in 0..384u16 {
+            let tile_addr = VRAM_TILE_DATA_BLOCK_0_ADDR + 16 * tile;
+            let (tile_x, tile_y) = ((tile % 16) as usize * 8, (tile / 16) as usize * 8);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let color = self.bgp[self.tile_color_index(tile_addr, x, y) as usize];
+                    image.set(
+                        tile_x + x as usize,
+                        tile_y + y as usize,
+                        color.as_rgba(&self.dmg_palette),
+                    );
+                }
+            }
+        }
+
+        image
+    }
+
+    /// Render the whole 256x256 area of one of the tilemaps, using the BG palette.
+    ///
+    /// The part of the map that is visible on screen is outlined.
+    pub fn render_tilemap(&self, map: TileMap) -> RgbImage {
+        let (map_area, viewport) = match map {
+            TileMap::Background => (
+                self.bg_tile_map_area,
+                Some((
+                    self.scx as usize,
+                    self.scy as usize,
+                    SCREEN_WIDTH,
+                    SCREEN_HEIGHT,
+                )),
+            ),
+            TileMap::Window => {
+                let (wx, wy) = (self.wx as usize, self.wy as usize);
+                let visible = wx < SCREEN_WIDTH + 7 && wy < SCREEN_HEIGHT;
+                // The window starts at WX-7, which can be off-screen to the left
+                let viewport = (
+                    7usize.saturating_sub(wx),
+                    0,
+                    (SCREEN_WIDTH + 7 - wx).min(SCREEN_WIDTH),
+                    SCREEN_HEIGHT - wy,
+                );
+                (self.window_tile_map_area, visible.then_some(viewport))
+            }
+        };
+        let map_base = if map_area { 0x9C00 } else { 0x9800 };
+
+        let mut image = RgbImage::new(256, 256);
+        for y in 0..=255u8 {
+            for x in 0..=255u8 {
+                let tile_id =
+                    self.read_vram_internal(map_base + (y as u16 / 8) * 32 + (x as u16 / 8));
+                let index = self.tile_color_index(self.bg_tile_addr(tile_id), x % 8, y % 8);
+                image.set(
+                    x as usize,
+                    y as usize,
+                    self.bgp[index as usize].as_rgba(&self.dmg_palette),
+                );
+            }
+        }
+
+        // Outline the viewport, which wraps around the edges of the map
+        if let Some((left, top, width, height)) = viewport {
+            for dx in 0..width {
+                let x = (left + dx) % 256;
+                image.set(x, top, VIEWPORT_COLOR);
+                image.set(x, (top + height - 1) % 256, VIEWPORT_COLOR);
+            }
+            for dy in 0..height {
+                let y = (top + dy) % 256;
+                image.set(left, y, VIEWPORT_COLOR);
+                image.set((left + width - 1) % 256, y, VIEWPORT_COLOR);
+            }
+        }
+
+        image
+    }
+
+    /// Render the current palettes (BGP, OBP0 and OBP1 from top to bottom) as 8x8 swatches.
+    pub fn render_palettes(&self) -> RgbImage {
+        let mut image = RgbImage::new(4 * 8, 3 * 8);
+        for (row, palette) in [&self.bgp, &self.obp0, &self.obp1].iter().enumerate() {
+            for (col, color) in palette.iter().enumerate() {
+                for y in 0..8 {
+                    for x in 0..8 {
+                        image.set(col * 8 + x, row * 8 + y, color.as_rgba(&self.dmg_palette));
+                    }
+                }
+            }
+        }
+
+        image
     }
 
     fn get_block0_tile_data(&self, tile_id: u8, tile_row: u8) -> (u8, u8) {
@@ -522,27 +917,42 @@ impl Gfx {
         (lo_byte, hi_byte)
     }
 
-    fn get_sprites_for_scanline(&self, y: u8) -> Vec<Sprite> {
-        let mut sprites = self
-            .oam_ram
-            .chunks(4)
-            .map(Sprite::new)
-            .filter(|sprite| sprite.matches_scanline(y, self.obj_size))
-            .collect::<Vec<_>>();
+    /// One step of the OAM scan (mode 2): select the given OAM entry for the current line if it
+    /// covers it. Like the hardware, this only looks at Y: the first 10 sprites on the line are
+    /// selected, even those that are off-screen horizontally and won't be drawn.
+    fn scan_oam_entry(&mut self, index: usize) {
+        let Some(data) = self.oam_ram.chunks(4).nth(index) else {
+            return;
+        };
+        let sprite = Sprite::new(data);
+        if self.line_sprites.len() < MAX_SPRITES_PER_LINE
+            && sprite.matches_scanline(self.ly, self.obj_size)
+        {
+            self.line_sprites.push(sprite);
+        }
+    }
 
-        // Order the sprites by smallest `x` as they have higher priority
-        sprites[..].sort_by(|s1, s2| s1.x.cmp(&s2.x));
-        // Return the first 10 sprites
-        sprites.into_iter().take(10).collect()
+    /// Colour index and colour of the pixel of the sprite at the given coordinates (in LCD space),
+    /// or `None` if it's transparent or outside of the sprite.
+    fn get_sprite_pixel(&self, sprite: &Sprite, x: u8, y: u8) -> Option<(u8, Color)> {
+        let (tile_x, tile_y) = sprite.get_tile_coordinates(x, y, self.obj_size)?;
+        let index = self.sprite_color_index(sprite, tile_x, tile_y);
+        self.get_sprite_color(sprite, index)
+            .map(|color| (index, color))
     }
 
-    fn get_sprite_pixel(&self, sprite: &Sprite, x: u8, y: u8) -> Option<Color> {
-        sprite
-            .get_tile_coordinates(x, y, self.obj_size)
-            .and_then(|(tile_x, tile_y)| self.get_sprite_color(sprite, tile_x, tile_y))
+    fn get_sprite_color(&self, sprite: &Sprite, color_byte: u8) -> Option<Color> {
+        // Color index 0 is transparent for sprites
+        if color_byte == 0 {
+            None
+        } else if sprite.obp1_palette() {
+            Some(self.obp1[color_byte as usize])
+        } else {
+            Some(self.obp0[color_byte as usize])
+        }
     }
 
-    fn get_sprite_color(&self, sprite: &Sprite, tile_x: u8, tile_y: u8) -> Option<Color> {
+    fn sprite_color_index(&self, sprite: &Sprite, tile_x: u8, tile_y: u8) -> u8 {
         let (lo_byte, hi_byte) = if self.obj_size {
             // upper tile
             let tile_idx_1 = sprite.tile_index & 0xFE;
@@ -564,57 +974,65 @@ impl Gfx {
         color_bits.set(1, lo_byte.view_bits::<Msb0>()[tile_x as usize]);
         color_bits.set(0, hi_byte.view_bits::<Msb0>()[tile_x as usize]);
 
-        // Color index 0 is transparent for sprites
-        if color_byte == 0 {
-            None
-        } else if sprite.obp1_palette() {
-            Some(self.obp1[color_byte as usize])
-        } else {
-            Some(self.obp0[color_byte as usize])
-        }
+        color_byte
     }
 
     fn write_pixel(&mut self, x: u8, y: u8, color: Color) {
-        self.lcd[y as usize * SCREEN_WIDTH + x as usize] = color.as_rgba();
+        let (r, g, b) = color.as_rgba(&self.dmg_palette);
+        let offset = (y as usize * SCREEN_WIDTH + x as usize) * 4;
+        let pixel = &mut self.lcd[offset..offset + 4];
+        if pixel != [r, g, b, 0xFF] {
+            pixel.copy_from_slice(&[r, g, b, 0xFF]);
+            self.dirty_lines.insert(y as usize);
+        }
     }
 
-    pub fn dump_oam(&self) {
-        println!("OAM:");
-        self.oam_ram
-            .chunks(4)
-            .map(Sprite::new)
-            .enumerate()
-            .for_each(|(i, s)| println!("  {:02}: {:?}", i, s));
+    pub fn dump_oam(&self) -> String {
+        let mut out = String::from("OAM:\n");
+        for (i, s) in self.oam_ram.chunks(4).map(Sprite::new).enumerate() {
+            let _ = writeln!(out, "  {:02}: {:?}", i, s);
+        }
+        out
     }
 
-    pub fn dump_sprite(&self, id: u8) {
+    pub fn dump_sprite(&self, id: u8) -> String {
+        let mut out = String::new();
         if id >= 40 {
-            return;
+            return out;
         }
 
         let offset = id as usize * 4;
         let data = &self.oam_ram[offset..offset + 4];
-        print!("Sprite data: ");
-        data.iter().for_each(|b| print!("{:02x} ", b));
-        println!("\n");
+        out.push_str("Sprite data: ");
+        data.iter().for_each(|b| {
+            let _ = write!(out, "{:02x} ", b);
+        });
+        out.push_str("\n\n");
 
         let sprite = Sprite::new(data);
 
         let height = if self.obj_size { 16 } else { 8 };
         for y in 0..height {
             for x in 0..8 {
-                let pixel = self.get_sprite_color(&sprite, x, y).unwrap_or(Color::White);
-                let (r, g, b) = pixel.as_rgba();
-                print!("{}", ansi_term::Color::RGB(r, g, b).paint("██"));
+                let index = self.sprite_color_index(&sprite, x, y);
+                let pixel = self
+                    .get_sprite_color(&sprite, index)
+                    .unwrap_or(Color::White);
+                let (r, g, b) = pixel.as_rgba(&self.dmg_palette);
+                let _ = write!(out, "{}", ansi_term::Color::RGB(r, g, b).paint("██"));
             }
-            println!();
+            out.push('\n');
         }
+        out
     }
 
-    pub fn dump_palettes(&self) {
-        println!("BGP:  {}", self.bgp.to_debug_str());
-        println!("OBP0: {}", self.obp0.to_debug_str());
-        println!("OBP1: {}", self.obp1.to_debug_str());
+    pub fn dump_palettes(&self) -> String {
+        format!(
+            "BGP:  {}\nOBP0: {}\nOBP1: {}\n",
+            self.bgp.to_debug_str(&self.dmg_palette),
+            self.obp0.to_debug_str(&self.dmg_palette),
+            self.obp1.to_debug_str(&self.dmg_palette)
+        )
     }
 
     /// Disable the LCD.
@@ -701,13 +1119,81 @@ impl Color {
         }
     }
 
-    fn as_rgba(&self) -> (u8, u8, u8) {
-        match self {
-            Color::White => (0xe0, 0xf8, 0xd0),     // #e0f8d0
-            Color::LightGray => (0x88, 0xc0, 0x70), // #88c070
-            Color::DarkGray => (0x30, 0x68, 0x50),  // #306850
-            Color::Black => (0x08, 0x18, 0x20),     // #081820
+    fn as_rgba(&self, palette: &DmgPalette) -> (u8, u8, u8) {
+        palette.0[self.as_u8() as usize]
+    }
+}
+
+/// The actual colours of the four shades of the DMG, from lightest to darkest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmgPalette(pub [(u8, u8, u8); 4]);
+
+impl DmgPalette {
+    /// The classic green shades
+    pub const GREEN: Self = Self([
+        (0xe0, 0xf8, 0xd0), // #e0f8d0
+        (0x88, 0xc0, 0x70), // #88c070
+        (0x30, 0x68, 0x50), // #306850
+        (0x08, 0x18, 0x20), // #081820
+    ]);
+    pub const GRAYSCALE: Self = Self([
+        (0xff, 0xff, 0xff),
+        (0xaa, 0xaa, 0xaa),
+        (0x55, 0x55, 0x55),
+        (0x00, 0x00, 0x00),
+    ]);
+    /// The shades of the Game Boy Pocket's screen
+    pub const POCKET: Self = Self([
+        (0xc4, 0xcf, 0xa1), // #c4cfa1
+        (0x8b, 0x95, 0x6d), // #8b956d
+        (0x4d, 0x53, 0x3c), // #4d533c
+        (0x1f, 0x1f, 0x1f), // #1f1f1f
+    ]);
+
+    /// The built-in palettes, with their names
+    pub const PRESETS: [(&'static str, Self); 3] = [
+        ("green", Self::GREEN),
+        ("grayscale", Self::GRAYSCALE),
+        ("pocket", Self::POCKET),
+    ];
+
+    /// Colour of the lightest shade, which is also what the screen looks like when it's off
+    pub fn lightest(&self) -> (u8, u8, u8) {
+        self.0[0]
+    }
+}
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        Self::GREEN
+    }
+}
+
+impl FromStr for DmgPalette {
+    type Err = anyhow::Error;
+
+    /// Parse either the name of one of the presets, or 4 comma-separated hex colours from
+    /// lightest to darkest (e.g. `e0f8d0,88c070,306850,081820`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, palette)) = Self::PRESETS.iter().find(|(name, _)| *name == s) {
+            return Ok(*palette);
         }
+
+        let colors = s
+            .split(',')
+            .map(|c| {
+                let c = c.trim().trim_start_matches('#');
+                ensure!(c.len() == 6, "Invalid colour: {}", c);
+                let rgb =
+                    u32::from_str_radix(c, 16).with_context(|| format!("Invalid colour: {}", c))?;
+                Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let colors: [(u8, u8, u8); 4] = colors
+            .try_into()
+            .map_err(|_| anyhow!("A palette needs exactly 4 colours"))?;
+
+        Ok(Self(colors))
     }
 }
 
@@ -723,6 +1209,25 @@ impl From<u8> for Color {
     }
 }
 
+/// The PPU's position in the frame and its internal state, e.g. to check the rendering in tests
+/// or show it in a debugger.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PpuState {
+    /// Whether the LCD and the PPU are switched on (LCDC bit 7)
+    pub enabled: bool,
+    /// Current mode: 0 (HBlank), 1 (VBlank), 2 (OAM scan) or 3 (drawing pixels)
+    pub mode: u8,
+    /// Line being drawn
+    pub ly: u8,
+    /// LY compare
+    pub lyc: u8,
+    /// Dot within the current line, from 0 to 455
+    pub dot: u16,
+    /// The window's internal line counter, i.e. the line of the window drawn next, which only
+    /// moves on when the window is actually drawn on a line
+    pub window_line: u8,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 enum Mode {
@@ -736,6 +1241,36 @@ enum Mode {
     Mode3 = 3,
 }
 
+/// Number of dots the PPU spent in each mode on each scanline of a frame.
+///
+/// Only the time during which the LCD is on is counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeStats {
+    /// Dots spent in modes 0 to 3, for each of the 154 scanlines
+    pub lines: [[u16; 4]; 154],
+}
+
+impl ModeStats {
+    /// Dots spent in modes 0 to 3 over the whole frame
+    pub fn totals(&self) -> [u32; 4] {
+        let mut totals = [0; 4];
+        for line in &self.lines {
+            for (total, dots) in totals.iter_mut().zip(line) {
+                *total += *dots as u32;
+            }
+        }
+        totals
+    }
+}
+
+impl Default for ModeStats {
+    fn default() -> Self {
+        Self {
+            lines: [[0; 4]; 154],
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum LineDrawingState {
     Idle,
@@ -744,6 +1279,61 @@ enum LineDrawingState {
     FramePushed,
 }
 
+/// Changes to the state of the LCD that the frame sink is notified of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LcdEvent {
+    Power(bool),
+    Palette(PaletteId, u8),
+    /// Lines from the first to the last one (inclusive) have been drawn
+    Lines(u8, u8),
+}
+
+/// The layers a pixel of the screen can come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    /// The background or the window
+    Background,
+    Sprite,
+}
+
+/// The layer shown when an opaque sprite pixel overlaps the background, by LCDC.0 (BG and window
+/// enable), the sprite's BG-over-OBJ attribute (bit 7) and the colour index of the BG pixel.
+const OBJ_PRIORITY: [[[Layer; 4]; 2]; 2] = {
+    use Layer::{Background as Bg, Sprite as Obj};
+    [
+        // LCDC.0 = 0: the background is blank, and the sprites are always on top of it, whatever
+        // their attribute
+        [[Obj; 4], [Obj; 4]],
+        // LCDC.0 = 1
+        [
+            // In front of the background
+            [Obj; 4],
+            // Behind the background, except its colour 0
+            [Obj, Bg, Bg, Bg],
+        ],
+    ]
+};
+
+/// Which layer is visible at a pixel where a sprite pixel with the colour index `obj_index`
+/// overlaps a BG/window pixel with the colour index `bg_index`. Colour 0 is transparent for
+/// sprites, so the background shows through it.
+///
+/// The colour indices are looked at before the palettes are applied: a BG colour 0 that the
+/// palette maps to black is still behind the sprites.
+fn visible_layer(
+    bg_and_window_enable: bool,
+    bg_index: u8,
+    obj_index: u8,
+    behind_bg: bool,
+) -> Layer {
+    if obj_index == 0 {
+        Layer::Background
+    } else {
+        OBJ_PRIORITY[bg_and_window_enable as usize][behind_bg as usize][bg_index as usize]
+    }
+}
+
+#[derive(Clone, Copy)]
 struct Sprite {
     x: u8,
     y: u8,
@@ -762,28 +1352,23 @@ impl Sprite {
         }
     }
 
+    /// Whether the sprite covers the given line. Y is the position of the top of the sprite plus
+    /// 16, so the sprites with Y < 16 are partially (or completely) above the screen.
     pub fn matches_scanline(&self, y: u8, double_size: bool) -> bool {
-        let effective_y = y + 16;
-        let top_y = self.y;
-        let bottom_y = if double_size {
-            top_y.wrapping_add(15)
-        } else {
-            top_y.wrapping_add(7)
-        };
-
-        (effective_y >= top_y) && (effective_y <= bottom_y)
+        let height = if double_size { 16 } else { 8 };
+        (y as u16 + 16).wrapping_sub(self.y as u16) < height
     }
 
     /// Convert the given coordinates (in LCD space) into tile-space coordinates.
+    ///
+    /// The row is taken modulo the height of the sprites, as the PPU does when LCDC.2 changes
+    /// between the OAM scan and the drawing of the line.
     pub fn get_tile_coordinates(&self, x: u8, y: u8, double_size: bool) -> Option<(u8, u8)> {
         let y_size = if double_size { 16 } else { 8 };
-        let effective_x = x + 8;
-        let effective_y = y + 16;
-        let left_x = self.x;
-        let right_x = self.x + 7;
-        if (effective_x >= left_x) && (effective_x <= right_x) {
-            let mut tile_x = effective_x - self.x;
-            let mut tile_y = effective_y - self.y;
+        let tile_x = (x as u16 + 8).wrapping_sub(self.x as u16);
+        if tile_x < 8 {
+            let mut tile_x = tile_x as u8;
+            let mut tile_y = (y + 16).wrapping_sub(self.y) & (y_size - 1);
 
             if self.is_x_flip() {
                 tile_x = 7 - tile_x;
@@ -829,10 +1414,10 @@ impl Debug for Sprite {
 struct Palette([Color; 4]);
 
 impl Palette {
-    fn to_debug_str(&self) -> String {
+    fn to_debug_str(&self, dmg_palette: &DmgPalette) -> String {
         let mut s = String::new();
         for c in self.0 {
-            let (r, g, b) = c.as_rgba();
+            let (r, g, b) = c.as_rgba(dmg_palette);
             s.push_str(&format!("{}", ansi_term::Color::RGB(r, g, b).paint("██")));
         }
         s
@@ -853,6 +1438,145 @@ impl DerefMut for Palette {
     }
 }
 
+impl Stateful for Gfx {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.bytes(&self.oam_ram);
+        w.bytes(&self.lcd);
+        w.u64(self.dots as u64);
+        w.bool(self.stat_changed);
+        w.u8(self.running_mode as u8);
+        w.u8(match self.line_drawing_state {
+            LineDrawingState::Idle => 0,
+            LineDrawingState::OamScan => 1,
+            LineDrawingState::Drawing => 2,
+            LineDrawingState::FramePushed => 3,
+        });
+        w.u8(self.line_sprites.len() as u8);
+        for sprite in &self.line_sprites {
+            w.u8(sprite.y);
+            w.u8(sprite.x);
+            w.u8(sprite.tile_index);
+            w.u8(sprite.attrs);
+        }
+        for flag in [
+            self.lcd_and_ppu_enabled,
+            self.window_tile_map_area,
+            self.window_enable,
+            self.bg_and_window_tile_data_area,
+            self.bg_tile_map_area,
+            self.obj_size,
+            self.obj_enabled,
+            self.bg_and_window_enable,
+        ] {
+            w.bool(flag);
+        }
+        for reg in [
+            self.scy,
+            self.scx,
+            self.line_scy,
+            self.line_scx,
+            self.ly,
+            self.lyc,
+            self.wy,
+            self.wx,
+        ] {
+            w.u8(reg);
+        }
+        w.u32(self.mode3_dots);
+        for flag in [
+            self.stat_lyc_eq_ly_itr_source,
+            self.stat_oam_itr_source,
+            self.stat_vblank_itr_source,
+            self.stat_hblank_itr_source,
+            self.stat_lyc_eq_ly_active,
+            self.stat_oam_active,
+            self.stat_vblank_active,
+            self.stat_hblank_active,
+        ] {
+            w.bool(flag);
+        }
+        w.u8(get_palette_as_byte(&self.bgp));
+        w.u8(get_palette_as_byte(&self.obp0));
+        w.u8(get_palette_as_byte(&self.obp1));
+        w.u8(self.window_internal_line_counter);
+    }
+
+    /// The whole screen is sent to the frame sink with the next frame, and the events not sent
+    /// yet are dropped.
+    fn load_state(&mut self, r: &mut StateReader) -> crate::Result<()> {
+        r.bytes_into(&mut self.vram)?;
+        r.bytes_into(&mut self.oam_ram)?;
+        r.bytes_into(&mut self.lcd)?;
+        self.dots = r.u64()? as usize;
+        self.stat_changed = r.bool()?;
+        self.running_mode = match r.u8_below(4)? {
+            0 => Mode::Mode0,
+            1 => Mode::Mode1,
+            2 => Mode::Mode2,
+            _ => Mode::Mode3,
+        };
+        self.line_drawing_state = match r.u8_below(4)? {
+            0 => LineDrawingState::Idle,
+            1 => LineDrawingState::OamScan,
+            2 => LineDrawingState::Drawing,
+            _ => LineDrawingState::FramePushed,
+        };
+        let sprites = r.u8_below(MAX_SPRITES_PER_LINE as u8 + 1)?;
+        self.line_sprites.clear();
+        for _ in 0..sprites {
+            let data = [r.u8()?, r.u8()?, r.u8()?, r.u8()?];
+            self.line_sprites.push(Sprite::new(&data));
+        }
+        for flag in [
+            &mut self.lcd_and_ppu_enabled,
+            &mut self.window_tile_map_area,
+            &mut self.window_enable,
+            &mut self.bg_and_window_tile_data_area,
+            &mut self.bg_tile_map_area,
+            &mut self.obj_size,
+            &mut self.obj_enabled,
+            &mut self.bg_and_window_enable,
+        ] {
+            *flag = r.bool()?;
+        }
+        for reg in [
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.line_scy,
+            &mut self.line_scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *reg = r.u8()?;
+        }
+        self.mode3_dots = r.u32()?;
+        for flag in [
+            &mut self.stat_lyc_eq_ly_itr_source,
+            &mut self.stat_oam_itr_source,
+            &mut self.stat_vblank_itr_source,
+            &mut self.stat_hblank_itr_source,
+            &mut self.stat_lyc_eq_ly_active,
+            &mut self.stat_oam_active,
+            &mut self.stat_vblank_active,
+            &mut self.stat_hblank_active,
+        ] {
+            *flag = r.bool()?;
+        }
+        set_palette_data(&mut self.bgp, r.u8()?);
+        set_palette_data(&mut self.obp0, r.u8()?);
+        set_palette_data(&mut self.obp1, r.u8()?);
+        self.window_internal_line_counter = r.u8()?;
+
+        self.pending_events.clear();
+        self.frame_ready = false;
+        self.dirty_lines = DirtyLines::all();
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -883,4 +1607,514 @@ mod tests {
 
         assert_eq!(0b11100100, get_palette_as_byte(&palette));
     }
+
+    #[derive(Default)]
+    struct EventSink {
+        power: Vec<bool>,
+        palettes: Vec<(PaletteId, u8)>,
+    }
+
+    impl FrameSink for EventSink {
+        fn push_frame(&mut self, _frame: &[u8]) {}
+
+        fn lcd_power_changed(&mut self, enabled: bool) {
+            self.power.push(enabled);
+        }
+
+        fn palette_changed(&mut self, palette: PaletteId, data: u8) {
+            self.palettes.push((palette, data));
+        }
+    }
+
+    #[test]
+    fn test_lcd_events() {
+        let mut gfx = Gfx::new();
+        let mut sink = EventSink::default();
+
+        gfx.write_reg(LCDC, 0x91);
+        gfx.write_reg(BGP, 0xE4);
+        // writing the same value again isn't a change
+        gfx.write_reg(BGP, 0xE4);
+        gfx.write_reg(OBP1, 0x1B);
+        gfx.write_reg(LCDC, 0x11);
+        gfx.flush(&mut sink);
+
+        assert_eq!(sink.power, vec![true, false]);
+        assert_eq!(
+            sink.palettes,
+            vec![(PaletteId::Bg, 0xE4), (PaletteId::Obj1, 0x1B)]
+        );
+    }
+
+    /// RGB colour of the given pixel of the LCD
+    fn lcd_pixel(gfx: &Gfx, x: usize, y: usize) -> (u8, u8, u8) {
+        let pixel = &gfx.lcd[(y * SCREEN_WIDTH + x) * 4..][..4];
+        assert_eq!(pixel[3], 0xFF);
+        (pixel[0], pixel[1], pixel[2])
+    }
+
+    #[test]
+    fn test_mode_stats() {
+        let mut gfx = Gfx::new();
+        gfx.write_reg(LCDC, 0x91);
+        // the stats are only available once a frame is complete, and the first one is a dot short
+        for _ in 0..3 * 154 * 456 / 200 {
+            gfx.dots(200);
+        }
+
+        let stats = gfx.mode_stats();
+        assert_eq!(stats.lines[0], [204, 0, 80, 172]);
+        assert_eq!(stats.lines[143], [204, 0, 80, 172]);
+        assert_eq!(stats.lines[144], [0, 456, 0, 0]);
+        assert_eq!(stats.lines[153], [0, 456, 0, 0]);
+        assert_eq!(stats.totals(), [144 * 204, 10 * 456, 144 * 80, 144 * 172]);
+    }
+
+    #[test]
+    fn test_scroll_latch() {
+        let mut gfx = Gfx::new();
+        gfx.write_reg(LCDC, 0x91);
+        // Tile 1 is solid black, and is used for the tilemap entry at (31, 0)
+        for addr in 0x8010..0x8020 {
+            gfx.write_vram(addr, 0xFF);
+        }
+        gfx.write_vram(0x9800 + 31, 1);
+        gfx.write_reg(BGP, 0xE4);
+        gfx.write_reg(SCX, 0xF8);
+
+        // start of mode 3 on line 0
+        gfx.dots(81);
+        assert_eq!(gfx.line_scx, 0xF8);
+        // the leftmost tile of the line (wrapping around) is the black one
+        assert_eq!(
+            lcd_pixel(&gfx, 0, 0),
+            Color::Black.as_rgba(&DmgPalette::GREEN)
+        );
+        assert_eq!(
+            lcd_pixel(&gfx, 8, 0),
+            Color::White.as_rgba(&DmgPalette::GREEN)
+        );
+
+        // changing SCX mid-line doesn't affect the current line...
+        gfx.write_reg(SCX, 0x00);
+        assert_eq!(gfx.line_scx, 0xF8);
+        // ...but is picked up by the next one
+        gfx.dots(228);
+        gfx.dots(228);
+        assert_eq!(gfx.line_scx, 0x00);
+        assert_eq!(
+            lcd_pixel(&gfx, 0, 1),
+            Color::White.as_rgba(&DmgPalette::GREEN)
+        );
+    }
+
+    #[test]
+    fn test_bg_and_window_enable() {
+        // The background is made of black tiles, and the window (on the right half of the screen)
+        // of light gray ones. A dark gray sprite covers the first 8 pixels.
+        let setup = |lcdc: u8| {
+            let mut gfx = Gfx::new();
+            for addr in [0x8010, 0x8011, 0x8020, 0x8031] {
+                gfx.write_vram(addr, 0xFF);
+            }
+            for x in 0..32 {
+                gfx.write_vram(0x9800 + x, 1);
+                gfx.write_vram(0x9C00 + x, 2);
+            }
+            for (i, b) in [16, 8, 3, 0].into_iter().enumerate() {
+                gfx.write_oam(OAM_START + i as u16, b);
+            }
+            // colour 0 of the BG palette is black, so that it's different from a blank BG
+            gfx.write_reg(BGP, 0b1110_0111);
+            gfx.write_reg(OBP0, 0b1110_0100);
+            gfx.write_reg(WX, 80 + 7);
+            gfx.write_reg(WY, 0);
+            gfx.write_reg(LCDC, 0b1101_0010 | lcdc);
+            // draw line 0
+            gfx.dots(81);
+            gfx
+        };
+        let rgb = |color: Color| color.as_rgba(&DmgPalette::GREEN);
+
+        // (LCDC.0 | LCDC.5, expected colours for sprite, background, window)
+        let cases = [
+            (0b0000_0000, [Color::DarkGray, Color::White, Color::White]),
+            (0b0010_0000, [Color::DarkGray, Color::White, Color::White]),
+            (0b0000_0001, [Color::DarkGray, Color::Black, Color::Black]),
+            (
+                0b0010_0001,
+                [Color::DarkGray, Color::Black, Color::LightGray],
+            ),
+        ];
+        for (lcdc, [sprite, bg, window]) in cases {
+            let gfx = setup(lcdc);
+            for x in 0..8 {
+                assert_eq!(
+                    lcd_pixel(&gfx, x, 0),
+                    rgb(sprite),
+                    "LCDC={:08b} x={}",
+                    lcdc,
+                    x
+                );
+            }
+            assert_eq!(lcd_pixel(&gfx, 40, 0), rgb(bg), "LCDC={:08b}", lcdc);
+            assert_eq!(lcd_pixel(&gfx, 120, 0), rgb(window), "LCDC={:08b}", lcdc);
+        }
+    }
+
+    #[test]
+    fn test_oam_scan() {
+        // Sprites use tiles 2 and 3, which are black
+        let setup = |lcdc: u8, sprites: &[[u8; 4]]| {
+            let mut gfx = Gfx::new();
+            for addr in 0x8020..0x8040 {
+                gfx.write_vram(addr, 0xFF);
+            }
+            for (i, &b) in sprites.iter().flatten().enumerate() {
+                gfx.write_oam(OAM_START + i as u16, b);
+            }
+            gfx.write_reg(OBP0, 0b1110_0100);
+            gfx.write_reg(LCDC, 0b1000_0010 | lcdc);
+            // draw lines 0 and 1
+            gfx.dots(DOTS_PER_LINE + MODE3_START + 1);
+            gfx
+        };
+        let black = Color::Black.as_rgba(&DmgPalette::GREEN);
+        let white = Color::White.as_rgba(&DmgPalette::GREEN);
+
+        // Only the first 10 sprites on a line are selected, even if they're off-screen...
+        let mut sprites = vec![[16, 168, 2, 0]; 10];
+        sprites.push([16, 8, 2, 0]);
+        let gfx = setup(0, &sprites);
+        assert_eq!(lcd_pixel(&gfx, 0, 0), white);
+        // ...but the sprites on other lines don't count
+        sprites[..10].fill([32, 168, 2, 0]);
+        let gfx = setup(0, &sprites);
+        assert_eq!(lcd_pixel(&gfx, 0, 0), black);
+
+        // Sprites partially above the screen: only the last row of the first one is visible, and
+        // the second one is just above it
+        let gfx = setup(0, &[[9, 8, 2, 0], [8, 16, 2, 0]]);
+        assert_eq!(lcd_pixel(&gfx, 0, 0), black);
+        assert_eq!(lcd_pixel(&gfx, 8, 0), white);
+        assert_eq!(lcd_pixel(&gfx, 0, 1), white);
+        // In 8x16 mode, the last row of the second tile
+        let gfx = setup(0b100, &[[1, 8, 2, 0]]);
+        assert_eq!(lcd_pixel(&gfx, 0, 0), black);
+        assert_eq!(lcd_pixel(&gfx, 0, 1), white);
+    }
+
+    #[test]
+    fn test_visible_layer() {
+        for bg_and_window_enable in [false, true] {
+            for behind_bg in [false, true] {
+                for bg_index in 0..4 {
+                    for obj_index in 0..4 {
+                        let expected = if obj_index == 0 {
+                            // transparent
+                            Layer::Background
+                        } else if bg_and_window_enable && behind_bg && bg_index != 0 {
+                            Layer::Background
+                        } else {
+                            Layer::Sprite
+                        };
+                        assert_eq!(
+                            visible_layer(bg_and_window_enable, bg_index, obj_index, behind_bg),
+                            expected,
+                            "LCDC.0={} behind_bg={} bg={} obj={}",
+                            bg_and_window_enable,
+                            behind_bg,
+                            bg_index,
+                            obj_index
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_obj_behind_bg() {
+        // The first tile of the background is colour 1 on the left half and colour 0 (which the
+        // palette makes black) on the right half. A dark gray sprite behind the background covers
+        // the whole tile.
+        let mut gfx = Gfx::new();
+        gfx.write_vram(0x8010, 0xF0);
+        gfx.write_vram(0x8020, 0xFF);
+        gfx.write_vram(0x8021, 0xFF);
+        gfx.write_vram(0x9800, 1);
+        for (i, b) in [16, 8, 2, 0x80].into_iter().enumerate() {
+            gfx.write_oam(OAM_START + i as u16, b);
+        }
+        gfx.write_reg(BGP, 0b1110_0111);
+        gfx.write_reg(OBP0, 0b1000_0000);
+        gfx.write_reg(LCDC, 0b1001_0011);
+        gfx.dots(MODE3_START + 1);
+
+        let rgb = |color: Color| color.as_rgba(&DmgPalette::GREEN);
+        assert_eq!(lcd_pixel(&gfx, 0, 0), rgb(Color::LightGray));
+        assert_eq!(lcd_pixel(&gfx, 4, 0), rgb(Color::DarkGray));
+    }
+
+    #[test]
+    fn test_render_vram() {
+        let mut gfx = Gfx::new();
+        gfx.write_reg(BGP, 0xE4);
+        // Tile 1: first row is colour 1, then colour 2, then colour 3
+        gfx.write_vram(0x8010, 0xFF);
+        gfx.write_vram(0x8013, 0xFF);
+        gfx.write_vram(0x8014, 0xFF);
+        gfx.write_vram(0x8015, 0xFF);
+
+        let tiles = gfx.render_tiles();
+        assert_eq!((tiles.width, tiles.height), (128, 192));
+        assert_eq!(tiles.pixels[0], Color::White.as_rgba(&DmgPalette::GREEN));
+        assert_eq!(
+            tiles.pixels[8],
+            Color::LightGray.as_rgba(&DmgPalette::GREEN)
+        );
+        assert_eq!(
+            tiles.pixels[128 + 8],
+            Color::DarkGray.as_rgba(&DmgPalette::GREEN)
+        );
+        assert_eq!(
+            tiles.pixels[2 * 128 + 15],
+            Color::Black.as_rgba(&DmgPalette::GREEN)
+        );
+
+        // Unsigned addressing, with tile 1 at (1, 1) in the map
+        gfx.write_reg(LCDC, 0x11);
+        gfx.write_vram(0x9800 + 33, 1);
+        gfx.write_reg(SCX, 0xF0);
+        gfx.write_reg(SCY, 0x04);
+        let map = gfx.render_tilemap(TileMap::Background);
+        assert_eq!((map.width, map.height), (256, 256));
+        assert_eq!(
+            map.pixels[8 * 256 + 9],
+            Color::LightGray.as_rgba(&DmgPalette::GREEN)
+        );
+        // viewport outline, wrapping around horizontally
+        assert_eq!(map.pixels[4 * 256 + 0xF0], VIEWPORT_COLOR);
+        assert_eq!(map.pixels[4 * 256 + 0x10], VIEWPORT_COLOR);
+        assert_eq!(map.pixels[4 * 256 + 0x8F], VIEWPORT_COLOR);
+        assert_eq!(
+            map.pixels[4 * 256 + 0x90],
+            Color::White.as_rgba(&DmgPalette::GREEN)
+        );
+        assert_eq!(map.pixels[147 * 256], VIEWPORT_COLOR);
+
+        let palettes = gfx.render_palettes();
+        assert_eq!(
+            palettes.pixels[3 * 8],
+            Color::Black.as_rgba(&DmgPalette::GREEN)
+        );
+        assert_eq!(
+            palettes.pixels[8 * 32],
+            Color::White.as_rgba(&DmgPalette::GREEN)
+        );
+    }
+
+    #[test]
+    fn test_read_only_registers() {
+        let mut gfx = Gfx::new();
+        gfx.write_reg(LCDC, 0x91);
+        gfx.write_reg(LYC, 1);
+        // line 1, mode 3
+        gfx.dots(228);
+        gfx.dots(228);
+        gfx.dots(81);
+        assert_eq!(gfx.read_reg(LY), 1);
+        assert_eq!(gfx.read_reg(STAT), 0b1000_0111);
+
+        // LY can't be written to
+        gfx.write_reg(LY, 0x42);
+        assert_eq!(gfx.read_reg(LY), 1);
+
+        // Only the interrupt sources of STAT can be written to
+        gfx.write_reg(STAT, 0b0111_1000);
+        assert_eq!(gfx.read_reg(STAT), 0b1111_1111);
+        gfx.write_reg(STAT, 0b0000_0000);
+        assert_eq!(gfx.read_reg(STAT), 0b1000_0111);
+
+        // LY reads 0 and STAT reports mode 0 while the LCD is off
+        gfx.write_reg(LCDC, 0x11);
+        assert_eq!(gfx.read_reg(LY), 0);
+        assert_eq!(gfx.read_reg(STAT), 0b1000_0000);
+    }
+
+    /// Number of dots mode 3 lasts on the next line.
+    fn next_mode3_length(gfx: &mut Gfx) -> u32 {
+        while gfx.read_reg(STAT) & 0b11 != 2 {
+            gfx.dots(1);
+        }
+        while gfx.read_reg(STAT) & 0b11 != 3 {
+            gfx.dots(1);
+        }
+        let mut dots = 0;
+        while gfx.read_reg(STAT) & 0b11 == 3 {
+            gfx.dots(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mut gfx = Gfx::new();
+        // background and sprites
+        gfx.write_reg(LCDC, 0x93);
+        assert_eq!(next_mode3_length(&mut gfx), 172);
+
+        // the first SCX % 8 pixels are discarded
+        gfx.write_reg(SCX, 3);
+        assert_eq!(next_mode3_length(&mut gfx), 175);
+
+        // A sprite at the left edge of the screen (X=8) waits 2 dots for the background fetcher
+        // on top of the 6 dots of its fetch, and a second one over the same tile doesn't wait
+        for (addr, b) in [(0xFE00, 16), (0xFE01, 8), (0xFE04, 16), (0xFE05, 9)] {
+            gfx.write_oam(addr, b);
+        }
+        assert_eq!(next_mode3_length(&mut gfx), 175 + 8 + 6);
+        // a sprite at X=0 always costs 11 dots
+        gfx.write_oam(0xFE05, 0);
+        assert_eq!(next_mode3_length(&mut gfx), 175 + 8 + 11);
+
+        // sprites are only fetched when they're enabled
+        gfx.write_reg(LCDC, 0x91);
+        assert_eq!(next_mode3_length(&mut gfx), 175);
+
+        // the window restarts the background fetcher
+        gfx.write_reg(SCX, 0);
+        gfx.write_reg(WX, 7);
+        gfx.write_reg(WY, 0);
+        gfx.write_reg(LCDC, 0xB1);
+        assert_eq!(next_mode3_length(&mut gfx), 178);
+    }
+
+    #[test]
+    fn test_ly_153() {
+        let mut gfx = Gfx::new();
+        gfx.write_reg(LCDC, 0x91);
+        gfx.write_reg(LYC, 0);
+        // LYC=LY interrupt source
+        gfx.write_reg(STAT, 0b0100_0000);
+        // last dot of line 152
+        gfx.dots(153 * 456 - 1);
+        assert_eq!(gfx.read_reg(LY), 152);
+
+        let mut ly_153_dots = 0;
+        let mut stat_interrupts = vec![];
+        for dot in 0..2 * 456 {
+            if gfx.dots(1).contains(InterruptFlag::STAT) {
+                stat_interrupts.push(dot);
+            }
+            if gfx.read_reg(LY) == 153 {
+                ly_153_dots += 1;
+            }
+            if dot >= 4 {
+                assert_eq!(gfx.read_reg(LY), 0, "dot {dot}");
+                assert_eq!(gfx.read_reg(STAT) & 0b100, 0b100, "dot {dot}");
+            }
+        }
+        // LY=153 is only visible for the first few dots, and LYC=0 matches from then on, without
+        // another interrupt at the start of line 0
+        assert_eq!(ly_153_dots, 4);
+        assert_eq!(stat_interrupts, vec![4]);
+    }
+
+    #[derive(Default)]
+    struct LineSink {
+        batches: Vec<(usize, usize)>,
+        frames: usize,
+    }
+
+    impl FrameSink for LineSink {
+        fn push_frame(&mut self, _frame: &[u8]) {
+            self.frames += 1;
+        }
+
+        fn push_lines(&mut self, first_line: usize, pixels: &[u8]) {
+            self.batches
+                .push((first_line, pixels.len() / (SCREEN_WIDTH * 4)));
+        }
+    }
+
+    #[test]
+    fn test_lines_per_update() {
+        let mut gfx = Gfx::new();
+        let mut sink = LineSink::default();
+        gfx.write_reg(LCDC, 0x91);
+        gfx.set_lines_per_update(Some(10));
+
+        // Run a whole frame, flushing after every M-cycle like the bus does
+        for _ in 0..(154 * 456 / 4) {
+            gfx.dots(4);
+            gfx.flush(&mut sink);
+        }
+
+        assert_eq!(sink.frames, 1);
+        assert_eq!(sink.batches.len(), 15);
+        assert_eq!(sink.batches[0], (0, 10));
+        assert_eq!(sink.batches[13], (130, 10));
+        assert_eq!(sink.batches[14], (140, 4));
+    }
+
+    #[derive(Default)]
+    struct DirtySink {
+        dirty: Vec<DirtyLines>,
+    }
+
+    impl FrameSink for DirtySink {
+        fn push_frame(&mut self, _frame: &[u8]) {}
+
+        fn dirty_lines(&mut self, lines: &DirtyLines) {
+            self.dirty.push(*lines);
+        }
+    }
+
+    #[test]
+    fn test_dirty_lines() {
+        let mut gfx = Gfx::new();
+        let mut sink = DirtySink::default();
+        // Tile 1 is solid black
+        for addr in 0x8010..0x8020 {
+            gfx.write_vram(addr, 0xFF);
+        }
+        gfx.write_reg(BGP, 0xE4);
+        gfx.write_reg(LCDC, 0x91);
+        let mut run_frame = |gfx: &mut Gfx| {
+            for _ in 0..154 * 456 / 4 {
+                gfx.dots(4);
+            }
+            gfx.flush(&mut sink);
+        };
+
+        // everything is dirty after turning the LCD on
+        run_frame(&mut gfx);
+        // nothing changed
+        run_frame(&mut gfx);
+        // the first row of tiles of the background turns black
+        gfx.write_vram(0x9800, 1);
+        run_frame(&mut gfx);
+
+        assert_eq!(sink.dirty.len(), 3);
+        assert_eq!(sink.dirty[0], DirtyLines::all());
+        assert!(sink.dirty[1].is_empty());
+        assert_eq!(sink.dirty[2].ranges().collect::<Vec<_>>(), vec![0..8]);
+    }
+
+    #[test]
+    fn test_parse_dmg_palette() {
+        assert_eq!("pocket".parse::<DmgPalette>().unwrap(), DmgPalette::POCKET);
+        assert_eq!(
+            "e0f8d0, #88c070,306850,081820"
+                .parse::<DmgPalette>()
+                .unwrap(),
+            DmgPalette::GREEN
+        );
+        assert!("e0f8d0,88c070,306850".parse::<DmgPalette>().is_err());
+        assert!("e0f8d0,88c070,306850,08182".parse::<DmgPalette>().is_err());
+        assert!("e0f8d0,88c070,306850,08182g".parse::<DmgPalette>().is_err());
+        assert!("purple".parse::<DmgPalette>().is_err());
+    }
 }
@@ -0,0 +1,115 @@
+//! Where the button presses come from: the keyboard, with a set of key bindings per player, an
+//! input movie, or anything else implementing [`InputSource`].
+use gb_rs::{gameboy::GameBoy, joypad::Button};
+use winit::event::VirtualKeyCode;
+use winit_input_helper::WinitInputHelper;
+
+/// Something driving the buttons of a Game Boy's joypad.
+pub trait InputSource {
+    /// Whether the given button is held at the given frame (counting from power on).
+    fn is_pressed(&self, button: Button, frame: u64) -> bool;
+}
+
+/// Set the buttons of the Game Boy at the given frame: a button is held if any of the sources
+/// holds it.
+pub fn apply_inputs(sources: &[&dyn InputSource], gb: &mut GameBoy, frame: u64) {
+    for button in Button::ALL {
+        let pressed = sources
+            .iter()
+            .any(|source| source.is_pressed(button, frame));
+        gb.set_button_pressed(button, pressed);
+    }
+}
+
+/// The key bound to each button of a joypad.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyBindings {
+    keys: [(VirtualKeyCode, Button); 8],
+}
+
+/// Arrows, A/B, Enter for Start and Space for Select
+pub const PLAYER1: KeyBindings = KeyBindings {
+    keys: [
+        (VirtualKeyCode::Up, Button::Up),
+        (VirtualKeyCode::Down, Button::Down),
+        (VirtualKeyCode::Left, Button::Left),
+        (VirtualKeyCode::Right, Button::Right),
+        (VirtualKeyCode::A, Button::A),
+        (VirtualKeyCode::B, Button::B),
+        (VirtualKeyCode::Return, Button::Start),
+        (VirtualKeyCode::Space, Button::Select),
+    ],
+};
+
+/// I/J/K/L, X for A and Z for B, Tab for Start and left Shift for Select, so that it doesn't
+/// collide with the first player's keys or with the emulator's own shortcuts
+pub const PLAYER2: KeyBindings = KeyBindings {
+    keys: [
+        (VirtualKeyCode::I, Button::Up),
+        (VirtualKeyCode::K, Button::Down),
+        (VirtualKeyCode::J, Button::Left),
+        (VirtualKeyCode::L, Button::Right),
+        (VirtualKeyCode::X, Button::A),
+        (VirtualKeyCode::Z, Button::B),
+        (VirtualKeyCode::Tab, Button::Start),
+        (VirtualKeyCode::LShift, Button::Select),
+    ],
+};
+
+/// A joypad driven by the keyboard, with the given bindings.
+#[derive(Debug)]
+pub struct Keyboard {
+    bindings: KeyBindings,
+    /// The buttons whose keys were held at the last update
+    held: Vec<Button>,
+}
+
+impl Keyboard {
+    pub fn new(bindings: KeyBindings) -> Self {
+        Self {
+            bindings,
+            held: Vec::new(),
+        }
+    }
+
+    /// Read the state of the keys.
+    pub fn update(&mut self, input: &WinitInputHelper) {
+        self.held = self
+            .bindings
+            .keys
+            .iter()
+            .filter(|(key, _)| input.key_held(*key))
+            .map(|(_, button)| *button)
+            .collect();
+    }
+
+    /// Whether any of the keys was held at the last update.
+    pub fn any_held(&self) -> bool {
+        !self.held.is_empty()
+    }
+}
+
+impl InputSource for Keyboard {
+    fn is_pressed(&self, button: Button, _frame: u64) -> bool {
+        self.held.contains(&button)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bindings() {
+        for bindings in [&PLAYER1, &PLAYER2] {
+            for button in Button::ALL {
+                let bound = bindings.keys.iter().any(|(_, b)| *b == button);
+                assert!(bound, "{:?} isn't bound", button);
+            }
+        }
+        // Both players can play on the same keyboard
+        for (key, _) in PLAYER1.keys {
+            assert!(PLAYER2.keys.iter().all(|(k, _)| *k != key), "{:?}", key);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use bitflags::bitflags;
 
 bitflags! {
+    #[derive(Default)]
     pub struct InterruptFlag: u8 {
         const VBLANK   = 0b00000001;
         const STAT = 0b00000010;
@@ -9,3 +10,17 @@ bitflags! {
         const JOYPAD   = 0b00010000;
     }
 }
+
+impl InterruptFlag {
+    /// The interrupt with the given name (`vblank`, `stat`, `timer`, `serial` or `joypad`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "vblank" => Some(Self::VBLANK),
+            "stat" => Some(Self::STAT),
+            "timer" => Some(Self::TIMER),
+            "serial" => Some(Self::SERIAL),
+            "joypad" => Some(Self::JOYPAD),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Addresses and bit definitions of the IO registers.
+use bitflags::bitflags;
+
+pub use crate::interrupt::InterruptFlag;
+
+/// P1/JOYP - Joypad
+pub const P1: u16 = 0xFF00;
+/// SB - Serial transfer data
+pub const SB: u16 = 0xFF01;
+/// SC - Serial transfer control
+pub const SC: u16 = 0xFF02;
+/// DIV - Divider register
+pub const DIV: u16 = 0xFF04;
+/// TIMA - Timer counter
+pub const TIMA: u16 = 0xFF05;
+/// TMA - Timer modulo
+pub const TMA: u16 = 0xFF06;
+/// TAC - Timer control
+pub const TAC: u16 = 0xFF07;
+/// IF - Interrupt flag
+pub const IF: u16 = 0xFF0F;
+
+// Sound
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR12: u16 = 0xFF12;
+pub const NR13: u16 = 0xFF13;
+pub const NR14: u16 = 0xFF14;
+pub const NR21: u16 = 0xFF16;
+pub const NR22: u16 = 0xFF17;
+pub const NR23: u16 = 0xFF18;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR32: u16 = 0xFF1C;
+pub const NR33: u16 = 0xFF1D;
+pub const NR34: u16 = 0xFF1E;
+pub const NR41: u16 = 0xFF20;
+pub const NR42: u16 = 0xFF21;
+pub const NR43: u16 = 0xFF22;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+/// Start of the wave pattern RAM (16 bytes)
+pub const WAVE_RAM: u16 = 0xFF30;
+
+// LCD
+/// LCDC - LCD control
+pub const LCDC: u16 = 0xFF40;
+/// STAT - LCD status
+pub const STAT: u16 = 0xFF41;
+/// SCY - Background viewport Y position
+pub const SCY: u16 = 0xFF42;
+/// SCX - Background viewport X position
+pub const SCX: u16 = 0xFF43;
+/// LY - LCD Y coordinate
+pub const LY: u16 = 0xFF44;
+/// LYC - LY compare
+pub const LYC: u16 = 0xFF45;
+/// DMA - OAM DMA source address and start
+pub const DMA: u16 = 0xFF46;
+/// BGP - Background palette
+pub const BGP: u16 = 0xFF47;
+/// OBP0 - Object palette 0
+pub const OBP0: u16 = 0xFF48;
+/// OBP1 - Object palette 1
+pub const OBP1: u16 = 0xFF49;
+/// WY - Window Y position
+pub const WY: u16 = 0xFF4A;
+/// WX - Window X position plus 7
+pub const WX: u16 = 0xFF4B;
+
+/// Writing to this register unmaps the boot ROM
+pub const BOOT: u16 = 0xFF50;
+/// IE - Interrupt enable
+pub const IE: u16 = 0xFFFF;
+
+/// Names and addresses of all the registers, in address order
+pub const REGISTERS: &[(&str, u16)] = &[
+    ("P1", P1),
+    ("SB", SB),
+    ("SC", SC),
+    ("DIV", DIV),
+    ("TIMA", TIMA),
+    ("TMA", TMA),
+    ("TAC", TAC),
+    ("IF", IF),
+    ("NR10", NR10),
+    ("NR11", NR11),
+    ("NR12", NR12),
+    ("NR13", NR13),
+    ("NR14", NR14),
+    ("NR21", NR21),
+    ("NR22", NR22),
+    ("NR23", NR23),
+    ("NR24", NR24),
+    ("NR30", NR30),
+    ("NR31", NR31),
+    ("NR32", NR32),
+    ("NR33", NR33),
+    ("NR34", NR34),
+    ("NR41", NR41),
+    ("NR42", NR42),
+    ("NR43", NR43),
+    ("NR44", NR44),
+    ("NR50", NR50),
+    ("NR51", NR51),
+    ("NR52", NR52),
+    ("LCDC", LCDC),
+    ("STAT", STAT),
+    ("SCY", SCY),
+    ("SCX", SCX),
+    ("LY", LY),
+    ("LYC", LYC),
+    ("DMA", DMA),
+    ("BGP", BGP),
+    ("OBP0", OBP0),
+    ("OBP1", OBP1),
+    ("WY", WY),
+    ("WX", WX),
+    ("BOOT", BOOT),
+    ("IE", IE),
+];
+
+/// Return the address of the register with the given name (case-insensitive).
+pub fn address_of(name: &str) -> Option<u16> {
+    REGISTERS
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, addr)| *addr)
+}
+
+/// Return the name of the register at the given address.
+pub fn name_of(addr: u16) -> Option<&'static str> {
+    REGISTERS
+        .iter()
+        .find(|(_, a)| *a == addr)
+        .map(|(name, _)| *name)
+}
+
+bitflags! {
+    /// Bits of P1/JOYP. Note that they are active low.
+    pub struct P1Flags: u8 {
+        const SELECT_ACTION    = 0b0010_0000;
+        const SELECT_DIRECTION = 0b0001_0000;
+        const DOWN_OR_START    = 0b0000_1000;
+        const UP_OR_SELECT     = 0b0000_0100;
+        const LEFT_OR_B        = 0b0000_0010;
+        const RIGHT_OR_A       = 0b0000_0001;
+    }
+}
+
+bitflags! {
+    /// Bits of TAC
+    pub struct Tac: u8 {
+        const ENABLE       = 0b0000_0100;
+        /// Mask of the input clock select bits
+        const CLOCK_SELECT = 0b0000_0011;
+    }
+}
+
+bitflags! {
+    /// Bits of LCDC
+    pub struct Lcdc: u8 {
+        const LCD_ENABLE          = 0b1000_0000;
+        /// Window tile map: 0=9800, 1=9C00
+        const WINDOW_TILE_MAP     = 0b0100_0000;
+        const WINDOW_ENABLE       = 0b0010_0000;
+        /// BG and window tile data: 0=8800 (signed tile ids), 1=8000
+        const BG_WINDOW_TILE_DATA = 0b0001_0000;
+        /// BG tile map: 0=9800, 1=9C00
+        const BG_TILE_MAP         = 0b0000_1000;
+        /// Object size: 0=8x8, 1=8x16
+        const OBJ_SIZE            = 0b0000_0100;
+        const OBJ_ENABLE          = 0b0000_0010;
+        const BG_WINDOW_ENABLE    = 0b0000_0001;
+    }
+}
+
+bitflags! {
+    /// Bits of STAT
+    pub struct Stat: u8 {
+        const LYC_INTERRUPT    = 0b0100_0000;
+        const OAM_INTERRUPT    = 0b0010_0000;
+        const VBLANK_INTERRUPT = 0b0001_0000;
+        const HBLANK_INTERRUPT = 0b0000_1000;
+        /// LY == LYC (read-only)
+        const LYC_EQ_LY        = 0b0000_0100;
+        /// Mask of the mode bits (read-only)
+        const MODE             = 0b0000_0011;
+    }
+}
+
+bitflags! {
+    /// Bits of NR52
+    pub struct Nr52: u8 {
+        const APU_ENABLE = 0b1000_0000;
+        /// Channel 4 is on (read-only)
+        const CH4_ON     = 0b0000_1000;
+        /// Channel 3 is on (read-only)
+        const CH3_ON     = 0b0000_0100;
+        /// Channel 2 is on (read-only)
+        const CH2_ON     = 0b0000_0010;
+        /// Channel 1 is on (read-only)
+        const CH1_ON     = 0b0000_0001;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_names() {
+        assert_eq!(address_of("lcdc"), Some(LCDC));
+        assert_eq!(address_of("NR52"), Some(NR52));
+        assert_eq!(address_of("FOO"), None);
+        assert_eq!(name_of(0xFF05), Some("TIMA"));
+        assert_eq!(name_of(0xFF03), None);
+        // sorted and without duplicates
+        assert!(REGISTERS.windows(2).all(|w| w[0].1 < w[1].1));
+    }
+}
@@ -1,4 +1,6 @@
-use bitvec::{order::Lsb0, view::BitView};
+use crate::error::Result;
+use crate::io_regs::P1Flags;
+use crate::state::{StateReader, StateWriter, Stateful};
 
 #[derive(Debug)]
 pub struct Joypad {
@@ -33,33 +35,28 @@ impl Default for Joypad {
 
 impl Joypad {
     pub fn read(&self) -> u8 {
-        let mut byte = 0xFFu8;
-        let bits = byte.view_bits_mut::<Lsb0>();
-        bits.set(4, !self.direction_selected);
-        bits.set(5, !self.action_selected);
-        if self.action_selected {
-            bits.set(0, !self.a_pressed);
-            bits.set(1, !self.b_pressed);
-            bits.set(2, !self.select_pressed);
-            bits.set(3, !self.start_pressed);
-        } else if self.direction_selected {
-            bits.set(0, !self.right_pressed);
-            bits.set(1, !self.left_pressed);
-            bits.set(2, !self.up_pressed);
-            bits.set(3, !self.down_pressed);
-        }
+        // All the bits are active low, so work with the inverted value
+        let mut p1 = P1Flags::from_bits_truncate(self.input_lines());
+        p1.set(P1Flags::SELECT_DIRECTION, self.direction_selected);
+        p1.set(P1Flags::SELECT_ACTION, self.action_selected);
 
-        byte
+        !p1.bits()
     }
 
-    pub fn write(&mut self, b: u8) {
-        let bits = b.view_bits::<Lsb0>();
-        self.direction_selected = !bits[4];
-        self.action_selected = !bits[5];
+    /// Write to P1. Returns `true` if a joypad interrupt should be requested.
+    pub fn write(&mut self, b: u8) -> bool {
+        let before = self.input_lines();
+        let p1 = P1Flags::from_bits_truncate(!b);
+        self.direction_selected = p1.contains(P1Flags::SELECT_DIRECTION);
+        self.action_selected = p1.contains(P1Flags::SELECT_ACTION);
+
+        // Selecting a group of buttons which are being held also pulls the lines low
+        is_falling_edge(before, self.input_lines())
     }
 
+    /// Press or release a button. Returns `true` if a joypad interrupt should be requested.
     pub fn set_button(&mut self, button: Button, is_pressed: bool) -> bool {
-        let orig_state = self.read();
+        let before = self.input_lines();
 
         match button {
             Button::Start => self.start_pressed = is_pressed,
@@ -72,8 +69,49 @@ impl Joypad {
             Button::Right => self.right_pressed = is_pressed,
         }
 
-        self.read() != orig_state
+        is_falling_edge(before, self.input_lines())
+    }
+
+    /// State of the 4 input lines P10-P13 (inverted, i.e. a set bit means the line is low).
+    ///
+    /// A button only pulls its line low when its group is selected. When both groups are selected,
+    /// a line is low if either of its buttons is pressed.
+    fn input_lines(&self) -> u8 {
+        let mut lines = P1Flags::empty();
+        if self.action_selected {
+            lines |= Self::buttons_to_lines(
+                self.a_pressed,
+                self.b_pressed,
+                self.select_pressed,
+                self.start_pressed,
+            );
+        }
+        if self.direction_selected {
+            lines |= Self::buttons_to_lines(
+                self.right_pressed,
+                self.left_pressed,
+                self.up_pressed,
+                self.down_pressed,
+            );
+        }
+
+        lines.bits()
     }
+
+    fn buttons_to_lines(p10: bool, p11: bool, p12: bool, p13: bool) -> P1Flags {
+        let mut lines = P1Flags::empty();
+        lines.set(P1Flags::RIGHT_OR_A, p10);
+        lines.set(P1Flags::LEFT_OR_B, p11);
+        lines.set(P1Flags::UP_OR_SELECT, p12);
+        lines.set(P1Flags::DOWN_OR_START, p13);
+        lines
+    }
+}
+
+/// The joypad interrupt is requested when any of the input lines goes from high to low.
+fn is_falling_edge(before: u8, after: u8) -> bool {
+    // the lines are inverted, so a line going low is a bit being set
+    after & !before != 0
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -88,3 +126,59 @@ pub enum Button {
     Left,
     Right,
 }
+
+impl Button {
+    /// All the buttons of the joypad
+    pub const ALL: [Button; 8] = [
+        Button::Start,
+        Button::Select,
+        Button::A,
+        Button::B,
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+    ];
+}
+
+/// Only the selected group of buttons is saved: which buttons are held is up to the host.
+impl Stateful for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.action_selected);
+        w.bool(self.direction_selected);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.action_selected = r.bool()?;
+        self.direction_selected = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_joypad_interrupt() {
+        let mut joypad = Joypad::default();
+        // nothing selected: pressing a button doesn't change P1 or request an interrupt
+        assert!(!joypad.write(0x30));
+        assert!(!joypad.set_button(Button::A, true));
+        assert_eq!(joypad.read(), 0xFF);
+        // selecting the action buttons while A is held pulls P10 low
+        assert!(joypad.write(0x10));
+        assert_eq!(joypad.read(), 0xDE);
+        // releasing a button is a rising edge
+        assert!(!joypad.set_button(Button::A, false));
+        // direction buttons aren't selected
+        assert!(!joypad.set_button(Button::Right, true));
+        assert!(joypad.set_button(Button::Start, true));
+        assert_eq!(joypad.read(), 0xD7);
+        // both groups selected: the lines are pulled low by either group
+        assert!(joypad.write(0x00));
+        assert_eq!(joypad.read(), 0xC6);
+        // P10 is already low because of Right
+        assert!(!joypad.set_button(Button::A, true));
+    }
+}
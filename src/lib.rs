@@ -1,24 +1,116 @@
-use std::collections::VecDeque;
-
+#[macro_use]
+pub mod anomaly;
 mod apu;
+pub mod breakpoint;
 mod bus;
 pub mod cartridge;
+pub mod cheats;
 mod cpu;
+mod dirty;
 pub mod disasm;
+mod error;
+pub mod frontend;
 pub mod gameboy;
 mod gfx;
 mod interrupt;
+pub mod io_regs;
 pub mod joypad;
+pub mod machine;
+mod memory;
+pub mod platform;
+mod profiling;
+mod state;
+pub mod symbols;
+mod tee;
+pub mod testing;
 mod timer;
+pub mod timing;
+
+pub use apu::{ApuSnapshot, ChannelSnapshot, DEFAULT_LOW_PASS_CUTOFF};
+pub use cpu::{CallFrame, CallKind, Cpu, CpuState, Reg, RegPair};
+pub use dirty::DirtyLines;
+pub use error::{GbError, Result};
+pub use gfx::{DmgPalette, ModeStats, PpuState, RgbImage, TileMap};
+pub use interrupt::InterruptFlag;
+pub use memory::Memory;
+pub use profiling::{CodeProfile, Hotspot, Stats};
+pub use tee::{TeeAudioSink, TeeFrameSink};
 
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
+/// Size of a frame in bytes, as pushed to a [`FrameSink`]
+pub const FRAME_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 4;
+
+/// Hash of a frame in RGBA format, to compare it with a known-good frame without keeping the whole
+/// image around.
+///
+/// This is the 64-bit FNV-1a hash, which unlike the hashers of `std` is the same on all platforms
+/// and versions of Rust, so the hashes can be saved. They depend on the colours of the
+/// [`DmgPalette`] though.
+pub fn frame_hash(frame: &[u8]) -> u64 {
+    frame.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The palettes of the DMG
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteId {
+    /// BGP - Background and window palette
+    Bg,
+    /// OBP0 - Object palette 0
+    Obj0,
+    /// OBP1 - Object palette 1
+    Obj1,
+}
 
 pub trait FrameSink {
-    fn push_frame(&mut self, frame: &[(u8, u8, u8)]);
+    /// Called with each complete frame.
+    ///
+    /// The frame is in RGBA format (4 bytes per pixel, line by line), which is what most graphics
+    /// APIs expect, so it can usually be copied as-is into a texture.
+    fn push_frame(&mut self, frame: &[u8]);
+
+    /// Called just before `push_frame()` with the lines that differ from the previously pushed
+    /// frame, so that the sink can skip the work for the lines (or whole frames) that didn't
+    /// change.
+    ///
+    /// The lines are all dirty for the first frame after the LCD is turned on.
+    fn dirty_lines(&mut self, _lines: &DirtyLines) {}
+
+    /// Called with the lines that have just been drawn, when sub-frame delivery is enabled with
+    /// [`GameBoy::set_lines_per_update()`](gameboy::GameBoy::set_lines_per_update).
+    ///
+    /// `pixels` contains one or more complete lines in RGBA format, starting at line `first_line`.
+    /// The complete frame is still pushed with `push_frame()` at the end of the frame.
+    fn push_lines(&mut self, _first_line: usize, _pixels: &[u8]) {}
+
+    /// Called when the LCD is turned on or off.
+    ///
+    /// No frames are pushed while the LCD is off, so it's up to the sink to decide what to display
+    /// in the meantime.
+    fn lcd_power_changed(&mut self, _enabled: bool) {}
+
+    /// Called when the data of one of the palettes changes.
+    fn palette_changed(&mut self, _palette: PaletteId, _data: u8) {}
 }
 
+/// Receives the sound produced by the APU.
+///
+/// The APU produces samples at a fixed rate, set with
+/// [`GameBoy::set_sample_rate()`](gameboy::GameBoy::set_sample_rate), and pushes them as soon as
+/// they're produced: a sink that can't keep up has to drop them.
 pub trait AudioSink {
-    fn push_sample(&mut self, sample: (i16, i16)) -> bool;
-    fn push_samples(&mut self, samples: &mut VecDeque<i16>);
+    /// Called with the samples produced since the previous call, interleaved as left, right,
+    /// left, right...
+    fn push_samples(&mut self, samples: &[i16]);
+
+    /// How full the sink's buffer is, between 0.0 (empty) and 1.0 (full), or `None` if it doesn't
+    /// buffer the samples.
+    ///
+    /// This is the backpressure signal for dynamic rate control: the host can run the emulation
+    /// slightly faster when the buffer runs low, and slightly slower when it fills up.
+    fn fill_level(&self) -> Option<f64> {
+        None
+    }
 }
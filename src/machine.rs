@@ -0,0 +1,97 @@
+//! Configuration of the emulated machine, chosen when creating a
+//! [`GameBoy`](crate::gameboy::GameBoy).
+use crate::{breakpoint::Breakpoint, cartridge::RamInit, cheats::Cheat, DmgPalette};
+
+/// The Game Boy model to emulate
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Model {
+    /// The original Game Boy
+    #[default]
+    Dmg,
+}
+
+/// Where the boot ROM comes from
+///
+/// The DMG boot ROM is only bundled with the library when the `bundled-boot-rom` feature is
+/// enabled (the default). Without it, the boot sequence is skipped unless the host provides its
+/// own boot ROM.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum BootRom {
+    /// The boot ROM bundled with the emulator
+    #[cfg(feature = "bundled-boot-rom")]
+    #[default]
+    Bundled,
+    /// A boot ROM dump, which must be 256 bytes long
+    Custom(Vec<u8>),
+    /// Don't run any boot ROM: start directly at the cartridge entry point, with the CPU and IO
+    /// registers set to their post-boot values
+    #[cfg_attr(not(feature = "bundled-boot-rom"), default)]
+    Skip,
+}
+
+/// Everything needed to create a [`GameBoy`](crate::gameboy::GameBoy), apart from the cartridge.
+///
+/// ```no_run
+/// # use gb_rs::{cartridge::Cartridge, gameboy::GameBoy, machine::{BootRom, MachineConfig}};
+/// # use gb_rs::DmgPalette;
+/// let config = MachineConfig::default()
+///     .boot_rom(BootRom::Skip)
+///     .palette(DmgPalette::POCKET)
+///     .breakpoint(0x0150);
+/// let gb = GameBoy::new(Cartridge::load("game.gb")?, config)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MachineConfig {
+    pub model: Model,
+    pub boot_rom: BootRom,
+    /// Colours of the screen
+    pub palette: DmgPalette,
+    /// Contents of the cartridge RAM at power on, if it isn't loaded from a save file
+    pub ram_init: RamInit,
+    /// Whether the `LD B,B` instruction pauses the execution, as used by some test ROMs
+    pub soft_break: bool,
+    /// Addresses at which the execution is paused
+    pub breakpoints: Vec<Breakpoint>,
+    /// Cheat codes active from power on
+    pub cheats: Vec<Cheat>,
+}
+
+impl MachineConfig {
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn boot_rom(mut self, boot_rom: BootRom) -> Self {
+        self.boot_rom = boot_rom;
+        self
+    }
+
+    pub fn palette(mut self, palette: DmgPalette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    pub fn ram_init(mut self, ram_init: RamInit) -> Self {
+        self.ram_init = ram_init;
+        self
+    }
+
+    pub fn soft_break(mut self, enabled: bool) -> Self {
+        self.soft_break = enabled;
+        self
+    }
+
+    /// Add a breakpoint at the given address, or at the given banked address (see [`Breakpoint`]).
+    pub fn breakpoint(mut self, breakpoint: impl Into<Breakpoint>) -> Self {
+        self.breakpoints.push(breakpoint.into());
+        self
+    }
+
+    pub fn cheat(mut self, cheat: Cheat) -> Self {
+        self.cheats.push(cheat);
+        self
+    }
+}
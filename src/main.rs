@@ -1,145 +1,706 @@
 use std::any::Any;
-use std::num::ParseIntError;
-use std::path::PathBuf;
+use std::fmt::Write as _;
+use std::num::{NonZeroU32, ParseIntError};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use config::Config;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{BufferSize, Sample, SampleRate, Stream, StreamConfig};
-use emulator::Emulator;
-use gb_rs::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use emulator::{Emulator, SyncMode};
+use gb_rs::{
+    breakpoint::Breakpoint,
+    cartridge::{Cartridge, RamInit},
+    cheats::parse_cheat_file,
+    disasm::Disassembler,
+    machine::{BootRom, MachineConfig},
+    DmgPalette, DEFAULT_LOW_PASS_CUTOFF, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
 use log::{debug, error, info, trace, warn};
-use pixels::{Pixels, SurfaceTexture};
+use movie::Movie;
 use ringbuf::{Consumer, HeapRb};
+use scheduler::{FrameScheduler, SpeedCounter};
+use screen::{ScaleMode, Screen};
+use stats::{AudioMonitor, AudioStats};
 use winit::{
     dpi::LogicalSize,
-    event::{Event, VirtualKeyCode},
+    event::{Event, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 use winit_input_helper::WinitInputHelper;
 
+mod accuracy;
+mod config;
 mod debugger;
+mod demo;
 mod emulator;
+mod input;
+mod movie;
+mod osd;
+mod recorder;
+mod scheduler;
+mod scope;
+mod screen;
+mod stats;
 
 #[derive(Parser)]
-#[command(about, version, author)]
+#[command(
+    about,
+    version,
+    author,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
     /// Disable sound output
     #[arg(short, long)]
     quiet: bool,
-    /// Set a breakpoint at the given address
-    #[arg(short, long, value_parser = parse_addr)]
-    breakpoint: Option<u16>,
+    /// Set a breakpoint at the given address (`<hex address>` or `<hex bank>:<hex address>`),
+    /// optionally with a condition on the registers (e.g. `"4123 if a==3e"`)
+    #[arg(short, long)]
+    breakpoint: Option<Breakpoint>,
     /// Enable software breakpoint
     ///
     /// If enabled, the `LD B,B` instruction triggers a breakpoint. Execution is paused and the
     /// debugger is started. This is useful for some test ROMS.
     #[arg(long)]
     enable_soft_break: bool,
+    /// Skip the boot sequence and start the cartridge directly
+    #[arg(long, conflicts_with = "bootrom")]
+    skip_boot: bool,
+    /// Path to a boot ROM to use instead of the bundled one
+    #[arg(long)]
+    bootrom: Option<PathBuf>,
+    /// Initial size of the window, as a multiple of the screen size
+    ///
+    /// Defaults to the size of the window when the emulator was last closed.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    scale: Option<u32>,
+    /// Save screenshots at N times the native 160x144 resolution
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    screenshot_scale: u32,
+    /// How the screen is scaled when the window is resized [default: integer]
+    #[arg(long, value_enum)]
+    scale_mode: Option<ScaleMode>,
+    /// What the emulation speed is synchronised to [default: time]
+    ///
+    /// Audio sync is ignored when sound is disabled.
+    #[arg(long, value_enum)]
+    sync: Option<SyncMode>,
+    /// Cutoff frequency (in Hz) of the low-pass filter that reduces the aliasing of the sound,
+    /// or 0 to disable it
+    #[arg(long, value_name = "HZ", default_value_t = DEFAULT_LOW_PASS_CUTOFF)]
+    low_pass: u32,
+    /// Print the bytes sent over the serial port to stdout
+    ///
+    /// This is how test ROMs such as Blargg's report their results.
+    #[arg(long)]
+    serial_stdout: bool,
+    /// Periodically log how fast the emulator runs, and how much time is spent in the CPU, PPU
+    /// and APU
+    #[arg(long)]
+    profile: bool,
+    /// Don't wait for the vertical sync when displaying frames
+    #[arg(long)]
+    no_vsync: bool,
+    /// Colours of the screen
+    ///
+    /// Either the name of a built-in palette (green, grayscale, pocket) or 4 comma-separated hex
+    /// colours from lightest to darkest, e.g. `e0f8d0,88c070,306850,081820`. Defaults to the
+    /// palette from the config file.
+    #[arg(long)]
+    palette: Option<String>,
+    /// Contents of the cartridge RAM at power on, when there's no save file
+    ///
+    /// One of `zeros`, `ff`, `random` or `random:<seed>`. Some games look for garbage in the RAM
+    /// to detect their first boot.
+    #[arg(long, value_name = "PATTERN", default_value = "zeros")]
+    ram_init: String,
+    /// Name of the save profile to use
+    ///
+    /// Each profile has its own battery RAM save file, so that several people can keep separate
+    /// saves for the same ROM.
+    #[arg(long, value_name = "NAME")]
+    save_profile: Option<String>,
+    /// Load the Game Genie and GameShark codes of the given cheat file
+    ///
+    /// The file has one code per line (`ABC-DEF`, `ABC-DEF-GHI` or `TTVVAAAA`), optionally followed
+    /// by a description. Lines starting with `#` are ignored. The codes are for the ROM given on
+    /// the command line, and are dropped when another ROM is opened.
+    #[arg(long, value_name = "FILE")]
+    cheats: Option<PathBuf>,
+    /// Record the screen to the given animated PNG from power on
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Quit after N frames have been produced
+    ///
+    /// Combined with `--inputs` and `--record`, this makes a fully scripted run, e.g. to capture
+    /// the reproduction of a bug.
+    #[arg(long, value_name = "N")]
+    frames: Option<u64>,
+    /// Replay the button presses of the given input movie instead of reading the keyboard
+    ///
+    /// The movie is a text file with a `<frame> <buttons>` line each time the buttons held
+    /// change, e.g. `120 start`, `125 -` or `200 right+a`.
+    #[arg(long, value_name = "FILE")]
+    inputs: Option<PathBuf>,
+    /// Play the given input movie on a loop after 30 seconds without any key pressed
+    ///
+    /// This demo (or attract mode) plays the movie from power on, and loops once the movie's last
+    /// line has been played, until a key is pressed. End the movie with a line such as `3600 -` to
+    /// choose how long it lasts. See `src/movie.rs` for the format of input movies.
+    #[arg(long, value_name = "FILE", conflicts_with = "inputs")]
+    demo: Option<PathBuf>,
+    /// Start from the given savestate, saved with the debugger's `savestate` command
+    #[arg(long, value_name = "FILE")]
+    load_state: Option<PathBuf>,
+    /// Make the emulation independent of the host's clock and entropy
+    ///
+    /// The emulation runs whole frames, paced by a virtual clock, and the `random` RAM pattern
+    /// uses a fixed seed, so that the same inputs (e.g. with `--inputs`) always give the same run.
+    /// Audio sync is ignored.
+    #[arg(long)]
+    deterministic: bool,
+    /// Check the ROM's header and exit
+    ///
+    /// Prints the decoded header as `key=value` lines and exits with status 0 if the header is
+    /// valid, 1 otherwise.
+    #[arg(long)]
+    validate: bool,
     /// Path to the ROM file
-    rom: PathBuf,
+    ///
+    /// Without it, the emulator starts without a cartridge: drop a ROM onto the window, or open
+    /// one of the recently played ROMs with Ctrl+1 to Ctrl+9.
+    rom: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Disassemble a ROM, or any blob of raw code (e.g. a boot ROM dump)
+    Disasm {
+        /// Address at which the first byte of the file is mapped
+        #[arg(long, value_parser = parse_addr, default_value = "0000")]
+        base: u16,
+        /// Path to the file to disassemble
+        file: PathBuf,
+    },
+    /// Run the test ROM suites and print how many tests pass in each category
+    ///
+    /// The results are also saved as JSON, one test per line, so that the effect of a change can
+    /// be checked by comparing them with those of a previous run.
+    Accuracy {
+        /// Directory containing the test ROM suites, as downloaded by `just test_roms`
+        #[arg(long, default_value = "test_roms")]
+        dir: PathBuf,
+        /// Suite to run (a sub-directory of `dir`), can be repeated [default: blargg,
+        /// mooneye-test-suite, dmg-acid2]
+        #[arg(long = "suite", value_name = "NAME")]
+        suites: Vec<String>,
+        /// Number of frames after which a test that hasn't reported its result is considered to
+        /// have timed out
+        #[arg(long, default_value = "3600")]
+        max_frames: u32,
+        /// File the results are saved to
+        #[arg(short, long, default_value = "accuracy.json")]
+        output: PathBuf,
+    },
 }
 
 fn parse_addr(s: &str) -> Result<u16, ParseIntError> {
     u16::from_str_radix(s, 16)
 }
 
+/// Disassemble the given file, which can be a whole ROM or just a raw blob of code.
+fn disasm(file: &Path, base: u16) -> Result<()> {
+    let data = std::fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let cartridge = Cartridge::from_bytes(data);
+    // Only print the header if it looks legit, as raw code won't have one
+    if cartridge.check_header().is_empty() {
+        let header = cartridge.header();
+        println!("; Title: {}", header.title);
+        println!("; Cartridge type: {}", header.cartridge_type);
+    }
+
+    let mut addr = base as usize;
+    for inst in Disassembler::new(cartridge.data()).run() {
+        println!("{addr:04X}\t{inst}");
+        addr += inst.bytes as usize;
+    }
+
+    Ok(())
+}
+
+/// Print a machine-readable report of the ROM's header, and return the process' exit code.
+fn validate(rom: &Path) -> i32 {
+    // Not using `Cartridge::load()`, which would stop at the first fatal problem
+    let (report, valid) = match std::fs::read(rom) {
+        Ok(data) => validation_report(Cartridge::from_bytes(data)),
+        Err(e) => (
+            format!("valid=false\nerror=Failed to open rom file: {}\n", e),
+            false,
+        ),
+    };
+    print!("{}", report);
+
+    i32::from(!valid)
+}
+
+/// The `key=value` lines printed by `--validate`, and whether the header is valid.
+fn validation_report(cartridge: Cartridge) -> (String, bool) {
+    let problems = cartridge.check_header();
+    let header = cartridge.header();
+    let mut out = String::new();
+    let _ = writeln!(out, "valid={}", problems.is_empty());
+    let _ = writeln!(out, "title={}", header.title);
+    let _ = writeln!(out, "licensee={}", header.licensee);
+    let _ = writeln!(
+        out,
+        "mapper={}",
+        header.cartridge_type.name().unwrap_or("UNKNOWN")
+    );
+    let _ = writeln!(out, "rom_size={:02x}", header.rom_size);
+    let _ = writeln!(out, "ram_size={:02x}", header.ram_size);
+    let _ = writeln!(out, "cgb={}", header.cgb);
+    let _ = writeln!(out, "sgb={}", header.sgb);
+    let _ = writeln!(
+        out,
+        "header_checksum={:02x}",
+        cartridge.compute_header_checksum()
+    );
+    let _ = writeln!(
+        out,
+        "global_checksum_ok={}",
+        header.global_checksum == cartridge.compute_global_checksum()
+    );
+    for problem in &problems {
+        let _ = writeln!(out, "error={}", problem);
+    }
+
+    (out, problems.is_empty())
+}
+
 fn main() -> Result<()> {
     // initialise logger
     env_logger::builder().parse_filters("gb_rs=debug").init();
+    emulator::install_crash_hook();
 
     let cli = Cli::parse();
 
+    match &cli.command {
+        Some(Commands::Disasm { base, file }) => return disasm(file, *base),
+        Some(Commands::Accuracy {
+            dir,
+            suites,
+            max_frames,
+            output,
+        }) => {
+            let suites = if suites.is_empty() {
+                accuracy::DEFAULT_SUITES
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            } else {
+                suites.clone()
+            };
+            return accuracy::run(dir, &suites, *max_frames, output);
+        }
+        None => {}
+    }
+    if cli.validate {
+        let rom = cli.rom.as_deref().context("No ROM file given")?;
+        std::process::exit(validate(rom));
+    }
+
+    let mut config = Config::load_or_default();
+    match &cli.rom {
+        Some(rom) => config.add_recent_rom(rom),
+        None => print_recent_roms(&config),
+    }
+    if let Some(scale) = cli.scale {
+        config.scale = scale;
+    }
+    if let Some(scale_mode) = cli.scale_mode {
+        config.scale_mode = scale_mode;
+    }
+    save_config(&config);
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
     let window = {
-        let size = LogicalSize::new(SCREEN_WIDTH as f64, SCREEN_HEIGHT as f64);
+        let min_size = LogicalSize::new(SCREEN_WIDTH as f64, SCREEN_HEIGHT as f64);
+        let size = LogicalSize::new(
+            (SCREEN_WIDTH as u32 * config.scale) as f64,
+            (SCREEN_HEIGHT as u32 * config.scale) as f64,
+        );
         WindowBuilder::new()
             .with_title("gb-rs")
             .with_inner_size(size)
-            .with_min_inner_size(size)
+            .with_min_inner_size(min_size)
             .build(&event_loop)
             .unwrap()
     };
 
-    let mut pixels = {
-        let window_size = window.inner_size();
-        let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, surface_texture)?
-    };
+    let mut screen = Screen::new(&window, config.scale_mode, !cli.no_vsync)?;
 
     // Buffer can hold 0.5s of samples (assuming 2 channels)
     let ringbuf = HeapRb::new(8102);
     let (producer, consumer) = ringbuf.split();
-    let mut emulator = Emulator::new(&cli.rom, producer, cli.breakpoint, cli.enable_soft_break)?;
+    let mut machine_config = MachineConfig::default().soft_break(cli.enable_soft_break);
+    if let Some(addr) = cli.breakpoint {
+        machine_config = machine_config.breakpoint(addr);
+    }
+    if cli.skip_boot {
+        machine_config = machine_config.boot_rom(BootRom::Skip);
+    } else if let Some(path) = &cli.bootrom {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read boot ROM {}", path.display()))?;
+        info!("Using boot ROM {}", path.display());
+        machine_config = machine_config.boot_rom(BootRom::Custom(data));
+    }
+    machine_config = machine_config.ram_init(
+        cli.ram_init
+            .parse::<RamInit>()
+            .context("Invalid --ram-init")?,
+    );
+    if let Some(path) = &cli.cheats {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let cheats = parse_cheat_file(&text)
+            .with_context(|| format!("Invalid cheat file {}", path.display()))?;
+        info!("Loaded {} cheats from {}", cheats.len(), path.display());
+        for cheat in cheats {
+            machine_config = machine_config.cheat(cheat);
+        }
+    }
+    if let Some(palette) = cli.palette {
+        config.palette = palette;
+    }
+    match config.palette.parse::<DmgPalette>() {
+        Ok(palette) => machine_config = machine_config.palette(palette),
+        Err(e) => warn!("Invalid palette {}: {:#}", config.palette, e),
+    }
+    let mut emulator = Emulator::new(
+        cli.rom.as_deref(),
+        producer,
+        machine_config,
+        cli.save_profile.clone(),
+        cli.deterministic,
+    )?;
+    emulator.set_volume(config.volume);
+    emulator.set_low_pass_cutoff((cli.low_pass > 0).then_some(cli.low_pass));
+    if let Some(path) = &cli.demo {
+        emulator.set_demo(Movie::load(path)?);
+    }
+    emulator.set_serial_stdout(cli.serial_stdout);
+    emulator.set_profiling(cli.profile);
+    emulator.set_screenshot_scale(cli.screenshot_scale);
+    if let Some(path) = &cli.inputs {
+        emulator.set_movie(Movie::load(path)?);
+    }
+    if let Some(path) = &cli.load_state {
+        emulator.load_state(path)?;
+    }
+    if let Some(frames) = cli.frames {
+        emulator.set_max_frames(frames);
+    }
+    if let Some(path) = &cli.record {
+        emulator.toggle_recording(Some(path.clone()));
+    }
     let _guard: Box<dyn Any> = if cli.quiet {
         init_no_audio(consumer);
         Box::new(())
     } else {
-        let stream = init_audio(consumer)?;
+        let (stream, sample_rate) = init_audio(consumer, emulator.audio_stats())?;
+        emulator.set_sample_rate(sample_rate);
+        emulator.set_sync_mode(cli.sync.unwrap_or_default());
         Box::new(stream)
     };
 
+    let audio_stats = emulator.audio_stats();
+    let mut audio_monitor = AudioMonitor::new();
+    let mut audio_warning = String::new();
+    let mut speed_status = String::new();
+    // Set the title once the window exists, and again whenever another ROM is loaded
+    let mut refresh_title = true;
+    let mut show_fps = false;
+    let mut scheduler = FrameScheduler::new();
+    let mut speed_counter = SpeedCounter::new();
+
     event_loop.run(move |event, _, control_flow| {
-        if let Event::RedrawRequested(_) = event {
-            emulator.render(pixels.get_frame_mut());
-            if let Err(e) = pixels.render() {
-                error!("Error while rendering frame: {}", e);
-                *control_flow = ControlFlow::Exit;
-                return;
+        // Save what can be saved if anything goes wrong
+        emulator.catch_crash(|emulator| {
+            if let Event::RedrawRequested(_) = event {
+                if let Err(e) = screen.render(emulator) {
+                    error!("Error while rendering frame: {}", e);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                speed_counter.frame_drawn();
             }
-        }
 
-        if input.update(&event) {
-            // Close events
-            if input.key_pressed(VirtualKeyCode::Escape) {
-                *control_flow = ControlFlow::Exit;
-                emulator.finish();
-                return;
+            if let Event::WindowEvent {
+                event: WindowEvent::DroppedFile(path),
+                ..
+            } = &event
+            {
+                refresh_title |= open_rom(emulator, &mut config, path);
             }
 
-            if let Some(size) = input.window_resized() {
-                if let Err(e) = pixels.resize_surface(size.width, size.height) {
-                    error!("Error while rendering frame: {e}");
+            if input.update(&event) {
+                // Close events
+                if input.key_pressed(VirtualKeyCode::Escape) {
                     *control_flow = ControlFlow::Exit;
+                    emulator.finish();
+                    save_config(&config);
                     return;
                 }
-            }
 
-            if input.key_pressed(VirtualKeyCode::D) {
-                emulator.start_debugger();
-            }
+                if let Some(size) = input.window_resized() {
+                    if let Err(e) = screen.resize(size.width, size.height) {
+                        error!("Error while resizing window: {e}");
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                    // Remember the size of the window for next time
+                    let size = size.to_logical::<f64>(window.scale_factor());
+                    config.scale = ((size.width / SCREEN_WIDTH as f64)
+                        .min(size.height / SCREEN_HEIGHT as f64)
+                        .round() as u32)
+                        .max(1);
+                }
 
-            if input.key_pressed(VirtualKeyCode::S) {
-                if let Err(e) = emulator.screenshot() {
-                    warn!("Failed to save screenshot: {}", e);
+                let mut title_changed = std::mem::take(&mut refresh_title);
+                if input.held_control() {
+                    if input.key_pressed(VirtualKeyCode::R) {
+                        if let Err(e) = emulator.reset() {
+                            warn!("Failed to reset: {:#}", e);
+                        }
+                    }
+                    if let Some(n) = RECENT_ROM_KEYS
+                        .iter()
+                        .position(|key| input.key_pressed(*key))
+                    {
+                        match config.recent_roms.get(n).cloned() {
+                            Some(rom) => title_changed |= open_rom(emulator, &mut config, &rom),
+                            None => info!("No recent ROM #{}", n + 1),
+                        }
+                    }
                 }
-            }
 
-            emulator.handle_input(&input);
-            if emulator.update() {
-                *control_flow = ControlFlow::Exit;
-                emulator.finish();
-                return;
+                if input.key_pressed(VirtualKeyCode::D) {
+                    emulator.start_debugger();
+                }
+
+                if input.key_pressed(VirtualKeyCode::P) {
+                    emulator.toggle_pause();
+                    title_changed = true;
+                }
+
+                if input.key_pressed(VirtualKeyCode::N) {
+                    emulator.advance_frame();
+                }
+
+                if input.key_pressed(VirtualKeyCode::Minus) {
+                    emulator.change_speed(false);
+                }
+                if input.key_pressed(VirtualKeyCode::Equals) {
+                    emulator.change_speed(true);
+                }
+
+                if input.key_pressed(VirtualKeyCode::C) {
+                    let (name, palette) = next_palette(&config.palette);
+                    info!("Switching to {} palette", name);
+                    emulator.set_palette(palette);
+                    emulator.show_message(format!("Palette: {}", name));
+                    config.palette = name.to_string();
+                }
+
+                if input.key_pressed(VirtualKeyCode::R) && !input.held_control() {
+                    emulator.toggle_recording(None);
+                }
+
+                if input.key_pressed(VirtualKeyCode::F) {
+                    show_fps = !show_fps;
+                    if !show_fps {
+                        emulator.set_status("");
+                    }
+                }
+
+                if input.key_pressed(VirtualKeyCode::V) {
+                    emulator.toggle_scope();
+                }
+
+                if input.key_pressed(VirtualKeyCode::S) || input.key_pressed(VirtualKeyCode::F12) {
+                    if let Err(e) = emulator.screenshot(None) {
+                        warn!("Failed to save screenshot: {}", e);
+                    }
+                }
+
+                if let Some(warning) = audio_monitor.check(&audio_stats) {
+                    if warning.is_empty() {
+                        info!("Audio is back to normal");
+                    } else {
+                        warn!("Audio is glitching: {}", warning);
+                    }
+                    audio_warning = warning;
+                    title_changed = true;
+                }
+                if let Some((fps, speed)) = speed_counter.update(emulator.frame_count()) {
+                    speed_status = format!("{:.1} FPS ({:.0}%)", fps, speed);
+                    if show_fps {
+                        emulator.set_status(&speed_status);
+                    }
+                    title_changed = true;
+                    if cli.profile {
+                        info!("{}", emulator.take_stats());
+                    }
+                }
+                if title_changed {
+                    window.set_title(&window_title(
+                        emulator.game_info().as_deref(),
+                        emulator.is_paused(),
+                        &speed_status,
+                        &audio_warning,
+                    ));
+                }
+
+                emulator.handle_input(&input);
+                if emulator.update() {
+                    *control_flow = ControlFlow::Exit;
+                    emulator.finish();
+                    save_config(&config);
+                    return;
+                }
+                window.request_redraw();
+                *control_flow = ControlFlow::WaitUntil(scheduler.next_frame());
             }
-            window.request_redraw();
-        }
+        })
     });
 }
 
-fn init_audio(mut consumer: Consumer<i16, Arc<HeapRb<i16>>>) -> Result<Stream> {
+/// Keys that open the recent ROMs, with Ctrl held
+const RECENT_ROM_KEYS: [VirtualKeyCode; 9] = [
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6,
+    VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8,
+    VirtualKeyCode::Key9,
+];
+
+/// Insert the given ROM in place of the current one and remember it in the recent ROMs. Returns
+/// whether it was loaded.
+fn open_rom(emulator: &mut Emulator, config: &mut Config, rom: &Path) -> bool {
+    match emulator.load_rom(rom) {
+        Ok(()) => {
+            config.add_recent_rom(rom);
+            save_config(config);
+            true
+        }
+        Err(e) => {
+            warn!("Failed to load {}: {:#}", rom.display(), e);
+            false
+        }
+    }
+}
+
+fn print_recent_roms(config: &Config) {
+    if config.recent_roms.is_empty() {
+        println!("No ROM inserted: drop one onto the window to play it.");
+        return;
+    }
+    println!("No ROM inserted: drop one onto the window, or open a recent one with Ctrl+<N>:");
+    for (i, rom) in config
+        .recent_roms
+        .iter()
+        .take(RECENT_ROM_KEYS.len())
+        .enumerate()
+    {
+        println!("  {}: {}", i + 1, rom.display());
+    }
+}
+
+/// Title of the window, with the game and the status messages that aren't empty:
+/// `gb-rs — <game> - <status>...`
+fn window_title(game: Option<&str>, paused: bool, speed: &str, audio_warning: &str) -> String {
+    let mut title = String::from("gb-rs");
+    if let Some(game) = game {
+        title.push_str(" — ");
+        title.push_str(game);
+    }
+    let paused = if paused { "Paused" } else { "" };
+    for status in [paused, speed, audio_warning] {
+        if !status.is_empty() {
+            title.push_str(" - ");
+            title.push_str(status);
+        }
+    }
+    title
+}
+
+/// Return the built-in palette that comes after the given one (or the first one if it's not a
+/// built-in palette).
+fn next_palette(current: &str) -> (&'static str, DmgPalette) {
+    let presets = DmgPalette::PRESETS;
+    let next = presets
+        .iter()
+        .position(|(name, _)| *name == current)
+        .map_or(0, |i| (i + 1) % presets.len());
+    presets[next]
+}
+
+fn save_config(config: &Config) {
+    if let Err(e) = config.save() {
+        warn!("Failed to save config: {:#}", e);
+    }
+}
+
+/// Sample rate to fall back to if the device doesn't tell us its preferred one
+const FALLBACK_SAMPLE_RATE: u32 = 44100;
+
+/// Start the audio output stream.
+///
+/// Returns the stream along with the sample rate it's been opened with, which is the native rate
+/// of the device if it can be queried.
+fn init_audio(
+    mut consumer: Consumer<i16, Arc<HeapRb<i16>>>,
+    stats: Arc<AudioStats>,
+) -> Result<(Stream, NonZeroU32)> {
     let host = cpal::default_host();
     let device = host
         .default_output_device()
         .context("error while querying config")?;
     debug!("Audio device: {:?}", device.name());
+    let sample_rate = match device.default_output_config() {
+        Ok(config) => config.sample_rate(),
+        Err(e) => {
+            warn!(
+                "Failed to get default audio config ({}), falling back to {}Hz",
+                e, FALLBACK_SAMPLE_RATE
+            );
+            SampleRate(FALLBACK_SAMPLE_RATE)
+        }
+    };
+    info!("Audio sample rate is {}Hz", sample_rate.0);
+    let rate = NonZeroU32::new(sample_rate.0).context("The audio device's sample rate is 0Hz")?;
     let config = StreamConfig {
         channels: 2,
-        sample_rate: SampleRate(44100),
+        sample_rate,
         buffer_size: BufferSize::Fixed(2048),
     };
     let err_fn = |err| {
@@ -149,6 +710,13 @@ fn init_audio(mut consumer: Consumer<i16, Arc<HeapRb<i16>>>) -> Result<Stream> {
         .build_output_stream(
             &config,
             move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                if stats.is_paused() {
+                    // Drop what's left of the sound from before the pause, rather than playing
+                    // it in bits between stretches of underrun noise
+                    consumer.clear();
+                    data.fill(0.0);
+                    return;
+                }
                 let mut fell_behind = false;
                 trace!("Writing {} audio samples", data.len());
                 for sample in data {
@@ -160,9 +728,9 @@ fn init_audio(mut consumer: Consumer<i16, Arc<HeapRb<i16>>>) -> Result<Stream> {
                         }
                     }
                 }
-                // if fell_behind {
-                //     debug!("Buffer underrun!");
-                // }
+                if fell_behind {
+                    stats.record_underrun();
+                }
             },
             err_fn,
         )
@@ -170,7 +738,7 @@ fn init_audio(mut consumer: Consumer<i16, Arc<HeapRb<i16>>>) -> Result<Stream> {
     stream.play().context("Failed to start stream")?;
     info!("Audio stream started!");
 
-    Ok(stream)
+    Ok((stream, rate))
 }
 
 fn init_no_audio(mut consumer: Consumer<i16, Arc<HeapRb<i16>>>) {
@@ -184,3 +752,29 @@ fn init_no_audio(mut consumer: Consumer<i16, Arc<HeapRb<i16>>>) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation_report() {
+        // An MBC1 cartridge, without the Nintendo logo
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0147] = 0x01;
+        let (report, valid) = validation_report(Cartridge::from_bytes(rom));
+        assert!(!valid);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "valid=false");
+        assert_eq!(lines[1], "title=TEST");
+        assert_eq!(lines[3], "mapper=MBC1");
+        assert!(
+            lines.contains(&"error=Nintendo logo mismatch"),
+            "{}",
+            report
+        );
+
+        assert_eq!(validate(Path::new("does/not/exist.gb")), 1);
+    }
+}
@@ -0,0 +1,92 @@
+//! What the CPU is connected to.
+use crate::{breakpoint::WatchHit, bus::Bus, interrupt::InterruptFlag, CpuState};
+
+/// The address space and peripherals as seen by the CPU.
+///
+/// The Game Boy's bus implements it, but a [`Cpu`](crate::Cpu) can be connected to anything else,
+/// e.g. plain RAM to test instructions on their own. Only the memory accesses and the passing of
+/// time are required: by default, no interrupt is ever requested, and the debugger hooks never
+/// fire.
+pub trait Memory {
+    /// Read a byte on behalf of the CPU.
+    fn read_byte(&mut self, addr: u16) -> u8;
+
+    /// Write a byte on behalf of the CPU.
+    fn write_byte(&mut self, addr: u16, value: u8);
+
+    /// Let the given number of clock cycles pass, e.g. to run the peripherals while the CPU
+    /// accesses the bus.
+    fn tick(&mut self, cycles: u8);
+
+    /// IE - the interrupts that are enabled
+    fn interrupt_enable(&self) -> InterruptFlag {
+        InterruptFlag::empty()
+    }
+
+    /// IF - the interrupts that are requested
+    fn interrupt_flag(&self) -> InterruptFlag {
+        InterruptFlag::empty()
+    }
+
+    /// Clear the request of an interrupt when it is dispatched.
+    fn ack_interrupt(&mut self, _flag: InterruptFlag) {}
+
+    fn interrupt_pending(&self) -> bool {
+        !(self.interrupt_enable() & self.interrupt_flag()).is_empty()
+    }
+
+    /// Whether the debugger should pause before executing the instruction at `pc`. `cpu_state`
+    /// returns the registers, for the conditional breakpoints.
+    fn is_exec_break(&self, _pc: u16, _cpu_state: &dyn Fn() -> CpuState) -> bool {
+        false
+    }
+
+    /// Whether the debugger should pause when dispatching the given interrupt.
+    fn is_interrupt_break(&self, _flag: InterruptFlag) -> bool {
+        false
+    }
+
+    /// The watchpoint hit by the last memory accesses, if any.
+    fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        None
+    }
+}
+
+impl Memory for Bus {
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        self.cpu_read_byte(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.cpu_write_byte(addr, value);
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycle(cycles);
+    }
+
+    fn interrupt_enable(&self) -> InterruptFlag {
+        Bus::interrupt_enable(self)
+    }
+
+    fn interrupt_flag(&self) -> InterruptFlag {
+        Bus::interrupt_flag(self)
+    }
+
+    fn ack_interrupt(&mut self, flag: InterruptFlag) {
+        Bus::ack_interrupt(self, flag);
+    }
+
+    fn is_exec_break(&self, pc: u16, cpu_state: &dyn Fn() -> CpuState) -> bool {
+        self.breakpoints
+            .is_exec_break(pc, || self.cartridge.current_rom_bank(), cpu_state)
+    }
+
+    fn is_interrupt_break(&self, flag: InterruptFlag) -> bool {
+        self.breakpoints.is_interrupt_break(flag)
+    }
+
+    fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+}
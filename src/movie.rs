@@ -0,0 +1,136 @@
+//! Input movies, to replay the same button presses on every run.
+//!
+//! A movie is a text file with one line per change of the buttons held, starting at the given
+//! frame (counting from 0) and lasting until the next line:
+//!
+//! ```text
+//! # press Start on frame 120 for 5 frames, then hold Right and A
+//! 120 start
+//! 125 -
+//! 200 right+a
+//! ```
+//!
+//! The buttons are `a`, `b`, `start`, `select`, `up`, `down`, `left` and `right`, separated by `+`,
+//! or `-` for none. Empty lines and lines starting with `#` are ignored.
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use gb_rs::joypad::Button;
+
+use crate::input::InputSource;
+
+const BUTTONS: [(&str, Button); 8] = [
+    ("a", Button::A),
+    ("b", Button::B),
+    ("start", Button::Start),
+    ("select", Button::Select),
+    ("up", Button::Up),
+    ("down", Button::Down),
+    ("left", Button::Left),
+    ("right", Button::Right),
+];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Movie {
+    /// The buttons held from each frame on, sorted by frame
+    changes: Vec<(u64, Vec<Button>)>,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid movie {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut changes: Vec<(u64, Vec<Button>)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parse_line = || -> Result<(u64, Vec<Button>)> {
+                let Some((frame, buttons)) = line.split_once(char::is_whitespace) else {
+                    bail!("Expected `<frame> <buttons>`");
+                };
+                let frame = frame.parse::<u64>().context("Invalid frame number")?;
+                if let Some((last, _)) = changes.last() {
+                    ensure!(frame > *last, "Frames must be in increasing order");
+                }
+                Ok((frame, parse_buttons(buttons.trim())?))
+            };
+            changes.push(parse_line().with_context(|| format!("Line {}", i + 1))?);
+        }
+        Ok(Self { changes })
+    }
+
+    /// Frame of the movie's last line, i.e. the last time the buttons change.
+    pub fn last_frame(&self) -> u64 {
+        self.changes.last().map_or(0, |(frame, _)| *frame)
+    }
+
+    /// The state of all the buttons at the given frame.
+    pub fn buttons_at(&self, frame: u64) -> impl Iterator<Item = (Button, bool)> + '_ {
+        let idx = self.changes.partition_point(|(f, _)| *f <= frame);
+        let held = idx.checked_sub(1).map_or(&[][..], |i| &self.changes[i].1);
+        BUTTONS
+            .iter()
+            .map(move |&(_, button)| (button, held.contains(&button)))
+    }
+}
+
+impl InputSource for Movie {
+    fn is_pressed(&self, button: Button, frame: u64) -> bool {
+        self.buttons_at(frame)
+            .any(|(b, pressed)| b == button && pressed)
+    }
+}
+
+fn parse_buttons(s: &str) -> Result<Vec<Button>> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    s.split('+')
+        .map(|name| {
+            BUTTONS
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|&(_, button)| button)
+                .with_context(|| format!("Unknown button `{name}`"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(movie: &Movie, frame: u64) -> Vec<Button> {
+        movie
+            .buttons_at(frame)
+            .filter(|(_, pressed)| *pressed)
+            .map(|(button, _)| button)
+            .collect()
+    }
+
+    #[test]
+    fn test_movie() {
+        let movie = Movie::parse("# comment\n\n120 start\n125 -\n200 Right+a\n").unwrap();
+        assert_eq!(held(&movie, 0), vec![]);
+        assert_eq!(held(&movie, 120), vec![Button::Start]);
+        assert_eq!(held(&movie, 124), vec![Button::Start]);
+        assert_eq!(held(&movie, 125), vec![]);
+        assert_eq!(held(&movie, 1000), vec![Button::A, Button::Right]);
+        assert_eq!(movie.buttons_at(0).count(), 8);
+        assert_eq!(movie.last_frame(), 200);
+
+        let err = Movie::parse("10 a\n5 b").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Line 2: Frames must be in increasing order"
+        );
+        assert!(Movie::parse("10 jump").is_err());
+        assert!(Movie::parse("10").is_err());
+    }
+}
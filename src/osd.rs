@@ -0,0 +1,201 @@
+//! On-screen display: short messages drawn over the Game Boy screen to give feedback on what the
+//! user just did, and an optional status line (e.g. the FPS counter).
+//!
+//! The text is drawn with a tiny 3x5 font directly onto the 160x144 frame, before it's scaled to
+//! the window, so it looks like part of the Game Boy's screen.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use gb_rs::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// How long a message stays on screen, fade-out included
+const MESSAGE_DURATION: Duration = Duration::from_millis(2000);
+/// How long the fade-out at the end of a message lasts
+const FADE_DURATION: Duration = Duration::from_millis(500);
+/// Maximum number of messages on screen at the same time: older ones are dropped first
+const MAX_MESSAGES: usize = 4;
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+/// Horizontal distance between the start of two characters
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+/// Vertical distance between the top of two lines (which leaves room for the shadow)
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+/// Distance between the text and the edges of the screen
+const MARGIN: usize = 2;
+
+const TEXT_COLOR: [u8; 3] = [0xFF, 0xFF, 0xFF];
+const SHADOW_COLOR: [u8; 3] = [0x00, 0x00, 0x00];
+
+#[derive(Default)]
+pub struct Osd {
+    /// Messages, oldest first, with the time they were shown
+    messages: VecDeque<(String, Instant)>,
+    /// Line shown permanently at the top of the screen (nothing if empty)
+    status: String,
+}
+
+impl Osd {
+    /// Show a message for a couple of seconds.
+    pub fn show(&mut self, text: impl Into<String>) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((text.into(), Instant::now()));
+    }
+
+    /// Set the status line, or hide it if `status` is empty.
+    pub fn set_status(&mut self, status: impl Into<String>) {
+        self.status = status.into();
+    }
+
+    /// Draw the status line and the current messages (the most recent at the bottom) onto the
+    /// given RGBA frame, and forget about the messages that have expired by `now`.
+    pub fn draw(&mut self, frame: &mut [u8], now: Instant) {
+        self.messages
+            .retain(|(_, shown)| now.saturating_duration_since(*shown) < MESSAGE_DURATION);
+
+        if !self.status.is_empty() {
+            draw_text(frame, MARGIN, MARGIN, &self.status, 1.0);
+        }
+        let bottom = SCREEN_HEIGHT - MARGIN - LINE_HEIGHT;
+        for (i, (text, shown)) in self.messages.iter().rev().enumerate() {
+            let left = MESSAGE_DURATION.saturating_sub(now.saturating_duration_since(*shown));
+            let alpha = (left.as_secs_f32() / FADE_DURATION.as_secs_f32()).min(1.0);
+            draw_text(frame, MARGIN, bottom - i * LINE_HEIGHT, text, alpha);
+        }
+    }
+}
+
+/// Draw `text` with its top-left corner at `(x, y)`, blended with the frame according to `alpha`
+/// (between 0.0 and 1.0). Whatever doesn't fit on the screen is cut off.
+fn draw_text(frame: &mut [u8], x: usize, y: usize, text: &str, alpha: f32) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph = glyph(c);
+        let left = x + i * ADVANCE;
+        for (dy, row) in glyph.iter().enumerate() {
+            for dx in 0..GLYPH_WIDTH {
+                if row & (1 << (GLYPH_WIDTH - 1 - dx)) != 0 {
+                    blend(frame, left + dx + 1, y + dy + 1, SHADOW_COLOR, alpha);
+                    blend(frame, left + dx, y + dy, TEXT_COLOR, alpha);
+                }
+            }
+        }
+    }
+}
+
+fn blend(frame: &mut [u8], x: usize, y: usize, color: [u8; 3], alpha: f32) {
+    if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+        return;
+    }
+    let pixel = &mut frame[(y * SCREEN_WIDTH + x) * 4..][..3];
+    for (p, c) in pixel.iter_mut().zip(color) {
+        *p = (*p as f32 * (1.0 - alpha) + c as f32 * alpha).round() as u8;
+    }
+}
+
+/// Rows of the glyph for the given character, top to bottom, the most significant of the 3 bits
+/// being the leftmost pixel. Lowercase letters are shown as uppercase, and characters without a
+/// glyph as `?`.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> &[u8] {
+        &frame[(y * SCREEN_WIDTH + x) * 4..][..4]
+    }
+
+    #[test]
+    fn test_osd() {
+        let gray = [0x80, 0x80, 0x80, 0xFF];
+        let blank = gray.repeat(SCREEN_WIDTH * SCREEN_HEIGHT);
+        let mut osd = Osd::default();
+        let start = Instant::now();
+
+        // "1" has a full bottom row, with its shadow below it
+        osd.messages.push_back(("1".to_string(), start));
+        let mut frame = blank.clone();
+        osd.draw(&mut frame, start);
+        let top = SCREEN_HEIGHT - MARGIN - LINE_HEIGHT;
+        let bottom = top + GLYPH_HEIGHT - 1;
+        assert_eq!(pixel(&frame, MARGIN, bottom), [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(pixel(&frame, MARGIN + 1, bottom + 1), [0, 0, 0, 0xFF]);
+        assert_eq!(pixel(&frame, MARGIN, top), gray);
+
+        // Fading out...
+        let mut frame = blank.clone();
+        osd.draw(&mut frame, start + MESSAGE_DURATION - FADE_DURATION / 2);
+        assert_eq!(pixel(&frame, MARGIN, bottom), [0xC0, 0xC0, 0xC0, 0xFF]);
+
+        // ...and gone
+        let mut frame = blank.clone();
+        osd.draw(&mut frame, start + MESSAGE_DURATION);
+        assert_eq!(frame, blank);
+        assert!(osd.messages.is_empty());
+
+        // The status stays, and text running off the screen is cut off
+        osd.set_status("W".repeat(100));
+        let mut frame = blank.clone();
+        osd.draw(&mut frame, start + MESSAGE_DURATION * 10);
+        assert_eq!(pixel(&frame, MARGIN, MARGIN), [0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+}
@@ -0,0 +1,190 @@
+//! Counters to measure the performance of the emulator, and of the game's code.
+use std::{collections::HashMap, fmt, time::Duration};
+
+/// Performance counters, returned by [`GameBoy::stats()`](crate::gameboy::GameBoy::stats).
+///
+/// The cycles and frames are always counted, but the time spent in the different components is
+/// only measured while profiling is enabled with
+/// [`GameBoy::set_profiling()`](crate::gameboy::GameBoy::set_profiling), as reading the clock
+/// isn't free.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Clock cycles emulated
+    pub cycles: u64,
+    /// Frames completed (frames aren't produced while the LCD is off)
+    pub frames: u64,
+    /// Time spent emulating
+    pub total_time: Duration,
+    /// Part of `total_time` spent in the PPU
+    pub ppu_time: Duration,
+    /// Part of `total_time` spent in the APU
+    pub apu_time: Duration,
+}
+
+impl Stats {
+    /// Time spent in the CPU, which includes everything that isn't the PPU or the APU (e.g. the
+    /// timer and the memory accesses).
+    pub fn cpu_time(&self) -> Duration {
+        self.total_time
+            .saturating_sub(self.ppu_time)
+            .saturating_sub(self.apu_time)
+    }
+
+    /// Emulated clock cycles per second of profiled time. The real Game Boy runs at 4194304.
+    pub fn cycles_per_second(&self) -> f64 {
+        let secs = self.total_time.as_secs_f64();
+        if secs > 0.0 {
+            self.cycles as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total_time.as_secs_f64().max(f64::EPSILON);
+        let percent = |d: Duration| 100.0 * d.as_secs_f64() / total;
+        write!(
+            f,
+            "{} frames, {:.2} MHz (CPU {:.0}%, PPU {:.0}%, APU {:.0}%)",
+            self.frames,
+            self.cycles_per_second() / 1_000_000.0,
+            percent(self.cpu_time()),
+            percent(self.ppu_time),
+            percent(self.apu_time)
+        )
+    }
+}
+
+/// Histogram of the instructions executed by the game, to find the hotspots of its code.
+///
+/// Instructions are counted by address, along with the ROM bank for the addresses in the ROM, as
+/// the same address runs different code in different banks. Recording is enabled with
+/// [`GameBoy::set_code_profiling()`](crate::gameboy::GameBoy::set_code_profiling).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CodeProfile {
+    counts: HashMap<(Option<u16>, u16), Hotspot>,
+    /// Total number of instructions executed
+    instructions: u64,
+    /// Total number of clock cycles spent executing them
+    cycles: u64,
+}
+
+/// How much time was spent executing the instruction at a given address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotspot {
+    /// ROM bank of the instruction, or `None` if it's not in the ROM
+    pub bank: Option<u16>,
+    pub addr: u16,
+    /// Number of times it's been executed
+    pub count: u64,
+    /// Clock cycles spent executing it (halting included)
+    pub cycles: u64,
+}
+
+impl CodeProfile {
+    /// Count one execution of the instruction at `addr`, which took `cycles` clock cycles.
+    pub(crate) fn record(&mut self, bank: Option<u16>, addr: u16, cycles: u64) {
+        let hotspot = self.counts.entry((bank, addr)).or_insert(Hotspot {
+            bank,
+            addr,
+            count: 0,
+            cycles: 0,
+        });
+        hotspot.count += 1;
+        hotspot.cycles += cycles;
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+
+    /// The `n` instructions the most clock cycles were spent on, in decreasing order.
+    pub fn hotspots(&self, n: usize) -> Vec<Hotspot> {
+        let mut hotspots = self.counts.values().copied().collect::<Vec<_>>();
+        hotspots.sort_by_key(|h| (std::cmp::Reverse(h.cycles), h.bank, h.addr));
+        hotspots.truncate(n);
+        hotspots
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Part of the total number of cycles spent on the given hotspot, in percent.
+    pub fn percent(&self, hotspot: &Hotspot) -> f64 {
+        100.0 * hotspot.cycles as f64 / self.cycles.max(1) as f64
+    }
+}
+
+impl fmt::Display for Hotspot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr)?,
+            None => write!(f, "{:04X}", self.addr)?,
+        }
+        write!(f, " {} cycles ({}x)", self.cycles, self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats {
+            cycles: 4_194_304,
+            frames: 60,
+            total_time: Duration::from_millis(500),
+            ppu_time: Duration::from_millis(200),
+            apu_time: Duration::from_millis(100),
+        };
+        assert_eq!(stats.cpu_time(), Duration::from_millis(200));
+        assert_eq!(stats.cycles_per_second(), 8_388_608.0);
+        assert_eq!(
+            stats.to_string(),
+            "60 frames, 8.39 MHz (CPU 40%, PPU 40%, APU 20%)"
+        );
+        assert_eq!(Stats::default().cycles_per_second(), 0.0);
+    }
+
+    #[test]
+    fn test_code_profile() {
+        let mut profile = CodeProfile::default();
+        profile.record(Some(0), 0x0150, 4);
+        for _ in 0..3 {
+            profile.record(Some(1), 0x4000, 8);
+        }
+        profile.record(Some(2), 0x4000, 12);
+        profile.record(None, 0xC000, 16);
+
+        assert_eq!(profile.instructions(), 6);
+        assert_eq!(profile.cycles(), 56);
+        let hotspots = profile.hotspots(2);
+        assert_eq!(
+            hotspots,
+            [
+                Hotspot {
+                    bank: Some(1),
+                    addr: 0x4000,
+                    count: 3,
+                    cycles: 24
+                },
+                Hotspot {
+                    bank: None,
+                    addr: 0xC000,
+                    count: 1,
+                    cycles: 16
+                },
+            ]
+        );
+        assert_eq!(hotspots[0].to_string(), "01:4000 24 cycles (3x)");
+        assert_eq!(hotspots[1].to_string(), "C000 16 cycles (1x)");
+        assert!((profile.percent(&hotspots[1]) - 28.57).abs() < 0.01);
+        assert_eq!(profile.hotspots(10).len(), 4);
+    }
+}
@@ -0,0 +1,189 @@
+//! Recording of the screen to an animated PNG.
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use gb_rs::{timing::frames_to_duration, DirtyLines, FrameSink, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// Records the frames it receives to an animated PNG (APNG).
+///
+/// The frames are stored as indices into a palette of the colours seen so far, and written to a
+/// temporary file as they arrive so that the memory used doesn't depend on the length of the
+/// recording. Consecutive identical frames are merged into one that is displayed for longer,
+/// without even looking at the pixels when the emulator reports that no lines changed. The APNG
+/// itself is written by [`finish()`](Self::finish), as it needs to know the number of frames
+/// beforehand.
+///
+/// Note that no frames are produced while the LCD is off, so these periods are skipped.
+pub struct Recorder {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    tmp: BufWriter<File>,
+    palette: Vec<[u8; 3]>,
+    /// The last frame received, as palette indices
+    last: Vec<u8>,
+    /// Number of Game Boy frames each frame written to the temporary file is displayed for
+    durations: Vec<u32>,
+    /// Set when the emulator reported that the next frame is identical to the previous one
+    unchanged: bool,
+    /// First error encountered while writing the temporary file
+    error: Option<anyhow::Error>,
+}
+
+impl Recorder {
+    /// Start a recording that will be saved to `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let tmp_path = path.with_extension("frames.tmp");
+        let tmp = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        Ok(Self {
+            path,
+            tmp_path,
+            tmp: BufWriter::new(tmp),
+            palette: Vec::new(),
+            last: Vec::new(),
+            durations: Vec::new(),
+            unchanged: false,
+            error: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of Game Boy frames recorded so far
+    pub fn frames(&self) -> u32 {
+        self.durations.iter().sum()
+    }
+
+    /// Convert an RGBA frame to palette indices, adding its colours to the palette.
+    fn index(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        let mut indices = Vec::with_capacity(PIXELS);
+        for pixel in frame.chunks_exact(4) {
+            let color = [pixel[0], pixel[1], pixel[2]];
+            let index = match self.palette.iter().position(|c| *c == color) {
+                Some(index) => index,
+                None if self.palette.len() < 256 => {
+                    self.palette.push(color);
+                    self.palette.len() - 1
+                }
+                None => bail!("Too many colours"),
+            };
+            indices.push(index as u8);
+        }
+        Ok(indices)
+    }
+
+    fn record(&mut self, frame: &[u8]) -> Result<()> {
+        if self.unchanged {
+            if let Some(duration) = self.durations.last_mut() {
+                *duration += 1;
+                return Ok(());
+            }
+        }
+        let indices = self.index(frame)?;
+        if indices == self.last {
+            if let Some(duration) = self.durations.last_mut() {
+                *duration += 1;
+                return Ok(());
+            }
+        }
+        self.tmp.write_all(&indices)?;
+        self.durations.push(1);
+        self.last = indices;
+        Ok(())
+    }
+
+    /// Write the APNG and delete the temporary file.
+    pub fn finish(mut self) -> Result<PathBuf> {
+        let result = self.write_apng();
+        let _ = fs::remove_file(&self.tmp_path);
+        result.with_context(|| format!("Failed to save recording to {}", self.path.display()))?;
+        Ok(self.path)
+    }
+
+    fn write_apng(&mut self) -> Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.durations.is_empty() {
+            bail!("No frames were recorded");
+        }
+        self.tmp.flush()?;
+        let mut frames = BufReader::new(File::open(&self.tmp_path)?);
+
+        let file = BufWriter::new(File::create(&self.path)?);
+        let mut encoder = png::Encoder::new(file, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(self.palette.concat());
+        encoder.set_animated(self.durations.len() as u32, 0)?;
+        let mut writer = encoder.write_header()?;
+
+        let mut frame = vec![0; PIXELS];
+        let mut elapsed = 0;
+        for &duration in &self.durations {
+            frames.read_exact(&mut frame)?;
+            let (num, den) = frame_delay(elapsed, duration);
+            writer.set_frame_delay(num, den)?;
+            writer.write_image_data(&frame)?;
+            elapsed += duration;
+        }
+        writer.finish()?;
+
+        Ok(())
+    }
+}
+
+impl FrameSink for Recorder {
+    fn push_frame(&mut self, frame: &[u8]) {
+        if self.error.is_none() {
+            if let Err(e) = self.record(frame) {
+                self.error = Some(e);
+            }
+        }
+        self.unchanged = false;
+    }
+
+    fn dirty_lines(&mut self, lines: &DirtyLines) {
+        self.unchanged = lines.is_empty();
+    }
+}
+
+/// Delay of a frame displayed for `duration` Game Boy frames after `elapsed` ones, as a fraction
+/// of a second.
+///
+/// The delays are rounded to the millisecond in a way that the errors don't accumulate, so the
+/// whole animation plays at exactly the Game Boy's frame rate.
+fn frame_delay(elapsed: u32, duration: u32) -> (u16, u16) {
+    let to_ms =
+        |frames: u32| (frames_to_duration(frames as u64).as_secs_f64() * 1000.0).round() as u32;
+    let ms = to_ms(elapsed + duration) - to_ms(elapsed);
+    match u16::try_from(ms) {
+        Ok(ms) => (ms, 1000),
+        Err(_) => ((ms / 1000).min(u16::MAX as u32) as u16, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_delay() {
+        // 16.74ms per frame
+        assert_eq!(frame_delay(0, 1), (17, 1000));
+        assert_eq!(frame_delay(1, 1), (16, 1000));
+        let total: u32 = (0..3584).map(|i| frame_delay(i, 1).0 as u32).sum();
+        assert_eq!(total, 60_006);
+        // 216000 frames of a static screen last a bit more than an hour
+        assert_eq!(frame_delay(0, 60 * 60 * 60), (3616, 1));
+    }
+}
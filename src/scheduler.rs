@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+use gb_rs::timing::{FRAMES_PER_SECOND, FRAME_DURATION};
+
+/// How often the frame rate is computed
+const SPEED_PERIOD: Duration = Duration::from_secs(1);
+
+/// Decides when the event loop should wake up to run the next frame.
+///
+/// The emulation itself is paced by wall-clock time, so all this does is make the event loop sleep
+/// between frames instead of spinning, which on high refresh rate monitors burns a lot of CPU for
+/// nothing.
+#[derive(Debug)]
+pub struct FrameScheduler {
+    next_frame: Instant,
+}
+
+impl FrameScheduler {
+    pub fn new() -> Self {
+        Self {
+            next_frame: Instant::now() + FRAME_DURATION,
+        }
+    }
+
+    /// Return the time at which the next frame is due.
+    pub fn next_frame(&mut self) -> Instant {
+        self.advance(Instant::now())
+    }
+
+    fn advance(&mut self, now: Instant) -> Instant {
+        if self.next_frame <= now {
+            self.next_frame += FRAME_DURATION;
+            if self.next_frame <= now {
+                // We're late by more than a frame (e.g. the window was being dragged around):
+                // don't try to catch up, just start again from now.
+                self.next_frame = now + FRAME_DURATION;
+            }
+        }
+        self.next_frame
+    }
+}
+
+impl Default for FrameScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Measures how many frames are displayed per second, and how fast the emulation is running
+/// compared to the real hardware.
+#[derive(Debug)]
+pub struct SpeedCounter {
+    last_update: Instant,
+    /// Frames displayed since the last update
+    frames_drawn: u32,
+    /// Frames emulated at the time of the last update
+    last_emulated: u64,
+}
+
+impl SpeedCounter {
+    pub fn new() -> Self {
+        Self {
+            last_update: Instant::now(),
+            frames_drawn: 0,
+            last_emulated: 0,
+        }
+    }
+
+    pub fn frame_drawn(&mut self) {
+        self.frames_drawn += 1;
+    }
+
+    /// Return the number of frames displayed per second and the emulation speed (in percent), if
+    /// it's time to update them.
+    ///
+    /// `emulated_frames` is the total number of frames produced by the emulator so far.
+    pub fn update(&mut self, emulated_frames: u64) -> Option<(f64, f64)> {
+        let elapsed = self.last_update.elapsed();
+        if elapsed < SPEED_PERIOD {
+            return None;
+        }
+        let secs = elapsed.as_secs_f64();
+        let fps = self.frames_drawn as f64 / secs;
+        let speed =
+            (emulated_frames - self.last_emulated) as f64 / secs / FRAMES_PER_SECOND * 100.0;
+
+        self.last_update = Instant::now();
+        self.frames_drawn = 0;
+        self.last_emulated = emulated_frames;
+
+        Some((fps, speed))
+    }
+}
+
+impl Default for SpeedCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_scheduler() {
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler {
+            next_frame: start + FRAME_DURATION,
+        };
+
+        // not due yet
+        assert_eq!(scheduler.advance(start), start + FRAME_DURATION);
+        // frames are scheduled at regular intervals, even if we wake up a bit late
+        let late = start + FRAME_DURATION + Duration::from_millis(2);
+        assert_eq!(scheduler.advance(late), start + 2 * FRAME_DURATION);
+        // but we don't try to catch up after a long pause
+        let very_late = start + 10 * FRAME_DURATION;
+        assert_eq!(scheduler.advance(very_late), very_late + FRAME_DURATION);
+    }
+}
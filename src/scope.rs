@@ -0,0 +1,100 @@
+//! Oscilloscope overlay: shows the output of each sound channel over the Game Boy screen, one lane
+//! per channel, along with a bar for its current volume.
+use gb_rs::{ApuSnapshot, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Height of the lane of each of the 4 channels
+const LANE_HEIGHT: usize = SCREEN_HEIGHT / 4;
+const VOLUME_BAR_WIDTH: usize = 3;
+/// Where the waveform starts, leaving room for the volume bar
+const WAVE_LEFT: usize = VOLUME_BAR_WIDTH + 2;
+
+/// Colors of channels 1 to 4 while they're playing
+const CHANNEL_COLORS: [[u8; 3]; 4] = [
+    [0xFF, 0x60, 0x60],
+    [0xFF, 0xC0, 0x40],
+    [0x60, 0xE0, 0x60],
+    [0x60, 0xA0, 0xFF],
+];
+/// Color of the channels that are disabled
+const INACTIVE_COLOR: [u8; 3] = [0x70, 0x70, 0x70];
+
+/// Draw the oscilloscope onto the given RGBA frame.
+pub fn draw(frame: &mut [u8], snapshot: &ApuSnapshot) {
+    // Darken the screen so that the scope stands out
+    for pixel in frame.chunks_exact_mut(4) {
+        for p in &mut pixel[..3] {
+            *p /= 3;
+        }
+    }
+
+    for (i, channel) in snapshot.channels.iter().enumerate() {
+        let top = i * LANE_HEIGHT;
+        let bottom = top + LANE_HEIGHT - 1;
+        let color = if channel.enabled {
+            CHANNEL_COLORS[i]
+        } else {
+            INACTIVE_COLOR
+        };
+
+        let bar_height = channel.volume as usize * (LANE_HEIGHT - 1) / 15;
+        for y in (bottom - bar_height)..bottom {
+            for x in 0..VOLUME_BAR_WIDTH {
+                set_pixel(frame, x, y, color);
+            }
+        }
+
+        // The DACs output 1.0 for silence and -1.0 at full volume: draw the silence at the bottom
+        // of the lane
+        let width = SCREEN_WIDTH - WAVE_LEFT;
+        let samples = &channel.samples[channel.samples.len().saturating_sub(width)..];
+        for (x, sample) in samples.iter().enumerate() {
+            let level = (1.0 + sample.clamp(-1.0, 1.0)) / 2.0;
+            let y = top + (level * (LANE_HEIGHT - 2) as f32).round() as usize;
+            set_pixel(frame, WAVE_LEFT + x, y, color);
+        }
+    }
+}
+
+fn set_pixel(frame: &mut [u8], x: usize, y: usize, color: [u8; 3]) {
+    frame[(y * SCREEN_WIDTH + x) * 4..][..3].copy_from_slice(&color);
+}
+
+#[cfg(test)]
+mod tests {
+    use gb_rs::ChannelSnapshot;
+
+    use super::*;
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> &[u8] {
+        &frame[(y * SCREEN_WIDTH + x) * 4..][..3]
+    }
+
+    #[test]
+    fn test_scope() {
+        let mut frame = [0x90, 0x90, 0x90, 0xFF].repeat(SCREEN_WIDTH * SCREEN_HEIGHT);
+        let mut snapshot = ApuSnapshot::default();
+        // Channel 2 playing at full volume
+        snapshot.channels[1] = ChannelSnapshot {
+            enabled: true,
+            dac_enabled: true,
+            volume: 15,
+            frequency: 440.0,
+            samples: vec![-1.0; 1000],
+        };
+        draw(&mut frame, &snapshot);
+
+        // Background darkened
+        assert_eq!(pixel(&frame, 100, 100), [0x30, 0x30, 0x30]);
+        // Full volume bar
+        assert_eq!(pixel(&frame, 0, LANE_HEIGHT + 1), CHANNEL_COLORS[1]);
+        // Waveform at the top of the lane, all the way to the right edge
+        assert_eq!(pixel(&frame, WAVE_LEFT, LANE_HEIGHT), CHANNEL_COLORS[1]);
+        assert_eq!(
+            pixel(&frame, SCREEN_WIDTH - 1, LANE_HEIGHT),
+            CHANNEL_COLORS[1]
+        );
+        // Channel 1 has no samples and no volume
+        assert_eq!(pixel(&frame, WAVE_LEFT, 0), [0x30, 0x30, 0x30]);
+        assert_eq!(pixel(&frame, 0, LANE_HEIGHT - 2), [0x30, 0x30, 0x30]);
+    }
+}
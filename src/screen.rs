@@ -0,0 +1,198 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use gb_rs::{FRAME_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH};
+use pixels::{Pixels, PixelsBuilder, SurfaceTexture};
+use winit::window::Window;
+
+use crate::emulator::Emulator;
+
+/// How the Game Boy screen is scaled to fit the window
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ScaleMode {
+    /// Largest integer multiple of the screen size that fits in the window
+    #[default]
+    Integer,
+    /// As large as possible while keeping the 10:9 aspect ratio
+    Aspect,
+    /// Stretch the screen to fill the whole window
+    Fill,
+}
+
+impl ScaleMode {
+    /// Return the area of a `width`x`height` surface the screen should be drawn to, as `(x, y,
+    /// width, height)`. The image is centered, and the rest of the surface is left black.
+    pub fn viewport(self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let (screen_w, screen_h) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        let (w, h) = match self {
+            ScaleMode::Integer => {
+                let scale = (width / screen_w).min(height / screen_h).max(1);
+                (screen_w * scale, screen_h * scale)
+            }
+            ScaleMode::Aspect => {
+                if width * screen_h > height * screen_w {
+                    (height * screen_w / screen_h, height)
+                } else {
+                    (width, width * screen_h / screen_w)
+                }
+            }
+            ScaleMode::Fill => (width, height),
+        };
+        let (w, h) = (w.min(width), h.min(height));
+        ((width - w) / 2, (height - h) / 2, w, h)
+    }
+}
+
+impl FromStr for ScaleMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "integer" => Ok(ScaleMode::Integer),
+            "aspect" => Ok(ScaleMode::Aspect),
+            "fill" => Ok(ScaleMode::Fill),
+            _ => bail!("Unknown scale mode {}", s),
+        }
+    }
+}
+
+impl std::fmt::Display for ScaleMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ScaleMode::Integer => "integer",
+            ScaleMode::Aspect => "aspect",
+            ScaleMode::Fill => "fill",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The window's drawing surface.
+///
+/// In integer mode, the pixel buffer is the size of the Game Boy screen and `pixels` takes care of
+/// the scaling. In the other modes, the buffer is the size of the window and the frame is scaled
+/// into it by hand, as `pixels` only ever scales by integer factors.
+pub struct Screen {
+    pixels: Pixels,
+    mode: ScaleMode,
+    /// Size of the surface, in physical pixels
+    size: (u32, u32),
+    /// Unscaled frame (only used when not in integer mode)
+    frame: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(window: &Window, mode: ScaleMode, vsync: bool) -> Result<Self> {
+        let window_size = window.inner_size();
+        let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, window);
+        let pixels = PixelsBuilder::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, surface_texture)
+            .enable_vsync(vsync)
+            .build()?;
+        let mut screen = Self {
+            pixels,
+            mode,
+            size: (window_size.width, window_size.height),
+            frame: vec![0; FRAME_SIZE],
+        };
+        screen.resize(window_size.width, window_size.height)?;
+
+        Ok(screen)
+    }
+
+    /// Resize the surface to the given size, in physical pixels.
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<()> {
+        if width == 0 || height == 0 {
+            // minimised window
+            return Ok(());
+        }
+        self.size = (width, height);
+        self.pixels.resize_surface(width, height)?;
+        if self.mode != ScaleMode::Integer {
+            self.pixels.resize_buffer(width, height)?;
+        }
+        Ok(())
+    }
+
+    /// Draw the current frame of the emulator to the window.
+    pub fn render(&mut self, emulator: &mut Emulator) -> Result<()> {
+        if self.mode == ScaleMode::Integer {
+            emulator.render(self.pixels.get_frame_mut());
+        } else {
+            emulator.render(&mut self.frame);
+            let (width, height) = self.size;
+            let (x, y, w, h) = self.mode.viewport(width, height);
+            blit_scaled(
+                &self.frame,
+                self.pixels.get_frame_mut(),
+                width as usize,
+                (x as usize, y as usize, w as usize, h as usize),
+            );
+        }
+        self.pixels.render()?;
+
+        Ok(())
+    }
+}
+
+/// Scale the RGBA screen-sized `src` image into the given area of `dst` using nearest-neighbour
+/// sampling. Pixels outside the area are set to black.
+fn blit_scaled(
+    src: &[u8],
+    dst: &mut [u8],
+    dst_width: usize,
+    (x, y, w, h): (usize, usize, usize, usize),
+) {
+    dst.fill(0);
+    for (row, line) in dst.chunks_exact_mut(dst_width * 4).enumerate() {
+        for p in line.chunks_exact_mut(4) {
+            p[3] = 255;
+        }
+        if row < y || row >= y + h {
+            continue;
+        }
+        let src_y = (row - y) * SCREEN_HEIGHT / h;
+        let src_line = &src[src_y * SCREEN_WIDTH * 4..][..SCREEN_WIDTH * 4];
+        for (col, p) in line[x * 4..(x + w) * 4].chunks_exact_mut(4).enumerate() {
+            let src_x = col * SCREEN_WIDTH / w;
+            p.copy_from_slice(&src_line[src_x * 4..][..4]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_viewport() {
+        // exact multiple
+        assert_eq!(ScaleMode::Integer.viewport(480, 432), (0, 0, 480, 432));
+        assert_eq!(ScaleMode::Aspect.viewport(480, 432), (0, 0, 480, 432));
+        // wider than 10:9
+        assert_eq!(ScaleMode::Integer.viewport(800, 450), (160, 9, 480, 432));
+        assert_eq!(ScaleMode::Aspect.viewport(800, 450), (150, 0, 500, 450));
+        assert_eq!(ScaleMode::Fill.viewport(800, 450), (0, 0, 800, 450));
+        // taller than 10:9
+        assert_eq!(ScaleMode::Aspect.viewport(320, 600), (0, 156, 320, 288));
+        // smaller than the screen
+        assert_eq!(ScaleMode::Integer.viewport(100, 100), (0, 0, 100, 100));
+    }
+
+    #[test]
+    fn test_blit_scaled() {
+        let mut src = [0, 0, 0, 255].repeat(SCREEN_WIDTH * SCREEN_HEIGHT);
+        // last pixel is white
+        src[(SCREEN_WIDTH * SCREEN_HEIGHT - 1) * 4..].fill(255);
+        let (width, height) = (SCREEN_WIDTH * 2 + 2, SCREEN_HEIGHT * 2);
+        let mut dst = vec![1; width * height * 4];
+        blit_scaled(&src, &mut dst, width, (1, 0, width - 2, height));
+
+        let pixel = |x: usize, y: usize| &dst[(y * width + x) * 4..][..4];
+        assert_eq!(pixel(0, 0), [0, 0, 0, 255]);
+        assert_eq!(pixel(width - 2, height - 1), [255, 255, 255, 255]);
+        assert_eq!(pixel(width - 3, height - 2), [255, 255, 255, 255]);
+        assert_eq!(pixel(width - 4, height - 2), [0, 0, 0, 255]);
+        assert_eq!(pixel(width - 1, height - 1), [0, 0, 0, 255]);
+    }
+}
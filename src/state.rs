@@ -0,0 +1,199 @@
+//! Savestates: a snapshot of the whole machine, from which the emulation resumes exactly where it
+//! was, cycle for cycle.
+//!
+//! A state is a plain binary dump of each component's registers and internal state, in a fixed
+//! order, after a header identifying the format and the game. It isn't meant to be portable
+//! across versions of the emulator: a state saved by another version is rejected.
+//!
+//! Only the emulated machine is saved. What belongs to the host or the debugger (the breakpoints,
+//! cheats and symbols, the audio filters, the performance counters...) is left as it is when a
+//! state is loaded.
+use std::fmt::Display;
+
+use crate::error::{GbError, Result};
+
+/// Identifies a savestate file
+const MAGIC: &[u8; 8] = b"GBRSSTAT";
+/// Changed whenever the layout of the states changes
+const VERSION: u16 = 1;
+
+/// Something whose state can be saved and restored.
+pub(crate) trait Stateful {
+    fn save_state(&self, w: &mut StateWriter);
+
+    /// Restore the state saved by `save_state()`. This may leave the object half-restored if the
+    /// state is invalid.
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()>;
+}
+
+/// Serializes a state, in little-endian order
+pub(crate) struct StateWriter(Vec<u8>);
+
+impl StateWriter {
+    /// Start a state for the game with the given global checksum (from the cartridge header).
+    pub(crate) fn new(checksum: u16) -> Self {
+        let mut w = Self(MAGIC.to_vec());
+        w.u16(VERSION);
+        w.u16(checksum);
+        w
+    }
+
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    pub(crate) fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub(crate) fn u16(&mut self, v: u16) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, v: u64) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    /// A block of bytes, preceded by its length.
+    pub(crate) fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.0.extend(v);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Deserializes a state written by a [`StateWriter`]
+pub(crate) struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    /// Check the header of the state, which must have been saved for the game with the given
+    /// global checksum.
+    pub(crate) fn new(data: &'a [u8], checksum: u16) -> Result<Self> {
+        let mut r = Self(data);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a savestate"));
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(invalid(format!(
+                "unsupported version {} (expected {})",
+                version, VERSION
+            )));
+        }
+        if r.u16()? != checksum {
+            return Err(invalid("saved with another game"));
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("truncated"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// A byte that must be lower than `limit`, e.g. the value of an enum.
+    pub(crate) fn u8_below(&mut self, limit: u8) -> Result<u8> {
+        let v = self.u8()?;
+        if v >= limit {
+            return Err(invalid(format!("invalid value {}", v)));
+        }
+        Ok(v)
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8_below(2)? != 0)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A block of bytes, which must be the same size as `buf`.
+    pub(crate) fn bytes_into(&mut self, buf: &mut [u8]) -> Result<()> {
+        let len = self.u32()? as usize;
+        if len != buf.len() {
+            return Err(invalid(format!(
+                "expected {} bytes, got {}",
+                buf.len(),
+                len
+            )));
+        }
+        buf.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    /// Check that the whole state has been read.
+    pub(crate) fn finish(self) -> Result<()> {
+        if !self.0.is_empty() {
+            return Err(invalid("unexpected data at the end"));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(reason: impl Display) -> GbError {
+    GbError::InvalidState(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = StateWriter::new(0x1234);
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0xBEEF);
+        w.u32(0xDEADBEEF);
+        w.u64(u64::MAX);
+        w.bytes(&[1, 2, 3]);
+        let data = w.finish();
+
+        let mut r = StateReader::new(&data, 0x1234).unwrap();
+        assert_eq!(r.u8().unwrap(), 0x12);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u16().unwrap(), 0xBEEF);
+        assert_eq!(r.u32().unwrap(), 0xDEADBEEF);
+        assert_eq!(r.u64().unwrap(), u64::MAX);
+        let mut buf = [0; 3];
+        r.bytes_into(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        r.finish().unwrap();
+
+        // Another game
+        let err = StateReader::new(&data, 0x4321).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Invalid savestate: saved with another game"
+        );
+        // Truncated
+        let mut r = StateReader::new(&data[..data.len() - 1], 0x1234).unwrap();
+        r.take(16).unwrap();
+        assert!(r.bytes_into(&mut buf).is_err());
+        assert!(StateReader::new(b"GBRS", 0x1234).is_err());
+    }
+}
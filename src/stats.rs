@@ -0,0 +1,134 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Number of underruns and overruns per check period above which audio glitches are reported
+const GLITCH_THRESHOLD: u64 = 5;
+/// How often the audio counters are checked
+const CHECK_PERIOD: Duration = Duration::from_secs(2);
+
+/// Counters of audio buffer problems, shared between the emulator and the audio thread.
+///
+/// An underrun means the audio device asked for samples but the ring buffer was empty, i.e. the
+/// emulator isn't producing samples fast enough (which points at system load). An overrun means
+/// the emulator couldn't push samples because the ring buffer was full, i.e. it's running ahead
+/// of the audio device.
+///
+/// No samples are produced while the emulation is paused, which isn't an underrun: the audio
+/// thread plays silence instead.
+#[derive(Debug, Default)]
+pub struct AudioStats {
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+    paused: AtomicBool,
+}
+
+impl AudioStats {
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Whether the emulation is paused, i.e. isn't producing any samples
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn record_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Total number of underruns since the emulator started
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Total number of overruns since the emulator started
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+}
+
+/// Periodically checks the audio counters, to tell the user when the audio is glitching.
+#[derive(Debug)]
+pub struct AudioMonitor {
+    last_check: Instant,
+    /// Underruns and overruns at the time of the last check
+    last_counts: (u64, u64),
+    glitching: bool,
+}
+
+impl AudioMonitor {
+    pub fn new() -> Self {
+        Self {
+            last_check: Instant::now(),
+            last_counts: (0, 0),
+            glitching: false,
+        }
+    }
+
+    /// Check the counters if a check is due.
+    ///
+    /// Returns `Some(message)` when the glitching state changes: the message is the warning to
+    /// display, or is empty once the audio is back to normal.
+    pub fn check(&mut self, stats: &AudioStats) -> Option<String> {
+        if self.last_check.elapsed() < CHECK_PERIOD {
+            return None;
+        }
+        self.last_check = Instant::now();
+        self.update((stats.underruns(), stats.overruns()))
+    }
+
+    fn update(&mut self, counts: (u64, u64)) -> Option<String> {
+        let underruns = counts.0 - self.last_counts.0;
+        let overruns = counts.1 - self.last_counts.1;
+        self.last_counts = counts;
+
+        let glitching = underruns + overruns > GLITCH_THRESHOLD;
+        if glitching == self.glitching {
+            return None;
+        }
+        self.glitching = glitching;
+        if glitching {
+            let cause = if underruns >= overruns {
+                "audio underruns, the system may be too busy"
+            } else {
+                "audio overruns, the emulator is running ahead of the audio device"
+            };
+            Some(format!(
+                "{cause} ({underruns} underruns, {overruns} overruns)"
+            ))
+        } else {
+            Some(String::new())
+        }
+    }
+}
+
+impl Default for AudioMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_monitor() {
+        let mut monitor = AudioMonitor::new();
+        // a few glitches are fine
+        assert_eq!(monitor.update((2, 1)), None);
+        // a lot of them aren't
+        let warning = monitor.update((20, 1)).unwrap();
+        assert!(warning.starts_with("audio underruns"), "{}", warning);
+        // the warning is only reported once
+        assert_eq!(monitor.update((40, 1)), None);
+        // and cleared when things go back to normal
+        assert_eq!(monitor.update((40, 1)), Some(String::new()));
+    }
+}
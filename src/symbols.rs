@@ -0,0 +1,125 @@
+//! Symbols of the game's code, read from the `.sym` files produced by RGBDS (`rgblink -n`), so
+//! that addresses can be shown as `label+offset`.
+//!
+//! Each line of a `.sym` file is a label with its bank and address, e.g. `01:4000 Main`.
+//! Everything after a `;` is a comment.
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Symbols {
+    /// Labels by bank and address
+    labels: BTreeMap<(u16, u16), String>,
+}
+
+impl Symbols {
+    /// Parse the content of a `.sym` file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut symbols = Symbols::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (bank, addr, name) = parse_line(line).with_context(|| format!("Line {}", i + 1))?;
+            symbols.insert(bank, addr, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, bank: u16, addr: u16, name: impl Into<String>) {
+        self.labels.insert((bank, addr), name.into());
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Name `addr` after the closest label at or before it, in the same bank and region of memory:
+    /// `Label` if it's exactly at the label, or `Label+$offset`.
+    pub fn lookup(&self, bank: u16, addr: u16) -> Option<String> {
+        let ((label_bank, label_addr), name) = self.labels.range(..=(bank, addr)).next_back()?;
+        if *label_bank != bank || region(*label_addr) != region(addr) {
+            return None;
+        }
+        Some(match addr - label_addr {
+            0 => name.clone(),
+            offset => format!("{}+${:X}", name, offset),
+        })
+    }
+
+    /// The name of the label exactly at `addr` in the given bank, if any.
+    pub fn label_at(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.labels.get(&(bank, addr)).map(String::as_str)
+    }
+}
+
+/// Parse `BB:AAAA Label`
+fn parse_line(line: &str) -> Result<(u16, u16, &str)> {
+    let (location, name) = line
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| anyhow!("Expected `<bank>:<address> <label>`, got `{}`", line))?;
+    let (bank, addr) = location
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected `<bank>:<address>`, got `{}`", location))?;
+    let bank = u16::from_str_radix(bank, 16).with_context(|| format!("Invalid bank {}", bank))?;
+    let addr =
+        u16::from_str_radix(addr, 16).with_context(|| format!("Invalid address {}", addr))?;
+    Ok((bank, addr, name.trim()))
+}
+
+/// Labels only name the addresses in their own region: the code at the start of a ROM bank isn't
+/// after the last label of the RAM before it.
+fn region(addr: u16) -> u8 {
+    match addr {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xFDFF => 4,
+        0xFE00..=0xFEFF => 5,
+        0xFF00..=0xFF7F => 6,
+        0xFF80..=0xFFFF => 7,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbols() {
+        let symbols = Symbols::parse(
+            "; File generated by rgblink\n\
+             00:0150 Start\n\
+             00:0200 Main   ; the main loop\n\
+             00:0210 Main.loop\n\
+             \n\
+             01:4000 Bank1Stuff\n\
+             00:c000 wCounter\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.lookup(0, 0x0150).as_deref(), Some("Start"));
+        assert_eq!(symbols.lookup(0, 0x0205).as_deref(), Some("Main+$5"));
+        assert_eq!(symbols.lookup(0, 0x0213).as_deref(), Some("Main.loop+$3"));
+        assert_eq!(symbols.lookup(1, 0x4010).as_deref(), Some("Bank1Stuff+$10"));
+        assert_eq!(symbols.lookup(0, 0xC001).as_deref(), Some("wCounter+$1"));
+        // Nothing before it in the bank, or only in another region
+        assert_eq!(symbols.lookup(0, 0x0100), None);
+        assert_eq!(symbols.lookup(2, 0x4010), None);
+        assert_eq!(symbols.lookup(0, 0x4010), None);
+        assert_eq!(symbols.label_at(0, 0x0210), Some("Main.loop"));
+
+        let err = Symbols::parse("00:0150 Start\n0150\n").unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Line 2: Expected `<bank>:<address> <label>`, got `0150`"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Sinks that forward their input to two other sinks, so that e.g. a display and a recorder can
+//! both receive the output of the emulator.
+//!
+//! They can be nested to feed more than two sinks: `TeeFrameSink(a, TeeFrameSink(b, c))`.
+use crate::{AudioSink, DirtyLines, FrameSink, PaletteId};
+
+/// A [`FrameSink`] that forwards everything to two other sinks.
+#[derive(Debug, Default)]
+pub struct TeeFrameSink<A, B>(pub A, pub B);
+
+impl<A: FrameSink, B: FrameSink> FrameSink for TeeFrameSink<A, B> {
+    fn push_frame(&mut self, frame: &[u8]) {
+        self.0.push_frame(frame);
+        self.1.push_frame(frame);
+    }
+
+    fn dirty_lines(&mut self, lines: &DirtyLines) {
+        self.0.dirty_lines(lines);
+        self.1.dirty_lines(lines);
+    }
+
+    fn push_lines(&mut self, first_line: usize, pixels: &[u8]) {
+        self.0.push_lines(first_line, pixels);
+        self.1.push_lines(first_line, pixels);
+    }
+
+    fn lcd_power_changed(&mut self, enabled: bool) {
+        self.0.lcd_power_changed(enabled);
+        self.1.lcd_power_changed(enabled);
+    }
+
+    fn palette_changed(&mut self, palette: PaletteId, data: u8) {
+        self.0.palette_changed(palette, data);
+        self.1.palette_changed(palette, data);
+    }
+}
+
+/// An [`AudioSink`] that forwards the samples to two other sinks.
+///
+/// The first sink is the primary one: its fill level is the one reported to the host.
+#[derive(Debug, Default)]
+pub struct TeeAudioSink<A, B>(pub A, pub B);
+
+impl<A: AudioSink, B: AudioSink> AudioSink for TeeAudioSink<A, B> {
+    fn push_samples(&mut self, samples: &[i16]) {
+        self.0.push_samples(samples);
+        self.1.push_samples(samples);
+    }
+
+    fn fill_level(&self) -> Option<f64> {
+        self.0.fill_level()
+    }
+}
+
+impl<T: FrameSink + ?Sized> FrameSink for &mut T {
+    fn push_frame(&mut self, frame: &[u8]) {
+        (**self).push_frame(frame);
+    }
+
+    fn dirty_lines(&mut self, lines: &DirtyLines) {
+        (**self).dirty_lines(lines);
+    }
+
+    fn push_lines(&mut self, first_line: usize, pixels: &[u8]) {
+        (**self).push_lines(first_line, pixels);
+    }
+
+    fn lcd_power_changed(&mut self, enabled: bool) {
+        (**self).lcd_power_changed(enabled);
+    }
+
+    fn palette_changed(&mut self, palette: PaletteId, data: u8) {
+        (**self).palette_changed(palette, data);
+    }
+}
+
+impl<T: AudioSink + ?Sized> AudioSink for &mut T {
+    fn push_samples(&mut self, samples: &[i16]) {
+        (**self).push_samples(samples);
+    }
+
+    fn fill_level(&self) -> Option<f64> {
+        (**self).fill_level()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingSink {
+        frames: usize,
+        lines: usize,
+        samples: Vec<i16>,
+        /// Size of the pretend audio buffer, for `fill_level()`
+        capacity: usize,
+    }
+
+    impl FrameSink for CountingSink {
+        fn push_frame(&mut self, _frame: &[u8]) {
+            self.frames += 1;
+        }
+
+        fn push_lines(&mut self, _first_line: usize, _pixels: &[u8]) {
+            self.lines += 1;
+        }
+    }
+
+    impl AudioSink for CountingSink {
+        fn push_samples(&mut self, samples: &[i16]) {
+            self.samples.extend_from_slice(samples);
+        }
+
+        fn fill_level(&self) -> Option<f64> {
+            Some(self.samples.len() as f64 / self.capacity as f64)
+        }
+    }
+
+    #[test]
+    fn test_tee_sinks() {
+        let mut a = CountingSink {
+            capacity: 8,
+            ..Default::default()
+        };
+        let mut b = CountingSink {
+            capacity: 10,
+            ..Default::default()
+        };
+
+        let mut frames = TeeFrameSink(&mut a, &mut b);
+        frames.push_frame(&[]);
+        frames.push_lines(0, &[]);
+        assert_eq!((a.frames, a.lines), (1, 1));
+        assert_eq!((b.frames, b.lines), (1, 1));
+
+        let mut samples = TeeAudioSink(&mut a, &mut b);
+        samples.push_samples(&[1, 2, 3, 4]);
+        // the primary sink's buffer is the one the host is told about
+        assert_eq!(samples.fill_level(), Some(0.5));
+        assert_eq!(a.samples, [1, 2, 3, 4]);
+        assert_eq!(b.samples, [1, 2, 3, 4]);
+    }
+}
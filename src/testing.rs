@@ -0,0 +1,241 @@
+//! Tools to test parts of the emulator in isolation, e.g. from integration tests, and to compare
+//! its output with reference screenshots.
+#[cfg(feature = "native")]
+use std::{fs::File, io::BufWriter, path::Path};
+
+#[cfg(feature = "native")]
+use anyhow::{bail, Context, Result};
+
+use crate::{cpu::Cpu, memory::Memory, CpuState};
+#[cfg(feature = "native")]
+use crate::{FRAME_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// A CPU on its own, connected to 64KB of plain RAM instead of the Game Boy's memory map and
+/// peripherals, to test instructions one at a time (e.g. with the SM83 single-step tests).
+///
+/// Nothing ever requests an interrupt: IE (0xFFFF) and IF (0xFF0F) are just bytes of RAM.
+pub struct FlatCpu {
+    cpu: Cpu,
+    memory: FlatMemory,
+}
+
+impl FlatCpu {
+    pub fn new() -> Self {
+        Self {
+            cpu: Cpu::new(false),
+            memory: FlatMemory(vec![0; 0x10000].into_boxed_slice()),
+        }
+    }
+
+    pub fn state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.cpu.set_state(state);
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.memory.0[addr as usize]
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.memory.0[addr as usize] = value;
+    }
+
+    /// Execute the instruction at PC, and return the number of clock cycles it took.
+    pub fn step(&mut self) -> u8 {
+        self.cpu.step(&mut self.memory)
+    }
+}
+
+impl Default for FlatCpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// M-cycles taken by each opcode on the DMG, when the condition of JR, RET, JP and CALL doesn't
+/// hold. The illegal opcodes and the CB prefix are 0.
+#[rustfmt::skip]
+const OPCODE_CYCLES: [u8; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, // 0x
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 1x
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 2x
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 3x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 4x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 5x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 6x
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1, // 7x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 8x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 9x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // Ax
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // Bx
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4, // Cx
+    2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4, // Dx
+    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, // Ex
+    3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4, // Fx
+];
+
+/// Number of clock cycles the DMG's CPU takes to execute the given opcode, or `None` for the
+/// illegal opcodes and the CB prefix (see [`cb_opcode_cycles()`]).
+///
+/// `taken` tells whether the condition of a conditional JR, RET, JP or CALL holds: it's ignored
+/// for the other opcodes.
+pub fn opcode_cycles(op: u8, taken: bool) -> Option<u8> {
+    // Bits 3 and 4 of the conditional jumps hold the condition
+    let taken_cycles = match op & 0xE7 {
+        0x20 => 3,
+        0xC0 => 5,
+        0xC2 => 4,
+        0xC4 => 6,
+        _ => 0,
+    };
+    let cycles = match OPCODE_CYCLES[op as usize] {
+        0 => return None,
+        _ if taken && taken_cycles > 0 => taken_cycles,
+        cycles => cycles,
+    };
+    Some(cycles * 4)
+}
+
+/// Number of clock cycles the DMG's CPU takes to execute the given CB-prefixed opcode, prefix
+/// included.
+pub fn cb_opcode_cycles(op: u8) -> u8 {
+    // (HL) operands take 2 more M-cycles to read and write back, or 1 for BIT
+    let cycles = match (op & 0x07, op >> 6) {
+        (6, 1) => 3,
+        (6, _) => 4,
+        _ => 2,
+    };
+    cycles * 4
+}
+
+/// Load a screen-sized PNG image, e.g. the reference screenshot of a test ROM, as an RGBA frame.
+#[cfg(feature = "native")]
+pub fn load_screenshot(path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    if info.width as usize != SCREEN_WIDTH || info.height as usize != SCREEN_HEIGHT {
+        bail!("{} isn't {SCREEN_WIDTH}x{SCREEN_HEIGHT}", path.display());
+    }
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        png::ColorType::Indexed => bail!("{}: unexpected indexed colours", path.display()),
+    };
+    let mut frame = Vec::with_capacity(FRAME_SIZE);
+    for line in buf.chunks(info.line_size).take(SCREEN_HEIGHT) {
+        for pixel in line[..SCREEN_WIDTH * channels].chunks(channels) {
+            match channels {
+                1 | 2 => frame.extend([pixel[0], pixel[0], pixel[0], 0xFF]),
+                _ => frame.extend([pixel[0], pixel[1], pixel[2], 0xFF]),
+            }
+        }
+    }
+    Ok(frame)
+}
+
+/// Save an RGBA frame as a PNG image.
+#[cfg(feature = "native")]
+pub fn save_screenshot(path: &Path, frame: &[u8]) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(frame)?;
+    Ok(())
+}
+
+/// Number of pixels of two RGBA frames whose colours differ (the alpha channel is ignored).
+pub fn count_differences(actual: &[u8], expected: &[u8]) -> usize {
+    actual
+        .chunks(4)
+        .zip(expected.chunks(4))
+        .filter(|(a, e)| a[..3] != e[..3])
+        .count()
+}
+
+/// Highlight the pixels that differ in red, on top of a faded copy of the expected frame.
+pub fn diff_frames(actual: &[u8], expected: &[u8]) -> Vec<u8> {
+    actual
+        .chunks(4)
+        .zip(expected.chunks(4))
+        .flat_map(|(a, e)| {
+            if a[..3] == e[..3] {
+                let faded = 0xC0 + e[0] / 4;
+                [faded, faded, faded, 0xFF]
+            } else {
+                [0xFF, 0x00, 0x00, 0xFF]
+            }
+        })
+        .collect()
+}
+
+/// The whole address space as RAM
+struct FlatMemory(Box<[u8]>);
+
+impl Memory for FlatMemory {
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        self.0[addr as usize]
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.0[addr as usize] = value;
+    }
+
+    fn tick(&mut self, _cycles: u8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_cpu() {
+        let mut cpu = FlatCpu::new();
+        // LD (HL),A at the top of the address space
+        cpu.write(0xFFF0, 0x77);
+        cpu.set_state(&CpuState {
+            a: 0x42,
+            h: 0xC0,
+            l: 0x00,
+            pc: 0xFFF0,
+            ..CpuState::default()
+        });
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.read(0xC000), 0x42);
+        assert_eq!(cpu.state().pc, 0xFFF1);
+
+        // PC wraps around at the end of the address space
+        cpu.set_state(&CpuState {
+            pc: 0xFFFF,
+            ..CpuState::default()
+        });
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.state().pc, 0x0000);
+    }
+
+    #[test]
+    fn test_diff_frames() {
+        let expected = [0, 0, 0, 0xFF, 0x80, 0x80, 0x80, 0xFF];
+        let actual = [0, 0, 0, 0x00, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(count_differences(&actual, &expected), 1);
+        assert_eq!(
+            diff_frames(&actual, &expected),
+            [0xC0, 0xC0, 0xC0, 0xFF, 0xFF, 0x00, 0x00, 0xFF]
+        );
+    }
+}
@@ -1,6 +1,10 @@
-use bitvec::{field::BitField, order::Lsb0, view::BitView};
+use bitvec::{order::Lsb0, view::BitView};
 use log::trace;
 
+use crate::error::Result;
+use crate::io_regs::Tac;
+use crate::state::{StateReader, StateWriter, Stateful};
+
 pub struct Timer {
     /// FF04 - DIV - Divider Register
     /// This register is incremented at a rate of 16384Hz (~16779Hz on SGB). In other words, it is
@@ -16,6 +20,9 @@ pub struct Timer {
     tac_input_clock_select: ClockSpeed,
 
     tima_has_overflowed: bool,
+    /// Whether TIMA was reloaded with TMA during the last M-cycle. Writes to TIMA are ignored
+    /// during that cycle, and writes to TMA go through to TIMA.
+    tima_reloading: bool,
 }
 
 impl Timer {
@@ -27,79 +34,137 @@ impl Timer {
             tac_timer_enable: false,
             tac_input_clock_select: ClockSpeed::Speed0,
             tima_has_overflowed: false,
+            tima_reloading: false,
         }
     }
 
-    pub fn cycle(&mut self, cycles: u8) -> bool {
+    pub fn cycle(&mut self, cycles: u32) -> bool {
         let mut request_interrupt = false;
-        for _ in 0..cycles {
+        let mut remaining = cycles;
+        self.tima_reloading = false;
+        while remaining > 0 {
             if self.tima_has_overflowed {
                 // When TIMA overflows, there is a 1-cycle delay before it is reloaded with TMA and
                 // an interrupt is triggered
                 self.tima_has_overflowed = false;
                 self.tima = self.tma;
                 request_interrupt = true;
+                // The CPU accesses the registers at the end of the cycles, so it only sees the
+                // reload cycle if it was one of the last 4
+                self.tima_reloading = remaining <= 4;
             }
+            // Nothing happens until the next falling edge, so jump straight to it
+            let step = if self.tac_timer_enable {
+                remaining.min(self.cycles_until_tick())
+            } else {
+                remaining
+            };
+            // Only the last cycle of the jump can be a falling edge, which update_div() looks for
+            self.div_timer = self.div_timer.wrapping_add((step - 1) as u16);
             self.update_div(self.div_timer.wrapping_add(1));
+            remaining -= step;
         }
         request_interrupt
     }
 
-    fn update_div(&mut self, new_value: u16) {
-        let old_div_timer = self.div_timer;
-        // Update DIV
-        self.div_timer = new_value;
-
-        // Update TIMA
-        if self.tac_timer_enable {
-            // Bit number of the system clock counter to check for a falling edge
-            let bit_num = match self.tac_input_clock_select {
-                ClockSpeed::Speed0 => 9,
-                ClockSpeed::Speed1 => 3,
-                ClockSpeed::Speed2 => 5,
-                ClockSpeed::Speed3 => 7,
-            };
-            let old_bit = old_div_timer.view_bits::<Lsb0>()[bit_num];
-            let new_bit = self.div_timer.view_bits::<Lsb0>()[bit_num];
-
-            if old_bit && !new_bit {
-                // Falling edge detected: update TIMA
-                let (new_tima, overflow) = self.tima.overflowing_add(1);
-                if overflow {
-                    self.tima_has_overflowed = true;
-                    self.tima = 0;
-                } else {
-                    self.tima = new_tima;
-                }
-            }
+    /// Number of clock cycles until the timer requests an interrupt, if it's enabled.
+    pub fn cycles_until_interrupt(&self) -> Option<u32> {
+        if self.tima_has_overflowed {
+            Some(1)
+        } else if self.tac_timer_enable {
+            // TIMA overflows on the last tick, and the interrupt comes one cycle later
+            let ticks = 0x100 - self.tima as u32;
+            Some(self.cycles_until_tick() + (ticks - 1) * self.tick_period() + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Bit number of the system clock counter whose falling edge increments TIMA
+    fn tick_bit(&self) -> u32 {
+        match self.tac_input_clock_select {
+            ClockSpeed::Speed0 => 9,
+            ClockSpeed::Speed1 => 3,
+            ClockSpeed::Speed2 => 5,
+            ClockSpeed::Speed3 => 7,
+        }
+    }
+
+    /// Number of clock cycles between two increments of TIMA
+    fn tick_period(&self) -> u32 {
+        2 << self.tick_bit()
+    }
+
+    /// Number of clock cycles until the next increment of TIMA
+    fn cycles_until_tick(&self) -> u32 {
+        let period = self.tick_period();
+        period - (self.div_timer as u32 & (period - 1))
+    }
+
+    /// The signal whose falling edges increment TIMA: the selected bit of the system counter,
+    /// ANDed with the enable bit of TAC.
+    ///
+    /// Anything that makes it fall increments TIMA, not just the counter: resetting DIV while the
+    /// selected bit is set, disabling the timer while it's set, or switching to a bit that isn't
+    /// set.
+    fn timer_input(&self) -> bool {
+        self.tac_timer_enable && self.div_timer.view_bits::<Lsb0>()[self.tick_bit() as usize]
+    }
+
+    /// Run `change` on the timer, and increment TIMA if it made the timer input fall.
+    fn with_falling_edge_check(&mut self, change: impl FnOnce(&mut Self)) {
+        let old_input = self.timer_input();
+        change(self);
+        if old_input && !self.timer_input() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (new_tima, overflow) = self.tima.overflowing_add(1);
+        if overflow {
+            self.tima_has_overflowed = true;
+            self.tima = 0;
+        } else {
+            self.tima = new_tima;
         }
     }
 
+    fn update_div(&mut self, new_value: u16) {
+        self.with_falling_edge_check(|timer| timer.div_timer = new_value);
+    }
+
     pub fn set_tac(&mut self, tac: u8) {
-        let bits = tac.view_bits::<Lsb0>();
-        self.tac_timer_enable = bits[2];
-        self.tac_input_clock_select = match bits[0..2].load::<u8>() {
-            0 => ClockSpeed::Speed0,
-            1 => ClockSpeed::Speed1,
-            2 => ClockSpeed::Speed2,
-            3 => ClockSpeed::Speed3,
-            _ => unreachable!(),
-        };
+        let tac = Tac::from_bits_truncate(tac);
+        self.with_falling_edge_check(|timer| {
+            timer.tac_timer_enable = tac.contains(Tac::ENABLE);
+            timer.tac_input_clock_select = match (tac & Tac::CLOCK_SELECT).bits() {
+                0 => ClockSpeed::Speed0,
+                1 => ClockSpeed::Speed1,
+                2 => ClockSpeed::Speed2,
+                3 => ClockSpeed::Speed3,
+                _ => unreachable!(),
+            };
+        });
     }
 
     pub fn tac(&self) -> u8 {
-        let mut tac = 0xFF; // unused are set to 1
-        let bits = tac.view_bits_mut::<Lsb0>();
-        bits.set(2, self.tac_timer_enable);
-        bits[0..2].store(self.tac_input_clock_select as u8);
+        let mut tac = Tac::from_bits_truncate(self.tac_input_clock_select as u8);
+        tac.set(Tac::ENABLE, self.tac_timer_enable);
 
-        tac
+        // unused bits are set to 1
+        tac.bits() | !Tac::all().bits()
     }
 
     pub fn div_timer(&self) -> u8 {
         (self.div_timer >> 8) as u8
     }
 
+    /// Set the full 16-bit system counter behind DIV.
+    pub fn set_div_counter(&mut self, value: u16) {
+        self.div_timer = value;
+    }
+
     pub fn reset_div_timer(&mut self) {
         self.update_div(0);
     }
@@ -110,7 +175,15 @@ impl Timer {
     }
 
     /// Set the timer's tima.
+    ///
+    /// Writing TIMA in the cycle after it overflowed cancels the reload from TMA (and the
+    /// interrupt), whereas writing it in the cycle of the reload has no effect.
     pub fn set_tima(&mut self, tima: u8) {
+        if self.tima_reloading {
+            trace!("Ignoring write to TIMA while it's reloaded");
+            return;
+        }
+        self.tima_has_overflowed = false;
         self.tima = tima;
     }
 
@@ -120,9 +193,14 @@ impl Timer {
     }
 
     /// Set the timer's tma.
+    ///
+    /// Writing TMA in the cycle TIMA is reloaded also changes TIMA.
     pub fn set_tma(&mut self, tma: u8) {
         trace!("Writing {:02x} to TMA", tma);
         self.tma = tma;
+        if self.tima_reloading {
+            self.tima = tma;
+        }
     }
 }
 
@@ -134,3 +212,153 @@ enum ClockSpeed {
     Speed2 = 2,
     Speed3 = 3,
 }
+
+impl Stateful for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.div_timer);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.bool(self.tac_timer_enable);
+        w.u8(self.tac_input_clock_select as u8);
+        w.bool(self.tima_has_overflowed);
+        w.bool(self.tima_reloading);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.div_timer = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac_timer_enable = r.bool()?;
+        self.tac_input_clock_select = match r.u8_below(4)? {
+            0 => ClockSpeed::Speed0,
+            1 => ClockSpeed::Speed1,
+            2 => ClockSpeed::Speed2,
+            _ => ClockSpeed::Speed3,
+        };
+        self.tima_has_overflowed = r.bool()?;
+        self.tima_reloading = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batched_cycles() {
+        for tac in 4..8 {
+            let mut single = Timer::new();
+            single.set_tac(tac);
+            single.set_tima(0xF0);
+            single.set_tma(0x80);
+            single.set_div_counter(0x1234);
+            let mut batched = Timer::new();
+            batched.set_tac(tac);
+            batched.set_tima(0xF0);
+            batched.set_tma(0x80);
+            batched.set_div_counter(0x1234);
+
+            let mut interrupts = Vec::new();
+            for cycle in 1..=20_000u32 {
+                if single.cycle(1) {
+                    interrupts.push(cycle);
+                }
+            }
+            // The interrupt is requested exactly when predicted...
+            assert_eq!(
+                Some(interrupts[0]),
+                batched.cycles_until_interrupt(),
+                "TAC={tac}"
+            );
+            // ...and running the timer in one go gives the same result
+            assert!(batched.cycle(interrupts[0]), "TAC={tac}");
+            batched.cycle(20_000 - interrupts[0]);
+            assert_eq!(batched.tima(), single.tima(), "TAC={tac}");
+            assert_eq!(batched.div_timer(), single.div_timer(), "TAC={tac}");
+        }
+    }
+
+    #[test]
+    fn test_falling_edge_glitches() {
+        // TAC=5 selects bit 3 of the system counter
+        let timer_with = |tac: u8, counter: u16| {
+            let mut timer = Timer::new();
+            timer.set_div_counter(counter);
+            timer.set_tac(tac);
+            timer.set_tima(0x10);
+            timer
+        };
+
+        // Resetting DIV only increments TIMA if the selected bit was set
+        let mut timer = timer_with(0b101, 0x0008);
+        timer.reset_div_timer();
+        assert_eq!(timer.tima(), 0x11);
+        let mut timer = timer_with(0b101, 0x0010);
+        timer.reset_div_timer();
+        assert_eq!(timer.tima(), 0x10);
+        // ...and if the timer is enabled
+        let mut timer = timer_with(0b001, 0x0008);
+        timer.reset_div_timer();
+        assert_eq!(timer.tima(), 0x10);
+
+        // Disabling the timer while the selected bit is set
+        let mut timer = timer_with(0b101, 0x0008);
+        timer.set_tac(0b001);
+        assert_eq!(timer.tima(), 0x11);
+        let mut timer = timer_with(0b101, 0x0010);
+        timer.set_tac(0b001);
+        assert_eq!(timer.tima(), 0x10);
+
+        // Switching from a set bit (3) to a cleared one (5), but not the other way around
+        let mut timer = timer_with(0b101, 0x0008);
+        timer.set_tac(0b110);
+        assert_eq!(timer.tima(), 0x11);
+        let mut timer = timer_with(0b110, 0x0008);
+        timer.set_tac(0b101);
+        assert_eq!(timer.tima(), 0x10);
+
+        // Enabling the timer never increments TIMA
+        let mut timer = timer_with(0b001, 0x0008);
+        timer.set_tac(0b101);
+        assert_eq!(timer.tima(), 0x10);
+    }
+
+    #[test]
+    fn test_tima_reload_writes() {
+        // TIMA overflows at the end of the 4th M-cycle
+        let overflowed = || {
+            let mut timer = Timer::new();
+            timer.set_tac(0b101);
+            timer.set_tima(0xFF);
+            timer.set_tma(0x80);
+            for _ in 0..4 {
+                assert!(!timer.cycle(4));
+            }
+            assert_eq!(timer.tima(), 0x00);
+            timer
+        };
+
+        // Writing TIMA in the cycle after the overflow cancels the reload and the interrupt
+        let mut timer = overflowed();
+        timer.set_tima(0x42);
+        assert!(!timer.cycle(4));
+        assert_eq!(timer.tima(), 0x42);
+
+        // Writing TIMA in the reload cycle is ignored, but writing TMA goes through
+        let mut timer = overflowed();
+        assert!(timer.cycle(4));
+        assert_eq!(timer.tima(), 0x80);
+        timer.set_tima(0x42);
+        assert_eq!(timer.tima(), 0x80);
+        timer.set_tma(0x90);
+        assert_eq!(timer.tima(), 0x90);
+
+        // After that, things are back to normal
+        assert!(!timer.cycle(4));
+        timer.set_tma(0xA0);
+        assert_eq!(timer.tima(), 0x90);
+        timer.set_tima(0x42);
+        assert_eq!(timer.tima(), 0x42);
+    }
+}
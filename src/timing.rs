@@ -0,0 +1,70 @@
+//! Timing of the Game Boy, and conversions between clock cycles, lines, frames and real time.
+//!
+//! On the DMG, a dot of the PPU is exactly one clock cycle, so dots and cycles are used
+//! interchangeably.
+use std::time::Duration;
+
+/// Frequency of the clock, in Hz
+pub const CYCLES_PER_SECOND: u32 = 4_194_304;
+/// Number of dots in a scanline, including the HBlank
+pub const DOTS_PER_LINE: u32 = 456;
+/// Number of visible scanlines
+pub const VISIBLE_LINES: u32 = 144;
+/// Number of scanlines in a frame, including the 10 lines of VBlank
+pub const LINES_PER_FRAME: u32 = 154;
+/// Number of clock cycles in a frame (70224)
+pub const CYCLES_PER_FRAME: u32 = DOTS_PER_LINE * LINES_PER_FRAME;
+/// Frame rate of the LCD (~59.7275Hz)
+pub const FRAMES_PER_SECOND: f64 = CYCLES_PER_SECOND as f64 / CYCLES_PER_FRAME as f64;
+/// Duration of a frame (~16.74ms)
+pub const FRAME_DURATION: Duration = cycles_to_duration(CYCLES_PER_FRAME as u64);
+
+/// Real time taken by the given number of clock cycles, rounded down to the nanosecond.
+pub const fn cycles_to_duration(cycles: u64) -> Duration {
+    Duration::from_nanos((cycles as u128 * 1_000_000_000 / CYCLES_PER_SECOND as u128) as u64)
+}
+
+/// Number of clock cycles in the given duration. This is fractional so that converting many short
+/// durations doesn't accumulate rounding errors.
+pub fn duration_to_cycles(duration: Duration) -> f64 {
+    duration.as_secs_f64() * CYCLES_PER_SECOND as f64
+}
+
+/// Real time taken by the given number of frames.
+pub fn frames_to_duration(frames: u64) -> Duration {
+    Duration::from_secs_f64(frames as f64 / FRAMES_PER_SECOND)
+}
+
+/// Split a number of dots since the start of a frame into a line and the dot within that line.
+pub const fn dots_to_line(dots: u32) -> (u32, u32) {
+    (dots / DOTS_PER_LINE, dots % DOTS_PER_LINE)
+}
+
+/// Number of dots from the start of a frame to the start of the given line.
+pub const fn line_to_dots(line: u32) -> u32 {
+    line * DOTS_PER_LINE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timing() {
+        assert_eq!(CYCLES_PER_FRAME, 70224);
+        assert!((FRAMES_PER_SECOND - 59.7275).abs() < 0.0001);
+        assert_eq!(FRAME_DURATION, Duration::from_nanos(16_742_706));
+        assert_eq!(
+            cycles_to_duration(CYCLES_PER_SECOND as u64),
+            Duration::from_secs(1)
+        );
+        assert_eq!(cycles_to_duration(1), Duration::from_nanos(238));
+        // the fractional part isn't lost
+        assert_eq!(duration_to_cycles(Duration::from_nanos(1000)), 4.194304);
+        assert_eq!(
+            frames_to_duration(FRAMES_PER_SECOND.round() as u64 * 10).as_millis(),
+            10045
+        );
+        assert_eq!(dots_to_line(line_to_dots(153) + 455), (153, 455));
+    }
+}
@@ -0,0 +1,67 @@
+//! Check the PPU against the dmg-acid2 test ROM (<https://github.com/mattcurrie/dmg-acid2>).
+//!
+//! The ROM isn't distributed with the emulator, so this test only runs when `DMG_ACID2_ROM` is set
+//! to its path. The reference image is `DMG_ACID2_REFERENCE` if set, or else `dmg-acid2-dmg.png`
+//! next to the ROM, as in the test ROMs downloaded by `just test_roms`:
+//!
+//! ```text
+//! DMG_ACID2_ROM=test_roms/dmg-acid2/dmg-acid2.gb cargo test --test dmg_acid2
+//! ```
+//!
+//! When the output doesn't match, it is saved along with an image highlighting the differing
+//! pixels in red, in cargo's temporary directory for integration tests.
+#![cfg(feature = "native")]
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use gb_rs::{
+    cartridge::Cartridge,
+    gameboy::GameBoy,
+    machine::{BootRom, MachineConfig},
+    testing::{count_differences, diff_frames, load_screenshot, save_screenshot},
+    DmgPalette,
+};
+
+/// The test is complete after a few frames; this leaves some margin
+const FRAMES: usize = 60;
+
+#[test]
+fn dmg_acid2() {
+    let Some(rom) = env::var_os("DMG_ACID2_ROM").map(PathBuf::from) else {
+        eprintln!("DMG_ACID2_ROM isn't set, skipping");
+        return;
+    };
+    let reference = env::var_os("DMG_ACID2_REFERENCE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| rom.with_file_name("dmg-acid2-dmg.png"));
+    let expected = load_screenshot(&reference).unwrap();
+
+    // The reference image uses these shades of grey
+    let config = MachineConfig::default()
+        .boot_rom(BootRom::Skip)
+        .palette(DmgPalette::GRAYSCALE);
+    let mut gb = GameBoy::new(Cartridge::load(&rom).unwrap(), config).unwrap();
+    for _ in 0..FRAMES {
+        gb.run_frame();
+    }
+    let actual = gb.run_frame().to_vec();
+
+    let differences = count_differences(&actual, &expected);
+    if differences > 0 {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+        save_screenshot(&dir.join("dmg-acid2-actual.png"), &actual).unwrap();
+        save_screenshot(
+            &dir.join("dmg-acid2-diff.png"),
+            &diff_frames(&actual, &expected),
+        )
+        .unwrap();
+        panic!(
+            "{differences} pixels differ from {}: see dmg-acid2-actual.png and dmg-acid2-diff.png in {}",
+            reference.display(),
+            dir.display()
+        );
+    }
+}
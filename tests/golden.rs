@@ -0,0 +1,124 @@
+//! Golden-image tests: run test ROMs for a number of frames, and compare the hash of the last frame
+//! with the one recorded in `tests/goldens.txt`, to catch regressions in the PPU without having to
+//! look at screenshots.
+//!
+//! The ROMs aren't distributed with the emulator, so this test only runs when `TEST_ROMS` is set
+//! to the directory of the test ROMs, e.g. the ones downloaded by `just test_roms`:
+//!
+//! ```text
+//! TEST_ROMS=test_roms cargo test --release --test golden
+//! ```
+//!
+//! Each line of `tests/goldens.txt` is `<ROM> <frames> <hash>`, with the path of the ROM relative
+//! to `TEST_ROMS`, and the hash of the last frame with the grayscale palette and without the boot
+//! ROM. Without that file, there is nothing to check.
+//!
+//! To add a ROM, or after a change that is meant to change what's on screen, run the test with
+//! `UPDATE_GOLDENS=1` to record the new hashes instead; a ROM is added with `-` as its hash. A
+//! golden that hasn't been recorded yet (`-`) is a failure, so that a ROM can't be left untested by
+//! mistake; the ROMs that can't be found are skipped.
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use gb_rs::{
+    cartridge::Cartridge,
+    frame_hash,
+    gameboy::GameBoy,
+    machine::{BootRom, MachineConfig},
+    DmgPalette,
+};
+
+struct Golden {
+    /// Path of the ROM, relative to the test ROMs directory
+    rom: String,
+    frames: u32,
+    /// Hash of the last frame, if recorded
+    hash: Option<u64>,
+}
+
+#[test]
+fn golden() {
+    let Some(dir) = env::var_os("TEST_ROMS").map(PathBuf::from) else {
+        eprintln!("TEST_ROMS isn't set, skipping");
+        return;
+    };
+    let update = env::var_os("UPDATE_GOLDENS").is_some();
+    let goldens_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/goldens.txt");
+    let text = match fs::read_to_string(&goldens_file) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!("{} doesn't exist, skipping", goldens_file.display());
+            return;
+        }
+        Err(e) => panic!("{}: {e}", goldens_file.display()),
+    };
+
+    let mut failures = Vec::new();
+    let mut output = String::new();
+    for line in text.lines() {
+        let Some(mut golden) = parse_golden(line) else {
+            output.push_str(line);
+            output.push('\n');
+            continue;
+        };
+        let path = dir.join(&golden.rom);
+        if !path.is_file() {
+            eprintln!("{} not found, skipping", path.display());
+        } else if update {
+            golden.hash = Some(run(&path, golden.frames));
+        } else if let Some(expected) = golden.hash {
+            let actual = run(&path, golden.frames);
+            if actual != expected {
+                failures.push(format!(
+                    "{}: expected {expected:016x}, got {actual:016x}",
+                    golden.rom
+                ));
+            }
+        } else {
+            failures.push(format!(
+                "{}: no golden recorded yet (run with UPDATE_GOLDENS=1)",
+                golden.rom
+            ));
+        }
+        let hash = golden
+            .hash
+            .map_or_else(|| "-".to_string(), |h| format!("{h:016x}"));
+        output.push_str(&format!("{} {} {}\n", golden.rom, golden.frames, hash));
+    }
+
+    if update {
+        fs::write(&goldens_file, output).unwrap();
+        eprintln!("Updated {}", goldens_file.display());
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Parse `<rom> <frames> <hash>`, or return `None` for comments and blank lines.
+fn parse_golden(line: &str) -> Option<Golden> {
+    if line.trim().is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let [rom, frames, hash] = fields[..] else {
+        panic!("Invalid golden `{line}`: expected `<rom> <frames> <hash>`");
+    };
+    Some(Golden {
+        rom: rom.to_string(),
+        frames: frames.parse().expect("invalid number of frames"),
+        hash: (hash != "-").then(|| u64::from_str_radix(hash, 16).expect("invalid hash")),
+    })
+}
+
+/// Run the ROM for the given number of frames, and return the hash of the last one.
+fn run(rom: &Path, frames: u32) -> u64 {
+    let config = MachineConfig::default()
+        .boot_rom(BootRom::Skip)
+        .palette(DmgPalette::GRAYSCALE);
+    let mut gb = GameBoy::new(Cartridge::load(rom).unwrap(), config).unwrap();
+    for _ in 1..frames {
+        gb.run_frame();
+    }
+    frame_hash(gb.run_frame())
+}
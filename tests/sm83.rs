@@ -0,0 +1,180 @@
+//! Check the CPU against the SM83 single-step tests (<https://github.com/SingleStepTests/sm83>):
+//! each test sets up the registers and a few bytes of memory, executes one instruction and gives
+//! the expected registers, memory and number of machine cycles.
+//!
+//! The tests aren't distributed with the emulator, so this test only runs when `SM83_TESTS` is set
+//! to the directory of the JSON files (or to a single one of them):
+//!
+//! ```text
+//! SM83_TESTS=test_roms/sm83/v1 cargo test --release --test sm83
+//! ```
+//!
+//! The tests model the SM83's fetch/execute overlap: PC is one past the opcode, which has already
+//! been fetched. This CPU fetches the opcode at PC when executing the instruction, so PC is one
+//! less than in the tests, both before and after.
+//!
+//! The number of cycles of every opcode is also checked against the DMG's timing table, which
+//! doesn't need the tests.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use gb_rs::{
+    testing::{cb_opcode_cycles, opcode_cycles, FlatCpu},
+    CpuState,
+};
+use serde_json::Value;
+
+/// How many failures to show in detail
+const SHOWN_FAILURES: usize = 20;
+
+#[test]
+fn sm83() {
+    let Some(path) = env::var_os("SM83_TESTS").map(PathBuf::from) else {
+        eprintln!("SM83_TESTS isn't set, skipping");
+        return;
+    };
+    let files = if path.is_dir() {
+        let mut files = fs::read_dir(&path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    } else {
+        vec![path]
+    };
+
+    let mut count = 0;
+    let mut failures = Vec::new();
+    for file in &files {
+        let tests = load_tests(file);
+        for test in tests.as_array().expect("a list of tests") {
+            count += 1;
+            if let Err(e) = run_test(test) {
+                failures.push(format!("{}: {}", test["name"].as_str().unwrap_or("?"), e));
+            }
+        }
+    }
+
+    for failure in failures.iter().take(SHOWN_FAILURES) {
+        eprintln!("{failure}");
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {count} tests failed in {} files",
+        failures.len(),
+        files.len()
+    );
+}
+
+#[test]
+fn cycles() {
+    // Run `code` from 0000 with the given flags
+    let run = |code: &[u8], f: u8| {
+        let mut cpu = FlatCpu::new();
+        for (addr, byte) in code.iter().enumerate() {
+            cpu.write(addr as u16, *byte);
+        }
+        cpu.set_state(&CpuState {
+            f,
+            sp: 0xFFFE,
+            ..CpuState::default()
+        });
+        cpu.step()
+    };
+
+    for op in 0..=0xFF {
+        // With Z and C clear, NZ and NC hold (bit 3 of the opcode is 0), and Z and C don't
+        for (f, nz) in [(0x00, true), (0xF0, false)] {
+            if let Some(expected) = opcode_cycles(op, (op & 0x08 == 0) == nz) {
+                assert_eq!(run(&[op], f), expected, "opcode {op:02X}, F={f:02X}");
+            }
+        }
+        assert_eq!(
+            run(&[0xCB, op], 0x00),
+            cb_opcode_cycles(op),
+            "opcode CB {op:02X}"
+        );
+    }
+}
+
+fn load_tests(path: &Path) -> Value {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+}
+
+/// Run a single test, and describe the first difference with the expected state, if any.
+fn run_test(test: &Value) -> Result<(), String> {
+    let mut cpu = FlatCpu::new();
+    let initial = &test["initial"];
+    cpu.set_state(&cpu_state(initial));
+    for (addr, value) in ram(initial) {
+        cpu.write(addr, value);
+    }
+
+    let cycles = cpu.step();
+
+    let expected = &test["final"];
+    let actual = cpu.state();
+    let expected_state = cpu_state(expected);
+    // HALT is part of the state in this emulator but not in the tests
+    let actual = CpuState {
+        halted: false,
+        ..actual
+    };
+    if actual != expected_state {
+        return Err(format!(
+            "expected registers {expected_state:?}, got {actual:?}"
+        ));
+    }
+    for (addr, value) in ram(expected) {
+        let actual = cpu.read(addr);
+        if actual != value {
+            return Err(format!(
+                "expected {value:02X} at {addr:04X}, got {actual:02X}"
+            ));
+        }
+    }
+    let expected_cycles = test["cycles"].as_array().map_or(0, Vec::len) * 4;
+    if cycles as usize != expected_cycles {
+        return Err(format!("expected {expected_cycles} cycles, got {cycles}"));
+    }
+    Ok(())
+}
+
+fn cpu_state(state: &Value) -> CpuState {
+    let byte = |name: &str| number(&state[name]) as u8;
+    let word = |name: &str| number(&state[name]) as u16;
+    CpuState {
+        a: byte("a"),
+        f: byte("f"),
+        b: byte("b"),
+        c: byte("c"),
+        d: byte("d"),
+        e: byte("e"),
+        h: byte("h"),
+        l: byte("l"),
+        sp: word("sp"),
+        pc: word("pc").wrapping_sub(1),
+        ime: number(&state["ime"]) != 0,
+        halted: false,
+    }
+}
+
+/// The `[address, value]` pairs of the memory in a state.
+fn ram(state: &Value) -> impl Iterator<Item = (u16, u8)> + '_ {
+    state["ram"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|pair| (number(&pair[0]) as u16, number(&pair[1]) as u8))
+}
+
+fn number(value: &Value) -> u64 {
+    value
+        .as_u64()
+        .unwrap_or_else(|| panic!("expected a number, got {value:?}"))
+}
@@ -0,0 +1,239 @@
+//! Web frontend for gb-rs: the screen is drawn on a canvas, the sound is played with WebAudio and
+//! the buttons are read from the keyboard.
+//!
+//! Build it with `wasm-pack build --target web` in this directory, serve the directory with any
+//! static web server and open `index.html`.
+use std::{num::NonZeroU32, ops::ControlFlow, time::Duration};
+
+use gb_rs::{
+    cartridge::Cartridge,
+    frontend::{self, lcd_off_color},
+    gameboy::GameBoy,
+    joypad::Button,
+    machine::{BootRom, MachineConfig},
+    platform::Platform,
+    AudioSink, DmgPalette, FrameSink, FRAME_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+use wasm_bindgen::{prelude::*, Clamped};
+use web_sys::{AudioContext, CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+
+/// Sample rate of the audio played through WebAudio
+const SAMPLE_RATE: u32 = 44100;
+/// Factor converting the 16-bit samples produced by the APU to WebAudio samples between -1.0 and
+/// 1.0, which gives the same level as the desktop frontend at 100% volume.
+const VOLUME: f32 = 1.0 / 32768.0;
+/// Longest time emulated at once, so that the emulator doesn't try to catch up with the time
+/// spent in a background tab
+const MAX_ELAPSED: Duration = Duration::from_millis(100);
+/// How far ahead the audio can be scheduled before samples are dropped, in seconds
+const MAX_AUDIO_LATENCY: f64 = 0.2;
+
+/// A Game Boy running in a web page.
+#[wasm_bindgen]
+pub struct WebEmulator {
+    emulator: frontend::Emulator<CanvasSink, WebAudioSink>,
+    canvas: CanvasRenderingContext2d,
+    /// Title of the game, from the cartridge header
+    title: String,
+    /// Timestamp of the last call to `frame()`, in milliseconds
+    last_timestamp: Option<f64>,
+}
+
+#[wasm_bindgen]
+impl WebEmulator {
+    /// Insert the given ROM and draw the screen on `canvas`, which should be 160x144.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: Vec<u8>, canvas: HtmlCanvasElement) -> Result<WebEmulator, JsError> {
+        let cartridge = Cartridge::from_bytes(rom);
+        if let Some(problem) = cartridge.check_header().into_iter().find(|p| p.is_fatal()) {
+            return Err(JsError::new(&problem.to_string()));
+        }
+        let title = cartridge.header().title.clone();
+        let config = MachineConfig::default().boot_rom(BootRom::Skip);
+        let palette = config.palette;
+        let mut gb = GameBoy::with_platform(cartridge, config, WebPlatform)
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        gb.set_sample_rate(NonZeroU32::new(SAMPLE_RATE).unwrap());
+
+        let canvas = canvas
+            .get_context("2d")
+            .ok()
+            .flatten()
+            .and_then(|ctx| ctx.dyn_into::<CanvasRenderingContext2d>().ok())
+            .ok_or_else(|| JsError::new("Can't get a 2D context for the canvas"))?;
+        let audio = AudioContext::new().map_err(|_| JsError::new("WebAudio isn't available"))?;
+
+        Ok(Self {
+            emulator: frontend::Emulator::new(
+                gb,
+                CanvasSink::new(palette),
+                WebAudioSink::new(audio),
+            ),
+            canvas,
+            title,
+            last_timestamp: None,
+        })
+    }
+
+    /// Title of the game, from the cartridge header.
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    /// Run the emulation up to `timestamp` (in milliseconds, as passed to the callbacks of
+    /// `requestAnimationFrame()`), then draw the screen and queue the sound.
+    pub fn frame(&mut self, timestamp: f64) -> Result<(), JsValue> {
+        let elapsed = self
+            .last_timestamp
+            .map_or(Duration::ZERO, |last| {
+                Duration::from_secs_f64((timestamp - last).max(0.0) / 1000.0)
+            })
+            .min(MAX_ELAPSED);
+        self.last_timestamp = Some(timestamp);
+
+        let _ = self
+            .emulator
+            .run_for(elapsed, 1.0, |_, _| ControlFlow::Continue(()));
+        self.emulator.audio_sink_mut().flush()?;
+
+        let sink = self.emulator.frame_sink_mut();
+        if std::mem::take(&mut sink.new_frame) {
+            let image = ImageData::new_with_u8_clamped_array_and_sh(
+                Clamped(&sink.buf),
+                SCREEN_WIDTH as u32,
+                SCREEN_HEIGHT as u32,
+            )?;
+            self.canvas.put_image_data(&image, 0.0, 0.0)?;
+        }
+        Ok(())
+    }
+
+    /// Press or release the button mapped to the given key (a `KeyboardEvent.code`). Returns
+    /// whether the key is mapped, i.e. if the event's default action should be prevented.
+    pub fn key(&mut self, code: &str, pressed: bool) -> bool {
+        let button = match code {
+            "ArrowUp" => Button::Up,
+            "ArrowDown" => Button::Down,
+            "ArrowLeft" => Button::Left,
+            "ArrowRight" => Button::Right,
+            "KeyA" => Button::A,
+            "KeyB" => Button::B,
+            "Enter" => Button::Start,
+            "Space" => Button::Select,
+            _ => return false,
+        };
+        self.emulator.set_button_pressed(button, pressed);
+        true
+    }
+}
+
+/// Keeps the most recent frame, to be drawn on the canvas.
+struct CanvasSink {
+    /// RGBA pixels of the frame, which is what `ImageData` expects
+    buf: Box<[u8]>,
+    /// Whether `buf` changed since it was last drawn
+    new_frame: bool,
+    /// Colour of the screen when the LCD is turned off
+    lcd_off_color: [u8; 4],
+}
+
+impl CanvasSink {
+    fn new(palette: DmgPalette) -> Self {
+        Self {
+            buf: vec![0; FRAME_SIZE].into_boxed_slice(),
+            new_frame: true,
+            lcd_off_color: lcd_off_color(palette),
+        }
+    }
+}
+
+impl FrameSink for CanvasSink {
+    fn push_frame(&mut self, frame: &[u8]) {
+        self.buf.copy_from_slice(frame);
+        self.new_frame = true;
+    }
+
+    fn lcd_power_changed(&mut self, enabled: bool) {
+        if !enabled {
+            for pixel in self.buf.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&self.lcd_off_color);
+            }
+            self.new_frame = true;
+        }
+    }
+}
+
+/// Collects the samples and plays them with WebAudio, in a buffer per animation frame scheduled
+/// right after the previous one.
+struct WebAudioSink {
+    context: AudioContext,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    /// Time at which the next buffer should start, in the audio context's clock (seconds)
+    next_start: f64,
+}
+
+impl WebAudioSink {
+    fn new(context: AudioContext) -> Self {
+        Self {
+            context,
+            left: Vec::new(),
+            right: Vec::new(),
+            next_start: 0.0,
+        }
+    }
+
+    /// Play the samples collected so far.
+    fn flush(&mut self) -> Result<(), JsValue> {
+        if self.left.is_empty() {
+            return Ok(());
+        }
+        let now = self.context.current_time();
+        if self.next_start > now + MAX_AUDIO_LATENCY {
+            // We're producing samples faster than they're played: drop these ones
+            self.left.clear();
+            self.right.clear();
+            return Ok(());
+        }
+        let buffer = self
+            .context
+            .create_buffer(2, self.left.len() as u32, SAMPLE_RATE as f32)?;
+        buffer.copy_to_channel(&self.left, 0)?;
+        buffer.copy_to_channel(&self.right, 1)?;
+        let source = self.context.create_buffer_source()?;
+        source.set_buffer(Some(&buffer));
+        source.connect_with_audio_node(&self.context.destination())?;
+        let start = self.next_start.max(now);
+        source.start_with_when(start)?;
+        self.next_start = start + buffer.duration();
+
+        self.left.clear();
+        self.right.clear();
+        Ok(())
+    }
+}
+
+impl AudioSink for WebAudioSink {
+    fn push_samples(&mut self, samples: &[i16]) {
+        // The samples are interleaved: left, right, left, right...
+        for pair in samples.chunks_exact(2) {
+            self.left.push(pair[0] as f32 * VOLUME);
+            self.right.push(pair[1] as f32 * VOLUME);
+        }
+    }
+}
+
+/// Time and entropy from the browser, as `std` can't get them on `wasm32-unknown-unknown`.
+struct WebPlatform;
+
+impl Platform for WebPlatform {
+    fn unix_time(&mut self) -> u64 {
+        (js_sys::Date::now() / 1000.0) as u64
+    }
+
+    fn fill_random(&mut self, buf: &mut [u8]) {
+        for b in buf {
+            *b = (js_sys::Math::random() * 256.0) as u8;
+        }
+    }
+}